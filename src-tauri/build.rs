@@ -1,3 +1,77 @@
 fn main() {
-    tauri_build::build()
+    // Every app command must be listed here and granted through a permission set in
+    // permissions/, otherwise no window can invoke it
+    tauri_build::try_build(tauri_build::Attributes::new().app_manifest(
+        tauri_build::AppManifest::new().commands(&[
+            "connect_vpn",
+            "preview_connect",
+            "connect_with_failover",
+            "switch_exit_server",
+            "disconnect_vpn",
+            "get_vpn_status",
+            "get_current_connection",
+            "get_forwarding_stats",
+            "get_split_route_stats",
+            "get_tunnel_info",
+            "get_disconnect_reason",
+            "get_error_history",
+            "get_event_log",
+            "get_connection_stats",
+            "get_active_policy",
+            "export_usage",
+            "benchmark_mtu",
+            "benchmark_dns",
+            "probe_via_interface",
+            "check_lan_safety",
+            "enable_connection_sharing",
+            "disable_connection_sharing",
+            "set_lan_bypass",
+            "get_connection_sharing",
+            "check_network_environment",
+            "get_network_environment",
+            "run_preflight_checks",
+            "get_onboarding_state",
+            "complete_onboarding_step",
+            "get_managed_policy",
+            "get_settings",
+            "update_settings",
+            "set_api_environment",
+            "set_hotkeys",
+            "save_profile",
+            "delete_profile",
+            "set_active_profile",
+            "get_presets",
+            "fetch_presets",
+            "save_preset",
+            "delete_preset",
+            "apply_preset",
+            "get_preset_servers",
+            "get_schedules",
+            "save_schedule",
+            "delete_schedule",
+            "get_schedule_preview",
+            "fetch_servers",
+            "fetch_dedicated_servers",
+            "get_servers_enriched",
+            "get_servers_view",
+            "get_server_latencies",
+            "get_latency_map",
+            "get_recommended_servers",
+            "set_server_annotation",
+            "set_server_favorite",
+            "generate_config",
+            "import_wg_config",
+            "prewarm_server",
+            "cancel_api_request",
+            "get_devices",
+            "disconnect_device",
+            "store_credentials",
+            "has_credentials",
+            "store_device_key",
+            "clear_credentials",
+            "get_mac_address",
+            "get_device_fingerprint",
+        ]),
+    ))
+    .expect("failed to run tauri-build");
 }
@@ -0,0 +1,95 @@
+//! Link-speed aware data-path tuning
+//!
+//! The default socket buffers and batch size suit a typical broadband link;
+//! on a gigabit link they cap throughput well below what the tunnel could
+//! carry. After connecting from a network for the first time its throughput is
+//! measured, and the buffers are sized to the bandwidth-delay product and the
+//! batch to the packet rate. Tuning is remembered per network and only ever
+//! raises what the settings ask for.
+
+use super::TunnelTuning;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Smallest socket buffer worth setting; below this the OS default is as good
+const MIN_BUFFER: usize = 256 * 1024;
+
+/// Largest socket buffer requested; most systems cap lower anyway
+const MAX_BUFFER: usize = 16 * 1024 * 1024;
+
+/// The measurement runs with the old buffers and may be limited by them, so
+/// the buffers get room for twice the bandwidth-delay product
+const BUFFER_HEADROOM: u64 = 2;
+
+/// Packet size the batch size is computed for
+const PACKET_SIZE: u64 = 1420;
+
+const MIN_BATCH: usize = 32;
+const MAX_BATCH: usize = 256;
+
+/// Data-path tuning derived from a throughput measurement on one network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkTuning {
+    /// Measured download throughput in bytes per second
+    pub throughput: u64,
+    pub socket_recv_buffer: usize,
+    pub socket_send_buffer: usize,
+    pub batch_size: usize,
+    /// Unix timestamp of the measurement
+    pub measured_at: i64,
+}
+
+impl LinkTuning {
+    /// Tuning for a link carrying `throughput` bytes per second with round trip `rtt`
+    pub fn for_link(throughput: u64, rtt: Duration, measured_at: i64) -> Self {
+        let bdp = throughput.saturating_mul(rtt.as_millis() as u64) / 1000;
+        let buffer = (bdp.saturating_mul(BUFFER_HEADROOM) as usize).clamp(MIN_BUFFER, MAX_BUFFER);
+        // Enough packets per iteration to drain a millisecond of traffic
+        let per_ms = (throughput / PACKET_SIZE / 1000) as usize;
+        let batch_size = per_ms.next_power_of_two().clamp(MIN_BATCH, MAX_BATCH);
+
+        Self {
+            throughput,
+            socket_recv_buffer: buffer,
+            socket_send_buffer: buffer,
+            batch_size,
+            measured_at,
+        }
+    }
+
+    /// Raise the data-path settings of `tuning` to this link's, keeping any
+    /// that are already larger
+    pub fn apply(&self, tuning: &mut TunnelTuning) {
+        let raise = |current: Option<usize>, tuned: usize| Some(current.unwrap_or(0).max(tuned));
+        tuning.socket_recv_buffer = raise(tuning.socket_recv_buffer, self.socket_recv_buffer);
+        tuning.socket_send_buffer = raise(tuning.socket_send_buffer, self.socket_send_buffer);
+        tuning.batch_size = tuning.batch_size.max(self.batch_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tuning_scales_with_the_link() {
+        // 1 Gbit/s at 40 ms: 5 MB in flight
+        let gigabit = LinkTuning::for_link(125_000_000, Duration::from_millis(40), 0);
+        assert_eq!(gigabit.socket_recv_buffer, 10_000_000);
+        assert_eq!(gigabit.batch_size, 128);
+
+        // Slow links keep the floor
+        let slow = LinkTuning::for_link(1_250_000, Duration::from_millis(40), 0);
+        assert_eq!(slow.socket_send_buffer, MIN_BUFFER);
+        assert_eq!(slow.batch_size, MIN_BATCH);
+
+        let mut tuning = TunnelTuning {
+            socket_recv_buffer: Some(32 * 1024 * 1024),
+            ..TunnelTuning::default()
+        };
+        gigabit.apply(&mut tuning);
+        assert_eq!(tuning.socket_recv_buffer, Some(32 * 1024 * 1024));
+        assert_eq!(tuning.socket_send_buffer, Some(10_000_000));
+        assert_eq!(tuning.batch_size, 128);
+    }
+}
@@ -0,0 +1,267 @@
+//! Tunnel backends
+//!
+//! A backend is one way of running the WireGuard tunnel: the embedded
+//! boringtun data path over a TUN device on every platform, plus the
+//! WireGuardNT kernel driver on Windows and wg-quick on Linux. The manager
+//! tries the candidates for the current settings in order and keeps the first
+//! that comes up; what surrounds them (the tunnel lock, routes, MTU changes)
+//! lives once in `WireGuardManager`.
+
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+use super::embedded::EmbeddedBackend;
+use super::network::{self, InterfaceState};
+use super::polling::ForwardingCounters;
+use super::routes::Prefix;
+use super::simulate::{self, SimulatedBackend};
+#[cfg(target_os = "windows")]
+use super::wgnt::KernelBackend;
+#[cfg(target_os = "linux")]
+use super::wgquick::{self, WgQuickBackend};
+#[cfg(target_os = "windows")]
+use super::WindowsBackend;
+use super::{PeerConfig, TunnelTuning, VpnConfig, VpnError};
+use futures::future::BoxFuture;
+use std::sync::Arc;
+
+/// How long to wait for the first handshake before giving up on a connect
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+pub fn handshake_timeout(tuning: &TunnelTuning) -> std::time::Duration {
+    std::time::Duration::from_secs(tuning.handshake_timeout_secs.max(1))
+}
+
+pub trait TunnelBackend: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Bring the tunnel up and wait for the first handshake; routing is left
+    /// to the caller
+    fn connect<'a>(
+        &'a mut self,
+        config: &'a VpnConfig,
+        tuning: &'a TunnelTuning,
+        traffic_padding: bool,
+    ) -> BoxFuture<'a, Result<(), VpnError>>;
+
+    /// Take the tunnel down, including whatever a failed connect left behind
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), VpnError>>;
+
+    /// OS name of the tunnel interface, once it exists
+    fn interface(&self) -> Option<String>;
+
+    /// Local UDP port the tunnel sends from
+    fn listen_port(&self) -> Option<u16>;
+
+    /// Bytes received and sent since connecting
+    fn transfer_stats(&self) -> Result<(u64, u64), VpnError>;
+
+    /// State of the tunnel interface as the OS reports it, once it exists and
+    /// where the OS can be asked
+    fn interface_state(&self) -> Option<InterfaceState> {
+        network::interface_state(&self.interface()?)
+    }
+
+    /// Detect a data path that died while the tunnel should be up
+    fn check_health(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Keepalive interval the adaptive tuner has settled on, if it is running
+    fn keepalive_interval(&self) -> BoxFuture<'_, Option<u16>> {
+        Box::pin(async { None })
+    }
+
+    /// Apply the socket buffers and batch size of `tuning` to the running tunnel
+    fn retune<'a>(&'a self, _tuning: &'a TunnelTuning) -> BoxFuture<'a, ()> {
+        Box::pin(async {})
+    }
+
+    /// For each peer in config order, how long data sent to it has gone
+    /// unanswered; empty when the data path isn't in this process
+    fn unanswered(&self) -> BoxFuture<'_, Vec<Option<std::time::Duration>>> {
+        Box::pin(async { Vec::new() })
+    }
+
+    /// Send peer `index`'s traffic to `endpoint` from now on and handshake with
+    /// it there, leaving the interface up
+    fn switch_endpoint(
+        &self,
+        _index: usize,
+        _endpoint: std::net::SocketAddr,
+    ) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async { Err(VpnError::PlatformNotSupported) })
+    }
+
+    /// Whether a connect failing with `error` should move on to the next
+    /// candidate; by default only when this backend may not run here at all
+    fn falls_back_on(&self, error: &VpnError) -> bool {
+        matches!(error, VpnError::PermissionDenied(_))
+    }
+}
+
+/// Backends to try for the tunnel `name`, in order
+pub fn candidates(
+    name: &str,
+    tuning: &TunnelTuning,
+    traffic_padding: bool,
+    forwarding: &Arc<ForwardingCounters>,
+) -> Vec<Box<dyn TunnelBackend>> {
+    if simulate::enabled() {
+        return vec![Box::new(SimulatedBackend::new(name))];
+    }
+    platform_candidates(name, tuning, traffic_padding, forwarding)
+}
+
+#[cfg(target_os = "windows")]
+fn platform_candidates(
+    name: &str,
+    tuning: &TunnelTuning,
+    traffic_padding: bool,
+    forwarding: &Arc<ForwardingCounters>,
+) -> Vec<Box<dyn TunnelBackend>> {
+    let mut backends: Vec<Box<dyn TunnelBackend>> = Vec::new();
+    if tuning.backend == WindowsBackend::Kernel {
+        if traffic_padding {
+            log::info!("Traffic padding needs the embedded tunnel, not using WireGuardNT");
+        } else {
+            backends.push(Box::new(KernelBackend::new(name)));
+        }
+    }
+    backends.push(Box::new(EmbeddedBackend::new(name, forwarding.clone())));
+    backends
+}
+
+/// wg-quick covers for the embedded tunnel when this process may not create
+/// network interfaces
+#[cfg(target_os = "linux")]
+fn platform_candidates(
+    name: &str,
+    _tuning: &TunnelTuning,
+    _traffic_padding: bool,
+    forwarding: &Arc<ForwardingCounters>,
+) -> Vec<Box<dyn TunnelBackend>> {
+    let mut backends: Vec<Box<dyn TunnelBackend>> =
+        vec![Box::new(EmbeddedBackend::new(name, forwarding.clone()))];
+    if wgquick::installed() {
+        backends.push(Box::new(WgQuickBackend::new(name)));
+    }
+    backends
+}
+
+#[cfg(target_os = "macos")]
+fn platform_candidates(
+    name: &str,
+    _tuning: &TunnelTuning,
+    _traffic_padding: bool,
+    forwarding: &Arc<ForwardingCounters>,
+) -> Vec<Box<dyn TunnelBackend>> {
+    vec![Box::new(EmbeddedBackend::new(name, forwarding.clone()))]
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn platform_candidates(
+    _name: &str,
+    _tuning: &TunnelTuning,
+    _traffic_padding: bool,
+    _forwarding: &Arc<ForwardingCounters>,
+) -> Vec<Box<dyn TunnelBackend>> {
+    Vec::new()
+}
+
+/// What the backends need from a config, checked before touching the system
+#[cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+pub struct TunnelParams {
+    pub private_key: [u8; 32],
+    /// In config order; a dual-stack server gives one of each family
+    pub addresses: Vec<Prefix>,
+    /// In config order, so the first is the primary peer
+    pub peers: Vec<PeerParams>,
+}
+
+/// What the backends need from one peer of a config
+#[cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+pub struct PeerParams {
+    pub public_key: [u8; 32],
+    pub endpoint: std::net::SocketAddr,
+    pub allowed_ips: Vec<Prefix>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl TunnelParams {
+    pub fn parse(config: &VpnConfig) -> Result<Self, VpnError> {
+        use base64::Engine;
+
+        // Parse private key
+        let private_key_bytes = base64::engine::general_purpose::STANDARD
+            .decode(&config.interface.private_key)
+            .map_err(|e| VpnError::ConfigError(format!("Invalid private key: {}", e)))?;
+
+        let private_key: [u8; 32] = private_key_bytes
+            .try_into()
+            .map_err(|_| VpnError::ConfigError("Private key must be 32 bytes".to_string()))?;
+
+        // Parse client IPs; a bare address is a host prefix
+        let addresses = config
+            .interface
+            .addresses()
+            .map(|address| address.parse())
+            .collect::<Result<Vec<Prefix>, _>>()?;
+        if addresses.is_empty() {
+            return Err(VpnError::ConfigError("Config has no address".to_string()));
+        }
+
+        let peers = config
+            .peers
+            .iter()
+            .map(PeerParams::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if peers.is_empty() {
+            return Err(VpnError::ConfigError("Config has no peers".to_string()));
+        }
+
+        Ok(Self {
+            private_key,
+            addresses,
+            peers,
+        })
+    }
+}
+
+impl PeerParams {
+    fn parse(peer: &PeerConfig) -> Result<Self, VpnError> {
+        use base64::Engine;
+
+        let public_key_bytes = base64::engine::general_purpose::STANDARD
+            .decode(&peer.public_key)
+            .map_err(|e| VpnError::ConfigError(format!("Invalid peer public key: {}", e)))?;
+
+        let public_key: [u8; 32] = public_key_bytes
+            .try_into()
+            .map_err(|_| VpnError::ConfigError("Peer public key must be 32 bytes".to_string()))?;
+
+        // Hostnames were resolved before the backend was started
+        let endpoint = peer.endpoint.parse().map_err(|_| {
+            VpnError::ConfigError(format!("Endpoint {} is not an address", peer.endpoint))
+        })?;
+
+        let allowed_ips = peer
+            .allowed_ips
+            .iter()
+            .map(|prefix| prefix.parse())
+            .collect::<Result<Vec<Prefix>, _>>()?;
+
+        Ok(Self {
+            public_key,
+            endpoint,
+            allowed_ips,
+            persistent_keepalive: peer
+                .persistent_keepalive
+                .map(|secs| secs.min(u16::MAX.into()) as u16),
+        })
+    }
+}
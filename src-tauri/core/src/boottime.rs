@@ -0,0 +1,73 @@
+//! Boot clock for session timers
+//!
+//! `Instant` stops while macOS and Windows sleep, so a session timer built on
+//! it falls behind across a suspend and limits like the rotation interval
+//! stretch by however long the machine slept. This clock keeps counting
+//! through sleep, yet unlike `SystemTime` it isn't moved by changes to the
+//! wall clock.
+
+use std::ops::Add;
+use std::time::Duration;
+
+/// A point in time measured from boot, time spent asleep included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BootInstant(Duration);
+
+impl BootInstant {
+    pub fn now() -> Self {
+        Self(since_boot())
+    }
+
+    /// Time from `earlier` to this instant, zero if `earlier` is later
+    pub fn saturating_duration_since(&self, earlier: BootInstant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Time since this instant, sleep included
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+}
+
+impl Add<Duration> for BootInstant {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        Self(self.0 + duration)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn since_boot() -> Duration {
+    clock(libc::CLOCK_BOOTTIME)
+}
+
+// Unlike CLOCK_UPTIME_RAW, which `Instant` uses, this one counts sleep
+#[cfg(target_os = "macos")]
+fn since_boot() -> Duration {
+    clock(libc::CLOCK_MONOTONIC)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn clock(id: libc::clockid_t) -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec for the call to fill in
+    unsafe { libc::clock_gettime(id, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+// Counts sleep and hibernation, unlike the performance counter behind `Instant`
+#[cfg(target_os = "windows")]
+fn since_boot() -> Duration {
+    // SAFETY: takes no arguments and can't fail
+    Duration::from_millis(unsafe { windows::Win32::System::SystemInformation::GetTickCount64() })
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn since_boot() -> Duration {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed()
+}
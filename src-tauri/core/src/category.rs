@@ -0,0 +1,120 @@
+//! Windows network category of the tunnel adapter
+//!
+//! Windows files every network under Public or Private, and the category picks
+//! the firewall profile and whether file sharing and network discovery are on.
+//! A new adapter's category depends on group policy and on what the user once
+//! chose for a network that looked the same, so it is set explicitly once
+//! Windows has identified the tunnel's network.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkCategory {
+    /// Firewall's public profile: no file sharing or discovery over the tunnel
+    #[default]
+    Public,
+    /// Firewall's private profile, for reaching shares on a company network
+    Private,
+}
+
+/// How long Windows gets to identify the tunnel's network
+#[cfg(target_os = "windows")]
+const IDENTIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[cfg(target_os = "windows")]
+const IDENTIFY_POLL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Set the category of the network on `interface`, waiting for Windows to identify it
+#[cfg(target_os = "windows")]
+pub async fn apply(interface: String, category: NetworkCategory) {
+    let adapter = match nlm::adapter_guid(&interface) {
+        Ok(adapter) => adapter,
+        Err(e) => {
+            log::warn!("Failed to look up adapter {}: {}", interface, e);
+            return;
+        }
+    };
+
+    let deadline = std::time::Instant::now() + IDENTIFY_TIMEOUT;
+    while std::time::Instant::now() < deadline {
+        match tokio::task::spawn_blocking(move || nlm::set_category(adapter, category)).await {
+            Ok(Ok(true)) => {
+                log::info!("Network category of {} set to {:?}", interface, category);
+                return;
+            }
+            Ok(Ok(false)) => tokio::time::sleep(IDENTIFY_POLL).await,
+            Ok(Err(e)) => {
+                log::warn!("Failed to set network category of {}: {}", interface, e);
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+    log::warn!(
+        "Windows did not identify the network on {} within {:?}; category left as is",
+        interface,
+        IDENTIFY_TIMEOUT
+    );
+}
+
+#[cfg(target_os = "windows")]
+mod nlm {
+    use super::NetworkCategory;
+    use windows::core::{GUID, HSTRING};
+    use windows::Win32::NetworkManagement::IpHelper::{
+        ConvertInterfaceAliasToLuid, ConvertInterfaceLuidToGuid,
+    };
+    use windows::Win32::NetworkManagement::Ndis::NET_LUID_LH;
+    use windows::Win32::Networking::NetworkListManager::{
+        INetworkListManager, NetworkListManager, NLM_NETWORK_CATEGORY_PRIVATE,
+        NLM_NETWORK_CATEGORY_PUBLIC,
+    };
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL, COINIT_MULTITHREADED,
+    };
+
+    pub fn adapter_guid(interface: &str) -> windows::core::Result<GUID> {
+        let mut luid = NET_LUID_LH::default();
+        let mut guid = GUID::zeroed();
+        unsafe {
+            ConvertInterfaceAliasToLuid(&HSTRING::from(interface), &mut luid).ok()?;
+            ConvertInterfaceLuidToGuid(&luid, &mut guid).ok()?;
+        }
+        Ok(guid)
+    }
+
+    /// Set the category of the network on `adapter`; `Ok(false)` while Windows
+    /// has no network for it yet
+    pub fn set_category(adapter: GUID, category: NetworkCategory) -> windows::core::Result<bool> {
+        unsafe {
+            CoInitializeEx(None, COINIT_MULTITHREADED).ok()?;
+            let result = set(adapter, category);
+            CoUninitialize();
+            result
+        }
+    }
+
+    unsafe fn set(adapter: GUID, category: NetworkCategory) -> windows::core::Result<bool> {
+        let manager: INetworkListManager = CoCreateInstance(&NetworkListManager, None, CLSCTX_ALL)?;
+        let connections = manager.GetNetworkConnections()?;
+        loop {
+            let mut connection = [None];
+            let mut fetched = 0u32;
+            connections.Next(&mut connection, Some(&mut fetched)).ok()?;
+            let Some(connection) = connection[0].take().filter(|_| fetched == 1) else {
+                return Ok(false);
+            };
+            if connection.GetAdapterId()? != adapter {
+                continue;
+            }
+
+            let network = connection.GetNetwork()?;
+            network.SetCategory(match category {
+                NetworkCategory::Public => NLM_NETWORK_CATEGORY_PUBLIC,
+                NetworkCategory::Private => NLM_NETWORK_CATEGORY_PRIVATE,
+            })?;
+            return Ok(true);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Minimal DNS wire format helpers
+//!
+//! Just enough of RFC 1035 to send A/AAAA queries and read the answers back.
+
+use super::VpnError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsAnswer {
+    pub address: IpAddr,
+    pub ttl: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsResponse {
+    pub id: u16,
+    pub rcode: u8,
+    pub answers: Vec<DnsAnswer>,
+}
+
+/// Build a recursive query for a single name
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(32 + name.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00]); // RD
+    packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x01]); // IN
+    packet
+}
+
+/// Parse a response, collecting A and AAAA answers
+pub fn parse_response(packet: &[u8]) -> Option<DnsResponse> {
+    if packet.len() < 12 {
+        return None;
+    }
+
+    let id = u16::from_be_bytes([packet[0], packet[1]]);
+    let rcode = packet[3] & 0x0f;
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    let ancount = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(packet, pos)? + 4;
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(packet, pos)?;
+        let header = packet.get(pos..pos + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        pos += 10;
+
+        let rdata = packet.get(pos..pos + rdlen)?;
+        let address = match (rtype, rdlen) {
+            (TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(
+                rdata[0], rdata[1], rdata[2], rdata[3],
+            ))),
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        };
+        if let Some(address) = address {
+            answers.push(DnsAnswer { address, ttl });
+        }
+        pos += rdlen;
+    }
+
+    Some(DnsResponse { id, rcode, answers })
+}
+
+/// Name asked about in the first question of a query
+pub fn question_name(packet: &[u8]) -> Option<String> {
+    if u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]) == 0 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some(labels.join("."));
+        }
+        // Questions are never compressed; anything else is malformed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+    }
+}
+
+/// Return the offset just past a (possibly compressed) name
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += 1 + len as usize;
+    }
+}
+
+/// Send a single UDP query and wait for the matching response
+pub async fn query(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> Result<DnsResponse, VpnError> {
+    let bind_addr = if server.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = tokio::net::UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|e| VpnError::ConnectionFailed(format!("DNS socket error: {}", e)))?;
+    query_on(&socket, server, name, qtype, timeout).await
+}
+
+/// Send a single query from `socket` and wait for the matching response
+pub async fn query_on(
+    socket: &tokio::net::UdpSocket,
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> Result<DnsResponse, VpnError> {
+    let id: u16 = rand::random();
+    socket
+        .send_to(&build_query(id, name, qtype), server)
+        .await
+        .map_err(|e| VpnError::ConnectionFailed(format!("DNS send failed: {}", e)))?;
+
+    let mut buf = [0u8; 1500];
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let (n, from) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf))
+            .await
+            .map_err(|_| VpnError::ConnectionFailed(format!("DNS query to {} timed out", server)))?
+            .map_err(|e| VpnError::ConnectionFailed(format!("DNS receive failed: {}", e)))?;
+
+        if from != server {
+            continue;
+        }
+        match parse_response(&buf[..n]) {
+            Some(response) if response.id == id => return Ok(response),
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compressed_answer() {
+        let mut packet = build_query(0x1234, "example.com", TYPE_A);
+        // Turn the query into a response with one answer
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = 0x01;
+        packet.extend_from_slice(&[0xc0, 0x0c]); // pointer to the question name
+        packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        packet.extend_from_slice(&300u32.to_be_bytes());
+        packet.extend_from_slice(&[0x00, 0x04, 93, 184, 216, 34]);
+
+        let response = parse_response(&packet).unwrap();
+        assert_eq!(question_name(&packet).as_deref(), Some("example.com"));
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode, 0);
+        assert_eq!(
+            response.answers,
+            vec![DnsAnswer {
+                address: IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+                ttl: 300,
+            }]
+        );
+    }
+}
@@ -0,0 +1,1347 @@
+//! Embedded WireGuard: boringtun over a TUN device
+//!
+//! The tunnel runs in this process, so nothing beyond the TUN driver needs to
+//! be installed: a wintun adapter on Windows, /dev/net/tun on Linux and utun
+//! on macOS. A forwarding task moves packets between the device and the UDP
+//! socket, while a timer task sends keepalives and rekeys.
+//!
+//! Every peer has its own WireGuard session over the one socket. Outbound
+//! packets go to the peer whose AllowedIPs match the destination most
+//! specifically, and datagrams are matched to a peer by the address they came from.
+//! The socket is dual-stack where the system allows, so IPv4 and IPv6
+//! endpoints can be mixed.
+
+use super::backend::{self, TunnelBackend, TunnelParams};
+#[cfg(target_os = "windows")]
+use super::category;
+use super::keepalive::KeepaliveTuner;
+use super::mss;
+use super::padding::{self, DecoySchedule};
+use super::pmtu;
+use super::polling::{ErrorBudget, ForwardingCounters, ForwardingError};
+use super::routes::Prefix;
+use super::tun::TunDevice;
+use super::wireguard::DEFAULT_MTU;
+use super::{TunnelTuning, VpnConfig, VpnError};
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Handshakes accepted per second before boringtun answers with cookie replies
+const HANDSHAKE_RATE_LIMIT: u64 = 100;
+
+/// How often the rate limiter's handshake count is reset
+const RATE_LIMITER_RESET: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Interval of WireGuard timer maintenance (keepalives, rekeys, handshake retries)
+const TIMER_TICK: std::time::Duration = std::time::Duration::from_millis(250);
+
+struct EmbeddedTunnel {
+    device: TunDevice,
+    rate_limiter: Arc<boringtun::noise::rate_limiter::RateLimiter>,
+    /// Shared by all peers; datagrams are sent to and matched by their endpoints
+    socket: PeerSocket,
+    /// In config order, so the first is the primary peer
+    peers: Vec<TunnelPeer>,
+    running: Arc<AtomicBool>,
+    /// Set when traffic padding is on; packets are padded up to at most `mtu`
+    decoys: Option<DecoySchedule>,
+    mtu: usize,
+    /// Rewrite the MSS of TCP SYNs in both directions to fit `mtu`
+    mss_clamp: bool,
+    /// Packets handled per forwarding loop iteration
+    batch_size: usize,
+    /// The only source addresses packets from the TUN may carry
+    addresses: Vec<std::net::IpAddr>,
+}
+
+/// The WireGuard session with one peer
+struct TunnelPeer {
+    tunnel: boringtun::noise::Tunn,
+    endpoint: std::net::SocketAddr,
+    /// Destinations sent to this peer and sources it may decrypt packets from
+    /// (cryptokey routing)
+    allowed_ips: Vec<Prefix>,
+    /// Sends keepalives in place of boringtun when adaptive keepalive is on
+    keepalive: Option<KeepaliveTuner>,
+    /// When data was first sent to the peer after it last answered
+    awaiting_reply_since: Option<std::time::Instant>,
+    /// Last error from the timers, so an idle tunnel that keeps failing the
+    /// same way is logged once rather than every tick; cleared when the peer
+    /// answers
+    timer_error: Option<String>,
+}
+
+pub struct EmbeddedBackend {
+    /// Tunnel name, which is also the interface name except on macOS
+    name: String,
+    /// utun interface the kernel numbered for the current tunnel
+    #[cfg(target_os = "macos")]
+    interface: Option<String>,
+    bytes_received: Arc<AtomicU64>,
+    bytes_sent: Arc<AtomicU64>,
+    forwarding: Arc<ForwardingCounters>,
+    /// Local UDP port the connected tunnel sends from
+    listen_port: Option<u16>,
+    /// Set while the tunnel is up
+    tunnel_handle: Option<Arc<tokio::sync::Mutex<EmbeddedTunnel>>>,
+    /// Forwarding and timer tasks of the current tunnel, checked by `check_health`
+    tasks: Vec<tokio::task::JoinHandle<Result<(), String>>>,
+    /// /etc/resolv.conf as it was before the tunnel's DNS replaced it
+    #[cfg(target_os = "linux")]
+    resolv_conf: Option<ResolvConf>,
+}
+
+impl EmbeddedBackend {
+    pub fn new(name: &str, forwarding: Arc<ForwardingCounters>) -> Self {
+        Self {
+            name: name.to_string(),
+            #[cfg(target_os = "macos")]
+            interface: None,
+            bytes_received: Arc::new(AtomicU64::new(0)),
+            bytes_sent: Arc::new(AtomicU64::new(0)),
+            forwarding,
+            listen_port: None,
+            tunnel_handle: None,
+            tasks: Vec::new(),
+            #[cfg(target_os = "linux")]
+            resolv_conf: None,
+        }
+    }
+
+    /// Create the wintun adapter with the tunnel's address, DNS and MTU and
+    /// start a session on it
+    #[cfg(target_os = "windows")]
+    fn open_device(
+        &mut self,
+        params: &TunnelParams,
+        config: &VpnConfig,
+        tuning: &TunnelTuning,
+    ) -> Result<TunDevice, VpnError> {
+        // Load wintun driver from app directory
+        log::info!("Loading wintun driver...");
+
+        let wintun = super::wireguard::load_wintun()?;
+
+        // Create adapter
+        log::info!("Creating network adapter '{}'...", self.name);
+        let adapter = wintun::Adapter::create(&wintun, &self.name, "SACVPN", None)
+            .map_err(|e| super::wireguard::adapter_error("wintun", "create the VPN adapter", e))?;
+
+        // Set adapter IP address
+        for address in &params.addresses {
+            log::info!("Configuring adapter with IP {}...", address);
+            super::wireguard::configure_adapter_ip(&self.name, address)?;
+        }
+        super::wireguard::configure_adapter_dns(&self.name, &config.interface.dns)?;
+        if let Some(mtu) = config.interface.mtu {
+            if let Err(e) = super::wireguard::set_interface_mtu(&self.name, mtu) {
+                log::warn!("{}", e);
+            }
+        }
+
+        // Start session (wrapped in Arc as required by wintun API)
+        let capacity = ring_capacity(tuning.ring_capacity);
+        log::info!("Starting session with ring capacity {} bytes", capacity);
+        let session =
+            Arc::new(adapter.start_session(capacity).map_err(|e| {
+                VpnError::WireGuardError(format!("Failed to start session: {}", e))
+            })?);
+        Ok(TunDevice::new(session))
+    }
+
+    /// Create the TUN interface with the tunnel's address, DNS and MTU
+    #[cfg(target_os = "linux")]
+    fn open_device(
+        &mut self,
+        params: &TunnelParams,
+        config: &VpnConfig,
+        _tuning: &TunnelTuning,
+    ) -> Result<TunDevice, VpnError> {
+        // A wg-quick interface of the same name would make the TUN setup fail
+        if super::wireguard::left_behind(&self.name) {
+            let _ = std::process::Command::new("ip")
+                .args(["link", "delete", "dev", &self.name])
+                .output();
+        }
+
+        log::info!("Creating TUN interface '{}'...", self.name);
+        let device = TunDevice::create(&self.name)?;
+
+        self.configure_interface(&params.addresses, config)?;
+        self.configure_interface_dns(&config.interface.dns)?;
+        Ok(device)
+    }
+
+    /// Create a utun interface with the tunnel's address, DNS and MTU
+    #[cfg(target_os = "macos")]
+    fn open_device(
+        &mut self,
+        params: &TunnelParams,
+        config: &VpnConfig,
+        _tuning: &TunnelTuning,
+    ) -> Result<TunDevice, VpnError> {
+        log::info!("Creating utun interface...");
+        let device = TunDevice::create()?;
+        self.interface = Some(device.name().to_string());
+
+        self.configure_interface(&params.addresses, config)?;
+        self.configure_interface_dns(&config.interface.dns)?;
+        Ok(device)
+    }
+
+    /// Run WireGuard over `device`: start the forwarding and timer tasks and
+    /// wait for the first handshake
+    async fn start(
+        &mut self,
+        device: TunDevice,
+        params: TunnelParams,
+        config: &VpnConfig,
+        tuning: &TunnelTuning,
+        traffic_padding: bool,
+    ) -> Result<(), VpnError> {
+        // Create WireGuard tunnel using boringtun
+        log::info!("Initializing WireGuard crypto...");
+        let rate_limiter = Arc::new(boringtun::noise::rate_limiter::RateLimiter::new(
+            &boringtun::x25519::PublicKey::from(&boringtun::x25519::StaticSecret::from(
+                params.private_key,
+            )),
+            HANDSHAKE_RATE_LIMIT,
+        ));
+        let mut peers = Vec::with_capacity(params.peers.len());
+        for (index, peer) in params.peers.into_iter().enumerate() {
+            // The server's interval is only the starting point when the tuner takes over
+            let keepalive = peer
+                .persistent_keepalive
+                .filter(|_| tuning.adaptive_keepalive)
+                .map(|secs| KeepaliveTuner::new(secs, std::time::Instant::now()));
+            let fixed_keepalive = match keepalive {
+                Some(_) => None,
+                None => peer.persistent_keepalive,
+            };
+            let tunnel = boringtun::noise::Tunn::new(
+                boringtun::x25519::StaticSecret::from(params.private_key),
+                boringtun::x25519::PublicKey::from(peer.public_key),
+                None, // Preshared key
+                fixed_keepalive,
+                index as u32, // Tunnel index
+                Some(rate_limiter.clone()),
+            )
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to create tunnel: {}", e)))?;
+            peers.push(TunnelPeer {
+                tunnel,
+                endpoint: peer.endpoint,
+                allowed_ips: peer.allowed_ips,
+                keepalive,
+                awaiting_reply_since: None,
+                timer_error: None,
+            });
+        }
+
+        // Create UDP socket for WireGuard traffic
+        log::info!("Creating UDP socket for WireGuard traffic...");
+        let socket = bind_socket(tuning.listen_port)
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to bind UDP socket: {}", e)))?;
+        self.listen_port = socket.inner.local_addr().ok().map(|addr| addr.port());
+
+        socket
+            .inner
+            .set_nonblocking(true)
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to set non-blocking: {}", e)))?;
+
+        set_socket_buffers(&socket.inner, tuning);
+        // The kill switch lets marked packets out, as it does wg-quick's
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = tuning.fwmark {
+            if let Err(e) = socket2::SockRef::from(&socket.inner).set_mark(mark) {
+                log::warn!("Failed to set socket mark {:#x}: {}", mark, e);
+            }
+        }
+
+        if traffic_padding {
+            log::warn!("{}", padding::BANDWIDTH_WARNING);
+        }
+
+        // Store tunnel handle
+        let running = Arc::new(AtomicBool::new(true));
+        let tunnel_state = EmbeddedTunnel {
+            device,
+            rate_limiter,
+            socket,
+            peers,
+            running: running.clone(),
+            decoys: traffic_padding.then(|| DecoySchedule::new(std::time::Instant::now())),
+            mtu: config.interface.mtu.unwrap_or(DEFAULT_MTU) as usize,
+            mss_clamp: tuning.mss_clamp,
+            batch_size: tuning.batch_size.max(1),
+            addresses: params.addresses.iter().map(|prefix| prefix.addr).collect(),
+        };
+
+        self.tunnel_handle = Some(Arc::new(tokio::sync::Mutex::new(tunnel_state)));
+
+        // Start packet forwarding tasks
+        self.start_packet_forwarding(running).await?;
+
+        // Only report success once every peer has actually answered
+        self.wait_for_handshake(backend::handshake_timeout(tuning))
+            .await
+    }
+
+    async fn start_packet_forwarding(&mut self, running: Arc<AtomicBool>) -> Result<(), VpnError> {
+        let tunnel_handle = self
+            .tunnel_handle
+            .as_ref()
+            .ok_or(VpnError::NotConnected)?
+            .clone();
+
+        let bytes_received = self.bytes_received.clone();
+        let bytes_sent = self.bytes_sent.clone();
+        self.forwarding.reset();
+        let mut poller = super::polling::Poller::new(self.forwarding.clone());
+
+        // Timers run on their own task so an idle tunnel still sends keepalives and rekeys
+        let timers = tokio::spawn({
+            let timers = Self::run_timers(tunnel_handle.clone(), running.clone());
+            async move {
+                timers.await;
+                Ok(())
+            }
+        });
+
+        // Spawn packet forwarding task
+        let counters = self.forwarding.clone();
+        let mut budget = ErrorBudget::new(self.forwarding.clone());
+        let forwarding = tokio::spawn(async move {
+            log::info!("Starting packet forwarding...");
+
+            let mut buf = [0u8; 65536];
+            let mut wg_buf = [0u8; 65536];
+
+            while running.load(Ordering::SeqCst) {
+                let mut tunnel = tunnel_handle.lock().await;
+                let batch_size = tunnel.batch_size;
+
+                // A bad packet must not take the whole data path down with it;
+                // a panic or an exhausted error budget ends the task instead, and
+                // the watchdog reconnects
+                let batch = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    forward_batch(
+                        &mut tunnel,
+                        batch_size,
+                        &mut buf,
+                        &mut wg_buf,
+                        &counters,
+                        &mut budget,
+                    )
+                }));
+                let (sent, received, processed) = match batch {
+                    Ok(Ok(counts)) => counts,
+                    Ok(Err(e)) => {
+                        log::error!("Packet forwarding giving up: {}", e);
+                        return Err(e);
+                    }
+                    Err(payload) => return Err(format!("panicked: {}", panic_message(&*payload))),
+                };
+
+                if sent > 0 {
+                    bytes_sent.fetch_add(sent, Ordering::SeqCst);
+                }
+                if received > 0 {
+                    bytes_received.fetch_add(received, Ordering::SeqCst);
+                }
+
+                drop(tunnel);
+                match poller.after_iteration(processed) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    // More packets are likely queued; let other tasks run without sleeping
+                    None => tokio::task::yield_now().await,
+                }
+            }
+
+            log::info!("Packet forwarding stopped");
+            Ok(())
+        });
+
+        self.tasks = vec![forwarding, timers];
+        Ok(())
+    }
+
+    async fn wait_for_handshake(&self, timeout: std::time::Duration) -> Result<(), VpnError> {
+        let tunnel_handle = self
+            .tunnel_handle
+            .as_ref()
+            .ok_or(VpnError::NotConnected)?
+            .clone();
+
+        {
+            let mut guard = tunnel_handle.lock().await;
+            let tunnel = &mut *guard;
+            let mut buf = [0u8; 256];
+            for peer in &mut tunnel.peers {
+                if let boringtun::noise::TunnResult::WriteToNetwork(data) =
+                    peer.tunnel.format_handshake_initiation(&mut buf, false)
+                {
+                    let _ = tunnel.socket.send_to(data, peer.endpoint);
+                }
+            }
+        }
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            let waiting: Vec<String> = tunnel_handle
+                .lock()
+                .await
+                .peers
+                .iter()
+                .filter(|peer| peer.tunnel.time_since_last_handshake().is_none())
+                .map(|peer| peer.endpoint.to_string())
+                .collect();
+            if waiting.is_empty() {
+                return Ok(());
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(VpnError::ConnectionFailed(format!(
+                    "Handshake with {} timed out after {:?}",
+                    waiting.join(", "),
+                    timeout
+                )));
+            }
+        }
+    }
+
+    async fn run_timers(
+        tunnel_handle: Arc<tokio::sync::Mutex<EmbeddedTunnel>>,
+        running: Arc<AtomicBool>,
+    ) {
+        let mut interval = tokio::time::interval(TIMER_TICK);
+        let mut last_rate_reset = std::time::Instant::now();
+        // Room for the largest decoy plus WireGuard's header and tag
+        let mut wg_buf = [0u8; padding::DECOY_MAX_LEN + 64];
+        let decoy = [0u8; padding::DECOY_MAX_LEN];
+
+        while running.load(Ordering::SeqCst) {
+            interval.tick().await;
+            let mut guard = tunnel_handle.lock().await;
+            let tunnel = &mut *guard;
+
+            if last_rate_reset.elapsed() >= RATE_LIMITER_RESET {
+                tunnel.rate_limiter.reset_count();
+                last_rate_reset = std::time::Instant::now();
+            }
+
+            let now = std::time::Instant::now();
+            for peer in &mut tunnel.peers {
+                match peer.tunnel.update_timers(&mut wg_buf) {
+                    boringtun::noise::TunnResult::WriteToNetwork(data) => {
+                        let _ = tunnel.socket.send_to(data, peer.endpoint);
+                    }
+                    boringtun::noise::TunnResult::Err(e) => {
+                        let error = format!("{:?}", e);
+                        if peer.timer_error.as_ref() != Some(&error) {
+                            log::warn!("WireGuard timer error for {}: {}", peer.endpoint, error);
+                            peer.timer_error = Some(error);
+                        }
+                    }
+                    _ => {}
+                }
+
+                // An empty packet is a WireGuard keepalive
+                if peer
+                    .keepalive
+                    .as_mut()
+                    .is_some_and(|keepalive| keepalive.keepalive_due(now))
+                {
+                    if let boringtun::noise::TunnResult::WriteToNetwork(data) =
+                        peer.tunnel.encapsulate(&[], &mut wg_buf)
+                    {
+                        let _ = tunnel.socket.send_to(data, peer.endpoint);
+                    }
+                }
+            }
+
+            // Zeros aren't an IP packet, so the peer drops the decoy after decrypting it
+            if let Some(len) = tunnel.decoys.as_mut().and_then(|decoys| decoys.due(now)) {
+                let peer = &mut tunnel.peers[0];
+                if let boringtun::noise::TunnResult::WriteToNetwork(data) =
+                    peer.tunnel.encapsulate(&decoy[..len], &mut wg_buf)
+                {
+                    let _ = tunnel.socket.send_to(data, peer.endpoint);
+                }
+            }
+        }
+    }
+
+    /// Give the TUN interface its addresses and MTU and bring it up
+    #[cfg(target_os = "linux")]
+    fn configure_interface(
+        &self,
+        addresses: &[Prefix],
+        config: &VpnConfig,
+    ) -> Result<(), VpnError> {
+        let mtu = config.interface.mtu.unwrap_or(DEFAULT_MTU).to_string();
+
+        for address in addresses {
+            log::info!("Configuring interface with IP {}...", address);
+            let address = address.to_string();
+            run_command("ip", &["address", "add", &address, "dev", &self.name])?;
+        }
+        run_command("ip", &["link", "set", "dev", &self.name, "mtu", &mtu, "up"])
+    }
+
+    /// Send all DNS queries to the tunnel's servers through systemd-resolved,
+    /// which forgets them again when the interface goes away
+    ///
+    /// Without a working systemd-resolved, /etc/resolv.conf is replaced instead
+    /// and restored on disconnect.
+    #[cfg(target_os = "linux")]
+    fn configure_interface_dns(&mut self, dns_servers: &[String]) -> Result<(), VpnError> {
+        if dns_servers.is_empty() {
+            return Ok(());
+        }
+
+        let mut dns = vec!["dns", self.name.as_str()];
+        dns.extend(dns_servers.iter().map(String::as_str));
+        let resolved = run_command("resolvectl", &dns)
+            .and_then(|()| run_command("resolvectl", &["domain", &self.name, "~."]));
+        let Err(e) = resolved else {
+            return Ok(());
+        };
+
+        log::warn!("{}, writing /etc/resolv.conf instead", e);
+        self.resolv_conf = Some(ResolvConf::replace(dns_servers)?);
+        Ok(())
+    }
+
+    /// Put back the /etc/resolv.conf the tunnel replaced, if it did
+    #[cfg(target_os = "linux")]
+    fn restore_resolv_conf(&mut self) {
+        if let Some(original) = self.resolv_conf.take() {
+            if let Err(e) = original.restore() {
+                log::error!("{}", e);
+            }
+        }
+    }
+
+    /// Give the utun interface its addresses and MTU and bring it up
+    #[cfg(target_os = "macos")]
+    fn configure_interface(
+        &self,
+        addresses: &[Prefix],
+        config: &VpnConfig,
+    ) -> Result<(), VpnError> {
+        let interface = self.interface.clone().ok_or(VpnError::NotConnected)?;
+        let mtu = config.interface.mtu.unwrap_or(DEFAULT_MTU).to_string();
+
+        for address in addresses {
+            log::info!("Configuring interface {} with IP {}...", interface, address);
+            let ip = address.addr.to_string();
+            match address.addr {
+                // utun is point-to-point; the address doubles as the destination
+                std::net::IpAddr::V4(_) => run_command(
+                    "ifconfig",
+                    &[&interface, "inet", &format!("{}/32", ip), &ip, "alias"],
+                )?,
+                std::net::IpAddr::V6(_) => run_command(
+                    "ifconfig",
+                    &[
+                        &interface,
+                        "inet6",
+                        &ip,
+                        "prefixlen",
+                        &address.len.to_string(),
+                        "alias",
+                    ],
+                )?,
+            }
+        }
+        run_command("ifconfig", &[&interface, "mtu", &mtu, "up"])
+    }
+
+    /// Make the tunnel's servers the resolvers for all domains
+    ///
+    /// A DNS entry in the dynamic store whose supplemental match domain is the
+    /// root applies to every query, ahead of the primary service's servers.
+    #[cfg(target_os = "macos")]
+    fn configure_interface_dns(&self, dns_servers: &[String]) -> Result<(), VpnError> {
+        // Anything but an address would change the meaning of the scutil script
+        let servers: Vec<&str> = dns_servers
+            .iter()
+            .map(String::as_str)
+            .filter(|server| match server.parse::<std::net::IpAddr>() {
+                Ok(_) => true,
+                Err(_) => {
+                    log::warn!("Skipping DNS server '{}', not an IP address", server);
+                    false
+                }
+            })
+            .collect();
+        if servers.is_empty() {
+            return Ok(());
+        }
+
+        scutil(&format!(
+            "d.init\nd.add ServerAddresses * {}\nd.add SupplementalMatchDomains * \"\"\nset {}\n",
+            servers.join(" "),
+            self.dns_key()
+        ))
+    }
+
+    /// Dynamic store key of the tunnel's DNS settings
+    #[cfg(target_os = "macos")]
+    fn dns_key(&self) -> String {
+        format!("State:/Network/Service/{}/DNS", self.name)
+    }
+}
+
+impl TunnelBackend for EmbeddedBackend {
+    fn name(&self) -> &'static str {
+        "embedded"
+    }
+
+    fn connect<'a>(
+        &'a mut self,
+        config: &'a VpnConfig,
+        tuning: &'a TunnelTuning,
+        traffic_padding: bool,
+    ) -> BoxFuture<'a, Result<(), VpnError>> {
+        Box::pin(async move {
+            log::info!("Using embedded WireGuard implementation (no external WireGuard needed)");
+
+            let params = TunnelParams::parse(config)?;
+            let device = self.open_device(&params, config, tuning)?;
+            let started = self
+                .start(device, params, config, tuning, traffic_padding)
+                .await;
+            #[cfg(target_os = "linux")]
+            if started.is_err() {
+                self.restore_resolv_conf();
+            }
+            started?;
+
+            // Windows only identifies the network once traffic flows, so don't wait for it
+            #[cfg(target_os = "windows")]
+            tokio::spawn(category::apply(self.name.clone(), tuning.network_category));
+
+            log::info!("Embedded WireGuard tunnel established successfully!");
+            Ok(())
+        })
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            log::info!("Stopping embedded WireGuard tunnel...");
+
+            #[cfg(target_os = "linux")]
+            self.restore_resolv_conf();
+
+            // Unlike systemd-resolved, macOS keeps the DNS entry after the interface is gone
+            #[cfg(target_os = "macos")]
+            if self.interface.take().is_some() {
+                if let Err(e) = scutil(&format!("remove {}\n", self.dns_key())) {
+                    log::warn!("{}", e);
+                }
+            }
+
+            // Stop the packet forwarding
+            if let Some(ref handle) = self.tunnel_handle {
+                let tunnel = handle.lock().await;
+                tunnel.running.store(false, Ordering::SeqCst);
+            }
+
+            // Drop the tunnel handle (this closes the adapter or TUN interface)
+            self.tunnel_handle = None;
+            self.tasks.clear();
+            self.listen_port = None;
+
+            // Reset stats
+            self.bytes_received.store(0, Ordering::SeqCst);
+            self.bytes_sent.store(0, Ordering::SeqCst);
+
+            log::info!("Embedded WireGuard tunnel disconnected");
+            Ok(())
+        })
+    }
+
+    fn interface(&self) -> Option<String> {
+        #[cfg(target_os = "macos")]
+        let interface = self.interface.clone();
+
+        #[cfg(not(target_os = "macos"))]
+        let interface = Some(self.name.clone());
+
+        interface
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    fn transfer_stats(&self) -> Result<(u64, u64), VpnError> {
+        // The kernel counts traffic on the interface, as it does for wg-quick's
+        #[cfg(target_os = "linux")]
+        let stats = super::wireguard::interface_transfer(&self.name);
+
+        #[cfg(not(target_os = "linux"))]
+        let stats = Ok((
+            self.bytes_received.load(Ordering::SeqCst),
+            self.bytes_sent.load(Ordering::SeqCst),
+        ));
+
+        stats
+    }
+
+    /// Catches the forwarding or timer task panicking or returning early
+    fn check_health(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            if let Some(index) = self.tasks.iter().position(|task| task.is_finished()) {
+                let reason = task_exit_reason(self.tasks.swap_remove(index).await);
+                return Err(VpnError::WireGuardError(format!(
+                    "Packet forwarding {}",
+                    reason
+                )));
+            }
+            Ok(())
+        })
+    }
+
+    fn keepalive_interval(&self) -> BoxFuture<'_, Option<u16>> {
+        Box::pin(async move {
+            match &self.tunnel_handle {
+                Some(handle) => handle.lock().await.peers[0]
+                    .keepalive
+                    .as_ref()
+                    .map(|keepalive| keepalive.interval_secs()),
+                None => None,
+            }
+        })
+    }
+
+    fn retune<'a>(&'a self, tuning: &'a TunnelTuning) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            if let Some(handle) = &self.tunnel_handle {
+                let mut tunnel = handle.lock().await;
+                set_socket_buffers(&tunnel.socket.inner, tuning);
+                tunnel.batch_size = tuning.batch_size.max(1);
+            }
+        })
+    }
+
+    fn unanswered(&self) -> BoxFuture<'_, Vec<Option<std::time::Duration>>> {
+        Box::pin(async move {
+            match &self.tunnel_handle {
+                Some(handle) => handle
+                    .lock()
+                    .await
+                    .peers
+                    .iter()
+                    .map(|peer| peer.awaiting_reply_since.map(|since| since.elapsed()))
+                    .collect(),
+                None => Vec::new(),
+            }
+        })
+    }
+
+    /// Packets sent before the new address answers the handshake are lost, as
+    /// during any rekey
+    fn switch_endpoint(
+        &self,
+        index: usize,
+        endpoint: std::net::SocketAddr,
+    ) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            let handle = self.tunnel_handle.as_ref().ok_or(VpnError::NotConnected)?;
+            let mut guard = handle.lock().await;
+            let tunnel = &mut *guard;
+            let peer = tunnel.peers.get_mut(index).ok_or(VpnError::NotConnected)?;
+            peer.endpoint = endpoint;
+            peer.awaiting_reply_since = None;
+
+            let mut buf = [0u8; 256];
+            if let boringtun::noise::TunnResult::WriteToNetwork(data) =
+                peer.tunnel.format_handshake_initiation(&mut buf, true)
+            {
+                tunnel.socket.send_to(data, endpoint).map_err(|e| {
+                    VpnError::ConnectionFailed(format!("Failed to reach {}: {}", endpoint, e))
+                })?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Describe how a supervised task ended, including the panic message if it panicked
+fn task_exit_reason(result: Result<Result<(), String>, tokio::task::JoinError>) -> String {
+    match result {
+        Err(e) if e.is_panic() => format!("panicked: {}", panic_message(&*e.into_panic())),
+        Err(_) => "was cancelled".to_string(),
+        Ok(Err(reason)) => format!("failed: {}", reason),
+        Ok(Ok(())) => "stopped unexpectedly".to_string(),
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Larger buffers absorb bursts on fast links that overflow the OS defaults
+fn set_socket_buffers(socket: &std::net::UdpSocket, tuning: &TunnelTuning) {
+    let sock = socket2::SockRef::from(socket);
+    if let Some(size) = tuning.socket_recv_buffer {
+        if let Err(e) = sock.set_recv_buffer_size(size) {
+            log::warn!("Failed to set socket receive buffer to {}: {}", size, e);
+        }
+    }
+    if let Some(size) = tuning.socket_send_buffer {
+        if let Err(e) = sock.set_send_buffer_size(size) {
+            log::warn!("Failed to set socket send buffer to {}: {}", size, e);
+        }
+    }
+}
+
+/// Move up to `batch_size` packets each way, returning (bytes sent, bytes received, packets)
+///
+/// Batching here means draining up to `batch_size` packets per direction under
+/// a single lock before yielding, rather than recvmmsg/sendmmsg, which Winsock
+/// doesn't have.
+/// Individual failures are dropped and counted; the batch only fails once a
+/// socket or TUN error exceeds its budget.
+fn forward_batch(
+    tunnel: &mut EmbeddedTunnel,
+    batch_size: usize,
+    buf: &mut [u8],
+    wg_buf: &mut [u8],
+    counters: &ForwardingCounters,
+    budget: &mut ErrorBudget,
+) -> Result<(u64, u64, usize), String> {
+    use boringtun::noise::TunnResult;
+
+    let mut sent = 0u64;
+    let mut received = 0u64;
+    let mut processed = 0usize;
+    let now = std::time::Instant::now();
+
+    // Read from TUN and send to WireGuard
+    for _ in 0..batch_size {
+        let len = match tunnel.device.receive(buf) {
+            Ok(Some(len)) => len,
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("TUN read failed: {}", e);
+                budget.record(ForwardingError::Tun)?;
+                break;
+            }
+        };
+        processed += 1;
+
+        // Anything not sent from the tunnel address is spoofed or leaked from
+        // another interface, and would let the server be used to forge traffic
+        if packet_source(&buf[..len]).is_some_and(|src| !tunnel.addresses.contains(&src)) {
+            log::debug!("Dropped outbound packet with a foreign source address");
+            counters.count_spoofed();
+            continue;
+        }
+
+        let destination = packet_destination(&buf[..len]);
+        let Some(index) = destination.and_then(|destination| {
+            select_peer(
+                tunnel.peers.iter().map(|peer| peer.allowed_ips.as_slice()),
+                destination,
+            )
+        }) else {
+            log::debug!(
+                "Dropped outbound packet to {:?}, which no peer routes",
+                destination
+            );
+            counters.count_unrouted();
+            continue;
+        };
+
+        if tunnel.mss_clamp {
+            mss::clamp(&mut buf[..len], tunnel.mtu as u32);
+        }
+
+        // Answer packets that can't fit like a router would, so the sender's
+        // path MTU discovery adapts instead of retransmitting into the void
+        if let Some(reply) = pmtu::too_big_reply(&buf[..len], tunnel.mtu) {
+            counters.count_too_big();
+            write_to_tun(&tunnel.device, &reply, budget)?;
+            continue;
+        }
+
+        sent += len as u64;
+        let peer = &mut tunnel.peers[index];
+        if let Some(keepalive) = peer.keepalive.as_mut() {
+            keepalive.on_outbound(now);
+        }
+        peer.awaiting_reply_since.get_or_insert(now);
+
+        // Pad with zeros, which the peer trims off using the IP header's length
+        let padded = if tunnel.decoys.is_some() {
+            let padded = padding::padded_len(len, tunnel.mtu);
+            buf[len..padded].fill(0);
+            padded
+        } else {
+            len
+        };
+
+        // Encrypt and send
+        match peer.tunnel.encapsulate(&buf[..padded], wg_buf) {
+            TunnResult::WriteToNetwork(data) => {
+                send_datagram(&tunnel.socket, data, peer.endpoint, budget)?
+            }
+            TunnResult::Err(e) => {
+                log::debug!("Encapsulation failed: {:?}", e);
+                budget.record(ForwardingError::Crypto)?;
+            }
+            _ => {}
+        }
+    }
+
+    // Read from WireGuard and write to TUN
+    for _ in 0..batch_size {
+        let (n, from) = match tunnel.socket.recv_from(buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                // No data available, continue
+                break;
+            }
+            Err(e) => {
+                log::debug!("Socket error: {}", e);
+                budget.record(ForwardingError::Socket)?;
+                break;
+            }
+        };
+        processed += 1;
+
+        let Some(peer) = tunnel.peers.iter_mut().find(|peer| peer.endpoint == from) else {
+            log::debug!(
+                "Dropped datagram from {}, which is no peer's endpoint",
+                from
+            );
+            continue;
+        };
+        received += n as u64;
+        if let Some(keepalive) = peer.keepalive.as_mut() {
+            keepalive.on_inbound(now);
+        }
+        peer.awaiting_reply_since = None;
+        peer.timer_error = None;
+
+        // Decrypt and write to TUN; the source address lets the rate
+        // limiter answer handshake floods with cookie replies
+        let (data, src) = match peer.tunnel.decapsulate(Some(from.ip()), &buf[..n], wg_buf) {
+            TunnResult::WriteToTunnelV4(data, src) => (data, std::net::IpAddr::V4(src)),
+            TunnResult::WriteToTunnelV6(data, src) => (data, std::net::IpAddr::V6(src)),
+            TunnResult::WriteToNetwork(data) => {
+                send_datagram(&tunnel.socket, data, peer.endpoint, budget)?;
+
+                // A completed handshake or cookie exchange may release queued
+                // packets; keep draining until boringtun reports Done
+                while let TunnResult::WriteToNetwork(data) =
+                    peer.tunnel.decapsulate(None, &[], wg_buf)
+                {
+                    send_datagram(&tunnel.socket, data, peer.endpoint, budget)?;
+                }
+                continue;
+            }
+            TunnResult::Err(e) => {
+                log::debug!("Dropped datagram: {:?}", e);
+                budget.record(ForwardingError::Crypto)?;
+                continue;
+            }
+            TunnResult::Done => continue,
+        };
+
+        // The peer may only speak for the addresses it was configured with
+        if !peer.allowed_ips.iter().any(|prefix| prefix.contains(src)) {
+            log::debug!("Dropped inbound packet from {} outside AllowedIPs", src);
+            counters.count_disallowed();
+            continue;
+        }
+        // Inbound SYN-ACKs too, so neither side sends segments too big to fit
+        if tunnel.mss_clamp {
+            mss::clamp(data, tunnel.mtu as u32);
+        }
+        write_to_tun(&tunnel.device, data, budget)?;
+    }
+
+    Ok((sent, received, processed))
+}
+
+/// Source address of an IPv4 or IPv6 packet
+fn packet_source(packet: &[u8]) -> Option<std::net::IpAddr> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let octets: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            Some(std::net::Ipv4Addr::from(octets).into())
+        }
+        6 if packet.len() >= 40 => {
+            let octets: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            Some(std::net::Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+/// Destination address of an IPv4 or IPv6 packet
+fn packet_destination(packet: &[u8]) -> Option<std::net::IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let octets: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            Some(std::net::Ipv4Addr::from(octets).into())
+        }
+        6 => {
+            let octets: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            Some(std::net::Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+/// Index of the peer whose AllowedIPs hold `destination` most specifically,
+/// the earlier peer winning a tie
+fn select_peer<'a>(
+    allowed_ips: impl IntoIterator<Item = &'a [Prefix]>,
+    destination: std::net::IpAddr,
+) -> Option<usize> {
+    allowed_ips
+        .into_iter()
+        .enumerate()
+        .flat_map(|(index, prefixes)| {
+            prefixes
+                .iter()
+                .filter(|prefix| prefix.contains(destination))
+                .map(move |prefix| (prefix.len, index))
+        })
+        .min_by_key(|&(len, index)| (std::cmp::Reverse(len), index))
+        .map(|(_, index)| index)
+}
+
+fn write_to_tun(device: &TunDevice, data: &[u8], budget: &mut ErrorBudget) -> Result<(), String> {
+    match device.send(data) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            log::debug!("TUN write failed: {}", e);
+            budget.record(ForwardingError::Tun)
+        }
+    }
+}
+
+fn send_datagram(
+    socket: &PeerSocket,
+    data: &[u8],
+    to: std::net::SocketAddr,
+    budget: &mut ErrorBudget,
+) -> Result<(), String> {
+    match socket.send_to(data, to) {
+        Ok(_) => Ok(()),
+        // A full send buffer drops the datagram like any other UDP loss
+        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => {
+            log::debug!("Socket send failed: {}", e);
+            budget.record(ForwardingError::Socket)
+        }
+    }
+}
+
+/// The tunnel's UDP socket, dual-stack where the system has IPv6 so peers of
+/// either family share it
+struct PeerSocket {
+    inner: std::net::UdpSocket,
+    /// IPv4 endpoints are then reached at their IPv4-mapped IPv6 address
+    dual_stack: bool,
+}
+
+impl PeerSocket {
+    fn send_to(&self, data: &[u8], to: std::net::SocketAddr) -> std::io::Result<usize> {
+        let to = match to {
+            std::net::SocketAddr::V4(v4) if self.dual_stack => {
+                std::net::SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            to => to,
+        };
+        self.inner.send_to(data, to)
+    }
+
+    /// Like `UdpSocket::recv_from`, with IPv4 senders at their IPv4 address
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, std::net::SocketAddr)> {
+        let (len, from) = self.inner.recv_from(buf)?;
+        Ok((
+            len,
+            std::net::SocketAddr::new(from.ip().to_canonical(), from.port()),
+        ))
+    }
+
+    /// Bind `port` on every IPv6 and IPv4 address, or only the IPv4 ones
+    /// when IPv6 is disabled
+    fn bind(port: u16) -> std::io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let dual_stack =
+            Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)).and_then(|socket| {
+                socket.set_only_v6(false)?;
+                let any = std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+                socket.bind(&any.into())?;
+                Ok(socket)
+            });
+        match dual_stack {
+            Ok(socket) => Ok(Self {
+                inner: socket.into(),
+                dual_stack: true,
+            }),
+            Err(e) => {
+                log::debug!("No dual-stack socket ({}), using IPv4 only", e);
+                Ok(Self {
+                    inner: std::net::UdpSocket::bind(("0.0.0.0", port))?,
+                    dual_stack: false,
+                })
+            }
+        }
+    }
+}
+
+/// UDP socket on the configured listen port, falling back to a random port if it is taken
+fn bind_socket(requested: Option<u16>) -> std::io::Result<PeerSocket> {
+    if let Some(port) = requested.filter(|&port| port != 0) {
+        match PeerSocket::bind(port) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                log::warn!(
+                    "Listen port {} is unavailable ({}), using a random port",
+                    port,
+                    e
+                )
+            }
+        }
+    }
+    PeerSocket::bind(0)
+}
+
+/// Run a command that configures the tunnel interface, failing with its stderr
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run_command(program: &str, args: &[&str]) -> Result<(), VpnError> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| VpnError::WireGuardError(format!("Failed to run {}: {}", program, e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(VpnError::WireGuardError(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// What /etc/resolv.conf was before the tunnel replaced it
+#[cfg(target_os = "linux")]
+enum ResolvConf {
+    /// A symlink, as to systemd-resolved's or resolvconf's generated file
+    Link(std::path::PathBuf),
+    File(Vec<u8>),
+    Missing,
+}
+
+#[cfg(target_os = "linux")]
+impl ResolvConf {
+    const PATH: &'static str = "/etc/resolv.conf";
+
+    /// Point the system resolver at `dns_servers`, returning what to restore
+    fn replace(dns_servers: &[String]) -> Result<Self, VpnError> {
+        let error = |e: std::io::Error| {
+            VpnError::WireGuardError(format!("Failed to configure DNS in {}: {}", Self::PATH, e))
+        };
+
+        let contents = resolv_conf_contents(dns_servers);
+        if contents.is_empty() {
+            return Err(VpnError::ConfigError(
+                "No DNS server is an IP address".to_string(),
+            ));
+        }
+
+        let original = match std::fs::symlink_metadata(Self::PATH) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                Self::Link(std::fs::read_link(Self::PATH).map_err(error)?)
+            }
+            Ok(_) => Self::File(std::fs::read(Self::PATH).map_err(error)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::Missing,
+            Err(e) => return Err(error(e)),
+        };
+
+        // Writing through a symlink would change the file another resolver manages
+        if !matches!(original, Self::Missing) {
+            std::fs::remove_file(Self::PATH).map_err(error)?;
+        }
+        if let Err(e) = std::fs::write(Self::PATH, contents) {
+            let _ = original.restore();
+            return Err(error(e));
+        }
+        Ok(original)
+    }
+
+    fn restore(&self) -> Result<(), VpnError> {
+        let error = |e: std::io::Error| {
+            VpnError::WireGuardError(format!("Failed to restore {}: {}", Self::PATH, e))
+        };
+
+        match std::fs::remove_file(Self::PATH) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(error(e)),
+        }
+        match self {
+            Self::Link(target) => std::os::unix::fs::symlink(target, Self::PATH).map_err(error),
+            Self::File(contents) => std::fs::write(Self::PATH, contents).map_err(error),
+            Self::Missing => Ok(()),
+        }
+    }
+}
+
+/// resolv.conf naming `dns_servers`; empty if none of them is an address
+#[cfg(target_os = "linux")]
+fn resolv_conf_contents(dns_servers: &[String]) -> String {
+    // Anything but an address could add other resolver options
+    let nameservers: String = dns_servers
+        .iter()
+        .filter(|server| server.parse::<std::net::IpAddr>().is_ok())
+        .map(|server| format!("nameserver {}\n", server))
+        .collect();
+    if nameservers.is_empty() {
+        return nameservers;
+    }
+    format!("# Generated by SACVPN while connected\n{}", nameservers)
+}
+
+/// Feed a script of commands to scutil, which edits the dynamic store
+#[cfg(target_os = "macos")]
+fn scutil(script: &str) -> Result<(), VpnError> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("scutil")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| VpnError::WireGuardError(format!("Failed to run scutil: {}", e)))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to write to scutil: {}", e)))?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| VpnError::WireGuardError(format!("Failed to run scutil: {}", e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        log::warn!("scutil DNS config warning: {}", stderr);
+    }
+    Ok(())
+}
+
+/// Wintun ring capacity to use, clamped to the driver's valid power-of-two range
+#[cfg(target_os = "windows")]
+fn ring_capacity(requested: Option<u32>) -> u32 {
+    requested
+        .map(|c| {
+            c.clamp(wintun::MIN_RING_CAPACITY, wintun::MAX_RING_CAPACITY)
+                .next_power_of_two()
+        })
+        .unwrap_or(wintun::MAX_RING_CAPACITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_resolv_conf_lists_only_addresses() {
+        let servers = ["10.8.0.1".to_string(), "options ndots:15".to_string()];
+        assert_eq!(
+            resolv_conf_contents(&servers),
+            "# Generated by SACVPN while connected\nnameserver 10.8.0.1\n"
+        );
+        assert_eq!(resolv_conf_contents(&servers[1..]), "");
+    }
+
+    #[tokio::test]
+    async fn test_task_exit_reason_reports_panic_message() {
+        let panicked: Result<Result<(), String>, _> =
+            tokio::spawn(async { panic!("ring buffer overflow") }).await;
+        assert_eq!(task_exit_reason(panicked), "panicked: ring buffer overflow");
+
+        let returned = tokio::spawn(async { Ok(()) }).await;
+        assert_eq!(task_exit_reason(returned), "stopped unexpectedly");
+
+        let failed = tokio::spawn(async { Err("too many socket errors".to_string()) }).await;
+        assert_eq!(task_exit_reason(failed), "failed: too many socket errors");
+    }
+
+    #[test]
+    fn test_select_peer_by_longest_prefix() {
+        let prefixes = |list: &[&str]| -> Vec<Prefix> {
+            list.iter().map(|prefix| prefix.parse().unwrap()).collect()
+        };
+        let peers = [
+            prefixes(&["0.0.0.0/0"]),
+            prefixes(&["10.20.0.0/16", "fd00::/8"]),
+            prefixes(&["10.20.5.0/24"]),
+            prefixes(&["10.20.0.0/16"]),
+        ];
+        let select = |destination: &str| {
+            select_peer(
+                peers.iter().map(|prefixes| prefixes.as_slice()),
+                destination.parse().unwrap(),
+            )
+        };
+
+        assert_eq!(select("1.1.1.1"), Some(0));
+        assert_eq!(select("10.20.9.9"), Some(1));
+        assert_eq!(select("10.20.5.7"), Some(2));
+        assert_eq!(select("fd00::1"), Some(1));
+        assert_eq!(select("2001:db8::1"), None);
+    }
+
+    #[test]
+    fn test_packet_source_reads_both_families() {
+        let mut v4 = vec![0x45; 20];
+        v4[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        assert_eq!(packet_source(&v4), Some("10.8.0.2".parse().unwrap()));
+
+        let mut v6 = vec![0x60; 40];
+        let address: std::net::Ipv6Addr = "fd00::2".parse().unwrap();
+        v6[8..24].copy_from_slice(&address.octets());
+        assert_eq!(packet_source(&v6), Some(address.into()));
+
+        assert_eq!(packet_source(&v6[..39]), None);
+    }
+
+    #[test]
+    fn test_peer_socket_talks_to_ipv4_peers() {
+        let socket = PeerSocket::bind(0).unwrap();
+        let port = socket.inner.local_addr().unwrap().port();
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        peer.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&b"ping"[..], peer_addr));
+
+        socket.send_to(b"pong", peer_addr).unwrap();
+        let (len, from) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from.port(), port);
+    }
+}
@@ -0,0 +1,171 @@
+//! Connection event log
+//!
+//! A single timeline of what happened to the connection: status transitions,
+//! reconnect attempts, network changes, leak warnings and the privileged
+//! commands run along the way, for the UI's activity feed. Events are kept in
+//! memory since the app started, oldest dropped first.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Events kept, oldest dropped first
+const MAX_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    /// The connection status changed
+    Status,
+    /// The watchdog restoring a dropped session
+    Reconnect,
+    /// The tunnel came up over a different network than the last one
+    Network,
+    /// Traffic seen leaving outside the tunnel
+    Leak,
+    /// A command run with administrator rights, such as a firewall rule change
+    Privileged,
+    /// A server sent a config that differs from the one it sent before
+    Config,
+    /// The session moved to another server in its country for privacy
+    Rotation,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Unix timestamp
+    pub timestamp: i64,
+    pub category: EventCategory,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Which events `query` returns
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EventFilter {
+    /// Categories to include; empty includes all
+    pub categories: Vec<EventCategory>,
+    /// Leave out events less severe than this
+    pub min_severity: Option<Severity>,
+    /// Only the newest this many matching events
+    pub limit: Option<usize>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        (self.categories.is_empty() || self.categories.contains(&event.category))
+            && self.min_severity.is_none_or(|min| event.severity >= min)
+    }
+}
+
+static EVENTS: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+
+/// Add an event to the log, timestamped now
+pub fn record(category: EventCategory, severity: Severity, message: impl Into<String>) {
+    push(
+        &mut EVENTS.lock().unwrap(),
+        Event {
+            timestamp: chrono::Utc::now().timestamp(),
+            category,
+            severity,
+            message: message.into(),
+        },
+    );
+}
+
+fn push(events: &mut VecDeque<Event>, event: Event) {
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// Events matching `filter` with timestamps in `[from, to)`, oldest first;
+/// open ends are unbounded
+pub fn query(filter: &EventFilter, from: Option<i64>, to: Option<i64>) -> Vec<Event> {
+    select(&EVENTS.lock().unwrap(), filter, from, to)
+}
+
+fn select(
+    events: &VecDeque<Event>,
+    filter: &EventFilter,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Vec<Event> {
+    let mut matching: Vec<Event> = events
+        .iter()
+        .filter(|event| from.is_none_or(|from| event.timestamp >= from))
+        .filter(|event| to.is_none_or(|to| event.timestamp < to))
+        .filter(|event| filter.matches(event))
+        .cloned()
+        .collect();
+    if let Some(limit) = filter.limit {
+        let excess = matching.len().saturating_sub(limit);
+        matching.drain(..excess);
+    }
+    matching
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: i64, category: EventCategory, severity: Severity) -> Event {
+        Event {
+            timestamp,
+            category,
+            severity,
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_select_filters_and_limits() {
+        let events = VecDeque::from([
+            event(10, EventCategory::Status, Severity::Info),
+            event(20, EventCategory::Reconnect, Severity::Warning),
+            event(30, EventCategory::Status, Severity::Error),
+            event(40, EventCategory::Leak, Severity::Error),
+        ]);
+
+        let all = select(&events, &EventFilter::default(), None, None);
+        assert_eq!(all.len(), 4);
+
+        let in_range = select(&events, &EventFilter::default(), Some(20), Some(40));
+        assert_eq!(
+            in_range.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            [20, 30]
+        );
+
+        let filter = EventFilter {
+            categories: vec![EventCategory::Status, EventCategory::Reconnect],
+            min_severity: Some(Severity::Warning),
+            limit: Some(1),
+        };
+        let newest = select(&events, &filter, None, None);
+        assert_eq!(newest.len(), 1);
+        assert_eq!(newest[0].timestamp, 30);
+    }
+
+    #[test]
+    fn test_oldest_events_dropped() {
+        let mut events = VecDeque::new();
+        for timestamp in 0..MAX_EVENTS as i64 + 5 {
+            push(
+                &mut events,
+                event(timestamp, EventCategory::Status, Severity::Info),
+            );
+        }
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events.front().map(|e| e.timestamp), Some(5));
+    }
+}
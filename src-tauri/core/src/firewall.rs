@@ -0,0 +1,1248 @@
+//! Firewall management for the kill switch, WebRTC leak protection, the
+//! inbound block on the tunnel and TCP MSS clamping
+//!
+//! Rule sets are built as plain command lines first and executed second, so the
+//! same rules can be applied and rolled back symmetrically.
+
+use super::events::{self, EventCategory, Severity};
+use super::simulate;
+use super::VpnError;
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, Output, Stdio};
+
+/// Private and link-local ranges allowed through the kill switch when LAN access is on
+pub const LAN_RANGES: [&str; 4] = [
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+];
+
+/// UDP ports of STUN/TURN (used by WebRTC) and Teredo, as inclusive ranges
+const LEAK_PORTS: [(u16, u16); 4] = [(3478, 3479), (3544, 3544), (5349, 5349), (19302, 19309)];
+
+/// Inputs needed to build the firewall rule sets
+#[derive(Debug, Clone)]
+pub struct KillSwitchParams {
+    pub tunnel_name: String,
+    /// Only the Windows and macOS rules match on it
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub tunnel_address: IpAddr,
+    /// Addresses of every peer endpoint
+    pub endpoint_hosts: Vec<IpAddr>,
+    pub allow_lan: bool,
+    /// Ports left reachable through the tunnel by the inbound block
+    pub forwarded_ports: Vec<u16>,
+    /// Firewall mark on the tunnel's own encrypted packets
+    pub fwmark: Option<u32>,
+}
+
+/// Tracks which firewall changes are currently applied so they can be rolled back
+#[derive(Default)]
+pub struct Firewall {
+    rollback: Option<Vec<Step>>,
+    leak_rollback: Option<Vec<Step>>,
+    inbound_rollback: Option<Vec<Step>>,
+    sharing_rollback: Option<Vec<Step>>,
+    mss_rollback: Option<Vec<Step>>,
+}
+
+impl Firewall {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Block all traffic that doesn't go through the tunnel or to the endpoint
+    pub fn enable_kill_switch(&mut self, params: &KillSwitchParams) -> Result<(), VpnError> {
+        log::info!("Enabling kill switch (LAN access: {})", params.allow_lan);
+        apply(
+            &mut self.rollback,
+            enable_commands(params),
+            disable_commands(params),
+        )
+    }
+
+    pub fn kill_switch_engaged(&self) -> bool {
+        self.rollback.is_some()
+    }
+
+    /// Remove every rule added by `enable_kill_switch`
+    pub fn disable_kill_switch(&mut self) -> Result<(), VpnError> {
+        if self.rollback.is_some() {
+            log::info!("Disabling kill switch");
+        }
+        remove(&mut self.rollback)
+    }
+
+    /// Keep STUN and Teredo traffic inside the tunnel and turn off IPv6 transition tunnels
+    ///
+    /// Browsers send WebRTC STUN requests from every local interface, which
+    /// reveals the real address no matter how traffic is routed.
+    pub fn enable_leak_protection(&mut self, params: &KillSwitchParams) -> Result<(), VpnError> {
+        log::info!("Enabling WebRTC leak protection");
+        apply(
+            &mut self.leak_rollback,
+            leak_enable_commands(params),
+            leak_disable_commands(params),
+        )
+    }
+
+    /// Remove every rule added by `enable_leak_protection`
+    pub fn disable_leak_protection(&mut self) -> Result<(), VpnError> {
+        if self.leak_rollback.is_some() {
+            log::info!("Disabling WebRTC leak protection");
+        }
+        remove(&mut self.leak_rollback)
+    }
+
+    /// Drop unsolicited inbound traffic arriving through the tunnel
+    ///
+    /// Servers that route instead of NAT expose the tunnel address to the
+    /// internet; only `forwarded_ports` stay reachable.
+    pub fn enable_inbound_block(&mut self, params: &KillSwitchParams) -> Result<(), VpnError> {
+        log::info!(
+            "Blocking inbound tunnel traffic (forwarded ports: {:?})",
+            params.forwarded_ports
+        );
+        apply(
+            &mut self.inbound_rollback,
+            inbound_enable_commands(params),
+            inbound_disable_commands(params),
+        )
+    }
+
+    /// Remove every rule added by `enable_inbound_block`
+    pub fn disable_inbound_block(&mut self) -> Result<(), VpnError> {
+        if self.inbound_rollback.is_some() {
+            log::info!("Allowing inbound tunnel traffic");
+        }
+        remove(&mut self.inbound_rollback)
+    }
+
+    /// Clamp the MSS of TCP connections through the tunnel to fit `mtu`
+    ///
+    /// Does nothing on Windows, where the embedded tunnel clamps in its data path.
+    pub fn enable_mss_clamp(
+        &mut self,
+        params: &KillSwitchParams,
+        mtu: u32,
+    ) -> Result<(), VpnError> {
+        let enable = mss_enable_commands(params, mtu);
+        if enable.is_empty() {
+            return Ok(());
+        }
+        log::info!("Clamping TCP MSS to the tunnel MTU of {}", mtu);
+        apply(
+            &mut self.mss_rollback,
+            enable,
+            mss_disable_commands(params, mtu),
+        )
+    }
+
+    /// Remove every rule added by `enable_mss_clamp`
+    pub fn disable_mss_clamp(&mut self) -> Result<(), VpnError> {
+        if self.mss_rollback.is_some() {
+            log::info!("Removing TCP MSS clamping");
+        }
+        remove(&mut self.mss_rollback)
+    }
+
+    /// Share the tunnel with devices on `lan_interface`, e.g. a console or TV
+    ///
+    /// The devices use this machine as their gateway; their traffic is NATed
+    /// into the tunnel and never forwarded anywhere else.
+    pub fn enable_sharing(
+        &mut self,
+        params: &KillSwitchParams,
+        lan_interface: &str,
+    ) -> Result<(), VpnError> {
+        // The rollback captures the state to restore, so it must see the system unshared
+        remove(&mut self.sharing_rollback)?;
+
+        let enable = sharing_enable_commands(params, lan_interface);
+        if enable.is_empty() {
+            return Err(VpnError::PlatformNotSupported);
+        }
+        log::info!("Sharing the tunnel with {}", lan_interface);
+        apply(
+            &mut self.sharing_rollback,
+            enable,
+            sharing_disable_commands(params, lan_interface),
+        )
+    }
+
+    /// Stop sharing and restore the forwarding setup from before `enable_sharing`
+    pub fn disable_sharing(&mut self) -> Result<(), VpnError> {
+        if self.sharing_rollback.is_some() {
+            log::info!("Stopping connection sharing");
+        }
+        remove(&mut self.sharing_rollback)
+    }
+}
+
+/// Run a rule set in place of the one tracked in `slot`, keeping its rollback
+fn apply(
+    slot: &mut Option<Vec<Step>>,
+    enable: Vec<Step>,
+    rollback: Vec<Step>,
+) -> Result<(), VpnError> {
+    remove(slot)?;
+
+    let mut releases = Vec::new();
+    if let Err(e) = run_all(&enable, &mut releases) {
+        // Never leave a half-applied rule set behind
+        let _ = run_rollback(&[rollback, releases].concat());
+        return Err(e);
+    }
+
+    *slot = Some(rollback.into_iter().chain(releases).collect());
+    Ok(())
+}
+
+fn remove(slot: &mut Option<Vec<Step>>) -> Result<(), VpnError> {
+    match slot.take() {
+        Some(rollback) => run_rollback(&rollback),
+        None => Ok(()),
+    }
+}
+
+/// Command lines `enable_kill_switch` would run, without running them
+pub fn planned_commands(params: &KillSwitchParams) -> Vec<String> {
+    enable_commands(params)
+        .iter()
+        .map(Step::to_string)
+        .collect()
+}
+
+/// Command lines `enable_leak_protection` would run, without running them
+pub fn planned_leak_commands(params: &KillSwitchParams) -> Vec<String> {
+    leak_enable_commands(params)
+        .iter()
+        .map(Step::to_string)
+        .collect()
+}
+
+/// Command lines `enable_inbound_block` would run, without running them
+pub fn planned_inbound_commands(params: &KillSwitchParams) -> Vec<String> {
+    inbound_enable_commands(params)
+        .iter()
+        .map(Step::to_string)
+        .collect()
+}
+
+/// Command lines `enable_mss_clamp` would run, without running them
+pub fn planned_mss_commands(params: &KillSwitchParams, mtu: u32) -> Vec<String> {
+    mss_enable_commands(params, mtu)
+        .iter()
+        .map(Step::to_string)
+        .collect()
+}
+
+/// `LEAK_PORTS` joined with `separator`, ranges written as `low{range}high`
+#[cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+fn leak_ports(separator: &str, range: &str) -> String {
+    LEAK_PORTS
+        .iter()
+        .map(|&(low, high)| {
+            if low == high {
+                low.to_string()
+            } else {
+                format!("{}{}{}", low, range, high)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn join_addresses(addresses: &[IpAddr], separator: &str) -> String {
+    addresses
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Every local address except the tunnel's, as netsh ranges
+///
+/// netsh block rules always win over allow rules, so "everything but the
+/// tunnel" has to be spelled out as the ranges around it.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn all_but(address: &str) -> String {
+    let ipv6 = "::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff";
+    let Ok(address) = address.parse::<std::net::Ipv4Addr>() else {
+        return "any".to_string();
+    };
+
+    let n = u32::from(address);
+    let mut ranges = Vec::new();
+    if n > 0 {
+        ranges.push(format!("0.0.0.0-{}", std::net::Ipv4Addr::from(n - 1)));
+    }
+    if n < u32::MAX {
+        ranges.push(format!(
+            "{}-255.255.255.255",
+            std::net::Ipv4Addr::from(n + 1)
+        ));
+    }
+    ranges.push(ipv6.to_string());
+    ranges.join(",")
+}
+
+/// Run `commands` in order, adding the steps that undo their side effects to `releases`
+fn run_all(commands: &[Step], releases: &mut Vec<Step>) -> Result<(), VpnError> {
+    for step in commands {
+        let Some((program, args)) = step.argv.split_first() else {
+            continue;
+        };
+        if simulate::enabled() {
+            log::info!("Simulated: {}", step);
+            continue;
+        }
+
+        let output = execute(program, args, step.input.as_deref()).map_err(|e| {
+            events::record(
+                EventCategory::Privileged,
+                Severity::Error,
+                format!("Could not run: {}", step),
+            );
+            VpnError::PermissionDenied(format!("Failed to run {}: {}", program, e))
+        })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            events::record(
+                EventCategory::Privileged,
+                Severity::Error,
+                format!("Failed: {}", step),
+            );
+            return Err(VpnError::WireGuardError(format!(
+                "Firewall command '{}' failed: {}",
+                step,
+                stderr.trim()
+            )));
+        }
+        events::record(EventCategory::Privileged, Severity::Info, step.to_string());
+        releases.extend(release_step(&step.argv, &output));
+    }
+    Ok(())
+}
+
+fn execute(program: &str, args: &[String], input: Option<&str>) -> std::io::Result<Output> {
+    let Some(input) = input else {
+        return Command::new(program).args(args).output();
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    child.wait_with_output()
+}
+
+/// Run every rollback command even if some fail, reporting the first failure
+fn run_rollback(commands: &[Step]) -> Result<(), VpnError> {
+    let mut first_error = None;
+    for step in commands {
+        if let Err(e) = run_all(std::slice::from_ref(step), &mut Vec::new()) {
+            log::warn!("Rollback step failed: {}", e);
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// One command of a rule set
+#[derive(Debug, Clone)]
+struct Step {
+    argv: Vec<String>,
+    /// Written to the command's stdin, so rule text never passes through a shell
+    input: Option<String>,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.argv.join(" "))?;
+        if let Some(input) = &self.input {
+            write!(f, " <<< '{}'", input.replace('\n', "; "))?;
+        }
+        Ok(())
+    }
+}
+
+fn argv(parts: &[&str]) -> Step {
+    Step {
+        argv: parts.iter().map(|s| s.to_string()).collect(),
+        input: None,
+    }
+}
+
+/// `parts` run with `input` on stdin
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn piped(parts: &[&str], input: String) -> Step {
+    Step {
+        input: Some(input),
+        ..argv(parts)
+    }
+}
+
+/// The step giving back what a successful `command` took
+///
+/// Each `pfctl -E` takes a reference on pf and prints its token; `pfctl -X`
+/// drops it again, so pf turns off only once no one else holds a reference.
+#[cfg(target_os = "macos")]
+fn release_step(command: &[String], output: &Output) -> Option<Step> {
+    if command != ["pfctl", "-E"] {
+        return None;
+    }
+    let text = [&output.stderr[..], &output.stdout[..]].concat();
+    let token = pf_token(&String::from_utf8_lossy(&text))?;
+    Some(argv(&["pfctl", "-X", &token]))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn release_step(_command: &[String], _output: &Output) -> Option<Step> {
+    None
+}
+
+/// The reference token in `pfctl -E` output, e.g. "Token : 1234567890"
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn pf_token(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        let value = value.trim();
+        (key.trim() == "Token" && !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()))
+            .then(|| value.to_string())
+    })
+}
+
+#[cfg(target_os = "windows")]
+const RULE_PREFIX: &str = "SACVPN-KillSwitch";
+
+/// Every port except `ports`, as netsh ranges; `None` when that leaves nothing
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn ports_except(ports: &[u16]) -> Option<String> {
+    let mut ports = ports.to_vec();
+    ports.retain(|&p| p != 0);
+    ports.sort_unstable();
+    ports.dedup();
+
+    let mut ranges = Vec::new();
+    let mut next = 1u32;
+    for port in ports.into_iter().map(u32::from).chain([65536]) {
+        match port - next {
+            0 => {}
+            1 => ranges.push(next.to_string()),
+            _ => ranges.push(format!("{}-{}", next, port - 1)),
+        }
+        next = port + 1;
+    }
+    (!ranges.is_empty()).then(|| ranges.join(","))
+}
+
+#[cfg(target_os = "windows")]
+fn enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let mut commands = vec![
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "set",
+            "allprofiles",
+            "firewallpolicy",
+            "blockinbound,blockoutbound",
+        ]),
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &format!("name={}-Tunnel", RULE_PREFIX),
+            "dir=out",
+            "action=allow",
+            &format!("localip={}", params.tunnel_address),
+        ]),
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &format!("name={}-Endpoint", RULE_PREFIX),
+            "dir=out",
+            "action=allow",
+            "protocol=udp",
+            &format!("remoteip={}", join_addresses(&params.endpoint_hosts, ",")),
+        ]),
+    ];
+
+    if params.allow_lan {
+        commands.push(argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &format!("name={}-LAN", RULE_PREFIX),
+            "dir=out",
+            "action=allow",
+            &format!("remoteip={}", LAN_RANGES.join(",")),
+        ]));
+    }
+
+    commands
+}
+
+#[cfg(target_os = "windows")]
+fn disable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let suffixes: &[&str] = if params.allow_lan {
+        &["Tunnel", "Endpoint", "LAN"]
+    } else {
+        &["Tunnel", "Endpoint"]
+    };
+
+    let mut commands: Vec<Step> = suffixes
+        .iter()
+        .map(|suffix| {
+            argv(&[
+                "netsh",
+                "advfirewall",
+                "firewall",
+                "delete",
+                "rule",
+                &format!("name={}-{}", RULE_PREFIX, suffix),
+            ])
+        })
+        .collect();
+
+    // Restore the Windows default policy
+    commands.insert(
+        0,
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "set",
+            "allprofiles",
+            "firewallpolicy",
+            "blockinbound,allowoutbound",
+        ]),
+    );
+    commands
+}
+
+#[cfg(target_os = "windows")]
+fn leak_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    vec![
+        argv(&["netsh", "interface", "teredo", "set", "state", "disabled"]),
+        argv(&["netsh", "interface", "6to4", "set", "state", "disabled"]),
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &format!("name={}-STUN", LEAK_RULE_PREFIX),
+            "dir=out",
+            "action=block",
+            "protocol=udp",
+            &format!("remoteport={}", leak_ports(",", "-")),
+            &format!("localip={}", all_but(&params.tunnel_address.to_string())),
+        ]),
+    ]
+}
+
+#[cfg(target_os = "windows")]
+fn leak_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    vec![
+        argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "delete",
+            "rule",
+            &format!("name={}-STUN", LEAK_RULE_PREFIX),
+        ]),
+        argv(&["netsh", "interface", "teredo", "set", "state", "default"]),
+        argv(&["netsh", "interface", "6to4", "set", "state", "default"]),
+    ]
+}
+
+#[cfg(target_os = "windows")]
+const LEAK_RULE_PREFIX: &str = "SACVPN-LeakProtection";
+
+#[cfg(target_os = "windows")]
+const INBOUND_RULE_PREFIX: &str = "SACVPN-InboundBlock";
+
+/// Block rules win over any allow rule, so forwarded ports are carved out of
+/// the blocked port ranges rather than allowed separately
+#[cfg(target_os = "windows")]
+fn inbound_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let rule = |suffix: &str, protocol: &str, localport: Option<&str>| {
+        let mut rule = argv(&[
+            "netsh",
+            "advfirewall",
+            "firewall",
+            "add",
+            "rule",
+            &format!("name={}-{}", INBOUND_RULE_PREFIX, suffix),
+            "dir=in",
+            "action=block",
+            &format!("protocol={}", protocol),
+            &format!("localip={}", params.tunnel_address),
+        ]);
+        if let Some(localport) = localport {
+            rule.push(format!("localport={}", localport));
+        }
+        rule
+    };
+
+    if params.forwarded_ports.is_empty() {
+        return vec![rule("All", "any", None)];
+    }
+
+    let mut commands = vec![rule("ICMP", "icmpv4", None)];
+    if let Some(blocked) = ports_except(&params.forwarded_ports) {
+        commands.push(rule("TCP", "tcp", Some(&blocked)));
+        commands.push(rule("UDP", "udp", Some(&blocked)));
+    }
+    commands
+}
+
+#[cfg(target_os = "windows")]
+fn inbound_disable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let suffixes: &[&str] = if params.forwarded_ports.is_empty() {
+        &["All"]
+    } else {
+        &["ICMP", "TCP", "UDP"]
+    };
+    suffixes
+        .iter()
+        .map(|suffix| {
+            argv(&[
+                "netsh",
+                "advfirewall",
+                "firewall",
+                "delete",
+                "rule",
+                &format!("name={}-{}", INBOUND_RULE_PREFIX, suffix),
+            ])
+        })
+        .collect()
+}
+
+/// Internet Connection Sharing through its COM API: the tunnel is the shared
+/// (public) connection and the LAN adapter the private one
+#[cfg(target_os = "windows")]
+fn ics_command(params: &KillSwitchParams, lan_interface: &str, enable: bool) -> Step {
+    let (public, private) = if enable {
+        ("EnableSharing(0)", "EnableSharing(1)")
+    } else {
+        ("DisableSharing()", "DisableSharing()")
+    };
+    let script = format!(
+        "$share = New-Object -ComObject HNetCfg.HNetShare; $found = 0; \
+         foreach ($c in $share.EnumEveryConnection) {{ \
+         $name = $share.NetConnectionProps.Invoke($c).Name; \
+         $config = $share.INetSharingConfigurationForINetConnection.Invoke($c); \
+         if ($name -eq {}) {{ $config.{}; $found++ }} \
+         elseif ($name -eq {}) {{ $config.{}; $found++ }} }}; \
+         if ($found -lt 2) {{ throw 'Network adapter not found' }}",
+        ps_quote(&params.tunnel_name),
+        public,
+        ps_quote(lan_interface),
+        private
+    );
+    argv(&[
+        "powershell",
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        &script,
+    ])
+}
+
+/// A PowerShell single-quoted string literal
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn ps_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(target_os = "windows")]
+fn sharing_enable_commands(params: &KillSwitchParams, lan_interface: &str) -> Vec<Step> {
+    vec![ics_command(params, lan_interface, true)]
+}
+
+#[cfg(target_os = "windows")]
+fn sharing_disable_commands(params: &KillSwitchParams, lan_interface: &str) -> Vec<Step> {
+    vec![ics_command(params, lan_interface, false)]
+}
+
+#[cfg(target_os = "linux")]
+const CHAIN: &str = "SACVPN_KILLSWITCH";
+
+#[cfg(target_os = "linux")]
+const LEAK_CHAIN: &str = "SACVPN_LEAKS";
+
+#[cfg(target_os = "linux")]
+const INBOUND_CHAIN: &str = "SACVPN_INBOUND";
+
+#[cfg(target_os = "linux")]
+fn enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let mut commands = vec![
+        argv(&["iptables", "-N", CHAIN]),
+        argv(&["iptables", "-A", CHAIN, "-o", "lo", "-j", "ACCEPT"]),
+        argv(&[
+            "iptables",
+            "-A",
+            CHAIN,
+            "-o",
+            &params.tunnel_name,
+            "-j",
+            "ACCEPT",
+        ]),
+        argv(&[
+            "iptables",
+            "-A",
+            CHAIN,
+            "-d",
+            &join_addresses(&params.endpoint_hosts, ","),
+            "-p",
+            "udp",
+            "-j",
+            "ACCEPT",
+        ]),
+    ];
+
+    // The endpoint rule misses a roamed or re-resolved endpoint; the mark doesn't
+    if let Some(fwmark) = params.fwmark {
+        commands.push(argv(&[
+            "iptables",
+            "-A",
+            CHAIN,
+            "-m",
+            "mark",
+            "--mark",
+            &format!("{:#x}", fwmark),
+            "-j",
+            "ACCEPT",
+        ]));
+    }
+
+    if params.allow_lan {
+        for range in LAN_RANGES {
+            commands.push(argv(&[
+                "iptables", "-A", CHAIN, "-d", range, "-j", "ACCEPT",
+            ]));
+        }
+    }
+
+    commands.push(argv(&["iptables", "-A", CHAIN, "-j", "REJECT"]));
+    commands.push(argv(&["iptables", "-I", "OUTPUT", "-j", CHAIN]));
+    commands
+}
+
+#[cfg(target_os = "linux")]
+fn disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    vec![
+        argv(&["iptables", "-D", "OUTPUT", "-j", CHAIN]),
+        argv(&["iptables", "-F", CHAIN]),
+        argv(&["iptables", "-X", CHAIN]),
+    ]
+}
+
+#[cfg(target_os = "linux")]
+fn leak_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let ports = leak_ports(",", ":");
+    let mut commands = Vec::new();
+    for iptables in ["iptables", "ip6tables"] {
+        commands.extend([
+            argv(&[iptables, "-N", LEAK_CHAIN]),
+            argv(&[
+                iptables,
+                "-A",
+                LEAK_CHAIN,
+                "-o",
+                &params.tunnel_name,
+                "-j",
+                "RETURN",
+            ]),
+            argv(&[
+                iptables,
+                "-A",
+                LEAK_CHAIN,
+                "-p",
+                "udp",
+                "-m",
+                "multiport",
+                "--dports",
+                &ports,
+                "-j",
+                "REJECT",
+            ]),
+        ]);
+    }
+    // 6to4 and other IPv6-in-IPv4 tunnels
+    commands.push(argv(&[
+        "iptables", "-A", LEAK_CHAIN, "-p", "41", "-j", "DROP",
+    ]));
+    commands.push(argv(&["iptables", "-I", "OUTPUT", "-j", LEAK_CHAIN]));
+    commands.push(argv(&["ip6tables", "-I", "OUTPUT", "-j", LEAK_CHAIN]));
+    commands
+}
+
+#[cfg(target_os = "linux")]
+fn leak_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    ["iptables", "ip6tables"]
+        .into_iter()
+        .flat_map(|iptables| {
+            [
+                argv(&[iptables, "-D", "OUTPUT", "-j", LEAK_CHAIN]),
+                argv(&[iptables, "-F", LEAK_CHAIN]),
+                argv(&[iptables, "-X", LEAK_CHAIN]),
+            ]
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn inbound_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let mut commands = Vec::new();
+    // An IPv6 tunnel address is as reachable as the IPv4 one
+    for iptables in ["iptables", "ip6tables"] {
+        commands.extend([
+            argv(&[iptables, "-N", INBOUND_CHAIN]),
+            // Replies to connections we opened
+            argv(&[
+                iptables,
+                "-A",
+                INBOUND_CHAIN,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "ESTABLISHED,RELATED",
+                "-j",
+                "RETURN",
+            ]),
+        ]);
+        for port in &params.forwarded_ports {
+            for protocol in ["tcp", "udp"] {
+                commands.push(argv(&[
+                    iptables,
+                    "-A",
+                    INBOUND_CHAIN,
+                    "-p",
+                    protocol,
+                    "--dport",
+                    &port.to_string(),
+                    "-j",
+                    "RETURN",
+                ]));
+            }
+        }
+        commands.push(argv(&[iptables, "-A", INBOUND_CHAIN, "-j", "DROP"]));
+        commands.push(argv(&[
+            iptables,
+            "-I",
+            "INPUT",
+            "-i",
+            &params.tunnel_name,
+            "-j",
+            INBOUND_CHAIN,
+        ]));
+    }
+    commands
+}
+
+#[cfg(target_os = "linux")]
+fn inbound_disable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    ["iptables", "ip6tables"]
+        .into_iter()
+        .flat_map(|iptables| {
+            [
+                argv(&[
+                    iptables,
+                    "-D",
+                    "INPUT",
+                    "-i",
+                    &params.tunnel_name,
+                    "-j",
+                    INBOUND_CHAIN,
+                ]),
+                argv(&[iptables, "-F", INBOUND_CHAIN]),
+                argv(&[iptables, "-X", INBOUND_CHAIN]),
+            ]
+        })
+        .collect()
+}
+
+/// Rewrites SYNs leaving through the tunnel, including those of shared devices
+#[cfg(target_os = "linux")]
+fn mss_rule(iptables: &str, action: &str, tunnel: &str) -> Step {
+    argv(&[
+        iptables,
+        "-t",
+        "mangle",
+        action,
+        "POSTROUTING",
+        "-o",
+        tunnel,
+        "-p",
+        "tcp",
+        "--tcp-flags",
+        "SYN,RST",
+        "SYN",
+        "-j",
+        "TCPMSS",
+        "--clamp-mss-to-pmtu",
+    ])
+}
+
+// The kernel reads the MTU off the route, so the rules follow MTU changes
+#[cfg(target_os = "linux")]
+fn mss_enable_commands(params: &KillSwitchParams, _mtu: u32) -> Vec<Step> {
+    ["iptables", "ip6tables"]
+        .into_iter()
+        .map(|iptables| mss_rule(iptables, "-A", &params.tunnel_name))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn mss_disable_commands(params: &KillSwitchParams, _mtu: u32) -> Vec<Step> {
+    ["iptables", "ip6tables"]
+        .into_iter()
+        .map(|iptables| mss_rule(iptables, "-D", &params.tunnel_name))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+const SHARING_CHAIN: &str = "SACVPN_SHARING";
+
+#[cfg(target_os = "linux")]
+fn sharing_enable_commands(params: &KillSwitchParams, lan_interface: &str) -> Vec<Step> {
+    let tunnel = params.tunnel_name.as_str();
+    vec![
+        argv(&["sysctl", "-w", "net.ipv4.ip_forward=1"]),
+        argv(&["iptables", "-N", SHARING_CHAIN]),
+        argv(&[
+            "iptables",
+            "-A",
+            SHARING_CHAIN,
+            "-i",
+            lan_interface,
+            "-o",
+            tunnel,
+            "-j",
+            "ACCEPT",
+        ]),
+        argv(&[
+            "iptables",
+            "-A",
+            SHARING_CHAIN,
+            "-i",
+            tunnel,
+            "-o",
+            lan_interface,
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "ACCEPT",
+        ]),
+        // Shared devices must not fall back to the physical uplink if the tunnel drops
+        argv(&[
+            "iptables",
+            "-A",
+            SHARING_CHAIN,
+            "-i",
+            lan_interface,
+            "-j",
+            "REJECT",
+        ]),
+        argv(&["iptables", "-I", "FORWARD", "-j", SHARING_CHAIN]),
+        argv(&[
+            "iptables",
+            "-t",
+            "nat",
+            "-A",
+            "POSTROUTING",
+            "-o",
+            tunnel,
+            "-j",
+            "MASQUERADE",
+        ]),
+    ]
+}
+
+/// Built before the enable commands run, so it restores the forwarding setting found then
+#[cfg(target_os = "linux")]
+fn sharing_disable_commands(params: &KillSwitchParams, _lan_interface: &str) -> Vec<Step> {
+    let forwarding = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")
+        .map(|value| value.trim().to_string())
+        .unwrap_or_else(|_| "0".to_string());
+    vec![
+        argv(&[
+            "iptables",
+            "-t",
+            "nat",
+            "-D",
+            "POSTROUTING",
+            "-o",
+            &params.tunnel_name,
+            "-j",
+            "MASQUERADE",
+        ]),
+        argv(&["iptables", "-D", "FORWARD", "-j", SHARING_CHAIN]),
+        argv(&["iptables", "-F", SHARING_CHAIN]),
+        argv(&["iptables", "-X", SHARING_CHAIN]),
+        argv(&[
+            "sysctl",
+            "-w",
+            &format!("net.ipv4.ip_forward={}", forwarding),
+        ]),
+    ]
+}
+
+// Anchors under com.apple/ are evaluated by the stock macOS pf.conf
+#[cfg(target_os = "macos")]
+const PF_ANCHOR: &str = "com.apple/sacvpn.killswitch";
+
+/// Turn pf on and replace the rules of `anchor`, passed to pfctl on stdin
+#[cfg(target_os = "macos")]
+fn pf_load(anchor: &str, rules: &[String]) -> Vec<Step> {
+    vec![
+        argv(&["pfctl", "-E"]),
+        piped(&["pfctl", "-a", anchor, "-f", "-"], rules.join("\n")),
+    ]
+}
+
+#[cfg(target_os = "macos")]
+fn enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let mut rules = vec![
+        "block drop out all".to_string(),
+        "pass out quick on lo0 all".to_string(),
+        format!("pass out quick from {} to any", params.tunnel_address),
+    ];
+    if !params.endpoint_hosts.is_empty() {
+        rules.push(format!(
+            "pass out quick proto udp to {{ {} }}",
+            join_addresses(&params.endpoint_hosts, ", ")
+        ));
+    }
+
+    if params.allow_lan {
+        for range in LAN_RANGES {
+            rules.push(format!("pass out quick to {}", range));
+        }
+    }
+
+    pf_load(PF_ANCHOR, &rules)
+}
+
+#[cfg(target_os = "macos")]
+fn disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    vec![argv(&["pfctl", "-a", PF_ANCHOR, "-F", "all"])]
+}
+
+#[cfg(target_os = "macos")]
+const PF_LEAK_ANCHOR: &str = "com.apple/sacvpn.leaks";
+
+#[cfg(target_os = "macos")]
+fn leak_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let ports = leak_ports(" ", ":");
+    let rules = [
+        // 6to4 and other IPv6-in-IPv4 tunnels
+        "block drop out quick proto 41 all".to_string(),
+        format!(
+            "block drop out quick inet proto udp from ! {} to any port {{ {} }}",
+            params.tunnel_address, ports
+        ),
+        format!(
+            "block drop out quick inet6 proto udp to any port {{ {} }}",
+            ports
+        ),
+    ];
+
+    pf_load(PF_LEAK_ANCHOR, &rules)
+}
+
+#[cfg(target_os = "macos")]
+fn leak_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    vec![argv(&["pfctl", "-a", PF_LEAK_ANCHOR, "-F", "all"])]
+}
+
+#[cfg(target_os = "macos")]
+const PF_INBOUND_ANCHOR: &str = "com.apple/sacvpn.inbound";
+
+/// pf keeps state for outbound connections, so replies never reach these rules
+#[cfg(target_os = "macos")]
+fn inbound_enable_commands(params: &KillSwitchParams) -> Vec<Step> {
+    let mut rules = Vec::new();
+    if !params.forwarded_ports.is_empty() {
+        let ports: Vec<String> = params.forwarded_ports.iter().map(u16::to_string).collect();
+        rules.push(format!(
+            "pass in quick inet proto {{ tcp udp }} to {} port {{ {} }}",
+            params.tunnel_address,
+            ports.join(" ")
+        ));
+    }
+    rules.push(format!(
+        "block drop in quick inet to {}",
+        params.tunnel_address
+    ));
+
+    pf_load(PF_INBOUND_ANCHOR, &rules)
+}
+
+#[cfg(target_os = "macos")]
+fn inbound_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    vec![argv(&["pfctl", "-a", PF_INBOUND_ANCHOR, "-F", "all"])]
+}
+
+#[cfg(target_os = "macos")]
+const PF_MSS_ANCHOR: &str = "com.apple/sacvpn.mss";
+
+/// Scrub rules for both directions, so SYN-ACKs are clamped as well as SYNs
+#[cfg(target_os = "macos")]
+fn mss_enable_commands(params: &KillSwitchParams, mtu: u32) -> Vec<Step> {
+    let mss = super::mss::max_mss(mtu, false);
+    let rules = [
+        format!(
+            "scrub out proto tcp from {} to any max-mss {}",
+            params.tunnel_address, mss
+        ),
+        format!(
+            "scrub in proto tcp from any to {} max-mss {}",
+            params.tunnel_address, mss
+        ),
+    ];
+
+    pf_load(PF_MSS_ANCHOR, &rules)
+}
+
+#[cfg(target_os = "macos")]
+fn mss_disable_commands(_params: &KillSwitchParams, _mtu: u32) -> Vec<Step> {
+    vec![argv(&["pfctl", "-a", PF_MSS_ANCHOR, "-F", "all"])]
+}
+
+// macOS Internet Sharing has no supported command-line interface
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn sharing_enable_commands(_params: &KillSwitchParams, _lan_interface: &str) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn sharing_disable_commands(_params: &KillSwitchParams, _lan_interface: &str) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mss_enable_commands(_params: &KillSwitchParams, _mtu: u32) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn mss_disable_commands(_params: &KillSwitchParams, _mtu: u32) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn inbound_enable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn inbound_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn leak_enable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn leak_disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn enable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn disable_commands(_params: &KillSwitchParams) -> Vec<Step> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_but_excludes_only_the_tunnel_address() {
+        assert_eq!(
+            all_but("10.8.0.2"),
+            "0.0.0.0-10.8.0.1,10.8.0.3-255.255.255.255,::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        assert!(all_but("0.0.0.0").starts_with("0.0.0.1-255.255.255.255,"));
+        assert_eq!(all_but("not an address"), "any");
+        assert_eq!(leak_ports(",", "-"), "3478-3479,3544,5349,19302-19309");
+        assert_eq!(ps_quote("Wi-Fi 'Home'"), "'Wi-Fi ''Home'''");
+    }
+
+    #[test]
+    fn test_ports_except_leaves_forwarded_ports_open() {
+        assert_eq!(
+            ports_except(&[8080, 22, 23, 22]).as_deref(),
+            Some("1-21,24-8079,8081-65535")
+        );
+        assert_eq!(ports_except(&[1, 65535]).as_deref(), Some("2-65534"));
+        assert_eq!(ports_except(&[]).as_deref(), Some("1-65535"));
+    }
+
+    #[test]
+    fn test_pf_rules_go_to_stdin() {
+        let step = piped(&["pfctl", "-a", "sacvpn", "-f", "-"], "a\nb".to_string());
+        assert_eq!(step.argv, ["pfctl", "-a", "sacvpn", "-f", "-"]);
+        assert_eq!(step.to_string(), "pfctl -a sacvpn -f - <<< 'a; b'");
+
+        let output = "No ALTQ support in kernel\npf enabled\nToken : 1234567890\n";
+        assert_eq!(pf_token(output).as_deref(), Some("1234567890"));
+        assert_eq!(pf_token("pf enabled\nToken : 12; rm -rf /"), None);
+        assert_eq!(pf_token("pf already enabled"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inbound_block_covers_ipv6() {
+        let params = KillSwitchParams {
+            tunnel_name: "sacvpn0".to_string(),
+            tunnel_address: "10.8.0.2".parse().unwrap(),
+            endpoint_hosts: Vec::new(),
+            allow_lan: false,
+            forwarded_ports: vec![8080],
+            fwmark: None,
+        };
+        let commands = planned_inbound_commands(&params);
+        for iptables in ["iptables", "ip6tables"] {
+            let hook = format!("{} -I INPUT -i sacvpn0 -j {}", iptables, INBOUND_CHAIN);
+            assert!(commands.contains(&hook));
+            let forwarded = format!(
+                "{} -A {} -p udp --dport 8080 -j RETURN",
+                iptables, INBOUND_CHAIN
+            );
+            assert!(commands.contains(&forwarded));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Handle to a `VpnManager` owned by its own task
+//!
+//! The manager lives on a single task that runs calls one at a time in the
+//! order they arrive, so a connect, a watchdog reconnect and a status poll can
+//! never interleave. Handles are cheap to clone and independent of each other:
+//! the app keeps one in Tauri's managed state, and tests can create as many
+//! isolated managers as they like.
+
+use super::VpnManager;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn for<'a> FnOnce(&'a mut VpnManager) -> BoxFuture<'a, ()> + Send>;
+
+#[derive(Clone)]
+pub struct VpnHandle {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl VpnHandle {
+    /// Handle to `manager`, plus the task owning it for the caller to spawn on
+    /// its runtime
+    pub fn new(manager: VpnManager) -> (Self, impl Future<Output = ()> + Send + 'static) {
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        let task = async move {
+            let mut manager = manager;
+            while let Some(job) = queue.recv().await {
+                // A panicking call must not take every later one down with it
+                if AssertUnwindSafe(job(&mut manager))
+                    .catch_unwind()
+                    .await
+                    .is_err()
+                {
+                    log::error!("VPN manager call panicked");
+                }
+            }
+        };
+        (Self { jobs }, task)
+    }
+
+    /// Run `f` with exclusive access to the manager and return its result
+    ///
+    /// Calling the handle again from inside `f` deadlocks.
+    pub async fn call<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut VpnManager) -> BoxFuture<'a, T> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |vpn| {
+            Box::pin(async move {
+                let _ = reply.send(f(vpn).await);
+            })
+        });
+        if self.jobs.send(job).is_err() {
+            panic!("VPN manager task has stopped");
+        }
+        match result.await {
+            Ok(value) => value,
+            // The call panicked; the task has logged it and moved on
+            Err(_) => panic!("VPN manager call failed"),
+        }
+    }
+
+    /// `call` for work that doesn't need to await
+    pub async fn with<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut VpnManager) -> T + Send + 'static,
+    {
+        self.call(move |vpn| {
+            let value = f(vpn);
+            Box::pin(async move { value })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ErrorPhase, VpnError, VpnStatus};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_isolated_handles() {
+        let (first, task) = VpnHandle::new(VpnManager::new());
+        tokio::spawn(task);
+        let (second, task) = VpnHandle::new(VpnManager::new());
+        tokio::spawn(task);
+
+        first
+            .with(|vpn| {
+                let error = VpnError::ConnectionFailed("timed out".to_string());
+                vpn.record_error(ErrorPhase::Connecting, &error);
+            })
+            .await;
+        assert_eq!(first.with(|vpn| vpn.error_history()).await.len(), 1);
+        assert!(second.with(|vpn| vpn.error_history()).await.is_empty());
+        assert_eq!(
+            second.with(|vpn| vpn.get_status()).await,
+            VpnStatus::Disconnected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_survives_panicking_call() {
+        let (handle, task) = VpnHandle::new(VpnManager::new());
+        tokio::spawn(task);
+
+        let panicking = handle.clone();
+        let result = tokio::spawn(async move {
+            panicking.with(|_| panic!("boom")).await;
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            handle.with(|vpn| vpn.get_status()).await,
+            VpnStatus::Disconnected
+        );
+    }
+}
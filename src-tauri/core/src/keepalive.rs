@@ -0,0 +1,170 @@
+//! Adaptive persistent keepalive
+//!
+//! A fixed keepalive is either too short for a friendly NAT, waking the radio
+//! for nothing, or too long for an aggressive one that drops the binding and
+//! with it any traffic the server tries to send. The tuner starts from the
+//! configured interval, stretches it while the binding holds and shrinks it as
+//! soon as it sees the binding expire: inbound traffic that went quiet and only
+//! came back right after a keepalive reopened the path.
+
+// Only the embedded tunnel sends its own keepalives
+#![cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+
+use std::time::{Duration, Instant};
+
+/// Shortest interval the tuner will use, however aggressive the NAT
+pub const MIN_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Longest interval the tuner will try
+pub const MAX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the binding must hold before a longer interval is tried
+const GROW_AFTER: Duration = Duration::from_secs(600);
+
+/// Amount the interval grows by after each stable period
+const GROW_STEP: Duration = Duration::from_secs(5);
+
+/// Inbound arriving this soon after a keepalive was waiting for the binding to reopen
+const RESUME_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveTuner {
+    interval: Duration,
+    /// Interval at which the binding was last seen to expire; never grown back to
+    ceiling: Option<Duration>,
+    last_inbound: Option<Instant>,
+    /// Last packet of any kind sent to the peer
+    last_sent: Instant,
+    last_keepalive: Option<Instant>,
+    stable_since: Instant,
+}
+
+impl KeepaliveTuner {
+    pub fn new(initial_secs: u16, now: Instant) -> Self {
+        Self {
+            interval: Duration::from_secs(initial_secs.into()).clamp(MIN_INTERVAL, MAX_INTERVAL),
+            ceiling: None,
+            last_inbound: None,
+            last_sent: now,
+            last_keepalive: None,
+            stable_since: now,
+        }
+    }
+
+    pub fn interval_secs(&self) -> u16 {
+        self.interval.as_secs() as u16
+    }
+
+    /// A data packet was sent to the peer
+    pub fn on_outbound(&mut self, now: Instant) {
+        self.last_sent = now;
+        self.last_keepalive = None;
+    }
+
+    /// A packet arrived from the peer
+    pub fn on_inbound(&mut self, now: Instant) {
+        let resumed_by_keepalive = self
+            .last_keepalive
+            .is_some_and(|sent| now.duration_since(sent) <= RESUME_WINDOW);
+        let silent = self.last_inbound.map(|last| now.duration_since(last));
+
+        // A keepalive carries no request, so a reply right after one means the
+        // server had traffic for us that the NAT was dropping
+        if resumed_by_keepalive && silent.is_some_and(|silent| silent > self.interval) {
+            self.binding_expired(now);
+        }
+        self.last_inbound = Some(now);
+        self.last_keepalive = None;
+    }
+
+    /// Whether a keepalive should be sent now; records it as sent if so
+    pub fn keepalive_due(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.stable_since) >= GROW_AFTER {
+            self.grow(now);
+        }
+        if now.duration_since(self.last_sent) < self.interval {
+            return false;
+        }
+        self.last_sent = now;
+        self.last_keepalive = Some(now);
+        true
+    }
+
+    fn binding_expired(&mut self, now: Instant) {
+        let shorter = Duration::from_secs(self.interval.as_secs() * 2 / 3).max(MIN_INTERVAL);
+        log::info!(
+            "NAT binding expired within {}s, keepalive now every {}s",
+            self.interval.as_secs(),
+            shorter.as_secs()
+        );
+        self.ceiling = Some(self.interval);
+        self.interval = shorter;
+        self.stable_since = now;
+    }
+
+    fn grow(&mut self, now: Instant) {
+        let limit = self
+            .ceiling
+            .map(|ceiling| ceiling.saturating_sub(GROW_STEP))
+            .unwrap_or(MAX_INTERVAL)
+            .clamp(MIN_INTERVAL, MAX_INTERVAL);
+        let longer = (self.interval + GROW_STEP).min(limit);
+        if longer > self.interval {
+            log::debug!(
+                "NAT binding stable, keepalive now every {}s",
+                longer.as_secs()
+            );
+            self.interval = longer;
+        }
+        self.stable_since = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_binding_shortens_interval() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut tuner = KeepaliveTuner::new(25, start);
+        tuner.on_inbound(at(0));
+        assert!(!tuner.keepalive_due(at(24_000)));
+
+        // A request and its reply are not a sign of anything
+        tuner.on_outbound(at(30_000));
+        tuner.on_inbound(at(30_200));
+        assert_eq!(tuner.interval_secs(), 25);
+
+        // Nothing came in until the keepalive went out
+        assert!(tuner.keepalive_due(at(55_000)));
+        tuner.on_inbound(at(55_300));
+        assert_eq!(tuner.interval_secs(), 16);
+    }
+
+    #[test]
+    fn test_stable_binding_grows_interval_below_ceiling() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut tuner = KeepaliveTuner::new(5, start);
+        assert_eq!(tuner.interval_secs(), 15);
+
+        tuner.keepalive_due(at(600));
+        assert_eq!(tuner.interval_secs(), 20);
+
+        tuner.ceiling = Some(Duration::from_secs(25));
+        tuner.keepalive_due(at(1200));
+        tuner.keepalive_due(at(1800));
+        assert_eq!(tuner.interval_secs(), 20);
+
+        let mut friendly = KeepaliveTuner::new(60, start);
+        friendly.keepalive_due(at(600));
+        assert_eq!(friendly.interval_secs(), 60);
+    }
+}
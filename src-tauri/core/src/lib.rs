@@ -0,0 +1,1861 @@
+//! SACVPN's VPN engine
+//!
+//! Tunnel backends, routing, DNS, firewall rules and connection stats, behind
+//! a `VpnManager` reached through a `VpnHandle`. Nothing here depends on
+//! Tauri: the desktop app, and any CLI or privileged service, drive the same
+//! engine, and the app reaches it under its old module name, `vpn`.
+
+mod autotune;
+mod backend;
+mod boottime;
+mod category;
+pub mod dns;
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+mod embedded;
+pub mod events;
+mod firewall;
+mod handle;
+mod keepalive;
+mod mss;
+mod network;
+mod ownership;
+mod padding;
+mod pmtu;
+mod polling;
+mod prewarm;
+mod recovery;
+mod resolve;
+mod rotation;
+mod routes;
+pub mod scoped;
+pub mod simulate;
+mod split;
+mod stats;
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+mod tun;
+pub mod watchdog;
+mod wgconf;
+#[cfg(target_os = "windows")]
+mod wgnt;
+mod wgquick;
+mod wireguard;
+
+pub use autotune::LinkTuning;
+pub use boottime::BootInstant;
+pub use category::NetworkCategory;
+pub use handle::VpnHandle;
+pub use network::{InterfaceState, LanInfo};
+pub use polling::ForwardingStats;
+pub use prewarm::prewarm;
+pub use recovery::{ErrorCode, ErrorReport};
+pub use resolve::AddressFamily;
+pub use rotation::{RotationMode, RotationPolicy};
+pub use split::{SplitMode, SplitRouteStats, SplitTunnelSettings};
+pub use stats::{format_speed, ConnectionStats, SessionSummary, StatsSettings};
+pub use watchdog::ReconnectPolicy;
+pub use wgconf::import_wg_quick;
+#[cfg(target_os = "windows")]
+pub use wireguard::{install_driver, wintun_dll_paths};
+
+use events::{EventCategory, Severity};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+#[derive(Debug, Error)]
+pub enum VpnError {
+    #[error("Connection failed: {0}")]
+    ConnectionFailed(String),
+
+    #[error("Disconnection failed: {0}")]
+    DisconnectionFailed(String),
+
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
+    #[error("Not connected")]
+    NotConnected,
+
+    #[error("Already connected")]
+    AlreadyConnected,
+
+    #[error("Platform not supported")]
+    PlatformNotSupported,
+
+    #[error("WireGuard error: {0}")]
+    WireGuardError(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Tunnel in use: {0}")]
+    TunnelInUse(String),
+
+    #[error("Tunnel interface down: {0}")]
+    InterfaceDown(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VpnStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+    /// Up, but a server has gone unanswered long enough that its hostname is
+    /// being looked up again in case it moved
+    Reconnecting,
+    Disconnecting,
+    /// Waiting for the network to reach the internet, as behind a captive
+    /// portal, before connecting or restoring the session
+    #[serde(rename = "no_network")]
+    NoNetwork,
+    Error(String),
+}
+
+impl VpnStatus {
+    /// Whether the tunnel is up, including while it follows a moved server
+    pub fn is_up(&self) -> bool {
+        matches!(self, Self::Connected | Self::Reconnecting)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawVpnConfig")]
+pub struct VpnConfig {
+    pub interface: InterfaceConfig,
+    /// Never empty; the first peer is the server itself and the rest, when a
+    /// gateway cluster publishes them, carry their own AllowedIPs
+    pub peers: Vec<PeerConfig>,
+}
+
+impl VpnConfig {
+    /// The peer at the server's address, which the uplink, stealth ports and
+    /// display follow
+    pub fn primary_peer(&self) -> &PeerConfig {
+        &self.peers[0]
+    }
+
+    pub fn primary_peer_mut(&mut self) -> &mut PeerConfig {
+        &mut self.peers[0]
+    }
+}
+
+/// `VpnConfig` as sent, accepting the single `peer` of older configs
+#[derive(Deserialize)]
+struct RawVpnConfig {
+    interface: InterfaceConfig,
+    #[serde(default)]
+    peers: Vec<PeerConfig>,
+    peer: Option<PeerConfig>,
+}
+
+impl TryFrom<RawVpnConfig> for VpnConfig {
+    type Error = String;
+
+    fn try_from(raw: RawVpnConfig) -> Result<Self, Self::Error> {
+        let mut peers = raw.peers;
+        if let Some(peer) = raw.peer {
+            peers.insert(0, peer);
+        }
+        if peers.is_empty() {
+            return Err("config has no peers".to_string());
+        }
+        Ok(Self {
+            interface: raw.interface,
+            peers,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceConfig {
+    pub private_key: String,
+    /// One or more comma-separated addresses, like wg-quick's `Address`, so a
+    /// dual-stack server can hand out an IPv4 and an IPv6 address
+    pub address: String,
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+}
+
+impl InterfaceConfig {
+    /// Each address of `address`, with its prefix length if it has one
+    pub fn addresses(&self) -> impl Iterator<Item = &str> {
+        self.address
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+    }
+
+    /// The first address without its prefix length
+    pub fn primary_address(&self) -> &str {
+        self.addresses()
+            .next()
+            .and_then(|address| address.split('/').next())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerConfig {
+    pub public_key: String,
+    pub endpoint: String,
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u32>,
+    /// Other addresses or ports the same peer answers on, such as the regional
+    /// POPs of an anycast server or its 443 and 53 fallbacks, tried in order
+    /// when `endpoint` doesn't answer the handshake or stops answering
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternate_endpoints: Vec<String>,
+}
+
+/// Errors kept by `VpnManager::error_history`, oldest dropped first
+const MAX_ERROR_HISTORY: usize = 50;
+
+/// What the manager was doing when an error occurred
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPhase {
+    Connecting,
+    /// Restoring a session that dropped
+    Reconnecting,
+    /// The tunnel failed while up
+    Connected,
+    Disconnecting,
+}
+
+/// One `VpnStatus::Error` occurrence, kept so intermittent failures can be reviewed
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorRecord {
+    /// Unix timestamp
+    pub timestamp: i64,
+    pub phase: ErrorPhase,
+    pub code: recovery::ErrorCode,
+    pub message: String,
+}
+
+/// Why the last session ended
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", content = "message", rename_all = "snake_case")]
+pub enum DisconnectReason {
+    UserRequested,
+    HandshakeTimeout,
+    NetworkLost,
+    /// The OS took the tunnel interface down or removed it
+    InterfaceDown,
+    /// The account no longer accepts this device's peer
+    ServerRevoked,
+    Error(String),
+}
+
+impl DisconnectReason {
+    /// Derive a reason from the error that brought the session down
+    pub fn from_error(error: &VpnError) -> Self {
+        match recovery::classify(error) {
+            recovery::ErrorCode::HandshakeTimeout => Self::HandshakeTimeout,
+            recovery::ErrorCode::EndpointUnreachable | recovery::ErrorCode::DnsFailure => {
+                Self::NetworkLost
+            }
+            recovery::ErrorCode::InterfaceDown => Self::InterfaceDown,
+            recovery::ErrorCode::AccessRevoked => Self::ServerRevoked,
+            _ => Self::Error(error.to_string()),
+        }
+    }
+
+    /// Whether the session should be restored automatically
+    pub fn should_reconnect(&self) -> bool {
+        // A revoked peer will not come back by retrying the same config
+        !matches!(self, Self::UserRequested | Self::ServerRevoked)
+    }
+}
+
+/// Effective per-connection settings, resolved from global settings and the active profile
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionPolicy {
+    pub kill_switch: bool,
+    pub dns: Vec<String>,
+    /// Route the LAN ranges around the tunnel and let them through the kill switch
+    pub allow_lan: bool,
+    /// Keep WebRTC STUN and Teredo traffic inside the tunnel
+    pub block_webrtc_leaks: bool,
+    /// Drop unsolicited inbound traffic arriving through the tunnel
+    pub block_inbound: bool,
+    /// Ports left reachable when `block_inbound` is on
+    pub forwarded_ports: Vec<u16>,
+    /// Fall back to the server's alternate ports when the default one is blocked
+    pub stealth_ports: bool,
+    pub split_tunnel: SplitTunnelSettings,
+    /// Pad packets and send decoys against traffic analysis (embedded tunnel only)
+    pub traffic_padding: bool,
+    pub tuning: TunnelTuning,
+}
+
+/// System changes a connect would make, computed without applying any of them
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangePlan {
+    /// Tunnel interface created for the session
+    pub interface: String,
+    pub address: String,
+    pub mtu: Option<u32>,
+    /// Routes added, in order (e.g. "0.0.0.0/1 dev SACVPN")
+    pub routes: Vec<String>,
+    /// Resolvers set on the tunnel interface
+    pub dns: Vec<String>,
+    /// Kill switch, leak protection and inbound block firewall commands
+    pub firewall: Vec<String>,
+    /// Costs and caveats of the chosen settings, for the user to confirm
+    pub warnings: Vec<String>,
+}
+
+/// Interface details of the connected tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelInfo {
+    pub interface: String,
+    pub address: String,
+    pub endpoint: String,
+    pub mtu: u32,
+    /// Local UDP port the tunnel sends from
+    pub listen_port: Option<u16>,
+    /// Port pinned in the tunnel tuning; differs from `listen_port` when it was taken
+    pub requested_listen_port: Option<u16>,
+    /// The tunnel interface as the OS reports it, where it can be asked
+    pub interface_state: Option<InterfaceState>,
+}
+
+/// How long data may go unanswered before a peer is moved to its next endpoint;
+/// a live peer answers within 10 s, with a keepalive if nothing else, and
+/// boringtun retries the handshake after 15 s
+const ENDPOINT_FAILOVER_AFTER: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// How long data may go unanswered, past endpoint failover, before a peer's
+/// hostname is looked up again in case the server moved; also the least time
+/// between two lookups, so a server that is down isn't looked up every check
+const ROAM_AFTER: std::time::Duration = std::time::Duration::from_secs(45);
+
+/// Alternate UDP ports servers also accept WireGuard on, tried in order when
+/// the default port is blocked; networks rarely filter DNS, NTP or QUIC
+const STEALTH_PORTS: [u16; 3] = [53, 123, 443];
+
+/// Data path that carries the tunnel on Windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowsBackend {
+    /// wintun + boringtun in this process
+    #[default]
+    Embedded,
+    /// The WireGuardNT kernel driver, falling back to the embedded tunnel
+    /// when wireguard.dll is missing or the driver fails to come up
+    Kernel,
+}
+
+/// Advanced data-path tuning for the embedded tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TunnelTuning {
+    /// Wintun ring capacity in bytes, `None` for the driver maximum
+    pub ring_capacity: Option<u32>,
+    /// SO_RCVBUF of the WireGuard UDP socket, `None` for the OS default
+    pub socket_recv_buffer: Option<usize>,
+    /// SO_SNDBUF of the WireGuard UDP socket, `None` for the OS default
+    pub socket_send_buffer: Option<usize>,
+    /// Packets handled per forwarding loop iteration
+    pub batch_size: usize,
+    /// Firewall mark of the tunnel's encrypted packets (Linux); the kill
+    /// switch lets marked packets out whatever their destination
+    pub fwmark: Option<u32>,
+    /// Tune the persistent keepalive to the NAT instead of using the server's
+    /// fixed interval (embedded tunnel only)
+    pub adaptive_keepalive: bool,
+    /// Local UDP port for the tunnel, for firewalls that only allow known source
+    /// ports; a random port is used when it is taken
+    pub listen_port: Option<u16>,
+    /// Lower the MSS of TCP connections through the tunnel so their packets fit
+    /// its MTU, avoiding hangs on networks that break path MTU discovery
+    pub mss_clamp: bool,
+    /// Windows network category of the tunnel adapter, which decides the
+    /// firewall profile and file sharing on it (Windows only)
+    pub network_category: NetworkCategory,
+    /// Measure the link after the first connect from a network and raise the
+    /// socket buffers and batch size to match (embedded tunnel only)
+    pub autotune: bool,
+    /// Data path of the tunnel (Windows only); traffic padding, adaptive
+    /// keepalive, MSS clamping and autotuning need the embedded one
+    pub backend: WindowsBackend,
+    /// Seconds to wait for the server's first handshake before a connect fails
+    pub handshake_timeout_secs: u64,
+    /// Address family to connect over when an endpoint's hostname has both
+    pub endpoint_family: AddressFamily,
+    /// Seconds to wait for an endpoint's hostname to resolve
+    pub resolve_timeout_secs: u64,
+}
+
+impl Default for TunnelTuning {
+    fn default() -> Self {
+        Self {
+            ring_capacity: None,
+            socket_recv_buffer: None,
+            socket_send_buffer: None,
+            batch_size: 32,
+            fwmark: None,
+            adaptive_keepalive: true,
+            listen_port: None,
+            mss_clamp: true,
+            network_category: NetworkCategory::default(),
+            autotune: true,
+            backend: WindowsBackend::default(),
+            handshake_timeout_secs: 10,
+            endpoint_family: AddressFamily::default(),
+            resolve_timeout_secs: 5,
+        }
+    }
+}
+
+pub struct VpnManager {
+    status: Arc<RwLock<VpnStatus>>,
+    stats: Arc<RwLock<ConnectionStats>>,
+    current_config: Arc<RwLock<Option<VpnConfig>>>,
+    /// Id of the server the current session belongs to
+    server_id: Option<String>,
+    active_policy: Option<SessionPolicy>,
+    /// Set once a session is established; cleared by a user disconnect or giving up
+    reconnect_armed: bool,
+    last_disconnect: Option<DisconnectReason>,
+    /// When peers' hostnames were last looked up again, see `roam`
+    roamed_at: Option<std::time::Instant>,
+    /// Most recent errors, newest last
+    error_history: VecDeque<ErrorRecord>,
+    /// Network the current tunnel runs over, see `network::network_id`
+    network: Option<String>,
+    /// Keepalive interval learned per network, reused when connecting from it again
+    keepalive_by_network: HashMap<String, u16>,
+    /// Endpoint port that got through on each network, tried first next time
+    port_by_network: HashMap<String, u16>,
+    /// Data-path tuning measured on each network, applied when connecting from it
+    link_by_network: HashMap<String, LinkTuning>,
+    /// LAN interface the tunnel is shared with, if any
+    sharing: Option<String>,
+    /// Local resolver doing domain-based split tunneling for the current tunnel
+    split: Option<split::Interceptor>,
+    /// Told about every session once it is over
+    session_observer: Option<Box<dyn Fn(SessionSummary) + Send + Sync>>,
+    firewall: firewall::Firewall,
+    wireguard: wireguard::WireGuardManager,
+}
+
+impl VpnManager {
+    pub fn new() -> Self {
+        Self {
+            status: Arc::new(RwLock::new(VpnStatus::Disconnected)),
+            stats: Arc::new(RwLock::new(ConnectionStats::default())),
+            current_config: Arc::new(RwLock::new(None)),
+            server_id: None,
+            active_policy: None,
+            reconnect_armed: false,
+            last_disconnect: None,
+            roamed_at: None,
+            error_history: VecDeque::new(),
+            network: None,
+            keepalive_by_network: HashMap::new(),
+            port_by_network: HashMap::new(),
+            link_by_network: HashMap::new(),
+            sharing: None,
+            split: None,
+            session_observer: None,
+            firewall: firewall::Firewall::new(),
+            wireguard: wireguard::WireGuardManager::new(),
+        }
+    }
+
+    pub async fn connect(
+        &mut self,
+        server_id: &str,
+        config: VpnConfig,
+        policy: SessionPolicy,
+    ) -> Result<(), VpnError> {
+        let current_status = self.status.read().await.clone();
+        if current_status.is_up() {
+            return Err(VpnError::AlreadyConnected);
+        }
+        policy.split_tunnel.check(policy.kill_switch)?;
+
+        self.reconnect_armed = false;
+        // A session still waiting to be restored ends here
+        self.end_session().await;
+        self.server_id = Some(server_id.to_string());
+        self.establish(config, policy, true).await?;
+        self.reconnect_armed = true;
+        Ok(())
+    }
+
+    /// Move the session to `server_id`, as close to make-before-break as one
+    /// tunnel allows
+    ///
+    /// The new endpoints are resolved and let through the kill switch while the
+    /// old tunnel still carries traffic, so the gap is no longer than a
+    /// reconnect and nothing leaves outside a tunnel meanwhile. If the new
+    /// server doesn't come up, the session goes back to the old one.
+    pub async fn switch_server(
+        &mut self,
+        server_id: &str,
+        config: VpnConfig,
+    ) -> Result<(), VpnError> {
+        if !self.status.read().await.is_up() {
+            return Err(VpnError::NotConnected);
+        }
+        let previous = self
+            .current_config
+            .read()
+            .await
+            .clone()
+            .ok_or(VpnError::NotConnected)?;
+        let policy = self.active_policy.clone().ok_or(VpnError::NotConnected)?;
+
+        for peer in &config.peers {
+            resolve::resolve(&peer.endpoint, &policy.tuning).await?;
+        }
+        if policy.kill_switch {
+            let mut params = self.kill_switch_params(&previous, &policy)?;
+            params
+                .endpoint_hosts
+                .extend(self.kill_switch_params(&config, &policy)?.endpoint_hosts);
+            self.firewall.enable_kill_switch(&params)?;
+        }
+
+        let previous_server = self.server_id.replace(server_id.to_string());
+        log::info!(
+            "Switching from {} to {}",
+            previous_server.as_deref().unwrap_or("server"),
+            server_id
+        );
+        let _ = self.update_stats(&StatsSettings::default()).await;
+        self.remember_keepalive().await;
+        let _ = self.wireguard.disconnect().await;
+        self.end_session().await;
+
+        let Err(e) = self.establish(config, policy.clone(), true).await else {
+            return Ok(());
+        };
+        log::warn!("Failed to switch to {}, going back: {}", server_id, e);
+        self.server_id = previous_server;
+        self.establish(previous, policy, true).await?;
+        Err(e)
+    }
+
+    /// Whether the session has been on its server long enough for `rotation`
+    /// to move it on
+    pub async fn rotation_due(&self, rotation: &RotationPolicy) -> bool {
+        *self.status.read().await == VpnStatus::Connected
+            && self
+                .stats
+                .read()
+                .await
+                .session_duration()
+                .is_some_and(|duration| rotation.is_due(duration))
+    }
+
+    /// Re-establish the last session after an unexpected drop
+    pub async fn reconnect(&mut self) -> Result<(), VpnError> {
+        let config = self
+            .current_config
+            .read()
+            .await
+            .clone()
+            .ok_or(VpnError::NotConnected)?;
+        let policy = self.active_policy.clone().unwrap_or_default();
+
+        log::info!("Reconnecting VPN...");
+
+        // Tear down the dead tunnel; kill switch rules stay until the new one is up
+        self.remember_keepalive().await;
+        let _ = self.wireguard.disconnect().await;
+        self.establish(config, policy, false).await
+    }
+
+    /// Catch a tunnel whose data path died while the session claims to be connected
+    ///
+    /// The dead tunnel and its routes are torn down and the session is marked as
+    /// failed so the watchdog restores it; kill switch rules stay in place until
+    /// the new tunnel is up.
+    pub async fn check_health(&mut self) {
+        if !self.status.read().await.is_up() {
+            return;
+        }
+        let Err(e) = self.wireguard.check_health().await else {
+            self.fail_over_endpoints().await;
+            self.roam().await;
+            return;
+        };
+
+        log::error!("Tunnel failed while connected: {}", e);
+        self.remember_keepalive().await;
+        if let Err(teardown) = self.wireguard.disconnect().await {
+            log::warn!("Failed to tear down dead tunnel: {}", teardown);
+        }
+        self.last_disconnect = Some(DisconnectReason::from_error(&e));
+        self.fail(ErrorPhase::Connected, &e).await;
+    }
+
+    /// Move peers that stopped answering to their alternate endpoints, keeping
+    /// the tunnel up, and remember the switch for reconnects
+    async fn fail_over_endpoints(&mut self) {
+        let switches = self
+            .wireguard
+            .fail_over_endpoints(ENDPOINT_FAILOVER_AFTER)
+            .await;
+        for switch in switches {
+            log::warn!(
+                "{} stopped answering, switched to {}",
+                switch.from,
+                switch.to
+            );
+            events::record(
+                EventCategory::Network,
+                Severity::Warning,
+                format!(
+                    "{} stopped answering, switched to {}",
+                    switch.from, switch.to
+                ),
+            );
+            if let Some(config) = self.current_config.write().await.as_mut() {
+                apply_switch(config, &switch);
+            }
+        }
+    }
+
+    /// Follow peers configured by hostname that went unanswered to where the
+    /// hostname points now, re-pointing the tunnel without taking it down
+    ///
+    /// The status is `Reconnecting` for as long as such a peer stays unanswered.
+    /// The lookup goes out of the physical interface, as the tunnel's resolvers
+    /// are behind the dead peer, and the kill switch lets it through meanwhile.
+    async fn roam(&mut self) {
+        let stale = self.wireguard.unanswered_hostnames(ROAM_AFTER).await;
+        if stale.is_empty() {
+            if *self.status.read().await == VpnStatus::Reconnecting {
+                self.set_status(VpnStatus::Connected).await;
+            }
+            return;
+        }
+        self.set_status(VpnStatus::Reconnecting).await;
+        if self.roamed_at.is_some_and(|at| at.elapsed() < ROAM_AFTER) {
+            return;
+        }
+        self.roamed_at = Some(std::time::Instant::now());
+
+        let config = self.current_config.read().await.clone();
+        let (Some(config), Some(policy)) = (config, self.active_policy.clone()) else {
+            return;
+        };
+        let scope = match self.physical_scope(Some(&config)).await {
+            Ok(scope) => scope,
+            Err(e) => {
+                log::warn!("Can't look up unanswered endpoints again: {}", e);
+                return;
+            }
+        };
+        if policy.kill_switch {
+            let result = self
+                .kill_switch_params(&config, &policy)
+                .and_then(|mut params| {
+                    params.endpoint_hosts.extend(scope.resolvers());
+                    self.firewall.enable_kill_switch(&params)
+                });
+            if let Err(e) = result {
+                log::warn!("Failed to let endpoint lookups through: {}", e);
+            }
+        }
+
+        for (index, endpoint) in stale {
+            let Some(port) = endpoint_port(&endpoint) else {
+                continue;
+            };
+            let address = match scope.resolve(endpoint_host(&endpoint)).await {
+                Ok(ip) => std::net::SocketAddr::new(ip, port),
+                Err(e) => {
+                    log::warn!("Failed to look up {} again: {}", endpoint, e);
+                    continue;
+                }
+            };
+            match self.wireguard.move_endpoint(index, address).await {
+                Ok(Some(switch)) => {
+                    resolve::remember(&endpoint, vec![address]);
+                    log::warn!("{} moved from {} to {}", endpoint, switch.from, switch.to);
+                    events::record(
+                        EventCategory::Network,
+                        Severity::Warning,
+                        format!("{} moved from {} to {}", endpoint, switch.from, switch.to),
+                    );
+                }
+                Ok(None) => log::info!("{} still points at {}", endpoint, address),
+                Err(e) => log::warn!("Failed to follow {} to {}: {}", endpoint, address, e),
+            }
+        }
+
+        // The kill switch follows the moved endpoints and closes the lookups' hole
+        if policy.kill_switch {
+            let result = self
+                .kill_switch_params(&config, &policy)
+                .and_then(|params| self.firewall.enable_kill_switch(&params));
+            if let Err(e) = result {
+                log::warn!("Failed to update kill switch rules: {}", e);
+            }
+        }
+    }
+
+    /// Whether the watchdog should try to restore the session
+    pub async fn needs_reconnect(&self) -> bool {
+        self.reconnect_armed
+            && matches!(
+                *self.status.read().await,
+                VpnStatus::Error(_) | VpnStatus::NoNetwork
+            )
+            && self
+                .last_disconnect
+                .as_ref()
+                .is_none_or(DisconnectReason::should_reconnect)
+    }
+
+    /// Record that the server no longer accepts this device's peer, so the
+    /// watchdog stops retrying a config that can't come back
+    pub fn mark_revoked(&mut self) {
+        self.last_disconnect = Some(DisconnectReason::ServerRevoked);
+    }
+
+    /// Why the last session ended, if it has
+    pub fn last_disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.last_disconnect.clone()
+    }
+
+    /// Show a connect or reconnect as waiting for the network to reach the
+    /// internet; false if a tunnel is already up or on its way
+    pub async fn hold_for_network(&self) -> bool {
+        let status = self.status.read().await.clone();
+        if !matches!(
+            status,
+            VpnStatus::Disconnected | VpnStatus::Error(_) | VpnStatus::NoNetwork
+        ) {
+            return false;
+        }
+        self.set_status(VpnStatus::NoNetwork).await;
+        true
+    }
+
+    /// End a hold from `hold_for_network` that no connect followed
+    pub async fn release_network_hold(&self) {
+        if !self.reconnect_armed && *self.status.read().await == VpnStatus::NoNetwork {
+            self.set_status(VpnStatus::Disconnected).await;
+        }
+    }
+
+    /// Whether kill switch rules are in place, holding back all traffic outside the tunnel
+    pub fn kill_switch_engaged(&self) -> bool {
+        self.firewall.kill_switch_engaged()
+    }
+
+    /// Stop reconnecting, optionally releasing the kill switch
+    pub async fn abandon_reconnect(&mut self, release_kill_switch: bool) {
+        self.reconnect_armed = false;
+        if release_kill_switch {
+            if let Err(e) = self.firewall.disable_kill_switch() {
+                log::warn!("Failed to remove kill switch rules: {}", e);
+            }
+            if let Err(e) = self.firewall.disable_leak_protection() {
+                log::warn!("Failed to remove leak protection rules: {}", e);
+            }
+            if let Err(e) = self.firewall.disable_inbound_block() {
+                log::warn!("Failed to remove inbound block rules: {}", e);
+            }
+            if let Err(e) = self.firewall.disable_mss_clamp() {
+                log::warn!("Failed to remove MSS clamping rules: {}", e);
+            }
+            if let Err(e) = self.disable_sharing() {
+                log::warn!("Failed to stop connection sharing: {}", e);
+            }
+            self.stop_split().await;
+        }
+        self.end_session().await;
+    }
+
+    /// Report each finished session (disconnected, or reconnecting given up) to `observer`
+    pub fn on_session_end(&mut self, observer: impl Fn(SessionSummary) + Send + Sync + 'static) {
+        self.session_observer = Some(Box::new(observer));
+    }
+
+    /// Hand the session to the observer and reset the stats, once per session
+    async fn end_session(&mut self) {
+        let stats = std::mem::take(&mut *self.stats.write().await);
+        let summary = self
+            .server_id
+            .as_deref()
+            .and_then(|server_id| stats.summary(server_id));
+        if let (Some(summary), Some(observer)) = (summary, self.session_observer.as_ref()) {
+            observer(summary);
+        }
+    }
+
+    /// Bring up the tunnel; `new_session` is false when restoring a dropped session
+    async fn establish(
+        &mut self,
+        mut config: VpnConfig,
+        mut policy: SessionPolicy,
+        new_session: bool,
+    ) -> Result<(), VpnError> {
+        // Update status to connecting
+        self.set_status(VpnStatus::Connecting).await;
+        // Started again below for the new tunnel
+        self.stop_split().await;
+
+        apply_dns(&mut config, &policy);
+
+        // Start from the keepalive learned on this network last time
+        let previous_network = self.network.take();
+        self.network = self
+            .wireguard
+            .uplink(&config.primary_peer().endpoint, &policy.tuning)
+            .await
+            .map(|route| network::network_id(&route));
+        if let (Some(previous), Some(network)) = (&previous_network, &self.network) {
+            if previous != network {
+                events::record(
+                    EventCategory::Network,
+                    Severity::Info,
+                    format!("Network changed from {} to {}", previous, network),
+                );
+            }
+        }
+        let learned = self
+            .network
+            .as_ref()
+            .and_then(|network| self.keepalive_by_network.get(network));
+        if let Some(&learned) = learned {
+            if policy.tuning.adaptive_keepalive
+                && config.primary_peer().persistent_keepalive.is_some()
+            {
+                config.primary_peer_mut().persistent_keepalive = Some(learned.into());
+            }
+        }
+        // And from the buffers and batch size measured on it
+        let link = self
+            .network
+            .as_ref()
+            .and_then(|network| self.link_by_network.get(network));
+        if let Some(link) = link.filter(|_| policy.tuning.autotune) {
+            link.apply(&mut policy.tuning);
+        }
+
+        // Store config
+        *self.current_config.write().await = Some(config.clone());
+
+        // The tunnel's DNS points at the split tunneling resolver, which relays
+        // to the real servers
+        let upstream_dns = config.interface.dns.clone();
+        let lan_bypass = lan_bypass(&policy, &upstream_dns);
+        if policy.split_tunnel.is_active() {
+            match self.start_split(&config, &policy).await {
+                Ok(()) => config.interface.dns = vec![split::LISTEN_IP.to_string()],
+                Err(e) => log::warn!("Split tunneling is off for this connection: {}", e),
+            }
+        }
+
+        // Connect via WireGuard, then apply the session policy as one unit
+        let result = match self
+            .connect_tunnel(&mut config, &policy, lan_bypass.as_deref())
+            .await
+        {
+            Ok(()) => {
+                if policy.split_tunnel.mode == split::SplitMode::Tunnel {
+                    if let (Some(split), Some(interface)) =
+                        (&self.split, self.wireguard.interface())
+                    {
+                        split.route_via(interface);
+                    }
+                }
+                // Reconnects go straight to the port that worked, and start from the real DNS
+                config.interface.dns = upstream_dns;
+                *self.current_config.write().await = Some(config.clone());
+                self.apply_policy(&config, &policy).await
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.stop_split().await;
+        }
+
+        match result {
+            Ok(()) => {
+                // A rebuilt tunnel adapter loses its Windows sharing settings
+                if let Some(lan_interface) = self.sharing.clone() {
+                    let result = self
+                        .kill_switch_params(&config, &policy)
+                        .and_then(|params| self.firewall.enable_sharing(&params, &lan_interface));
+                    if let Err(e) = result {
+                        log::warn!("Failed to restore connection sharing: {}", e);
+                    }
+                }
+                self.active_policy = Some(policy);
+                self.last_disconnect = None;
+                self.set_status(VpnStatus::Connected).await;
+
+                // Initialize stats; a reconnect keeps the session totals and timer
+                let mut stats = self.stats.write().await;
+                if new_session || stats.started_at.is_none() {
+                    *stats = ConnectionStats {
+                        connected_since: Some(chrono::Utc::now().timestamp()),
+                        started_at: Some(BootInstant::now()),
+                        ..ConnectionStats::default()
+                    };
+                } else {
+                    stats.tunnel_uploaded = 0;
+                    stats.tunnel_downloaded = 0;
+                }
+
+                log::info!("VPN connected successfully");
+                Ok(())
+            }
+            Err(e) => {
+                // Only a session that was up can drop; a failed first connect is just an error
+                if self.reconnect_armed {
+                    self.last_disconnect = Some(DisconnectReason::from_error(&e));
+                }
+                let phase = if new_session {
+                    ErrorPhase::Connecting
+                } else {
+                    ErrorPhase::Reconnecting
+                };
+                self.fail(phase, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Bring the tunnel up, moving to the stealth ports if the default one is blocked
+    ///
+    /// The port that got through last time on this network is tried first.
+    async fn connect_tunnel(
+        &mut self,
+        config: &mut VpnConfig,
+        policy: &SessionPolicy,
+        lan_bypass: Option<&[IpAddr]>,
+    ) -> Result<(), VpnError> {
+        if !policy.stealth_ports {
+            return self.connect_endpoints(config, policy, lan_bypass).await;
+        }
+
+        let default = config.primary_peer().endpoint.clone();
+        let remembered = self
+            .network
+            .as_ref()
+            .and_then(|network| self.port_by_network.get(network))
+            .copied();
+
+        let mut last_error = None;
+        for endpoint in stealth_endpoints(&default, remembered) {
+            config.primary_peer_mut().endpoint = endpoint;
+            match self.connect_endpoints(config, policy, lan_bypass).await {
+                Ok(()) => {
+                    if let (Some(network), Some(port)) = (
+                        self.network.clone(),
+                        endpoint_port(&config.primary_peer().endpoint),
+                    ) {
+                        self.port_by_network.insert(network, port);
+                    }
+                    return Ok(());
+                }
+                // Only a silent endpoint suggests a filtered port; anything else
+                // fails the same way on every port
+                Err(e) if recovery::is_unanswered(&e) => {
+                    log::warn!("No answer on {}: {}", config.primary_peer().endpoint, e);
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    config.primary_peer_mut().endpoint = default;
+                    return Err(e);
+                }
+            }
+        }
+
+        config.primary_peer_mut().endpoint = default;
+        Err(last_error.unwrap_or(VpnError::NotConnected))
+    }
+
+    /// Bring the tunnel up on the first of each peer's endpoints that answers,
+    /// keeping those in `config` so reconnects start from them
+    async fn connect_endpoints(
+        &mut self,
+        config: &mut VpnConfig,
+        policy: &SessionPolicy,
+        lan_bypass: Option<&[IpAddr]>,
+    ) -> Result<(), VpnError> {
+        let switches = self
+            .wireguard
+            .connect(config, &policy.tuning, policy.traffic_padding, lan_bypass)
+            .await?;
+        for switch in switches {
+            log::warn!("{} didn't answer, connected to {}", switch.from, switch.to);
+            events::record(
+                EventCategory::Network,
+                Severity::Warning,
+                format!("{} didn't answer, connected to {}", switch.from, switch.to),
+            );
+            apply_switch(config, &switch);
+        }
+        Ok(())
+    }
+
+    /// Start the local resolver that routes the configured domains around (or into) the tunnel
+    async fn start_split(
+        &mut self,
+        config: &VpnConfig,
+        policy: &SessionPolicy,
+    ) -> Result<(), VpnError> {
+        let upstream = config
+            .interface
+            .dns
+            .iter()
+            .filter_map(|server| server.parse::<std::net::IpAddr>().ok())
+            .map(|ip| std::net::SocketAddr::new(ip, 53))
+            .collect();
+
+        policy.split_tunnel.check(policy.kill_switch)?;
+        let (gateway, interface) = match policy.split_tunnel.mode {
+            split::SplitMode::Bypass => {
+                let uplink = self
+                    .wireguard
+                    .uplink(&config.primary_peer().endpoint, &policy.tuning)
+                    .await
+                    .ok_or_else(|| {
+                        VpnError::ConnectionFailed("No route to the internet".to_string())
+                    })?;
+                (uplink.gateway, Some(uplink.interface))
+            }
+            // On macOS the OS numbers the utun interface, so it's only known
+            // once the tunnel is up; see `route_via`
+            split::SplitMode::Tunnel => (None, None),
+        };
+
+        self.split = Some(
+            split::Interceptor::start(&policy.split_tunnel, upstream, gateway, interface).await?,
+        );
+        Ok(())
+    }
+
+    async fn stop_split(&mut self) {
+        if let Some(split) = self.split.take() {
+            split.stop().await;
+        }
+    }
+
+    /// Share the tunnel with devices on `lan_interface` until disconnect
+    pub async fn enable_sharing(&mut self, lan_interface: &str) -> Result<(), VpnError> {
+        if !self.status.read().await.is_up() {
+            return Err(VpnError::NotConnected);
+        }
+        let config = self
+            .current_config
+            .read()
+            .await
+            .clone()
+            .ok_or(VpnError::NotConnected)?;
+        let policy = self.active_policy.clone().unwrap_or_default();
+
+        network::warn_if_large(lan_interface, "Sharing the connection");
+        let params = self.kill_switch_params(&config, &policy)?;
+        self.firewall.enable_sharing(&params, lan_interface)?;
+        self.sharing = Some(lan_interface.to_string());
+        Ok(())
+    }
+
+    pub fn disable_sharing(&mut self) -> Result<(), VpnError> {
+        self.sharing = None;
+        self.firewall.disable_sharing()
+    }
+
+    /// Let the connected session reach the local network, or route it through
+    /// the tunnel again, without reconnecting
+    ///
+    /// Does nothing while disconnected; the next connect picks the setting up.
+    pub async fn set_allow_lan(&mut self, allow_lan: bool) -> Result<(), VpnError> {
+        if !self.status.read().await.is_up() {
+            return Ok(());
+        }
+        let Some(mut policy) = self.active_policy.clone() else {
+            return Ok(());
+        };
+        if policy.allow_lan == allow_lan {
+            return Ok(());
+        }
+        let config = self
+            .current_config
+            .read()
+            .await
+            .clone()
+            .ok_or(VpnError::NotConnected)?;
+
+        policy.allow_lan = allow_lan;
+        let lan_bypass = lan_bypass(&policy, &config.interface.dns);
+        self.wireguard.reroute(&config, lan_bypass.as_deref())?;
+        if policy.kill_switch {
+            let params = self.kill_switch_params(&config, &policy)?;
+            self.firewall.enable_kill_switch(&params)?;
+        }
+        self.active_policy = Some(policy);
+        log::info!(
+            "LAN access {}",
+            if allow_lan { "allowed" } else { "blocked" }
+        );
+        Ok(())
+    }
+
+    /// Size of the local network on `interface`, or on the physical uplink if none is given
+    pub async fn lan_safety(&self, interface: Option<&str>) -> Result<LanInfo, VpnError> {
+        let interface = match interface {
+            Some(interface) => interface.to_string(),
+            None => {
+                // Any public address stands in for the endpoint before connecting
+                let endpoint = self
+                    .current_config
+                    .read()
+                    .await
+                    .as_ref()
+                    .map(|config| config.primary_peer().endpoint.clone())
+                    .unwrap_or_else(|| "1.1.1.1:53".to_string());
+                let tuning = self
+                    .active_policy
+                    .as_ref()
+                    .map(|policy| policy.tuning.clone())
+                    .unwrap_or_default();
+                self.wireguard
+                    .uplink(&endpoint, &tuning)
+                    .await
+                    .map(|route| route.interface)
+                    .ok_or_else(|| {
+                        VpnError::ConnectionFailed("No route to the internet".to_string())
+                    })?
+            }
+        };
+        network::local_network(&interface)
+    }
+
+    /// The interface a diagnostic probe named `interface` may send through:
+    /// "tunnel", "physical" or the local address of either
+    pub async fn probe_scope(&self, interface: &str) -> Result<scoped::ProbeScope, VpnError> {
+        let config = self.current_config.read().await.clone();
+        let tunnel =
+            match (&config, self.wireguard.interface()) {
+                (Some(config), Some(name)) => Some(scoped::ProbeScope {
+                    interface: name,
+                    address: config.interface.primary_address().parse().map_err(|e| {
+                        VpnError::ConfigError(format!("Invalid tunnel address: {}", e))
+                    })?,
+                    dns: config
+                        .interface
+                        .dns
+                        .iter()
+                        .filter_map(|server| server.parse().ok())
+                        .collect(),
+                }),
+                _ => None,
+            };
+
+        match interface {
+            "tunnel" => tunnel.ok_or(VpnError::NotConnected),
+            "physical" => self.physical_scope(config.as_ref()).await,
+            other => {
+                let address: std::net::IpAddr = other.parse().map_err(|_| {
+                    VpnError::ConfigError(format!(
+                        "Unknown interface \"{}\": expected tunnel, physical or a local IP address",
+                        other
+                    ))
+                })?;
+                // Only an address whose interface is known can be probed without leaking
+                let physical = self.physical_scope(config.as_ref()).await.ok();
+                tunnel
+                    .into_iter()
+                    .chain(physical)
+                    .find(|scope| scope.address == address)
+                    .ok_or_else(|| {
+                        VpnError::ConfigError(format!(
+                            "{} is neither the tunnel's nor the physical interface's address",
+                            address
+                        ))
+                    })
+            }
+        }
+    }
+
+    /// The uplink the tunnel runs over, or would run over before connecting
+    async fn physical_scope(
+        &self,
+        config: Option<&VpnConfig>,
+    ) -> Result<scoped::ProbeScope, VpnError> {
+        // Any public address stands in for the endpoint before connecting
+        let endpoint = config
+            .map(|config| config.primary_peer().endpoint.clone())
+            .unwrap_or_else(|| "1.1.1.1:53".to_string());
+        let tuning = self
+            .active_policy
+            .as_ref()
+            .map(|policy| policy.tuning.clone())
+            .unwrap_or_default();
+        let no_route = || VpnError::ConnectionFailed("No route to the internet".to_string());
+
+        let route = self
+            .wireguard
+            .uplink(&endpoint, &tuning)
+            .await
+            .ok_or_else(no_route)?;
+        // The endpoint is routed around the tunnel, so the source address the OS
+        // picks for it belongs to the uplink. Connecting a UDP socket sends nothing.
+        let target = resolve::resolve(&endpoint, &tuning).await?;
+        let socket = tokio::net::UdpSocket::bind(match target {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        })
+        .await
+        .map_err(|e| VpnError::ConnectionFailed(e.to_string()))?;
+        socket.connect(target).await.map_err(|_| no_route())?;
+        let address = socket
+            .local_addr()
+            .map_err(|e| VpnError::ConnectionFailed(e.to_string()))?
+            .ip();
+
+        Ok(scoped::ProbeScope {
+            interface: route.interface,
+            address,
+            dns: Vec::new(),
+        })
+    }
+
+    /// LAN interface the tunnel is currently shared with
+    pub fn sharing_interface(&self) -> Option<String> {
+        self.sharing.clone()
+    }
+
+    /// Network the current tunnel runs over, see `network::network_id`
+    pub fn network(&self) -> Option<String> {
+        self.network.clone()
+    }
+
+    /// Start from link tuning measured in earlier runs, keyed by network
+    pub fn load_link_tuning(&mut self, tuning: HashMap<String, LinkTuning>) {
+        self.link_by_network = tuning;
+    }
+
+    /// Remember the tuning measured on `network`, applying it to the running
+    /// tunnel if that is still the network it runs over
+    pub async fn tune_link(&mut self, network: String, link: LinkTuning) {
+        if self.network.as_ref() == Some(&network) {
+            if let Some(policy) = self.active_policy.as_mut() {
+                link.apply(&mut policy.tuning);
+                log::info!(
+                    "Tuned for {} B/s: {} B socket buffers, batches of {}",
+                    link.throughput,
+                    link.socket_recv_buffer,
+                    link.batch_size
+                );
+                self.wireguard.retune(&policy.tuning).await;
+            }
+        }
+        self.link_by_network.insert(network, link);
+    }
+
+    /// Keep the keepalive interval the tunnel settled on for the next connect from this network
+    async fn remember_keepalive(&mut self) {
+        if let (Some(network), Some(interval)) = (
+            self.network.clone(),
+            self.wireguard.keepalive_interval().await,
+        ) {
+            self.keepalive_by_network.insert(network, interval);
+        }
+    }
+
+    pub async fn disconnect(&mut self) -> Result<(), VpnError> {
+        let current_status = self.status.read().await.clone();
+        if current_status == VpnStatus::Disconnected {
+            return Err(VpnError::NotConnected);
+        }
+
+        // Update status to disconnecting
+        self.set_status(VpnStatus::Disconnecting).await;
+        self.reconnect_armed = false;
+        self.last_disconnect = Some(DisconnectReason::UserRequested);
+
+        // Roll back the session policy before tearing down the tunnel
+        if let Err(e) = self.firewall.disable_kill_switch() {
+            log::warn!("Failed to remove kill switch rules: {}", e);
+        }
+        if let Err(e) = self.firewall.disable_leak_protection() {
+            log::warn!("Failed to remove leak protection rules: {}", e);
+        }
+        if let Err(e) = self.firewall.disable_inbound_block() {
+            log::warn!("Failed to remove inbound block rules: {}", e);
+        }
+        if let Err(e) = self.firewall.disable_mss_clamp() {
+            log::warn!("Failed to remove MSS clamping rules: {}", e);
+        }
+        if let Err(e) = self.disable_sharing() {
+            log::warn!("Failed to stop connection sharing: {}", e);
+        }
+        self.stop_split().await;
+
+        // Final transfer counters for the usage history
+        let _ = self.update_stats(&StatsSettings::default()).await;
+
+        // Disconnect WireGuard
+        self.remember_keepalive().await;
+        match self.wireguard.disconnect().await {
+            Ok(()) => {
+                self.set_status(VpnStatus::Disconnected).await;
+                *self.current_config.write().await = None;
+                self.end_session().await;
+                self.server_id = None;
+                self.active_policy = None;
+
+                log::info!("VPN disconnected successfully");
+                Ok(())
+            }
+            Err(e) => {
+                self.fail(ErrorPhase::Disconnecting, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Move to `status`, adding the transition to the event log
+    async fn set_status(&self, status: VpnStatus) {
+        let mut current = self.status.write().await;
+        if *current == status {
+            return;
+        }
+        let server = self.server_id.as_deref().unwrap_or("server");
+        let (severity, message) = match &status {
+            VpnStatus::Disconnected => (Severity::Info, "Disconnected".to_string()),
+            VpnStatus::Connecting => (Severity::Info, format!("Connecting to {}", server)),
+            VpnStatus::Connected => (Severity::Info, format!("Connected to {}", server)),
+            VpnStatus::Reconnecting => (Severity::Warning, format!("Reconnecting to {}", server)),
+            VpnStatus::Disconnecting => (Severity::Info, "Disconnecting".to_string()),
+            VpnStatus::NoNetwork => (
+                Severity::Warning,
+                "Waiting for the network to reach the internet".to_string(),
+            ),
+            VpnStatus::Error(error) => (Severity::Error, error.clone()),
+        };
+        *current = status;
+        events::record(EventCategory::Status, severity, message);
+    }
+
+    /// Enter the error state and remember the error
+    async fn fail(&mut self, phase: ErrorPhase, error: &VpnError) {
+        self.record_error(phase, error);
+        self.set_status(VpnStatus::Error(error.to_string())).await;
+    }
+
+    fn record_error(&mut self, phase: ErrorPhase, error: &VpnError) {
+        if self.error_history.len() == MAX_ERROR_HISTORY {
+            self.error_history.pop_front();
+        }
+        self.error_history.push_back(ErrorRecord {
+            timestamp: chrono::Utc::now().timestamp(),
+            phase,
+            code: recovery::classify(error),
+            message: error.to_string(),
+        });
+    }
+
+    /// Errors since the app started, oldest first
+    pub fn error_history(&self) -> Vec<ErrorRecord> {
+        self.error_history.iter().cloned().collect()
+    }
+
+    /// Apply the session's firewall rules for a freshly connected tunnel, tearing it
+    /// down on failure
+    async fn apply_policy(
+        &mut self,
+        config: &VpnConfig,
+        policy: &SessionPolicy,
+    ) -> Result<(), VpnError> {
+        let params = match self.kill_switch_params(config, policy) {
+            Ok(params) => params,
+            Err(e) => {
+                let _ = self.wireguard.disconnect().await;
+                return Err(e);
+            }
+        };
+        if policy.kill_switch && policy.allow_lan {
+            let uplink = self
+                .wireguard
+                .uplink(&config.primary_peer().endpoint, &policy.tuning)
+                .await;
+            if let Some(route) = uplink {
+                network::warn_if_large(&route.interface, "Allowing LAN access");
+            }
+        }
+        if policy.kill_switch {
+            if let Err(e) = self.firewall.enable_kill_switch(&params) {
+                let _ = self.wireguard.disconnect().await;
+                return Err(e);
+            }
+        }
+
+        let mut result = Ok(());
+        if policy.block_webrtc_leaks {
+            result = self.firewall.enable_leak_protection(&params);
+        } else if let Err(e) = self.firewall.disable_leak_protection() {
+            log::warn!("Failed to remove leak protection rules: {}", e);
+        }
+        if result.is_ok() {
+            if policy.block_inbound {
+                result = self.firewall.enable_inbound_block(&params);
+            } else if let Err(e) = self.firewall.disable_inbound_block() {
+                log::warn!("Failed to remove inbound block rules: {}", e);
+            }
+        }
+
+        // Clamping only prevents hangs; a tunnel without it still works
+        let clamp = if policy.tuning.mss_clamp {
+            self.firewall.enable_mss_clamp(&params, tunnel_mtu(config))
+        } else {
+            self.firewall.disable_mss_clamp()
+        };
+        if let Err(e) = clamp {
+            log::warn!("Failed to update MSS clamping rules: {}", e);
+        }
+
+        if result.is_err() {
+            let _ = self.wireguard.disconnect().await;
+            // A first connect has no session to protect; don't leave the user offline
+            if !self.reconnect_armed {
+                let _ = self.firewall.disable_kill_switch();
+                let _ = self.firewall.disable_leak_protection();
+                let _ = self.firewall.disable_mss_clamp();
+            }
+        }
+        result
+    }
+
+    fn kill_switch_params(
+        &self,
+        config: &VpnConfig,
+        policy: &SessionPolicy,
+    ) -> Result<firewall::KillSwitchParams, VpnError> {
+        kill_switch_params(self.wireguard.tunnel_name(), config, policy)
+    }
+
+    /// Name of the tunnel interface connects create
+    pub fn tunnel_name(&self) -> String {
+        self.wireguard.tunnel_name().to_string()
+    }
+
+    /// Config of the current connection, if any
+    pub async fn get_config(&self) -> Option<VpnConfig> {
+        self.current_config.read().await.clone()
+    }
+
+    /// Interface details of the connected tunnel, if any
+    pub async fn tunnel_info(&self) -> Option<TunnelInfo> {
+        if !self.status.read().await.is_up() {
+            return None;
+        }
+        let config = self.get_config().await?;
+        Some(TunnelInfo {
+            interface: self.wireguard.tunnel_name().to_string(),
+            mtu: tunnel_mtu(&config),
+            address: config.interface.address.clone(),
+            endpoint: config.primary_peer().endpoint.clone(),
+            listen_port: self.wireguard.listen_port(),
+            requested_listen_port: self
+                .active_policy
+                .as_ref()
+                .and_then(|policy| policy.tuning.listen_port),
+            interface_state: self.wireguard.interface_state(),
+        })
+    }
+
+    /// Change the MTU of the connected tunnel
+    pub async fn set_mtu(&self, mtu: u32) -> Result<(), VpnError> {
+        if !self.status.read().await.is_up() {
+            return Err(VpnError::NotConnected);
+        }
+        self.wireguard.set_mtu(mtu)?;
+
+        if let Some(config) = self.current_config.write().await.as_mut() {
+            config.interface.mtu = Some(mtu);
+        }
+        Ok(())
+    }
+
+    /// Busy/idle counters of the userspace forwarding loop
+    pub fn get_forwarding_stats(&self) -> ForwardingStats {
+        self.wireguard.forwarding_stats()
+    }
+
+    /// Host route counters of domain split tunneling, while it is running
+    pub fn get_split_route_stats(&self) -> Option<SplitRouteStats> {
+        self.split.as_ref().map(split::Interceptor::stats)
+    }
+
+    /// Whether the current connection sends all internet traffic through the
+    /// tunnel: its allowed IPs cover every IPv4 address and no domains bypass it
+    pub async fn full_tunnel(&self) -> bool {
+        let bypassing = self.active_policy.as_ref().is_some_and(|policy| {
+            policy.split_tunnel.is_active() && policy.split_tunnel.mode == split::SplitMode::Bypass
+        });
+        !bypassing
+            && self
+                .current_config
+                .read()
+                .await
+                .as_ref()
+                .is_some_and(covers_ipv4_internet)
+    }
+
+    /// Id of the server the current session belongs to
+    pub fn get_server_id(&self) -> Option<String> {
+        self.server_id.clone()
+    }
+
+    /// Settings applied to the current connection, if any
+    pub fn get_active_policy(&self) -> Option<SessionPolicy> {
+        self.active_policy.clone()
+    }
+
+    pub fn get_status(&self) -> VpnStatus {
+        // For synchronous access, we need to block
+        futures::executor::block_on(async { self.status.read().await.clone() })
+    }
+
+    pub fn get_stats(&self) -> ConnectionStats {
+        futures::executor::block_on(async { self.stats.read().await.clone() })
+    }
+
+    pub async fn update_stats(&self, settings: &StatsSettings) -> Result<(), VpnError> {
+        let status = self.status.read().await.clone();
+        if !status.is_up() {
+            return Ok(());
+        }
+
+        // Get stats from WireGuard
+        if let Ok((rx, tx)) = self.wireguard.get_transfer_stats().await {
+            self.stats.write().await.record_transfer(
+                rx,
+                tx,
+                BootInstant::now(),
+                settings.smoothing_samples,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// MTU the tunnel runs with
+fn tunnel_mtu(config: &VpnConfig) -> u32 {
+    config.interface.mtu.unwrap_or(wireguard::DEFAULT_MTU)
+}
+
+/// Host part of an `host:port` endpoint, with IPv6 brackets removed
+fn endpoint_host(endpoint: &str) -> &str {
+    let host = endpoint
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(endpoint);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Port part of a `host:port` endpoint
+fn endpoint_port(endpoint: &str) -> Option<u16> {
+    endpoint.rsplit_once(':')?.1.parse().ok()
+}
+
+/// Endpoints to try in order: the remembered port, the configured one, then the stealth ports
+fn stealth_endpoints(endpoint: &str, remembered: Option<u16>) -> Vec<String> {
+    let Some((host, default)) = endpoint
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+    else {
+        return vec![endpoint.to_string()];
+    };
+
+    let mut ports = Vec::new();
+    for port in remembered.into_iter().chain([default]).chain(STEALTH_PORTS) {
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    ports
+        .into_iter()
+        .map(|port| format!("{}:{}", host, port))
+        .collect()
+}
+
+/// Record a peer's switch in `config`: the endpoint it moved to becomes its
+/// endpoint and the one it left becomes its last alternate
+fn apply_switch(config: &mut VpnConfig, switch: &wireguard::EndpointSwitch) {
+    let Some(peer) = config.peers.get_mut(switch.peer) else {
+        return;
+    };
+    // The configured endpoint, so a hostname is resolved again on reconnect
+    let to = switch.to_endpoint.clone();
+    let from = std::mem::replace(&mut peer.endpoint, to.clone());
+    peer.alternate_endpoints.retain(|endpoint| *endpoint != to);
+    peer.alternate_endpoints.push(from);
+}
+
+/// Custom DNS from settings/profile replaces the server-provided resolvers
+fn apply_dns(config: &mut VpnConfig, policy: &SessionPolicy) {
+    if !policy.dns.is_empty() {
+        config.interface.dns = policy.dns.clone();
+    }
+}
+
+/// Whether the allowed IPs of `config`'s peers add up to the whole IPv4 internet
+fn covers_ipv4_internet(config: &VpnConfig) -> bool {
+    let allowed: Vec<routes::Prefix> = config
+        .peers
+        .iter()
+        .flat_map(|peer| &peer.allowed_ips)
+        .filter_map(|ip| ip.parse().ok())
+        .collect();
+    let everything = routes::Prefix {
+        addr: IpAddr::from([0, 0, 0, 0]),
+        len: 0,
+    };
+    routes::exclude(&[everything], &allowed).is_empty()
+}
+
+/// Firewall rule inputs for a session of `config` over `tunnel_name`
+fn kill_switch_params(
+    tunnel_name: &str,
+    config: &VpnConfig,
+    policy: &SessionPolicy,
+) -> Result<firewall::KillSwitchParams, VpnError> {
+    let address = config.interface.primary_address();
+    let tunnel_address = address
+        .parse()
+        .map_err(|_| VpnError::ConfigError(format!("Invalid tunnel address: {:?}", address)))?;
+
+    Ok(firewall::KillSwitchParams {
+        tunnel_name: tunnel_name.to_string(),
+        tunnel_address,
+        // Hostnames are let through at each address they resolved to
+        endpoint_hosts: config
+            .peers
+            .iter()
+            .flat_map(|peer| std::iter::once(&peer.endpoint).chain(&peer.alternate_endpoints))
+            .flat_map(|endpoint| {
+                let addresses = resolve::cached(endpoint);
+                if addresses.is_empty() {
+                    log::warn!(
+                        "{} isn't resolved; the firewall won't let it through",
+                        endpoint
+                    );
+                }
+                addresses.into_iter().map(|address| address.ip())
+            })
+            .collect(),
+        allow_lan: policy.allow_lan,
+        forwarded_ports: policy.forwarded_ports.clone(),
+        fwmark: policy.tuning.fwmark,
+    })
+}
+
+/// List the routes, DNS and firewall changes a connect over `interface` would
+/// make, without applying them
+///
+/// Nothing of the manager's is needed beyond `VpnManager::tunnel_name`, so the
+/// lookups don't hold it up.
+pub async fn preview(
+    interface: String,
+    mut config: VpnConfig,
+    policy: SessionPolicy,
+) -> Result<ChangePlan, VpnError> {
+    apply_dns(&mut config, &policy);
+
+    let lan_bypass = lan_bypass(&policy, &config.interface.dns);
+    let routes = wireguard::plan_routes(&config, &interface, &policy.tuning, lan_bypass.as_deref())
+        .await?
+        .iter()
+        .map(|route| route.to_string())
+        .collect();
+    let params = kill_switch_params(&interface, &config, &policy)?;
+    let mut firewall = Vec::new();
+    if policy.kill_switch {
+        firewall.extend(firewall::planned_commands(&params));
+    }
+    if policy.block_webrtc_leaks {
+        firewall.extend(firewall::planned_leak_commands(&params));
+    }
+    if policy.block_inbound {
+        firewall.extend(firewall::planned_inbound_commands(&params));
+    }
+    if policy.tuning.mss_clamp {
+        firewall.extend(firewall::planned_mss_commands(&params, tunnel_mtu(&config)));
+    }
+    let mut warnings = Vec::new();
+    if policy.traffic_padding {
+        warnings.push(padding::BANDWIDTH_WARNING.to_string());
+    }
+
+    Ok(ChangePlan {
+        interface,
+        address: config.interface.address,
+        mtu: config.interface.mtu,
+        routes,
+        dns: config.interface.dns,
+        firewall,
+        warnings,
+    })
+}
+
+/// Addresses kept in the tunnel when the LAN ranges bypass it, or None when
+/// LAN access is off; `dns` is the tunnel's real resolvers
+fn lan_bypass(policy: &SessionPolicy, dns: &[String]) -> Option<Vec<IpAddr>> {
+    policy.allow_lan.then(|| {
+        dns.iter()
+            .filter_map(|server| server.parse().ok())
+            .collect()
+    })
+}
+
+impl Default for VpnManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_vpn_manager_new() {
+        let manager = VpnManager::new();
+        assert_eq!(manager.get_status(), VpnStatus::Disconnected);
+    }
+
+    #[test]
+    fn test_error_history_is_bounded() {
+        let mut manager = VpnManager::new();
+        for attempt in 0..MAX_ERROR_HISTORY + 5 {
+            let error = VpnError::ConnectionFailed(format!("attempt {}", attempt));
+            manager.record_error(ErrorPhase::Reconnecting, &error);
+        }
+
+        let history = manager.error_history();
+        assert_eq!(history.len(), MAX_ERROR_HISTORY);
+        assert_eq!(history[0].message, "Connection failed: attempt 5");
+        assert_eq!(history[0].phase, ErrorPhase::Reconnecting);
+    }
+
+    #[test]
+    fn test_disconnect_reason_from_error() {
+        let timeout = VpnError::ConnectionFailed("Handshake timed out".to_string());
+        assert_eq!(
+            DisconnectReason::from_error(&timeout),
+            DisconnectReason::HandshakeTimeout
+        );
+        assert!(DisconnectReason::NetworkLost.should_reconnect());
+        assert!(!DisconnectReason::UserRequested.should_reconnect());
+        assert!(!DisconnectReason::ServerRevoked.should_reconnect());
+
+        let revoked = VpnError::ConfigError("Access revoked: 401 Unauthorized".to_string());
+        assert_eq!(
+            DisconnectReason::from_error(&revoked),
+            DisconnectReason::ServerRevoked
+        );
+    }
+
+    #[test]
+    fn test_stealth_endpoints_order() {
+        assert_eq!(
+            stealth_endpoints("vpn.example.com:51820", None),
+            [
+                "vpn.example.com:51820",
+                "vpn.example.com:53",
+                "vpn.example.com:123",
+                "vpn.example.com:443"
+            ]
+        );
+        assert_eq!(
+            stealth_endpoints("[2001:db8::1]:51820", Some(443)),
+            [
+                "[2001:db8::1]:443",
+                "[2001:db8::1]:51820",
+                "[2001:db8::1]:53",
+                "[2001:db8::1]:123"
+            ]
+        );
+        assert_eq!(endpoint_port("[2001:db8::1]:443"), Some(443));
+    }
+
+    #[test]
+    fn test_config_accepts_single_peer_shape() {
+        let interface = serde_json::json!({
+            "private_key": "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=",
+            "address": "10.8.0.2/32",
+            "dns": ["1.1.1.1"],
+            "mtu": null
+        });
+        let peer = |endpoint: &str, allowed_ip: &str| {
+            serde_json::json!({
+                "public_key": "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+                "endpoint": endpoint,
+                "allowed_ips": [allowed_ip],
+                "persistent_keepalive": 25
+            })
+        };
+
+        let old = serde_json::json!({
+            "interface": interface,
+            "peer": peer("203.0.113.7:51820", "0.0.0.0/0")
+        });
+        let config: VpnConfig = serde_json::from_value(old).unwrap();
+        assert_eq!(config.peers.len(), 1);
+        assert_eq!(config.primary_peer().endpoint, "203.0.113.7:51820");
+
+        let cluster = serde_json::json!({
+            "interface": interface,
+            "peers": [
+                peer("203.0.113.7:51820", "0.0.0.0/0"),
+                peer("203.0.113.8:51820", "10.20.0.0/16")
+            ]
+        });
+        let config: VpnConfig = serde_json::from_value(cluster).unwrap();
+        assert_eq!(config.peers[1].allowed_ips, ["10.20.0.0/16"]);
+        // Written back in the new shape
+        let saved = serde_json::to_value(&config).unwrap();
+        assert!(saved.get("peer").is_none());
+        assert_eq!(saved["peers"].as_array().map(Vec::len), Some(2));
+
+        let empty = serde_json::json!({ "interface": interface, "peers": [] });
+        assert!(serde_json::from_value::<VpnConfig>(empty).is_err());
+    }
+
+    #[test]
+    fn test_covers_ipv4_internet() {
+        let config = |allowed_ips: &[&str]| -> VpnConfig {
+            serde_json::from_value(serde_json::json!({
+                "interface": {
+                    "private_key": "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=",
+                    "address": "10.8.0.2/32",
+                    "dns": [],
+                    "mtu": null
+                },
+                "peer": {
+                    "public_key": "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=",
+                    "endpoint": "203.0.113.7:51820",
+                    "allowed_ips": allowed_ips,
+                    "persistent_keepalive": null
+                }
+            }))
+            .unwrap()
+        };
+
+        assert!(covers_ipv4_internet(&config(&["0.0.0.0/0", "::/0"])));
+        assert!(covers_ipv4_internet(&config(&["0.0.0.0/1", "128.0.0.0/1"])));
+        assert!(!covers_ipv4_internet(&config(&["::/0"])));
+        assert!(!covers_ipv4_internet(&config(&["10.0.0.0/8", "0.0.0.0/1"])));
+    }
+}
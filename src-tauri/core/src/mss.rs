@@ -0,0 +1,230 @@
+//! TCP MSS clamping
+//!
+//! Many networks drop the ICMP "fragmentation needed" messages path MTU
+//! discovery depends on. When two hosts agree on a segment size that fits the
+//! physical link but not the tunnel, the handshake and small requests get
+//! through and the first full-size segment vanishes, so some websites hang.
+//! Lowering the MSS option of SYN packets crossing the tunnel keeps both ends'
+//! segments small enough to fit.
+
+// The embedded tunnel clamps in its data path; wg-quick tunnels use firewall rules
+#![cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+
+const PROTO_TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// IP plus TCP header sizes, without options
+const IPV4_OVERHEAD: u32 = 20 + 20;
+const IPV6_OVERHEAD: u32 = 40 + 20;
+
+/// Largest MSS whose segments fit in a tunnel with this MTU
+pub fn max_mss(mtu: u32, ipv6: bool) -> u16 {
+    let overhead = if ipv6 { IPV6_OVERHEAD } else { IPV4_OVERHEAD };
+    mtu.saturating_sub(overhead).min(u32::from(u16::MAX)) as u16
+}
+
+/// Lower the MSS option of a TCP SYN to what fits in `mtu`, returning whether
+/// the packet was changed
+///
+/// Anything else, including SYNs that already ask for a small enough MSS, is
+/// left untouched.
+pub fn clamp(packet: &mut [u8], mtu: u32) -> bool {
+    let (ipv6, tcp_start) = match packet.first().map(|byte| byte >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            // Only the first fragment carries the TCP header
+            let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+            if packet[9] != PROTO_TCP || fragment_offset != 0 || header_len < 20 {
+                return false;
+            }
+            (false, header_len)
+        }
+        // SYNs with extension headers are rare enough to let through unclamped
+        Some(6) if packet.len() >= 40 && packet[6] == PROTO_TCP => (true, 40),
+        _ => return false,
+    };
+    let Some(tcp) = packet.get_mut(tcp_start..) else {
+        return false;
+    };
+    if tcp.len() < 20 || tcp[13] & TCP_SYN == 0 {
+        return false;
+    }
+    let header_len = usize::from(tcp[12] >> 4) * 4;
+    if header_len > tcp.len() {
+        return false;
+    }
+
+    let limit = max_mss(mtu, ipv6);
+    let mut i = 20;
+    while i < header_len {
+        match tcp[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let Some(len) = tcp.get(i + 1).map(|&len| usize::from(len)) else {
+                    break;
+                };
+                if len < 2 || i + len > header_len {
+                    break;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss <= limit {
+                        return false;
+                    }
+                    tcp[i + 2..i + 4].copy_from_slice(&limit.to_be_bytes());
+
+                    // A value at an odd offset straddles two checksum words
+                    let (old, new) = if (i + 2) % 2 == 0 {
+                        (mss, limit)
+                    } else {
+                        (mss.swap_bytes(), limit.swap_bytes())
+                    };
+                    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                    tcp[16..18].copy_from_slice(&adjust_checksum(checksum, old, new).to_be_bytes());
+                    return true;
+                }
+                i += len;
+            }
+        }
+    }
+    false
+}
+
+/// Update an Internet checksum for one 16-bit word changing (RFC 1624)
+fn adjust_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TCP checksum computed from scratch over the pseudo-header and segment
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let (pseudo, tcp) = if packet[0] >> 4 == 4 {
+            let tcp = &packet[20..];
+            let mut pseudo = packet[12..20].to_vec();
+            pseudo.extend_from_slice(&[0, PROTO_TCP]);
+            pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+            (pseudo, tcp)
+        } else {
+            let tcp = &packet[40..];
+            let mut pseudo = packet[8..40].to_vec();
+            pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, PROTO_TCP]);
+            (pseudo, tcp)
+        };
+        let mut data = pseudo;
+        data.extend_from_slice(tcp);
+        // The checksum field itself counts as zero
+        let offset = data.len() - tcp.len() + 16;
+        data[offset..offset + 2].fill(0);
+        if data.len() % 2 == 1 {
+            data.push(0);
+        }
+
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    fn with_checksum(mut packet: Vec<u8>, tcp_start: usize) -> Vec<u8> {
+        let checksum = tcp_checksum(&packet);
+        packet[tcp_start + 16..tcp_start + 18].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    fn tcp_header(flags: u8, options: &[u8]) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let mut tcp = vec![0u8; 20];
+        tcp[0..2].copy_from_slice(&51000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+        tcp[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        tcp[12] = ((header_len / 4) as u8) << 4;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&64240u16.to_be_bytes());
+        tcp.extend_from_slice(options);
+        tcp
+    }
+
+    fn ipv4_packet(flags: u8, options: &[u8]) -> Vec<u8> {
+        let tcp = tcp_header(flags, options);
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, PROTO_TCP, 0, 0];
+        packet[2..4].copy_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[10, 8, 0, 2, 93, 184, 216, 34]);
+        packet.extend_from_slice(&tcp);
+        with_checksum(packet, 20)
+    }
+
+    fn ipv6_packet(flags: u8, options: &[u8]) -> Vec<u8> {
+        let tcp = tcp_header(flags, options);
+        let mut packet = vec![0x60, 0, 0, 0, 0, 0, PROTO_TCP, 64];
+        packet[4..6].copy_from_slice(&(tcp.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet.extend_from_slice(&tcp);
+        with_checksum(packet, 40)
+    }
+
+    fn mss(packet: &[u8], option_at: usize) -> u16 {
+        u16::from_be_bytes([packet[option_at + 2], packet[option_at + 3]])
+    }
+
+    #[test]
+    fn test_clamps_ipv4_syn_and_fixes_checksum() {
+        // MSS 1460, SACK permitted
+        let mut packet = ipv4_packet(TCP_SYN, &[2, 4, 0x05, 0xb4, 4, 2, 1, 1]);
+        assert!(clamp(&mut packet, 1420));
+        assert_eq!(mss(&packet, 40), 1380);
+        assert_eq!(&packet[36..38], &tcp_checksum(&packet).to_be_bytes());
+
+        // Already small enough
+        assert!(!clamp(&mut packet, 1420));
+    }
+
+    #[test]
+    fn test_clamps_unaligned_option() {
+        let mut packet = ipv4_packet(TCP_SYN | 0x10, &[1, 2, 4, 0x05, 0xb4, 1, 1, 1]);
+        assert!(clamp(&mut packet, 1280));
+        assert_eq!(mss(&packet, 41), 1240);
+        assert_eq!(&packet[36..38], &tcp_checksum(&packet).to_be_bytes());
+    }
+
+    #[test]
+    fn test_clamps_ipv6_syn() {
+        let mut packet = ipv6_packet(TCP_SYN, &[2, 4, 0x05, 0xa0]);
+        assert!(clamp(&mut packet, 1420));
+        assert_eq!(mss(&packet, 60), 1360);
+        assert_eq!(&packet[56..58], &tcp_checksum(&packet).to_be_bytes());
+    }
+
+    #[test]
+    fn test_leaves_other_packets_alone() {
+        // Plain ACK
+        let mut ack = ipv4_packet(0x10, &[2, 4, 0x05, 0xb4]);
+        let original = ack.clone();
+        assert!(!clamp(&mut ack, 1420));
+        assert_eq!(ack, original);
+
+        // SYN without an MSS option, truncated options, not an IP packet
+        assert!(!clamp(&mut ipv4_packet(TCP_SYN, &[1, 1, 1, 1]), 1420));
+        assert!(!clamp(&mut ipv4_packet(TCP_SYN, &[3, 9, 7, 0]), 1420));
+        assert!(!clamp(&mut [0u8; 8], 1420));
+    }
+}
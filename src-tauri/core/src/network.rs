@@ -0,0 +1,276 @@
+//! Network awareness: which network the machine is on and how big it is
+//!
+//! Used to remember per-network tuning and to warn before exposing this machine
+//! to, or sharing the tunnel with, a network full of strangers, and to notice
+//! the OS taking the tunnel interface down under a live session.
+
+use super::routes::Route;
+use super::VpnError;
+use serde::Serialize;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::process::Command;
+
+/// Networks with a shorter prefix than this (more than 1022 hosts) are treated
+/// as large shared networks such as campus or hotel Wi-Fi
+pub const LARGE_NETWORK_PREFIX: u8 = 22;
+
+/// Identity of the network a route runs over: its gateway and interface
+pub fn network_id(route: &Route) -> String {
+    match route.gateway {
+        Some(gateway) => format!("{} dev {}", gateway, route.interface),
+        None => format!("dev {}", route.interface),
+    }
+}
+
+/// Address and size of the local network on one interface
+#[derive(Debug, Clone, Serialize)]
+pub struct LanInfo {
+    pub interface: String,
+    pub address: String,
+    pub prefix_len: u8,
+    /// Usable host addresses in the subnet
+    pub hosts: u64,
+    pub large: bool,
+    /// Shown before LAN access or connection sharing is turned on
+    pub warning: Option<String>,
+}
+
+impl LanInfo {
+    fn new(interface: &str, address: Ipv4Addr, prefix_len: u8) -> Self {
+        let hosts = (1u64 << (32 - u32::from(prefix_len.min(32)))).saturating_sub(2);
+        let large = prefix_len < LARGE_NETWORK_PREFIX;
+        let warning = large.then(|| {
+            format!(
+                "{} is on a large network (/{}, up to {} devices) that may include untrusted \
+                 devices; only allow LAN access or share the connection on networks you control",
+                interface, prefix_len, hosts
+            )
+        });
+        Self {
+            interface: interface.to_string(),
+            address: address.to_string(),
+            prefix_len,
+            hosts,
+            large,
+            warning,
+        }
+    }
+}
+
+/// Operational state of an interface, as the OS reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceState {
+    Up,
+    /// Turned off, as when the adapter is disabled
+    Down,
+    /// On, but without a link: unplugged, or no longer driven by the tunnel
+    MediaDisconnected,
+    /// Gone from the system
+    Missing,
+}
+
+impl fmt::Display for InterfaceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Up => "up",
+            Self::Down => "down",
+            Self::MediaDisconnected => "disconnected",
+            Self::Missing => "gone",
+        })
+    }
+}
+
+/// State of `interface`, or None where the OS can't be asked
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn interface_state(interface: &str) -> Option<InterfaceState> {
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: on success the list is ours until freeifaddrs below
+    if unsafe { libc::getifaddrs(&mut addresses) } != 0 {
+        return None;
+    }
+    // One entry per address, plus one for the link itself
+    let mut flags = None;
+    let mut entry = addresses;
+    while !entry.is_null() {
+        // SAFETY: entries and their names stay valid until freeifaddrs
+        let current = unsafe { &*entry };
+        let name = unsafe { std::ffi::CStr::from_ptr(current.ifa_name) };
+        if name.to_bytes() == interface.as_bytes() {
+            flags = Some(flags.unwrap_or(0) | current.ifa_flags);
+        }
+        entry = current.ifa_next;
+    }
+    // SAFETY: `addresses` came from getifaddrs and isn't used after this
+    unsafe { libc::freeifaddrs(addresses) };
+
+    Some(match flags {
+        None => InterfaceState::Missing,
+        Some(flags) if flags & libc::IFF_UP as libc::c_uint == 0 => InterfaceState::Down,
+        // Running means the link is up: a carrier, or a tun device still held open
+        Some(flags) if flags & libc::IFF_RUNNING as libc::c_uint == 0 => {
+            InterfaceState::MediaDisconnected
+        }
+        Some(_) => InterfaceState::Up,
+    })
+}
+
+/// State of `interface`, or None where the OS can't be asked
+#[cfg(target_os = "windows")]
+pub fn interface_state(interface: &str) -> Option<InterfaceState> {
+    use windows::core::HSTRING;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        ConvertInterfaceAliasToLuid, GetIfEntry2, MIB_IF_ROW2,
+    };
+    use windows::Win32::NetworkManagement::Ndis::{
+        IfOperStatusNotPresent, IfOperStatusUp, MediaConnectStateDisconnected,
+        NET_IF_ADMIN_STATUS_UP,
+    };
+
+    let mut row = MIB_IF_ROW2::default();
+    // SAFETY: `row` outlives both calls; GetIfEntry2 looks it up by the LUID set first
+    unsafe {
+        if ConvertInterfaceAliasToLuid(&HSTRING::from(interface), &mut row.InterfaceLuid)
+            .ok()
+            .is_err()
+        {
+            return Some(InterfaceState::Missing);
+        }
+        GetIfEntry2(&mut row).ok().ok()?;
+    }
+
+    Some(if row.AdminStatus != NET_IF_ADMIN_STATUS_UP {
+        InterfaceState::Down
+    } else if row.OperStatus == IfOperStatusNotPresent {
+        InterfaceState::Missing
+    } else if row.MediaConnectState == MediaConnectStateDisconnected
+        || row.OperStatus != IfOperStatusUp
+    {
+        InterfaceState::MediaDisconnected
+    } else {
+        InterfaceState::Up
+    })
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+pub fn interface_state(_interface: &str) -> Option<InterfaceState> {
+    None
+}
+
+/// Look up the IPv4 subnet of `interface`
+pub fn local_network(interface: &str) -> Result<LanInfo, VpnError> {
+    let output = address_command(interface).output().map_err(|e| {
+        VpnError::ConfigError(format!("Failed to read {} address: {}", interface, e))
+    })?;
+
+    let (address, prefix_len) = parse_address(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| VpnError::ConfigError(format!("No IPv4 address on {}", interface)))?;
+    Ok(LanInfo::new(interface, address, prefix_len))
+}
+
+/// Log the large-network warning for `interface`, if it applies
+pub fn warn_if_large(interface: &str, action: &str) {
+    match local_network(interface) {
+        Ok(LanInfo {
+            warning: Some(warning),
+            ..
+        }) => log::warn!("{}: {}", action, warning),
+        Ok(_) => {}
+        Err(e) => log::debug!("Could not size the network on {}: {}", interface, e),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn address_command(interface: &str) -> Command {
+    let mut command = Command::new("powershell");
+    command.args([
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        &format!(
+            "Get-NetIPAddress -AddressFamily IPv4 -InterfaceAlias '{}' | \
+             ForEach-Object {{ \"$($_.IPAddress)/$($_.PrefixLength)\" }}",
+            interface.replace('\'', "''")
+        ),
+    ]);
+    command
+}
+
+#[cfg(target_os = "linux")]
+fn address_command(interface: &str) -> Command {
+    let mut command = Command::new("ip");
+    command.args(["-o", "-4", "addr", "show", "dev", interface]);
+    command
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn address_command(interface: &str) -> Command {
+    let mut command = Command::new("ifconfig");
+    command.arg(interface);
+    command
+}
+
+/// First non-loopback IPv4 address with its prefix length, from either
+/// `a.b.c.d/nn` (ip, PowerShell) or `inet a.b.c.d netmask 0x...` (ifconfig)
+fn parse_address(output: &str) -> Option<(Ipv4Addr, u8)> {
+    output.lines().find_map(|line| {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let parsed: Option<(Ipv4Addr, u8)> = tokens
+            .iter()
+            .find_map(|token| {
+                let (address, prefix) = token.split_once('/')?;
+                Some((address.parse().ok()?, prefix.parse().ok()?))
+            })
+            .or_else(|| {
+                let at = tokens.iter().position(|&t| t == "inet")?;
+                let address = tokens.get(at + 1)?.parse().ok()?;
+                let mask = tokens.iter().position(|&t| t == "netmask")?;
+                let mask = tokens.get(mask + 1)?.trim_start_matches("0x");
+                let mask = u32::from_str_radix(mask, 16).ok()?;
+                Some((address, mask.count_ones() as u8))
+            });
+        parsed.filter(|(address, prefix)| !address.is_loopback() && *prefix <= 32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address_formats() {
+        let ip = "2: wlan0    inet 10.20.30.40/16 brd 10.20.255.255 scope global dynamic wlan0";
+        assert_eq!(parse_address(ip), Some((Ipv4Addr::new(10, 20, 30, 40), 16)));
+
+        let ifconfig = "en0: flags=8863<UP,BROADCAST>\n\
+                        \tinet 192.168.1.23 netmask 0xffffff00 broadcast 192.168.1.255";
+        assert_eq!(
+            parse_address(ifconfig),
+            Some((Ipv4Addr::new(192, 168, 1, 23), 24))
+        );
+        assert_eq!(parse_address("127.0.0.1/8"), None);
+    }
+
+    #[test]
+    fn test_large_networks_are_flagged() {
+        let campus = LanInfo::new("wlan0", Ipv4Addr::new(10, 20, 30, 40), 16);
+        assert!(campus.large);
+        assert_eq!(campus.hosts, 65_534);
+        assert!(campus.warning.is_some());
+
+        let home = LanInfo::new("wlan0", Ipv4Addr::new(192, 168, 1, 23), 24);
+        assert!(!home.large);
+        assert_eq!(home.warning, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interface_state_of_loopback_and_missing() {
+        assert_eq!(interface_state("lo"), Some(InterfaceState::Up));
+        assert_eq!(
+            interface_state("sacvpn-none0"),
+            Some(InterfaceState::Missing)
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Machine-wide ownership of the tunnel
+//!
+//! Only one process may run the data path for a tunnel name. A second instance,
+//! an old instance that outlived an update, or a service running as another
+//! user would otherwise create a second adapter and fight the first over routes
+//! and firewall rules. The owner holds a lock for as long as the tunnel is up;
+//! the OS drops it when the process exits, so a crash never leaves it stuck.
+//!
+//! On Windows the lock is a global mutex seen by every session. Elsewhere it is
+//! a file in the user's private directory: a shared location like /tmp would
+//! let another user plant a symlink there and have us write through it.
+
+use super::VpnError;
+
+/// Proof that this process owns the tunnel, released on drop
+#[derive(Debug)]
+pub struct TunnelLock {
+    #[cfg(target_os = "windows")]
+    mutex: windows::Win32::Foundation::HANDLE,
+    #[cfg(not(target_os = "windows"))]
+    _file: std::fs::File,
+}
+
+// The mutex handle is only closed, never waited on, so moving it between threads is fine
+#[cfg(target_os = "windows")]
+unsafe impl Send for TunnelLock {}
+#[cfg(target_os = "windows")]
+unsafe impl Sync for TunnelLock {}
+
+impl TunnelLock {
+    /// Take ownership of the tunnel, or fail if another process holds it
+    pub fn acquire(tunnel_name: &str) -> Result<Self, VpnError> {
+        #[cfg(target_os = "windows")]
+        {
+            use windows::core::HSTRING;
+            use windows::Win32::Foundation::{CloseHandle, GetLastError, ERROR_ALREADY_EXISTS};
+            use windows::Win32::System::Threading::CreateMutexW;
+
+            // Global\ makes the mutex visible across sessions, so a service counts too
+            let name = HSTRING::from(format!("Global\\SACVPN-Tunnel-{}", tunnel_name));
+            let mutex = unsafe { CreateMutexW(None, true, &name) }.map_err(|e| {
+                VpnError::WireGuardError(format!("Failed to create tunnel lock: {}", e))
+            })?;
+
+            if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+                unsafe {
+                    let _ = CloseHandle(mutex);
+                }
+                return Err(VpnError::TunnelInUse(format!(
+                    "Another SACVPN process or service already owns the '{}' tunnel",
+                    tunnel_name
+                )));
+            }
+            Ok(Self { mutex })
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            use std::fs::{OpenOptions, TryLockError};
+            use std::io::{Read, Seek, Write};
+
+            let path = lock_path(tunnel_name)?;
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create(true).truncate(false);
+            #[cfg(any(target_os = "linux", target_os = "macos"))]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.custom_flags(libc::O_NOFOLLOW);
+            }
+            let mut file = options.open(&path).map_err(|e| {
+                VpnError::WireGuardError(format!("Failed to open {:?}: {}", path, e))
+            })?;
+
+            match file.try_lock() {
+                Ok(()) => {
+                    let _ = file.set_len(0);
+                    let _ = write!(file, "{}", std::process::id());
+                    Ok(Self { _file: file })
+                }
+                Err(TryLockError::WouldBlock) => {
+                    let mut owner = String::new();
+                    let _ = file.rewind().and_then(|_| file.read_to_string(&mut owner));
+                    let owner = match owner.trim() {
+                        "" => "Another SACVPN process".to_string(),
+                        pid => format!("SACVPN process {}", pid),
+                    };
+                    Err(VpnError::TunnelInUse(format!(
+                        "{} already owns the '{}' tunnel",
+                        owner, tunnel_name
+                    )))
+                }
+                Err(TryLockError::Error(e)) => Err(VpnError::WireGuardError(format!(
+                    "Failed to lock {:?}: {}",
+                    path, e
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for TunnelLock {
+    fn drop(&mut self) {
+        use windows::Win32::Foundation::CloseHandle;
+        use windows::Win32::System::Threading::ReleaseMutex;
+
+        unsafe {
+            let _ = ReleaseMutex(self.mutex);
+            let _ = CloseHandle(self.mutex);
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn lock_path(tunnel_name: &str) -> Result<std::path::PathBuf, VpnError> {
+    Ok(private_dir()?.join(format!("{}.lock", tunnel_name)))
+}
+
+/// Private per-user directory for tunnel state, created on first use
+///
+/// Prefers a runtime directory the OS clears on logout or reboot, so a config
+/// holding the private key doesn't outlive the session if cleanup never runs.
+#[cfg(not(target_os = "windows"))]
+pub(super) fn private_dir() -> Result<std::path::PathBuf, VpnError> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::path::PathBuf;
+
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        });
+
+    let dir = base
+        .ok_or_else(|| VpnError::ConfigError("No home directory for tunnel state".to_string()))?
+        .join("sacvpn");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        // An existing directory keeps whatever mode it was created with
+        .and_then(|_| std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)))
+        .map_err(|e| VpnError::ConfigError(format!("Failed to create state dir: {}", e)))?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+#[cfg(not(target_os = "windows"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_owner_is_refused() {
+        let name = format!("SACVPN-test-{}", std::process::id());
+
+        let lock = TunnelLock::acquire(&name).unwrap();
+        match TunnelLock::acquire(&name) {
+            Err(VpnError::TunnelInUse(msg)) => {
+                assert!(msg.contains(&std::process::id().to_string()))
+            }
+            other => panic!("expected TunnelInUse, got {:?}", other),
+        }
+
+        drop(lock);
+        drop(TunnelLock::acquire(&name).unwrap());
+        let _ = std::fs::remove_file(lock_path(&name).unwrap());
+    }
+}
@@ -0,0 +1,188 @@
+//! ICMP "packet too big" replies for the userspace data path
+//!
+//! A packet bigger than the tunnel MTU that the sender doesn't allow to be
+//! fragmented can't be carried. Dropping it silently leaves the sender
+//! retransmitting the same size forever; answering with the ICMP error a router
+//! would send lets its path MTU discovery settle on a size that fits.
+
+// Only the embedded tunnel has a userspace data path
+#![cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_ICMPV6: u8 = 58;
+
+/// IPv4 "don't fragment" flag, in the flags/offset word
+const DONT_FRAGMENT: u16 = 0x4000;
+
+/// ICMP errors are kept to the size every host must accept
+const IPV4_MAX_ERROR_LEN: usize = 576;
+const IPV6_MAX_ERROR_LEN: usize = 1280;
+
+const REPLY_TTL: u8 = 64;
+
+/// ICMP error answering `packet`, if it is too big for `mtu` and may not be
+/// fragmented; the caller drops the packet and writes the reply back to the TUN
+pub fn too_big_reply(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if packet.len() <= mtu {
+        return None;
+    }
+    match packet.first()? >> 4 {
+        4 => ipv4_reply(packet, mtu),
+        6 => ipv6_reply(packet, mtu),
+        _ => None,
+    }
+}
+
+fn ipv4_reply(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    if header_len < 20 || packet.len() < header_len + 8 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    // Fragmentable packets are still sent; the outer UDP datagram gets fragmented instead
+    if flags & DONT_FRAGMENT == 0 {
+        return None;
+    }
+    // Never answer an ICMP error with another (RFC 1122 3.2.2)
+    if packet[9] == PROTO_ICMP && !matches!(packet[header_len], 0 | 8 | 13 | 15 | 17) {
+        return None;
+    }
+
+    let quoted = &packet[..packet.len().min(IPV4_MAX_ERROR_LEN - 28)];
+    let mut icmp = vec![3, 4, 0, 0, 0, 0];
+    icmp.extend_from_slice(&(mtu.min(usize::from(u16::MAX)) as u16).to_be_bytes());
+    icmp.extend_from_slice(quoted);
+    let checksum = internet_checksum(&icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut reply = vec![0x45, 0, 0, 0, 0, 0, 0, 0, REPLY_TTL, PROTO_ICMP, 0, 0];
+    reply[2..4].copy_from_slice(&((20 + icmp.len()) as u16).to_be_bytes());
+    // From the destination back to the sender, as if the first hop had answered
+    reply.extend_from_slice(&packet[16..20]);
+    reply.extend_from_slice(&packet[12..16]);
+    let checksum = internet_checksum(&reply);
+    reply[10..12].copy_from_slice(&checksum.to_be_bytes());
+    reply.extend_from_slice(&icmp);
+    Some(reply)
+}
+
+/// IPv6 routers never fragment, so every oversized packet gets an answer
+fn ipv6_reply(packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    if packet.len() < 40 {
+        return None;
+    }
+    // ICMPv6 types below 128 are errors
+    if packet[6] == PROTO_ICMPV6 && packet.get(40).is_none_or(|&kind| kind < 128) {
+        return None;
+    }
+
+    let quoted = &packet[..packet.len().min(IPV6_MAX_ERROR_LEN - 48)];
+    let mut icmp = vec![2, 0, 0, 0];
+    icmp.extend_from_slice(&(mtu as u32).to_be_bytes());
+    icmp.extend_from_slice(quoted);
+
+    let (source, destination) = (&packet[24..40], &packet[8..24]);
+    let mut pseudo = Vec::with_capacity(40 + icmp.len());
+    pseudo.extend_from_slice(source);
+    pseudo.extend_from_slice(destination);
+    pseudo.extend_from_slice(&(icmp.len() as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, PROTO_ICMPV6]);
+    pseudo.extend_from_slice(&icmp);
+    let checksum = internet_checksum(&pseudo);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut reply = vec![0x60, 0, 0, 0, 0, 0, PROTO_ICMPV6, REPLY_TTL];
+    reply[4..6].copy_from_slice(&(icmp.len() as u16).to_be_bytes());
+    reply.extend_from_slice(source);
+    reply.extend_from_slice(destination);
+    reply.extend_from_slice(&icmp);
+    Some(reply)
+}
+
+/// One's complement sum of 16-bit words (RFC 1071)
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(len: usize, flags: u16, protocol: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[6..8].copy_from_slice(&flags.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        packet[16..20].copy_from_slice(&[93, 184, 216, 34]);
+        packet
+    }
+
+    #[test]
+    fn test_ipv4_fragmentation_needed() {
+        let packet = ipv4_packet(1500, DONT_FRAGMENT, 6);
+        let reply = too_big_reply(&packet, 1420).unwrap();
+
+        assert_eq!(reply.len(), IPV4_MAX_ERROR_LEN);
+        assert_eq!(&reply[12..16], &[93, 184, 216, 34]);
+        assert_eq!(&reply[16..20], &[10, 8, 0, 2]);
+        assert_eq!(internet_checksum(&reply[..20]), 0);
+        // Type 3 code 4, next-hop MTU, then the start of the original packet
+        assert_eq!(&reply[20..22], &[3, 4]);
+        assert_eq!(u16::from_be_bytes([reply[26], reply[27]]), 1420);
+        assert_eq!(&reply[28..48], &packet[..20]);
+        assert_eq!(internet_checksum(&reply[20..]), 0);
+    }
+
+    #[test]
+    fn test_ipv4_without_reply() {
+        // Fits, may be fragmented, or is an ICMP error itself
+        assert!(too_big_reply(&ipv4_packet(1400, DONT_FRAGMENT, 6), 1420).is_none());
+        assert!(too_big_reply(&ipv4_packet(1500, 0, 17), 1420).is_none());
+        let mut error = ipv4_packet(1500, DONT_FRAGMENT, PROTO_ICMP);
+        error[20] = 3;
+        assert!(too_big_reply(&error, 1420).is_none());
+        error[20] = 8;
+        assert!(too_big_reply(&error, 1420).is_some());
+    }
+
+    #[test]
+    fn test_ipv6_packet_too_big() {
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&1460u16.to_be_bytes());
+        packet[6] = 6;
+        packet[8] = 0xfd;
+        packet[23] = 2;
+        packet[24..26].copy_from_slice(&[0x20, 0x01]);
+        packet[39] = 1;
+
+        let reply = too_big_reply(&packet, 1420).unwrap();
+        assert_eq!(reply.len(), IPV6_MAX_ERROR_LEN);
+        assert_eq!(&reply[8..24], &packet[24..40]);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+        assert_eq!(reply[40], 2);
+        assert_eq!(
+            u32::from_be_bytes([reply[44], reply[45], reply[46], reply[47]]),
+            1420
+        );
+
+        let mut pseudo = reply[8..40].to_vec();
+        pseudo.extend_from_slice(&((reply.len() - 40) as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, PROTO_ICMPV6]);
+        pseudo.extend_from_slice(&reply[40..]);
+        assert_eq!(internet_checksum(&pseudo), 0);
+    }
+}
@@ -0,0 +1,292 @@
+//! Adaptive polling and error accounting for the userspace forwarding loop
+//!
+//! Yields between iterations while traffic flows and backs off to longer sleeps
+//! once the tunnel goes idle, so an idle connection doesn't burn a full core.
+//! Per-packet failures are counted against a budget; a loop whose socket or
+//! TUN keeps failing gives up so the watchdog can reconnect. Crypto failures
+//! are only counted and logged, as anyone can send a datagram that fails to
+//! decrypt.
+
+// Only the embedded tunnel has a userspace forwarding loop
+#![cfg_attr(
+    not(any(target_os = "windows", target_os = "linux", target_os = "macos")),
+    allow(dead_code)
+)]
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Sleep between idle iterations in low-latency mode
+const LOW_LATENCY_SLEEP: Duration = Duration::from_millis(1);
+
+/// Consecutive idle iterations before switching to power-saving mode
+const POWER_SAVING_AFTER: u32 = 500;
+
+/// Upper bound on the sleep in power-saving mode
+const MAX_POWER_SAVING_SLEEP: Duration = Duration::from_millis(20);
+
+/// Window over which forwarding errors are counted against their budget
+const ERROR_WINDOW: Duration = Duration::from_secs(10);
+
+/// Per-packet failures the forwarding loop accounts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingError {
+    /// Encryption or decryption failed (bad MAC, unexpected or stale packets)
+    Crypto,
+    /// The UDP socket failed to send or receive
+    Socket,
+    /// Reading from or writing to the wintun ring failed
+    Tun,
+}
+
+impl ForwardingError {
+    /// Errors tolerated per `ERROR_WINDOW`; for crypto errors, the count past
+    /// which a warning is logged instead, as forged datagrams mustn't be able
+    /// to take the tunnel down
+    fn budget(self) -> u32 {
+        match self {
+            Self::Crypto => 500,
+            Self::Socket => 50,
+            Self::Tun => 50,
+        }
+    }
+}
+
+/// Counters shared between the forwarding task and diagnostics
+#[derive(Debug, Default)]
+pub struct ForwardingCounters {
+    busy: AtomicU64,
+    idle: AtomicU64,
+    power_saving: AtomicBool,
+    crypto_errors: AtomicU64,
+    socket_errors: AtomicU64,
+    tun_errors: AtomicU64,
+    too_big: AtomicU64,
+    spoofed: AtomicU64,
+    disallowed: AtomicU64,
+    unrouted: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ForwardingStats {
+    /// Loop iterations that moved at least one packet
+    pub busy_iterations: u64,
+    /// Loop iterations that found nothing to do
+    pub idle_iterations: u64,
+    pub power_saving: bool,
+    pub crypto_errors: u64,
+    pub socket_errors: u64,
+    pub tun_errors: u64,
+    /// Packets too big for the tunnel, answered with an ICMP error instead of sent
+    pub too_big_packets: u64,
+    /// Packets from the TUN not sent from the tunnel address, dropped
+    pub spoofed_packets: u64,
+    /// Decrypted packets from sources outside AllowedIPs, dropped
+    pub disallowed_packets: u64,
+    /// Packets from the TUN to destinations outside every peer's AllowedIPs, dropped
+    pub unrouted_packets: u64,
+}
+
+impl ForwardingCounters {
+    pub fn snapshot(&self) -> ForwardingStats {
+        ForwardingStats {
+            busy_iterations: self.busy.load(Ordering::Relaxed),
+            idle_iterations: self.idle.load(Ordering::Relaxed),
+            power_saving: self.power_saving.load(Ordering::Relaxed),
+            crypto_errors: self.crypto_errors.load(Ordering::Relaxed),
+            socket_errors: self.socket_errors.load(Ordering::Relaxed),
+            tun_errors: self.tun_errors.load(Ordering::Relaxed),
+            too_big_packets: self.too_big.load(Ordering::Relaxed),
+            spoofed_packets: self.spoofed.load(Ordering::Relaxed),
+            disallowed_packets: self.disallowed.load(Ordering::Relaxed),
+            unrouted_packets: self.unrouted.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.busy.store(0, Ordering::Relaxed);
+        self.idle.store(0, Ordering::Relaxed);
+        self.power_saving.store(false, Ordering::Relaxed);
+        self.crypto_errors.store(0, Ordering::Relaxed);
+        self.socket_errors.store(0, Ordering::Relaxed);
+        self.tun_errors.store(0, Ordering::Relaxed);
+        self.too_big.store(0, Ordering::Relaxed);
+        self.spoofed.store(0, Ordering::Relaxed);
+        self.disallowed.store(0, Ordering::Relaxed);
+        self.unrouted.store(0, Ordering::Relaxed);
+    }
+
+    pub fn count_too_big(&self) {
+        self.too_big.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_spoofed(&self) {
+        self.spoofed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_disallowed(&self) {
+        self.disallowed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_unrouted(&self) {
+        self.unrouted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn error_counter(&self, kind: ForwardingError) -> &AtomicU64 {
+        match kind {
+            ForwardingError::Crypto => &self.crypto_errors,
+            ForwardingError::Socket => &self.socket_errors,
+            ForwardingError::Tun => &self.tun_errors,
+        }
+    }
+}
+
+/// Counts forwarding errors per window and decides when the loop should give up
+pub struct ErrorBudget {
+    counters: Arc<ForwardingCounters>,
+    window_start: Instant,
+    /// Errors in the current window, indexed like `ForwardingError`
+    in_window: [u32; 3],
+}
+
+impl ErrorBudget {
+    pub fn new(counters: Arc<ForwardingCounters>) -> Self {
+        Self {
+            counters,
+            window_start: Instant::now(),
+            in_window: [0; 3],
+        }
+    }
+
+    /// Record an error; fails once a socket or TUN error has exceeded the
+    /// budget for the window
+    pub fn record(&mut self, kind: ForwardingError) -> Result<(), String> {
+        self.record_at(kind, Instant::now())
+    }
+
+    fn record_at(&mut self, kind: ForwardingError, now: Instant) -> Result<(), String> {
+        self.counters
+            .error_counter(kind)
+            .fetch_add(1, Ordering::Relaxed);
+
+        if now.duration_since(self.window_start) >= ERROR_WINDOW {
+            self.window_start = now;
+            self.in_window = [0; 3];
+        }
+
+        let count = &mut self.in_window[kind as usize];
+        *count += 1;
+        if kind == ForwardingError::Crypto {
+            if *count == kind.budget() + 1 {
+                log::warn!(
+                    "Dropped over {} datagrams that failed to decrypt within {:?}",
+                    kind.budget(),
+                    ERROR_WINDOW
+                );
+            }
+            return Ok(());
+        }
+        if *count > kind.budget() {
+            return Err(format!(
+                "exceeded the {:?} error budget ({} errors within {:?})",
+                kind, count, ERROR_WINDOW
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Chooses how long the forwarding loop waits after each iteration
+pub struct Poller {
+    counters: Arc<ForwardingCounters>,
+    idle_streak: u32,
+}
+
+impl Poller {
+    pub fn new(counters: Arc<ForwardingCounters>) -> Self {
+        Self {
+            counters,
+            idle_streak: 0,
+        }
+    }
+
+    /// Record an iteration and return the sleep before the next one, `None` to just yield
+    pub fn after_iteration(&mut self, processed: usize) -> Option<Duration> {
+        if processed > 0 {
+            self.counters.busy.fetch_add(1, Ordering::Relaxed);
+            if self.idle_streak >= POWER_SAVING_AFTER {
+                self.counters.power_saving.store(false, Ordering::Relaxed);
+            }
+            self.idle_streak = 0;
+            return None;
+        }
+
+        self.counters.idle.fetch_add(1, Ordering::Relaxed);
+        self.idle_streak = self.idle_streak.saturating_add(1);
+        if self.idle_streak < POWER_SAVING_AFTER {
+            return Some(LOW_LATENCY_SLEEP);
+        }
+
+        if self.idle_streak == POWER_SAVING_AFTER {
+            self.counters.power_saving.store(true, Ordering::Relaxed);
+        }
+
+        // Ramp up gradually so a short pause in traffic doesn't add much latency
+        let ramp = (self.idle_streak - POWER_SAVING_AFTER) / 10 + 2;
+        Some(Duration::from_millis(ramp as u64).min(MAX_POWER_SAVING_SLEEP))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poller_switches_modes() {
+        let counters = Arc::new(ForwardingCounters::default());
+        let mut poller = Poller::new(counters.clone());
+
+        assert_eq!(poller.after_iteration(4), None);
+        assert_eq!(poller.after_iteration(0), Some(LOW_LATENCY_SLEEP));
+
+        let last = (0..1000)
+            .map(|_| poller.after_iteration(0))
+            .last()
+            .flatten();
+        assert_eq!(last, Some(MAX_POWER_SAVING_SLEEP));
+        assert!(counters.snapshot().power_saving);
+
+        assert_eq!(poller.after_iteration(1), None);
+        let stats = counters.snapshot();
+        assert!(!stats.power_saving);
+        assert_eq!(stats.busy_iterations, 2);
+        assert_eq!(stats.idle_iterations, 1001);
+    }
+
+    #[test]
+    fn test_error_budget_resets_each_window() {
+        let counters = Arc::new(ForwardingCounters::default());
+        let mut budget = ErrorBudget::new(counters.clone());
+        let start = budget.window_start;
+
+        for _ in 0..ForwardingError::Socket.budget() {
+            assert!(budget.record_at(ForwardingError::Socket, start).is_ok());
+        }
+        assert!(budget.record_at(ForwardingError::Tun, start).is_ok());
+        assert!(budget.record_at(ForwardingError::Socket, start).is_err());
+
+        // Forged datagrams fail to decrypt too, so they never end the loop
+        for _ in 0..=2 * ForwardingError::Crypto.budget() {
+            assert!(budget.record_at(ForwardingError::Crypto, start).is_ok());
+        }
+
+        assert!(budget
+            .record_at(ForwardingError::Socket, start + ERROR_WINDOW)
+            .is_ok());
+        let stats = counters.snapshot();
+        assert_eq!(stats.socket_errors, 52);
+        assert_eq!(stats.tun_errors, 1);
+    }
+}
@@ -0,0 +1,22 @@
+//! Connect pre-warming
+//!
+//! Resolving the server's endpoint and loading the tunnel driver take a good
+//! part of a connect, yet change nothing on the system. Doing them while the
+//! user is still picking a server leaves only the tunnel setup for the click.
+
+use super::resolve;
+use super::{TunnelTuning, VpnConfig, VpnError};
+use std::net::SocketAddr;
+
+/// Resolve the config's endpoint and load the tunnel driver ahead of a connect,
+/// returning the endpoint's address
+pub async fn prewarm(config: &VpnConfig, tuning: &TunnelTuning) -> Result<SocketAddr, VpnError> {
+    // Kept by the resolver, which the connect asks again
+    let address = resolve::resolve(&config.primary_peer().endpoint, tuning).await?;
+
+    #[cfg(target_os = "windows")]
+    tokio::task::spawn_blocking(super::wireguard::preload_wintun)
+        .await
+        .map_err(|e| VpnError::ConnectionFailed(e.to_string()))??;
+    Ok(address)
+}
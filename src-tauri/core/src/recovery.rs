@@ -0,0 +1,351 @@
+//! Error recovery suggestions
+//!
+//! Maps common failure signatures to machine-readable recovery actions so the UI
+//! can offer "Fix it" buttons instead of dead-end error toasts.
+
+use super::VpnError;
+use serde::{Deserialize, Serialize};
+
+/// Machine-readable classification of a VPN failure
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    WintunLoadFailed,
+    EndpointUnreachable,
+    HandshakeTimeout,
+    PermissionDenied,
+    DnsFailure,
+    InvalidConfig,
+    AlreadyConnected,
+    TunnelInUse,
+    /// The OS took the tunnel interface down, as when the adapter was disabled
+    InterfaceDown,
+    /// The account already has as many devices connected as its plan allows
+    DeviceLimitReached,
+    /// The account no longer accepts this device or its sign-in
+    AccessRevoked,
+    /// The system clock is too far off for handshakes and TLS to succeed
+    ClockSkew,
+    /// The server is under maintenance or no longer offered
+    ServerUnavailable,
+    NotConnected,
+    PlatformNotSupported,
+    Unknown,
+}
+
+/// An action the UI can offer to resolve a failure
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryAction {
+    RestartAsAdmin,
+    ReinstallApp,
+    CheckInternetConnection,
+    TryAnotherServer,
+    Retry,
+    UseCustomDns,
+    RefreshConfig,
+    InstallWireGuardTools,
+    Disconnect,
+    CloseOtherInstance,
+    /// List the account's devices so the user can disconnect one
+    ManageDevices,
+    FixSystemClock,
+    /// Connect to the server in `ErrorReport::alternative_server`
+    ConnectAlternative,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoverySuggestion {
+    pub action: RecoveryAction,
+    pub description: String,
+}
+
+/// Error payload returned to the frontend with suggestions attached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub code: ErrorCode,
+    pub message: String,
+    pub suggestions: Vec<RecoverySuggestion>,
+    /// Id of the server offered instead of the one that failed, if any
+    #[serde(default)]
+    pub alternative_server: Option<String>,
+}
+
+impl ErrorReport {
+    /// Report for a failure classified outside the VPN layer
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            suggestions: suggestions_for(code),
+            alternative_server: None,
+        }
+    }
+
+    /// Offer `server_id` in place of the failed server; without one there is
+    /// nothing to connect to, so that suggestion is dropped
+    pub fn with_alternative(mut self, alternative: Option<(String, String)>) -> Self {
+        match alternative {
+            Some((server_id, server_name)) => {
+                for suggestion in &mut self.suggestions {
+                    if suggestion.action == RecoveryAction::ConnectAlternative {
+                        suggestion.description = format!("Connect to {} instead", server_name);
+                    }
+                }
+                self.alternative_server = Some(server_id);
+            }
+            None => self
+                .suggestions
+                .retain(|suggestion| suggestion.action != RecoveryAction::ConnectAlternative),
+        }
+        self
+    }
+}
+
+impl From<&VpnError> for ErrorReport {
+    fn from(error: &VpnError) -> Self {
+        let code = classify(error);
+        Self {
+            code,
+            message: error.to_string(),
+            suggestions: suggestions_for(code),
+            alternative_server: None,
+        }
+    }
+}
+
+impl From<VpnError> for ErrorReport {
+    fn from(error: VpnError) -> Self {
+        Self::from(&error)
+    }
+}
+
+/// Whether the endpoint never answered, as when a firewall drops its port;
+/// another port or address of the server may still get through
+pub fn is_unanswered(error: &VpnError) -> bool {
+    matches!(
+        classify(error),
+        ErrorCode::HandshakeTimeout | ErrorCode::EndpointUnreachable
+    )
+}
+
+/// Classify an error by variant and, for free-form errors, by message signature
+pub fn classify(error: &VpnError) -> ErrorCode {
+    match error {
+        VpnError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+        VpnError::AlreadyConnected => ErrorCode::AlreadyConnected,
+        VpnError::TunnelInUse(_) => ErrorCode::TunnelInUse,
+        VpnError::InterfaceDown(_) => ErrorCode::InterfaceDown,
+        VpnError::NotConnected => ErrorCode::NotConnected,
+        VpnError::PlatformNotSupported => ErrorCode::PlatformNotSupported,
+        VpnError::ConfigError(msg) => {
+            let lower = msg.to_lowercase();
+            if lower.contains("device limit") {
+                ErrorCode::DeviceLimitReached
+            } else if lower.contains("revoked") {
+                ErrorCode::AccessRevoked
+            } else if is_dns_failure(msg) {
+                ErrorCode::DnsFailure
+            } else {
+                ErrorCode::InvalidConfig
+            }
+        }
+        VpnError::ConnectionFailed(msg)
+        | VpnError::DisconnectionFailed(msg)
+        | VpnError::WireGuardError(msg) => classify_message(msg),
+    }
+}
+
+fn classify_message(msg: &str) -> ErrorCode {
+    let lower = msg.to_lowercase();
+
+    if lower.contains("wintun driver") || lower.contains("wireguardnt driver") {
+        ErrorCode::WintunLoadFailed
+    } else if lower.contains("handshake") {
+        ErrorCode::HandshakeTimeout
+    } else if lower.contains("access is denied")
+        || lower.contains("administrator")
+        || lower.contains("root privileges")
+    {
+        ErrorCode::PermissionDenied
+    } else if is_dns_failure(&lower) {
+        ErrorCode::DnsFailure
+    } else if lower.contains("unreachable")
+        || lower.contains("failed to connect to endpoint")
+        || lower.contains("timed out")
+    {
+        ErrorCode::EndpointUnreachable
+    } else {
+        ErrorCode::Unknown
+    }
+}
+
+fn is_dns_failure(msg: &str) -> bool {
+    let lower = msg.to_lowercase();
+    lower.contains("resolve") || lower.contains("dns") || lower.contains("no such host")
+}
+
+/// Ordered list of recovery suggestions for an error code, most useful first
+pub fn suggestions_for(code: ErrorCode) -> Vec<RecoverySuggestion> {
+    let actions: &[(RecoveryAction, &str)] = match code {
+        ErrorCode::WintunLoadFailed => &[
+            (
+                RecoveryAction::RestartAsAdmin,
+                "Restart SACVPN as administrator so the network driver can load",
+            ),
+            (
+                RecoveryAction::ReinstallApp,
+                "Reinstall SACVPN to restore the missing network driver",
+            ),
+        ],
+        ErrorCode::EndpointUnreachable => &[
+            (
+                RecoveryAction::CheckInternetConnection,
+                "Check that you are online",
+            ),
+            (
+                RecoveryAction::TryAnotherServer,
+                "Connect to a different server",
+            ),
+            (RecoveryAction::Retry, "Try connecting again"),
+        ],
+        ErrorCode::HandshakeTimeout => &[
+            (
+                RecoveryAction::RefreshConfig,
+                "Fetch a fresh configuration for this device",
+            ),
+            (
+                RecoveryAction::TryAnotherServer,
+                "Connect to a different server",
+            ),
+            (RecoveryAction::Retry, "Try connecting again"),
+        ],
+        ErrorCode::PermissionDenied => &[(
+            RecoveryAction::RestartAsAdmin,
+            "Restart SACVPN with administrator privileges",
+        )],
+        ErrorCode::DnsFailure => &[
+            (
+                RecoveryAction::CheckInternetConnection,
+                "Check that you are online",
+            ),
+            (
+                RecoveryAction::UseCustomDns,
+                "Switch to a custom DNS server",
+            ),
+        ],
+        ErrorCode::InvalidConfig => &[(
+            RecoveryAction::RefreshConfig,
+            "Fetch a fresh configuration for this device",
+        )],
+        ErrorCode::AlreadyConnected => &[(
+            RecoveryAction::Disconnect,
+            "Disconnect before connecting to another server",
+        )],
+        ErrorCode::TunnelInUse => &[(
+            RecoveryAction::CloseOtherInstance,
+            "Quit the other SACVPN instance or service that is running the tunnel",
+        )],
+        ErrorCode::InterfaceDown => &[(
+            RecoveryAction::Retry,
+            "Reconnect to bring the tunnel adapter back",
+        )],
+        ErrorCode::DeviceLimitReached => &[(
+            RecoveryAction::ManageDevices,
+            "Disconnect one of your other devices to free up a connection",
+        )],
+        ErrorCode::AccessRevoked => &[
+            (
+                RecoveryAction::ManageDevices,
+                "Check that this device is still on your account",
+            ),
+            (
+                RecoveryAction::RefreshConfig,
+                "Fetch a fresh configuration for this device",
+            ),
+        ],
+        ErrorCode::ClockSkew => &[
+            (
+                RecoveryAction::FixSystemClock,
+                "Set your system clock to the correct time, ideally automatically",
+            ),
+            (RecoveryAction::Retry, "Try connecting again"),
+        ],
+        ErrorCode::ServerUnavailable => &[
+            (
+                RecoveryAction::ConnectAlternative,
+                "Connect to a similar server instead",
+            ),
+            (
+                RecoveryAction::TryAnotherServer,
+                "Connect to a different server",
+            ),
+        ],
+        ErrorCode::NotConnected => &[],
+        ErrorCode::PlatformNotSupported => &[(
+            RecoveryAction::InstallWireGuardTools,
+            "Install the WireGuard tools for your platform",
+        )],
+        ErrorCode::Unknown => &[(RecoveryAction::Retry, "Try again")],
+    };
+
+    actions
+        .iter()
+        .map(|(action, description)| RecoverySuggestion {
+            action: *action,
+            description: description.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_failure_signatures() {
+        let wintun = VpnError::WireGuardError(
+            "Failed to load wintun driver. The wintun.dll file is missing or corrupt.".to_string(),
+        );
+        assert_eq!(classify(&wintun), ErrorCode::WintunLoadFailed);
+
+        let endpoint = VpnError::WireGuardError("Failed to connect to endpoint: os error".into());
+        assert_eq!(classify(&endpoint), ErrorCode::EndpointUnreachable);
+
+        let denied = VpnError::PermissionDenied("Administrator privileges required".into());
+        let report = ErrorReport::from(&denied);
+        assert_eq!(report.code, ErrorCode::PermissionDenied);
+        assert_eq!(report.suggestions[0].action, RecoveryAction::RestartAsAdmin);
+
+        let limit = VpnError::ConfigError(
+            "Device limit reached: your plan allows 5 devices connected at once".into(),
+        );
+        let report = ErrorReport::from(&limit);
+        assert_eq!(report.code, ErrorCode::DeviceLimitReached);
+        assert_eq!(report.suggestions[0].action, RecoveryAction::ManageDevices);
+
+        let revoked = VpnError::ConfigError(
+            "Access revoked: the account no longer accepts this device (403 Forbidden)".into(),
+        );
+        assert_eq!(classify(&revoked), ErrorCode::AccessRevoked);
+    }
+
+    #[test]
+    fn test_alternative_server_offer() {
+        let message = "New York is under maintenance";
+        let report = ErrorReport::new(ErrorCode::ServerUnavailable, message)
+            .with_alternative(Some(("us-east-2".into(), "Newark".into())));
+        assert_eq!(report.alternative_server.as_deref(), Some("us-east-2"));
+        assert_eq!(
+            report.suggestions[0].description,
+            "Connect to Newark instead"
+        );
+
+        let report = ErrorReport::new(ErrorCode::ServerUnavailable, message).with_alternative(None);
+        assert_eq!(report.alternative_server, None);
+        assert_eq!(
+            report.suggestions[0].action,
+            RecoveryAction::TryAnotherServer
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Endpoint resolution
+//!
+//! The API hands out endpoints by hostname, like `vpn-de1.sacvpn.com:51820`.
+//! They are looked up without blocking and within a timeout, and the answers
+//! are kept for a minute so the routes, firewall and tunnel of one connect
+//! agree on an address. Configs keep the hostname, so a reconnect looks it up
+//! again and follows the server if it moved.
+
+use super::{TunnelTuning, VpnError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long the addresses a hostname resolved to are trusted
+const RESOLVED_TTL: Duration = Duration::from_secs(60);
+
+/// Address family used when an endpoint's hostname has addresses of both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    /// Whichever the system resolver lists first
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+/// What a hostname resolved to, and when
+struct Resolved {
+    addresses: Vec<SocketAddr>,
+    at: Instant,
+}
+
+static RESOLVED: OnceLock<Mutex<HashMap<String, Resolved>>> = OnceLock::new();
+
+fn resolved() -> &'static Mutex<HashMap<String, Resolved>> {
+    RESOLVED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Every address `endpoint` is known to have without a lookup: itself if it
+/// is an address, or what its hostname resolved to recently
+pub fn cached(endpoint: &str) -> Vec<SocketAddr> {
+    if let Ok(address) = endpoint.parse::<SocketAddr>() {
+        return vec![address];
+    }
+    let mut resolved = resolved().lock().unwrap();
+    resolved.retain(|_, entry| entry.at.elapsed() < RESOLVED_TTL);
+    resolved
+        .get(endpoint)
+        .map(|entry| entry.addresses.clone())
+        .unwrap_or_default()
+}
+
+/// Take `addresses` as what `endpoint` resolves to from now on, for an answer
+/// that didn't come from the system resolver
+pub fn remember(endpoint: &str, addresses: Vec<SocketAddr>) {
+    if addresses.is_empty() {
+        return;
+    }
+    let entry = Resolved {
+        addresses,
+        at: Instant::now(),
+    };
+    resolved()
+        .lock()
+        .unwrap()
+        .insert(endpoint.to_string(), entry);
+}
+
+/// Address of a `host:port` endpoint in the family `tuning` prefers, looking
+/// the hostname up unless it was recently
+pub async fn resolve(endpoint: &str, tuning: &TunnelTuning) -> Result<SocketAddr, VpnError> {
+    let mut addresses = cached(endpoint);
+    if addresses.is_empty() {
+        let timeout = Duration::from_secs(tuning.resolve_timeout_secs.max(1));
+        addresses = match tokio::time::timeout(timeout, tokio::net::lookup_host(endpoint)).await {
+            Ok(Ok(addresses)) => addresses.collect(),
+            Ok(Err(e)) => {
+                return Err(VpnError::ConfigError(format!(
+                    "Failed to resolve endpoint {}: {}",
+                    endpoint, e
+                )))
+            }
+            Err(_) => {
+                return Err(VpnError::ConnectionFailed(format!(
+                    "Failed to resolve endpoint {} within {}s",
+                    endpoint,
+                    timeout.as_secs()
+                )))
+            }
+        };
+        remember(endpoint, addresses.clone());
+    }
+    pick(&addresses, tuning.endpoint_family)
+        .ok_or_else(|| VpnError::ConfigError(format!("Failed to resolve endpoint {}", endpoint)))
+}
+
+/// The first address of the preferred family, or the first of any
+fn pick(addresses: &[SocketAddr], family: AddressFamily) -> Option<SocketAddr> {
+    let preferred = addresses.iter().find(|address| match family {
+        AddressFamily::Any => true,
+        AddressFamily::PreferIpv4 => address.is_ipv4(),
+        AddressFamily::PreferIpv6 => address.is_ipv6(),
+    });
+    preferred.or(addresses.first()).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_prefers_family_and_falls_back() {
+        let addresses: Vec<SocketAddr> = ["[2001:db8::7]:51820", "203.0.113.7:51820"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+
+        assert_eq!(pick(&addresses, AddressFamily::Any), Some(addresses[0]));
+        assert_eq!(
+            pick(&addresses, AddressFamily::PreferIpv4),
+            Some(addresses[1])
+        );
+        assert_eq!(
+            pick(&addresses[..1], AddressFamily::PreferIpv4),
+            Some(addresses[0])
+        );
+        assert_eq!(pick(&[], AddressFamily::PreferIpv6), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_takes_addresses_without_lookup() {
+        let tuning = TunnelTuning::default();
+        let address = resolve("[2001:db8::7]:51820", &tuning).await.unwrap();
+        assert_eq!(address, "[2001:db8::7]:51820".parse().unwrap());
+        assert!(cached("vpn-unknown.invalid:51820").is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+//! Server rotation policy
+//!
+//! Users who don't want one exit address tied to them for long can have the
+//! session moved to another server in its country every few hours, or every
+//! connect go to a different server than the last. Which server comes next,
+//! and its config, are the app's to provide; the manager decides when a
+//! session is due and moves it over with `VpnManager::switch_server`.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationMode {
+    #[default]
+    Off,
+    /// Move the session to another server every `interval_hours`
+    Interval,
+    /// Connect to the next server in the country each time
+    EveryConnect,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RotationPolicy {
+    pub mode: RotationMode,
+    /// Hours on one server before moving on, in `Interval` mode
+    pub interval_hours: u32,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RotationMode::Off,
+            interval_hours: 6,
+        }
+    }
+}
+
+impl RotationPolicy {
+    /// Whether a session on one server for `connected_for` should move on
+    pub fn is_due(&self, connected_for: Duration) -> bool {
+        let interval = Duration::from_secs(u64::from(self.interval_hours.max(1)) * 60 * 60);
+        self.mode == RotationMode::Interval && connected_for >= interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_due_only_on_interval() {
+        let policy = RotationPolicy {
+            mode: RotationMode::Interval,
+            interval_hours: 6,
+        };
+        let hours = |hours: u64| Duration::from_secs(hours * 60 * 60);
+        assert!(!policy.is_due(hours(5)));
+        assert!(policy.is_due(hours(6)));
+
+        let every_connect = RotationPolicy {
+            mode: RotationMode::EveryConnect,
+            ..policy
+        };
+        assert!(!every_connect.is_due(hours(24)));
+        assert!(!RotationPolicy::default().is_due(hours(24)));
+    }
+}
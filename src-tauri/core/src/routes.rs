@@ -0,0 +1,1031 @@
+//! Cross-platform route management
+//!
+//! Routes go through a per-OS backend (IP Helper on Windows, iproute2 on Linux,
+//! `route` on macOS) and are installed as a transaction: a `RouteTable` remembers
+//! exactly what it added, so a failed install or a disconnect undoes only that.
+
+use super::VpnError;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An address prefix in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl Prefix {
+    /// Prefix matching a single address
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            len: max_len(addr),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether `addr` falls within this prefix
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether every address of `other` falls within this prefix
+    pub fn covers(&self, other: &Prefix) -> bool {
+        self.len <= other.len && self.contains(other.addr)
+    }
+
+    /// This prefix less the addresses of `cut`, as the fewest prefixes
+    fn without(&self, cut: &Prefix) -> Vec<Prefix> {
+        if cut.covers(self) {
+            return Vec::new();
+        }
+        if !self.covers(cut) {
+            return vec![*self];
+        }
+        // Halve towards `cut`, keeping the half without it each time
+        let mut kept = Vec::new();
+        let mut current = self.network();
+        while current.len < cut.len {
+            let [low, high] = current.halves();
+            let (toward, away) = if low.covers(cut) {
+                (low, high)
+            } else {
+                (high, low)
+            };
+            kept.push(away);
+            current = toward;
+        }
+        kept
+    }
+
+    /// The two prefixes one bit longer that make up this one
+    fn halves(&self) -> [Prefix; 2] {
+        let low = Prefix {
+            addr: self.addr,
+            len: self.len + 1,
+        }
+        .network();
+        let high = match low.addr {
+            IpAddr::V4(addr) => IpAddr::V4(Ipv4Addr::from(
+                u32::from(addr) | 1 << (31 - u32::from(self.len)),
+            )),
+            IpAddr::V6(addr) => IpAddr::V6(Ipv6Addr::from(
+                u128::from(addr) | 1 << (127 - u32::from(self.len)),
+            )),
+        };
+        [low, Prefix { addr: high, ..low }]
+    }
+
+    /// The prefix with its host bits cleared, as route tables want it; configs
+    /// sometimes list `192.168.50.1/24` for `192.168.50.0/24`
+    pub fn network(&self) -> Self {
+        let addr = match self.addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+        Self {
+            addr,
+            len: self.len,
+        }
+    }
+
+    /// The prefix length written as a mask, `255.255.240.0` for a /20
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub fn netmask(&self) -> IpAddr {
+        let ones = match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::BROADCAST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(u128::MAX)),
+        };
+        Prefix {
+            addr: ones,
+            len: self.len,
+        }
+        .network()
+        .addr
+    }
+
+    /// The two halves of a default route, which take precedence over the
+    /// system default without replacing it
+    fn split_default(&self) -> [Prefix; 2] {
+        let (low, high): (IpAddr, IpAddr) = match self.addr {
+            IpAddr::V4(_) => (
+                Ipv4Addr::new(0, 0, 0, 0).into(),
+                Ipv4Addr::new(128, 0, 0, 0).into(),
+            ),
+            IpAddr::V6(_) => (
+                Ipv6Addr::UNSPECIFIED.into(),
+                Ipv6Addr::new(0x8000, 0, 0, 0, 0, 0, 0, 0).into(),
+            ),
+        };
+        [Prefix { addr: low, len: 1 }, Prefix { addr: high, len: 1 }]
+    }
+}
+
+fn max_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = VpnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || VpnError::ConfigError(format!("Invalid address prefix: {}", s));
+
+        let (addr, len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let len = match len {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len(addr),
+        };
+        if len > max_len(addr) {
+            return Err(invalid());
+        }
+        Ok(Self { addr, len })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: Prefix,
+    /// Next hop, `None` for an on-link route
+    pub gateway: Option<IpAddr>,
+    pub interface: String,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gateway {
+            Some(gateway) => write!(
+                f,
+                "{} via {} dev {}",
+                self.destination, gateway, self.interface
+            ),
+            None => write!(f, "{} dev {}", self.destination, self.interface),
+        }
+    }
+}
+
+/// Per-OS primitive route operations
+pub trait RouteBackend: Send + Sync {
+    fn add(&self, route: &Route) -> Result<(), VpnError>;
+    fn delete(&self, route: &Route) -> Result<(), VpnError>;
+    /// Route the system currently uses to reach `dest`
+    fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError>;
+}
+
+/// Routes installed for the current tunnel
+pub struct RouteTable {
+    backend: Box<dyn RouteBackend>,
+    installed: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::with_backend(platform_backend())
+    }
+
+    pub fn with_backend(backend: Box<dyn RouteBackend>) -> Self {
+        Self {
+            backend,
+            installed: Vec::new(),
+        }
+    }
+
+    /// Send `allowed_ips` through the tunnel interface
+    ///
+    /// When a default route is captured, the endpoint keeps a host route via the
+    /// current gateway so the encrypted traffic doesn't loop into the tunnel.
+    pub fn route_through_tunnel(
+        &mut self,
+        allowed_ips: &[String],
+        interface: &str,
+        endpoints: &[IpAddr],
+    ) -> Result<(), VpnError> {
+        let routes = self.plan(allowed_ips, interface, endpoints)?;
+        self.install(&routes)
+    }
+
+    /// Routes `route_through_tunnel` would add, without changing anything
+    ///
+    /// Endpoints the allowed IPs cover keep their current route, so the
+    /// encrypted traffic to them doesn't loop back into the tunnel. Their host
+    /// routes come first, before anything is routed into the tunnel.
+    pub fn plan(
+        &self,
+        allowed_ips: &[String],
+        interface: &str,
+        endpoints: &[IpAddr],
+    ) -> Result<Vec<Route>, VpnError> {
+        let prefixes = allowed_ips
+            .iter()
+            .map(|ip| ip.parse())
+            .collect::<Result<Vec<Prefix>, _>>()?;
+
+        let mut routes: Vec<Route> = Vec::new();
+        for &endpoint in endpoints {
+            let exempt = Prefix::host(endpoint);
+            if !prefixes.iter().any(|p| p.contains(endpoint))
+                || routes.iter().any(|route| route.destination == exempt)
+            {
+                continue;
+            }
+            let current = self.backend.lookup(endpoint)?;
+            // As with routes a crashed session left on a reused adapter
+            if current.interface == interface {
+                return Err(VpnError::ConnectionFailed(format!(
+                    "{} is already routed through {}, not the physical network",
+                    endpoint, interface
+                )));
+            }
+            routes.push(Route {
+                destination: exempt,
+                ..current
+            });
+        }
+        routes.extend(tunnel_routes(&prefixes, interface));
+        Ok(routes)
+    }
+
+    /// Route the system currently uses to reach `dest`
+    pub fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+        self.backend.lookup(dest)
+    }
+
+    /// Add every route or none of them
+    pub fn install(&mut self, routes: &[Route]) -> Result<(), VpnError> {
+        for route in routes {
+            log::info!("Adding route {} via {}", route.destination, route.interface);
+            if let Err(e) = self.backend.add(route) {
+                self.rollback();
+                return Err(e);
+            }
+            self.installed.push(route.clone());
+        }
+        Ok(())
+    }
+
+    /// Move from the installed routes to exactly `routes`
+    ///
+    /// New routes go in before stale ones come out, so no address falls out of
+    /// the tunnel meanwhile; if one can't be added, those added before it are
+    /// removed again and the old routes stay.
+    pub fn replace(&mut self, routes: &[Route]) -> Result<(), VpnError> {
+        let kept = self.installed.len();
+        for route in routes {
+            if self.installed.contains(route) {
+                continue;
+            }
+            log::info!("Adding route {} via {}", route.destination, route.interface);
+            if let Err(e) = self.backend.add(route) {
+                for added in self.installed.split_off(kept).iter().rev() {
+                    if let Err(e) = self.backend.delete(added) {
+                        log::warn!("Failed to remove route {}: {}", added.destination, e);
+                    }
+                }
+                return Err(e);
+            }
+            self.installed.push(route.clone());
+        }
+
+        let stale: Vec<Route> = self
+            .installed
+            .iter()
+            .filter(|route| !routes.contains(route))
+            .cloned()
+            .collect();
+        for route in stale.iter().rev() {
+            log::info!("Removing route {}", route.destination);
+            if let Err(e) = self.remove(route) {
+                log::warn!("Failed to remove route {}: {}", route.destination, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep `endpoint` out of the tunnel by the same route as `like`, an
+    /// endpoint exempted when the tunnel came up, for a peer that moved to it
+    ///
+    /// Nothing is added if `like` wasn't exempted, as the tunnel's routes
+    /// didn't cover it.
+    pub fn exempt_like(&mut self, endpoint: IpAddr, like: IpAddr) -> Result<(), VpnError> {
+        let destination = Prefix::host(endpoint);
+        if self
+            .installed
+            .iter()
+            .any(|route| route.destination == destination)
+        {
+            return Ok(());
+        }
+        let Some(template) = self
+            .installed
+            .iter()
+            .find(|route| route.destination == Prefix::host(like))
+        else {
+            return Ok(());
+        };
+        if endpoint.is_ipv4() != like.is_ipv4() {
+            return Err(VpnError::ConnectionFailed(format!(
+                "No route to {} through the uplink of {}",
+                endpoint, like
+            )));
+        }
+        let route = Route {
+            destination,
+            ..template.clone()
+        };
+        log::info!("Adding route {} via {}", route.destination, route.interface);
+        self.backend.add(&route)?;
+        self.installed.push(route);
+        Ok(())
+    }
+
+    /// Add one route on its own; unlike `install`, a failure leaves the routes
+    /// already installed alone
+    pub fn add(&mut self, route: &Route) -> Result<(), VpnError> {
+        self.backend.add(route)?;
+        self.installed.push(route.clone());
+        Ok(())
+    }
+
+    /// Remove a single route added through `install` or `add`
+    pub fn remove(&mut self, route: &Route) -> Result<(), VpnError> {
+        self.backend.delete(route)?;
+        self.installed.retain(|installed| installed != route);
+        Ok(())
+    }
+
+    /// Remove every installed route, newest first, continuing past failures
+    pub fn rollback(&mut self) {
+        while let Some(route) = self.installed.pop() {
+            if let Err(e) = self.backend.delete(&route) {
+                log::warn!("Failed to remove route {}: {}", route.destination, e);
+            }
+        }
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `prefixes` less the addresses in any of `excluded`
+pub fn exclude(prefixes: &[Prefix], excluded: &[Prefix]) -> Vec<Prefix> {
+    let mut remaining: Vec<Prefix> = prefixes.iter().map(Prefix::network).collect();
+    for cut in excluded {
+        remaining = remaining
+            .into_iter()
+            .flat_map(|prefix| prefix.without(cut))
+            .collect();
+    }
+    remaining
+}
+
+/// On-link routes for the tunnel, one per prefix, with default routes split
+/// into halves
+///
+/// A prefix listed twice, by one peer or two, gets one route, as adding the
+/// same route again fails.
+fn tunnel_routes(prefixes: &[Prefix], interface: &str) -> Vec<Route> {
+    let mut destinations: Vec<Prefix> = Vec::new();
+    for prefix in prefixes {
+        let split = if prefix.is_default() {
+            prefix.split_default().to_vec()
+        } else {
+            vec![prefix.network()]
+        };
+        for destination in split {
+            if !destinations.contains(&destination) {
+                destinations.push(destination);
+            }
+        }
+    }
+    destinations
+        .into_iter()
+        .map(|destination| Route {
+            destination,
+            gateway: None,
+            interface: interface.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run(argv: &[String]) -> Result<String, VpnError> {
+    let output = std::process::Command::new(&argv[0])
+        .args(&argv[1..])
+        .output()
+        .map_err(|e| VpnError::PermissionDenied(format!("Failed to run {}: {}", argv[0], e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(VpnError::WireGuardError(format!(
+            "Route command '{}' failed: {}",
+            argv.join(" "),
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Value following `key` in whitespace-separated command output
+#[cfg(any(target_os = "linux", target_os = "macos", test))]
+fn field_after<'a>(output: &'a str, key: &str) -> Option<&'a str> {
+    let mut tokens = output.split_whitespace();
+    tokens.find(|t| *t == key)?;
+    tokens.next()
+}
+
+#[cfg(target_os = "linux")]
+fn platform_backend() -> Box<dyn RouteBackend> {
+    Box::new(IpRoute)
+}
+
+/// iproute2, which talks rtnetlink to the kernel
+#[cfg(target_os = "linux")]
+struct IpRoute;
+
+#[cfg(target_os = "linux")]
+impl IpRoute {
+    fn argv(verb: &str, route: &Route) -> Vec<String> {
+        let family = if route.destination.addr.is_ipv4() {
+            "-4"
+        } else {
+            "-6"
+        };
+        let mut argv: Vec<String> = ["ip", family, "route", verb]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        argv.push(route.destination.to_string());
+        if let Some(gateway) = route.gateway {
+            argv.extend(["via".to_string(), gateway.to_string()]);
+        }
+        argv.extend(["dev".to_string(), route.interface.clone()]);
+        argv
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl RouteBackend for IpRoute {
+    fn add(&self, route: &Route) -> Result<(), VpnError> {
+        run(&Self::argv("add", route)).map(|_| ())
+    }
+
+    fn delete(&self, route: &Route) -> Result<(), VpnError> {
+        run(&Self::argv("del", route)).map(|_| ())
+    }
+
+    fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+        let output = run(&[
+            "ip".to_string(),
+            "-o".to_string(),
+            "route".to_string(),
+            "get".to_string(),
+            dest.to_string(),
+        ])?;
+
+        let interface = field_after(&output, "dev")
+            .ok_or_else(|| VpnError::WireGuardError(format!("No route to {}", dest)))?;
+        Ok(Route {
+            destination: Prefix::host(dest),
+            gateway: field_after(&output, "via").and_then(|gw| gw.parse().ok()),
+            interface: interface.to_string(),
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn platform_backend() -> Box<dyn RouteBackend> {
+    Box::new(BsdRoute)
+}
+
+/// The BSD `route` tool, which drives the routing socket
+#[cfg(target_os = "macos")]
+struct BsdRoute;
+
+#[cfg(target_os = "macos")]
+impl BsdRoute {
+    fn argv(verb: &str, route: &Route) -> Vec<String> {
+        let family = if route.destination.addr.is_ipv4() {
+            "-inet"
+        } else {
+            "-inet6"
+        };
+        let mut argv: Vec<String> = ["route", "-n", verb, family, "-net"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        argv.push(route.destination.to_string());
+        match route.gateway {
+            Some(gateway) => argv.push(gateway.to_string()),
+            None => argv.extend(["-interface".to_string(), route.interface.clone()]),
+        }
+        argv
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl RouteBackend for BsdRoute {
+    fn add(&self, route: &Route) -> Result<(), VpnError> {
+        run(&Self::argv("add", route)).map(|_| ())
+    }
+
+    fn delete(&self, route: &Route) -> Result<(), VpnError> {
+        run(&Self::argv("delete", route)).map(|_| ())
+    }
+
+    fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+        let family = if dest.is_ipv4() { "-inet" } else { "-inet6" };
+        let output = run(&[
+            "route".to_string(),
+            "-n".to_string(),
+            "get".to_string(),
+            family.to_string(),
+            dest.to_string(),
+        ])?;
+
+        let interface = field_after(&output, "interface:")
+            .ok_or_else(|| VpnError::WireGuardError(format!("No route to {}", dest)))?;
+        Ok(Route {
+            destination: Prefix::host(dest),
+            gateway: field_after(&output, "gateway:").and_then(|gw| gw.parse().ok()),
+            interface: interface.to_string(),
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn platform_backend() -> Box<dyn RouteBackend> {
+    Box::new(ip_helper::IpHelper)
+}
+
+/// Native routing table access through the IP Helper API
+#[cfg(target_os = "windows")]
+mod ip_helper {
+    use super::{Prefix, Route, RouteBackend};
+    use crate::VpnError;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use windows::core::HSTRING;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        ConvertInterfaceAliasToLuid, ConvertInterfaceLuidToAlias, CreateIpForwardEntry2,
+        DeleteIpForwardEntry2, GetBestRoute2, InitializeIpForwardEntry, MIB_IPFORWARD_ROW2,
+    };
+    use windows::Win32::NetworkManagement::Ndis::NET_LUID_LH;
+    use windows::Win32::Networking::WinSock::{AF_INET, SOCKADDR_INET};
+
+    const ROUTE_METRIC: u32 = 1;
+
+    pub struct IpHelper;
+
+    fn luid(interface: &str) -> Result<NET_LUID_LH, VpnError> {
+        let mut luid = NET_LUID_LH::default();
+        unsafe { ConvertInterfaceAliasToLuid(&HSTRING::from(interface), &mut luid) }
+            .ok()
+            .map_err(|e| {
+                VpnError::WireGuardError(format!("Unknown interface {}: {}", interface, e))
+            })?;
+        Ok(luid)
+    }
+
+    fn alias(luid: &NET_LUID_LH) -> Result<String, VpnError> {
+        let mut buf = [0u16; 257];
+        unsafe { ConvertInterfaceLuidToAlias(luid, &mut buf) }
+            .ok()
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to name interface: {}", e)))?;
+        let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+        Ok(String::from_utf16_lossy(&buf[..len]))
+    }
+
+    fn sockaddr(addr: IpAddr) -> SOCKADDR_INET {
+        SocketAddr::new(addr, 0).into()
+    }
+
+    fn ip(addr: &SOCKADDR_INET) -> IpAddr {
+        unsafe {
+            if addr.si_family == AF_INET {
+                IpAddr::V4(Ipv4Addr::from(addr.Ipv4.sin_addr))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(addr.Ipv6.sin6_addr))
+            }
+        }
+    }
+
+    fn row(route: &Route) -> Result<MIB_IPFORWARD_ROW2, VpnError> {
+        let mut row = MIB_IPFORWARD_ROW2::default();
+        unsafe { InitializeIpForwardEntry(&mut row) };
+
+        let unspecified: IpAddr = match route.destination.addr {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        row.InterfaceLuid = luid(&route.interface)?;
+        row.DestinationPrefix.Prefix = sockaddr(route.destination.addr);
+        row.DestinationPrefix.PrefixLength = route.destination.len;
+        row.NextHop = sockaddr(route.gateway.unwrap_or(unspecified));
+        row.Metric = ROUTE_METRIC;
+        Ok(row)
+    }
+
+    impl RouteBackend for IpHelper {
+        fn add(&self, route: &Route) -> Result<(), VpnError> {
+            let row = row(route)?;
+            unsafe { CreateIpForwardEntry2(&row) }.ok().map_err(|e| {
+                VpnError::WireGuardError(format!(
+                    "Failed to add route {}: {}",
+                    route.destination, e
+                ))
+            })
+        }
+
+        fn delete(&self, route: &Route) -> Result<(), VpnError> {
+            let row = row(route)?;
+            unsafe { DeleteIpForwardEntry2(&row) }.ok().map_err(|e| {
+                VpnError::WireGuardError(format!(
+                    "Failed to delete route {}: {}",
+                    route.destination, e
+                ))
+            })
+        }
+
+        fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+            let destination = sockaddr(dest);
+            let mut best = MIB_IPFORWARD_ROW2::default();
+            let mut source = SOCKADDR_INET::default();
+            unsafe { GetBestRoute2(None, 0, None, &destination, 0, &mut best, &mut source) }
+                .ok()
+                .map_err(|e| VpnError::WireGuardError(format!("No route to {}: {}", dest, e)))?;
+
+            let gateway = ip(&best.NextHop);
+            Ok(Route {
+                destination: Prefix::host(dest),
+                gateway: (!gateway.is_unspecified()).then_some(gateway),
+                interface: alias(&best.InterfaceLuid)?,
+            })
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn platform_backend() -> Box<dyn RouteBackend> {
+    Box::new(Unsupported)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+struct Unsupported;
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+impl RouteBackend for Unsupported {
+    fn add(&self, _route: &Route) -> Result<(), VpnError> {
+        Err(VpnError::PlatformNotSupported)
+    }
+
+    fn delete(&self, _route: &Route) -> Result<(), VpnError> {
+        Err(VpnError::PlatformNotSupported)
+    }
+
+    fn lookup(&self, _dest: IpAddr) -> Result<Route, VpnError> {
+        Err(VpnError::PlatformNotSupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records operations and fails the add at a given position
+    struct FakeBackend {
+        log: Arc<Mutex<Vec<String>>>,
+        fail_on: usize,
+    }
+
+    impl RouteBackend for FakeBackend {
+        fn add(&self, route: &Route) -> Result<(), VpnError> {
+            let mut log = self.log.lock().unwrap();
+            if log.len() == self.fail_on {
+                return Err(VpnError::WireGuardError("add failed".to_string()));
+            }
+            log.push(format!("add {}", route.destination));
+            Ok(())
+        }
+
+        fn delete(&self, route: &Route) -> Result<(), VpnError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("del {}", route.destination));
+            Ok(())
+        }
+
+        fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+            Ok(Route {
+                destination: Prefix::host(dest),
+                gateway: Some("192.168.1.1".parse().unwrap()),
+                interface: "eth0".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_default_route_is_split_and_rolled_back_on_failure() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            fail_on: 2,
+        }));
+
+        let result = table.route_through_tunnel(
+            &["0.0.0.0/0".to_string()],
+            "SACVPN",
+            &["203.0.113.7".parse().unwrap()],
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "add 203.0.113.7/32",
+                "add 0.0.0.0/1",
+                "del 0.0.0.0/1",
+                "del 203.0.113.7/32",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exempt_like_copies_the_uplink_route() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            fail_on: usize::MAX,
+        }));
+        let old = "203.0.113.7".parse().unwrap();
+        table
+            .route_through_tunnel(&["0.0.0.0/0".to_string()], "SACVPN", &[old])
+            .unwrap();
+
+        let new = "198.51.100.4".parse().unwrap();
+        table.exempt_like(new, old).unwrap();
+        table.exempt_like(new, old).unwrap();
+        assert!(table
+            .exempt_like("2001:db8::7".parse().unwrap(), old)
+            .is_err());
+        assert_eq!(
+            log.lock().unwrap().last().map(String::as_str),
+            Some("add 198.51.100.4/32")
+        );
+        assert_eq!(table.installed.len(), 4);
+        assert_eq!(table.installed[3].gateway, table.installed[0].gateway);
+    }
+
+    #[test]
+    fn test_replace_adds_before_removing() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            fail_on: usize::MAX,
+        }));
+        let endpoint = ["203.0.113.7".parse().unwrap()];
+        table
+            .route_through_tunnel(&["0.0.0.0/0".to_string()], "SACVPN", &endpoint)
+            .unwrap();
+        log.lock().unwrap().clear();
+
+        let routes = table
+            .plan(&["0.0.0.0/2".to_string()], "SACVPN", &endpoint)
+            .unwrap();
+        table.replace(&routes).unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "add 0.0.0.0/2",
+                "del 128.0.0.0/1",
+                "del 0.0.0.0/1",
+                "del 203.0.113.7/32"
+            ]
+        );
+        assert_eq!(table.installed, routes);
+    }
+
+    #[test]
+    fn test_plan_changes_nothing() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            fail_on: usize::MAX,
+        }));
+
+        let plan: Vec<String> = table
+            .plan(
+                &["0.0.0.0/0".to_string()],
+                "SACVPN",
+                &["203.0.113.7".parse().unwrap()],
+            )
+            .unwrap()
+            .iter()
+            .map(|route| route.to_string())
+            .collect();
+
+        assert_eq!(
+            plan,
+            vec![
+                "203.0.113.7/32 via 192.168.1.1 dev eth0",
+                "0.0.0.0/1 dev SACVPN",
+                "128.0.0.0/1 dev SACVPN",
+            ]
+        );
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plan_refuses_an_endpoint_routed_into_the_tunnel() {
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: Arc::new(Mutex::new(Vec::new())),
+            fail_on: usize::MAX,
+        }));
+        let endpoint = ["203.0.113.7".parse().unwrap()];
+
+        assert!(table
+            .plan(&["0.0.0.0/0".to_string()], "eth0", &endpoint)
+            .is_err());
+        // An endpoint outside the tunnel's routes needs no host route at all
+        assert!(table
+            .plan(&["10.20.0.0/16".to_string()], "eth0", &endpoint)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_plan_exempts_each_covered_endpoint_once() {
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: Arc::new(Mutex::new(Vec::new())),
+            fail_on: usize::MAX,
+        }));
+
+        let endpoints = [
+            "10.20.0.1".parse().unwrap(),
+            "203.0.113.7".parse().unwrap(),
+            "10.20.0.1".parse().unwrap(),
+        ];
+        let plan: Vec<String> = table
+            .plan(&["10.20.0.0/16".to_string()], "SACVPN", &endpoints)
+            .unwrap()
+            .iter()
+            .map(|route| route.to_string())
+            .collect();
+
+        assert_eq!(
+            plan,
+            vec![
+                "10.20.0.1/32 via 192.168.1.1 dev eth0",
+                "10.20.0.0/16 dev SACVPN",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_routes_each_selective_prefix_once() {
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: Arc::new(Mutex::new(Vec::new())),
+            fail_on: usize::MAX,
+        }));
+
+        let allowed_ips =
+            ["10.0.0.0/8", "192.168.50.1/24", "fd00::1/64", "10.0.0.0/8"].map(str::to_string);
+        let plan: Vec<String> = table
+            .plan(&allowed_ips, "SACVPN", &["203.0.113.7".parse().unwrap()])
+            .unwrap()
+            .iter()
+            .map(|route| route.to_string())
+            .collect();
+
+        assert_eq!(
+            plan,
+            vec![
+                "10.0.0.0/8 dev SACVPN",
+                "192.168.50.0/24 dev SACVPN",
+                "fd00::/64 dev SACVPN",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exclude_cuts_ranges_out_of_prefixes() {
+        let parse = |prefixes: &[&str]| -> Vec<Prefix> {
+            prefixes
+                .iter()
+                .map(|prefix| prefix.parse().unwrap())
+                .collect()
+        };
+        let text = |prefixes: Vec<Prefix>| -> Vec<String> {
+            prefixes.iter().map(ToString::to_string).collect()
+        };
+
+        assert_eq!(
+            text(exclude(&parse(&["10.0.0.0/8"]), &parse(&["10.128.0.0/9"]))),
+            ["10.0.0.0/9"]
+        );
+        assert_eq!(
+            text(exclude(
+                &parse(&["192.168.0.0/22"]),
+                &parse(&["192.168.1.0/24"])
+            )),
+            ["192.168.2.0/23", "192.168.0.0/24"]
+        );
+        // Inside the cut, disjoint from it, or of the other family
+        assert!(exclude(&parse(&["10.20.0.0/16"]), &parse(&["10.0.0.0/8"])).is_empty());
+        assert_eq!(
+            text(exclude(
+                &parse(&["172.32.0.0/16", "::/0"]),
+                &parse(&["172.16.0.0/12"])
+            )),
+            ["172.32.0.0/16", "::/0"]
+        );
+
+        let lan = parse(&[
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "169.254.0.0/16",
+        ]);
+        let internet = exclude(&parse(&["0.0.0.0/0"]), &lan);
+        for address in [
+            "8.8.8.8",
+            "11.0.0.1",
+            "172.15.255.255",
+            "192.169.0.1",
+            "223.1.2.3",
+        ] {
+            let address = address.parse().unwrap();
+            assert_eq!(internet.iter().filter(|p| p.contains(address)).count(), 1);
+        }
+        for address in ["10.1.2.3", "172.20.0.1", "192.168.1.10", "169.254.10.1"] {
+            let address = address.parse().unwrap();
+            assert!(!internet.iter().any(|p| p.contains(address)));
+        }
+    }
+
+    #[test]
+    fn test_parse_prefix_and_route_get_output() {
+        assert_eq!("10.0.0.0/8".parse::<Prefix>().unwrap().len, 8);
+        assert_eq!("::1".parse::<Prefix>().unwrap().len, 128);
+        assert!("10.0.0.0/33".parse::<Prefix>().is_err());
+
+        let private: Prefix = "10.8.0.0/16".parse().unwrap();
+        assert!(private.contains("10.8.200.1".parse().unwrap()));
+        assert!(!private.contains("10.9.0.1".parse().unwrap()));
+        assert!(!private.contains("::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Prefix>()
+            .unwrap()
+            .contains("203.0.113.9".parse().unwrap()));
+
+        let netmask = |prefix: &str| prefix.parse::<Prefix>().unwrap().netmask().to_string();
+        assert_eq!(netmask("10.70.0.2/24"), "255.255.255.0");
+        assert_eq!(netmask("172.16.5.9/20"), "255.255.240.0");
+        assert_eq!(netmask("10.8.0.2"), "255.255.255.255");
+        assert_eq!(netmask("fd00::2/64"), "ffff:ffff:ffff:ffff::");
+
+        let linux = "1.1.1.1 via 192.168.1.1 dev wlan0 src 192.168.1.20 uid 1000";
+        assert_eq!(field_after(linux, "via"), Some("192.168.1.1"));
+        assert_eq!(field_after(linux, "dev"), Some("wlan0"));
+    }
+}
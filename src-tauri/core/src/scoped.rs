@@ -0,0 +1,202 @@
+//! Interface-scoped probes
+//!
+//! A diagnostic that lets the OS pick its route can go out of the wrong
+//! interface: a DNS leak test sent around the tunnel is itself a leak. Probe
+//! sockets are therefore pinned to one interface, with SO_BINDTODEVICE on
+//! Linux, IP_BOUND_IF on macOS and IP_UNICAST_IF on Windows, on top of being
+//! bound to its address. Hostnames are resolved through the same interface.
+
+use super::dns;
+use super::VpnError;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// How long a scoped hostname lookup may take
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Resolver used through an interface that has none of its own
+const FALLBACK_RESOLVER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(1, 1, 1, 1));
+
+/// The one interface a probe may send through
+#[derive(Debug, Clone)]
+pub struct ProbeScope {
+    /// OS name of the interface, e.g. `wlan0`, `utun4` or an adapter alias
+    pub interface: String,
+    /// Its address, which probes send from
+    pub address: IpAddr,
+    /// Resolvers reached through the interface
+    pub dns: Vec<IpAddr>,
+}
+
+impl ProbeScope {
+    /// UDP socket that can only send through the interface
+    pub fn udp_socket(&self) -> Result<tokio::net::UdpSocket, VpnError> {
+        scoped_socket(&self.interface, self.address)
+            .and_then(|socket| {
+                socket.set_nonblocking(true)?;
+                tokio::net::UdpSocket::from_std(socket)
+            })
+            .map_err(|e| {
+                VpnError::ConnectionFailed(format!(
+                    "Failed to bind a socket to {}: {}",
+                    self.interface, e
+                ))
+            })
+    }
+
+    /// Send a DNS query to `server` through the interface
+    pub async fn query(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        qtype: u16,
+        timeout: Duration,
+    ) -> Result<dns::DnsResponse, VpnError> {
+        dns::query_on(&self.udp_socket()?, server, name, qtype, timeout).await
+    }
+
+    /// Resolvers `resolve` asks: the interface's own, or a public one
+    pub fn resolvers(&self) -> Vec<IpAddr> {
+        match self.dns.as_slice() {
+            [] => vec![FALLBACK_RESOLVER],
+            servers => servers.to_vec(),
+        }
+    }
+
+    /// Address of `host` as the interface's resolvers answer, so not even the
+    /// lookup leaves through another interface
+    pub async fn resolve(&self, host: &str) -> Result<IpAddr, VpnError> {
+        if let Ok(address) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok(address);
+        }
+        let qtype = if self.address.is_ipv6() {
+            dns::TYPE_AAAA
+        } else {
+            dns::TYPE_A
+        };
+        let mut last_error = None;
+        for server in self.resolvers() {
+            match self
+                .query(SocketAddr::new(server, 53), host, qtype, LOOKUP_TIMEOUT)
+                .await
+            {
+                Ok(response) => match response.answers.first() {
+                    Some(answer) => return Ok(answer.address),
+                    None => {
+                        last_error = Some(VpnError::ConnectionFailed(format!(
+                            "{} has no address",
+                            host
+                        )))
+                    }
+                },
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| VpnError::ConnectionFailed(format!("Failed to resolve {}", host))))
+    }
+}
+
+/// UDP socket bound to `address` that the OS only sends through `interface`
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+fn scoped_socket(interface: &str, address: IpAddr) -> std::io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let local = SocketAddr::new(address, 0);
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+    bind_to_interface(&socket, interface, address)?;
+    socket.bind(&local.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn scoped_socket(_interface: &str, _address: IpAddr) -> std::io::Result<std::net::UdpSocket> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_interface(
+    socket: &socket2::Socket,
+    interface: &str,
+    _address: IpAddr,
+) -> std::io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(target_os = "macos")]
+fn bind_to_interface(
+    socket: &socket2::Socket,
+    interface: &str,
+    address: IpAddr,
+) -> std::io::Result<()> {
+    let name = std::ffi::CString::new(interface)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is a NUL-terminated string that outlives the call
+    let index = std::num::NonZeroU32::new(unsafe { libc::if_nametoindex(name.as_ptr()) })
+        .ok_or_else(std::io::Error::last_os_error)?;
+    match address {
+        IpAddr::V4(_) => socket.bind_device_by_index_v4(Some(index)),
+        IpAddr::V6(_) => socket.bind_device_by_index_v6(Some(index)),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn bind_to_interface(
+    socket: &socket2::Socket,
+    interface: &str,
+    address: IpAddr,
+) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+    use windows::core::HSTRING;
+    use windows::Win32::NetworkManagement::IpHelper::{
+        ConvertInterfaceAliasToLuid, ConvertInterfaceLuidToIndex,
+    };
+    use windows::Win32::NetworkManagement::Ndis::NET_LUID_LH;
+    use windows::Win32::Networking::WinSock::{
+        setsockopt, IPPROTO_IP, IPPROTO_IPV6, IPV6_UNICAST_IF, IP_UNICAST_IF, SOCKET,
+    };
+
+    let mut luid = NET_LUID_LH::default();
+    let mut index = 0u32;
+    // SAFETY: both out-parameters are valid for the duration of the calls
+    unsafe {
+        ConvertInterfaceAliasToLuid(&HSTRING::from(interface), &mut luid)
+            .ok()
+            .and_then(|()| ConvertInterfaceLuidToIndex(&luid, &mut index).ok())
+    }
+    .map_err(|e| std::io::Error::other(format!("Unknown interface: {}", e)))?;
+
+    // IPv4 takes the index in network byte order, IPv6 in host order
+    let (level, option, value) = match address {
+        IpAddr::V4(_) => (IPPROTO_IP.0, IP_UNICAST_IF, index.to_be_bytes()),
+        IpAddr::V6(_) => (IPPROTO_IPV6.0, IPV6_UNICAST_IF, index.to_ne_bytes()),
+    };
+    let socket = SOCKET(socket.as_raw_socket() as usize);
+    // SAFETY: `socket` is open for the duration of the call and `value` is a u32
+    if unsafe { setsockopt(socket, level, option, Some(&value)) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_takes_addresses_without_lookup() {
+        let scope = ProbeScope {
+            interface: "wg-test".to_string(),
+            address: "10.8.0.2".parse().unwrap(),
+            dns: Vec::new(),
+        };
+        assert_eq!(
+            scope.resolve("203.0.113.7").await.unwrap(),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            scope.resolve("[2001:db8::1]").await.unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Simulated tunnel for development and CI
+//!
+//! Launched with `--simulate`, the app runs every tunnel on `SimulatedBackend`,
+//! which needs neither admin rights nor a server that answers: connecting
+//! takes a handshake's worth of time, the traffic counters grow at a made-up
+//! rate, and routes and firewall rules are logged instead of applied. The
+//! server's endpoint is still resolved and the keys checked, so connect errors
+//! from a bad config show up as they would for real.
+
+use super::backend::{TunnelBackend, TunnelParams};
+use super::network::InterfaceState;
+use super::routes::{Prefix, Route, RouteBackend};
+use super::{TunnelTuning, VpnConfig, VpnError};
+use futures::future::BoxFuture;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How long the simulated handshake takes
+const HANDSHAKE_DELAY: Duration = Duration::from_millis(350);
+
+/// Listen port reported unless the tuning pins one
+const LISTEN_PORT: u16 = 51820;
+
+/// Average simulated download and upload rates in bytes per second
+const DOWNLOAD_RATE: f64 = 1_250_000.0;
+const UPLOAD_RATE: f64 = 160_000.0;
+
+/// Period over which the simulated rates swing between half and one and a half
+/// times their average, so speed graphs have something to show
+const SWING_PERIOD: Duration = Duration::from_secs(20);
+
+/// Gateway of the simulated uplink, from the documentation range
+const UPLINK_GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Simulate every tunnel from now on; call before the VPN manager is created
+pub fn enable() {
+    log::warn!("Simulating the tunnel: no traffic goes through the VPN");
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub struct SimulatedBackend {
+    name: String,
+    /// When the simulated handshake completed
+    connected_at: Option<tokio::time::Instant>,
+    listen_port: Option<u16>,
+}
+
+impl SimulatedBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            connected_at: None,
+            listen_port: None,
+        }
+    }
+}
+
+impl TunnelBackend for SimulatedBackend {
+    fn name(&self) -> &'static str {
+        "simulated"
+    }
+
+    fn connect<'a>(
+        &'a mut self,
+        config: &'a VpnConfig,
+        tuning: &'a TunnelTuning,
+        _traffic_padding: bool,
+    ) -> BoxFuture<'a, Result<(), VpnError>> {
+        Box::pin(async move {
+            let params = TunnelParams::parse(config)?;
+            for peer in &params.peers {
+                log::info!("Simulating a handshake with {}...", peer.endpoint);
+            }
+            tokio::time::sleep(HANDSHAKE_DELAY).await;
+
+            self.connected_at = Some(tokio::time::Instant::now());
+            self.listen_port = tuning
+                .listen_port
+                .filter(|&port| port != 0)
+                .or(Some(LISTEN_PORT));
+            Ok(())
+        })
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            self.connected_at = None;
+            self.listen_port = None;
+            Ok(())
+        })
+    }
+
+    fn interface(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    // There is no interface for the OS to report on
+    fn interface_state(&self) -> Option<InterfaceState> {
+        Some(InterfaceState::Up)
+    }
+
+    fn transfer_stats(&self) -> Result<(u64, u64), VpnError> {
+        let elapsed = self.connected_at.map(|at| at.elapsed()).unwrap_or_default();
+        Ok((
+            transferred(DOWNLOAD_RATE, elapsed),
+            transferred(UPLOAD_RATE, elapsed),
+        ))
+    }
+}
+
+/// Bytes moved in `elapsed` at an average of `rate` bytes per second
+fn transferred(rate: f64, elapsed: Duration) -> u64 {
+    use std::f64::consts::TAU;
+
+    let t = elapsed.as_secs_f64();
+    let period = SWING_PERIOD.as_secs_f64();
+    // Integral of rate * (1 + sin(TAU * t / period) / 2)
+    let swing = period / TAU / 2.0 * (1.0 - (TAU * t / period).cos());
+    (rate * (t + swing)).round() as u64
+}
+
+/// Route backend that only logs, with every destination reached over a made-up uplink
+pub struct LoggedRoutes;
+
+impl RouteBackend for LoggedRoutes {
+    fn add(&self, route: &Route) -> Result<(), VpnError> {
+        log::info!("Simulated: add route {}", route);
+        Ok(())
+    }
+
+    fn delete(&self, route: &Route) -> Result<(), VpnError> {
+        log::info!("Simulated: delete route {}", route);
+        Ok(())
+    }
+
+    fn lookup(&self, dest: IpAddr) -> Result<Route, VpnError> {
+        Ok(Route {
+            destination: Prefix::host(dest),
+            gateway: Some(UPLINK_GATEWAY.into()),
+            interface: "simulated0".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InterfaceConfig, PeerConfig};
+
+    #[test]
+    fn test_transferred_averages_the_rate() {
+        let at = |secs| transferred(1000.0, Duration::from_secs_f64(secs));
+        assert_eq!(at(0.0), 0);
+        assert_eq!(at(20.0), 20_000);
+        // Faster than average in the first half of the swing, slower in the second
+        assert!(at(10.0) > 10_000);
+        assert!((1..40).all(|tick| at(tick as f64 / 2.0) > at((tick - 1) as f64 / 2.0)));
+    }
+
+    #[tokio::test]
+    async fn test_simulated_connect_and_disconnect() {
+        let config = VpnConfig {
+            interface: InterfaceConfig {
+                private_key: "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=".to_string(),
+                address: "10.8.0.2/32".to_string(),
+                dns: vec!["1.1.1.1".to_string()],
+                mtu: None,
+            },
+            peers: vec![PeerConfig {
+                public_key: "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=".to_string(),
+                endpoint: "203.0.113.7:51820".to_string(),
+                allowed_ips: vec!["0.0.0.0/0".to_string()],
+                persistent_keepalive: Some(25),
+                alternate_endpoints: Vec::new(),
+            }],
+        };
+        let mut backend = SimulatedBackend::new("SACVPN");
+        let started = std::time::Instant::now();
+        backend
+            .connect(&config, &TunnelTuning::default(), false)
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= HANDSHAKE_DELAY);
+        assert_eq!(backend.listen_port(), Some(LISTEN_PORT));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (rx, tx) = backend.transfer_stats().unwrap();
+        assert!(rx > tx && tx > 0);
+
+        backend.disconnect().await.unwrap();
+        assert_eq!(backend.transfer_stats().unwrap(), (0, 0));
+        assert_eq!(backend.listen_port(), None);
+
+        let mut bad = config.clone();
+        bad.interface.private_key = "not a key".to_string();
+        assert!(backend
+            .connect(&bad, &TunnelTuning::default(), false)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,645 @@
+//! Domain-based split tunneling
+//!
+//! A local resolver stands in for the tunnel's DNS servers. Queries are relayed
+//! upstream unchanged, over TCP when the client asks over TCP (as it does after
+//! a truncated UDP answer); addresses returned for a configured domain get a host
+//! route outside (or inside) the tunnel before the answer is handed back, so
+//! the first connection already takes the right path. Routes expire with the
+//! record TTL. This is the only practical way to exclude services whose
+//! addresses come from a CDN and change all the time.
+//!
+//! CDNs hand out many addresses, so the routes are bounded: an address shared
+//! by several names gets one route that lives until the last name's record
+//! expires, lifetimes are capped, and once `MAX_ROUTES` are installed the one
+//! closest to expiring makes room for the next.
+
+use super::dns;
+use super::routes::{Prefix, Route, RouteTable};
+use super::VpnError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Where the local resolver listens; the tunnel's DNS is pointed here
+pub const LISTEN_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+/// Floor for route lifetimes; CDNs hand out TTLs of a few seconds and
+/// connections outlive them
+const MIN_ROUTE_TTL: Duration = Duration::from_secs(60);
+
+/// Ceiling for route lifetimes, so a day-long TTL doesn't pin a route for a day
+const MAX_ROUTE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Host routes installed at once
+const MAX_ROUTES: usize = 1024;
+
+/// How often expired routes are removed
+const EXPIRY_CHECK: Duration = Duration::from_secs(5);
+
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a client's TCP connection may sit without sending a query
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which way addresses of the configured domains are routed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitMode {
+    /// Around the tunnel, over the physical connection
+    #[default]
+    Bypass,
+    /// Through the tunnel, even when its allowed IPs don't cover them
+    Tunnel,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SplitTunnelSettings {
+    pub enabled: bool,
+    pub mode: SplitMode,
+    /// Domains whose addresses are split off, subdomains included (e.g. "mybank.com")
+    pub domains: Vec<String>,
+}
+
+impl SplitTunnelSettings {
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.domains.is_empty()
+    }
+
+    /// Refuse bypassing alongside the kill switch, whose rules would drop the
+    /// bypassed traffic or, depending on the firewall, let it leak
+    pub fn check(&self, kill_switch: bool) -> Result<(), VpnError> {
+        if self.is_active() && self.mode == SplitMode::Bypass && kill_switch {
+            return Err(VpnError::ConfigError(
+                "Domains can't bypass the tunnel while the kill switch is on".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` is one of `domains` or a subdomain of one
+pub fn matches(domains: &[String], name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    domains.iter().any(|domain| {
+        let domain = domain.trim_matches('.').to_ascii_lowercase();
+        !domain.is_empty()
+            && (name == domain
+                || name
+                    .strip_suffix(&domain)
+                    .is_some_and(|rest| rest.ends_with('.')))
+    })
+}
+
+/// Counters of the split tunneling routes, for diagnostics
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SplitRouteStats {
+    /// Host routes currently installed
+    pub active_routes: usize,
+    /// Most host routes installed at once this session
+    pub peak_routes: usize,
+    pub max_routes: usize,
+    /// Names keeping the installed routes alive, summed over routes
+    pub references: usize,
+    pub added: u64,
+    pub expired: u64,
+    /// Routes removed before expiring to stay under `max_routes`
+    pub evicted: u64,
+    /// Routes the OS refused to add
+    pub failed: u64,
+}
+
+/// A host route and the names whose answers keep it alive
+struct HostRoute {
+    route: Route,
+    /// Expiry of each name's reference
+    refs: HashMap<String, Instant>,
+}
+
+impl HostRoute {
+    fn expires(&self) -> Option<Instant> {
+        self.refs.values().max().copied()
+    }
+}
+
+/// Host routes added for resolved addresses
+struct SplitRoutes {
+    table: RouteTable,
+    /// Next hop for matched addresses, `None` for an on-link interface like the tunnel
+    gateway: Option<IpAddr>,
+    /// `None` until the tunnel interface exists, when routing into the tunnel
+    interface: Option<String>,
+    hosts: HashMap<IpAddr, HostRoute>,
+    max_routes: usize,
+    stats: SplitRouteStats,
+}
+
+impl SplitRoutes {
+    fn new(table: RouteTable, gateway: Option<IpAddr>, interface: Option<String>) -> Self {
+        Self {
+            table,
+            gateway,
+            interface,
+            hosts: HashMap::new(),
+            max_routes: MAX_ROUTES,
+            stats: SplitRouteStats::default(),
+        }
+    }
+
+    /// Route `address`, which `name` resolved to, for `ttl`
+    fn add(&mut self, name: &str, address: IpAddr, ttl: Duration, now: Instant) {
+        let expires = now + ttl.clamp(MIN_ROUTE_TTL, MAX_ROUTE_TTL);
+        if let Some(host) = self.hosts.get_mut(&address) {
+            let expiry = host.refs.entry(name.to_string()).or_insert(expires);
+            *expiry = (*expiry).max(expires);
+            return;
+        }
+        let Some(interface) = self.interface.clone() else {
+            log::debug!("No tunnel interface yet to route {} into", address);
+            return;
+        };
+        // A gateway only reaches addresses of its own family
+        if self
+            .gateway
+            .is_some_and(|gateway| gateway.is_ipv4() != address.is_ipv4())
+        {
+            return;
+        }
+        if self.hosts.len() >= self.max_routes {
+            self.evict();
+        }
+
+        let route = Route {
+            destination: Prefix::host(address),
+            gateway: self.gateway,
+            interface,
+        };
+        // One route at a time, so a failure doesn't take the others with it
+        match self.table.add(&route) {
+            Ok(()) => {
+                let refs = HashMap::from([(name.to_string(), expires)]);
+                self.hosts.insert(address, HostRoute { route, refs });
+                self.stats.added += 1;
+                self.stats.peak_routes = self.stats.peak_routes.max(self.hosts.len());
+            }
+            Err(e) => {
+                self.stats.failed += 1;
+                log::warn!("Failed to add split route for {}: {}", address, e);
+            }
+        }
+    }
+
+    /// Drop the references that ran out and the routes nothing refers to any more
+    fn expire(&mut self, now: Instant) {
+        for host in self.hosts.values_mut() {
+            host.refs.retain(|_, expires| *expires > now);
+        }
+        let expired: Vec<IpAddr> = self
+            .hosts
+            .iter()
+            .filter(|(_, host)| host.refs.is_empty())
+            .map(|(address, _)| *address)
+            .collect();
+        for address in expired {
+            self.remove(address);
+            self.stats.expired += 1;
+        }
+    }
+
+    /// Make room by removing the route closest to expiring
+    fn evict(&mut self) {
+        let Some(address) = self
+            .hosts
+            .iter()
+            .min_by_key(|(_, host)| host.expires())
+            .map(|(address, _)| *address)
+        else {
+            return;
+        };
+        log::debug!("Split route limit reached, evicting {}", address);
+        self.remove(address);
+        self.stats.evicted += 1;
+    }
+
+    fn remove(&mut self, address: IpAddr) {
+        if let Some(host) = self.hosts.remove(&address) {
+            if let Err(e) = self.table.remove(&host.route) {
+                log::warn!("Failed to remove split route for {}: {}", address, e);
+            }
+        }
+    }
+
+    fn stats(&self) -> SplitRouteStats {
+        SplitRouteStats {
+            active_routes: self.hosts.len(),
+            max_routes: self.max_routes,
+            references: self.hosts.values().map(|host| host.refs.len()).sum(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// The running local resolver and the routes it added
+pub struct Interceptor {
+    task: tokio::task::JoinHandle<()>,
+    routes: Arc<Mutex<SplitRoutes>>,
+}
+
+impl Interceptor {
+    /// Start answering on `LISTEN_IP`, routing matched addresses via `gateway`
+    /// on `interface`, or once `route_via` names it
+    pub async fn start(
+        settings: &SplitTunnelSettings,
+        upstream: Vec<SocketAddr>,
+        gateway: Option<IpAddr>,
+        interface: Option<String>,
+    ) -> Result<Self, VpnError> {
+        if upstream.is_empty() {
+            return Err(VpnError::ConfigError(
+                "Split tunneling needs at least one DNS server".to_string(),
+            ));
+        }
+        let listen_error = |e: std::io::Error| {
+            VpnError::PermissionDenied(format!("Failed to listen on {}:53: {}", LISTEN_IP, e))
+        };
+        let socket = UdpSocket::bind((LISTEN_IP, 53))
+            .await
+            .map_err(listen_error)?;
+        let listener = TcpListener::bind((LISTEN_IP, 53))
+            .await
+            .map_err(listen_error)?;
+
+        log::info!(
+            "Split tunneling {} domains via {}",
+            settings.domains.len(),
+            interface.as_deref().unwrap_or("the tunnel")
+        );
+        let routes = Arc::new(Mutex::new(SplitRoutes::new(
+            RouteTable::new(),
+            gateway,
+            interface,
+        )));
+        let resolver = Arc::new(Resolver {
+            domains: settings.domains.clone(),
+            upstream,
+            routes: routes.clone(),
+        });
+        let task = tokio::spawn(serve(Arc::new(socket), listener, resolver));
+        Ok(Self { task, routes })
+    }
+
+    /// Route matched addresses through `interface` from now on, for the tunnel
+    /// interface the OS named once it came up
+    pub fn route_via(&self, interface: String) {
+        self.routes.lock().unwrap().interface = Some(interface);
+    }
+
+    /// Route counters of this session
+    pub fn stats(&self) -> SplitRouteStats {
+        self.routes.lock().unwrap().stats()
+    }
+
+    /// Stop answering and remove every route added
+    pub async fn stop(self) {
+        self.task.abort();
+        let routes = self.routes;
+        // Removing routes runs commands, which mustn't stall the runtime
+        let removed = tokio::task::spawn_blocking(move || {
+            let mut routes = routes.lock().unwrap();
+            routes.hosts.clear();
+            routes.table.rollback();
+        })
+        .await;
+        if let Err(e) = removed {
+            log::warn!("Failed to remove split routes: {}", e);
+        }
+    }
+}
+
+/// What the UDP and TCP listeners share
+struct Resolver {
+    domains: Vec<String>,
+    upstream: Vec<SocketAddr>,
+    routes: Arc<Mutex<SplitRoutes>>,
+}
+
+impl Resolver {
+    /// Relay one query, adding routes for its answers before the client sees them
+    async fn answer(&self, query: &[u8], over_tcp: bool) -> Option<Vec<u8>> {
+        let response = if over_tcp {
+            relay_tcp(&self.upstream, query).await?
+        } else {
+            relay(&self.upstream, query).await?
+        };
+
+        let split = dns::question_name(query).filter(|name| matches(&self.domains, name));
+        if let Some(name) = split {
+            if let Some(parsed) = dns::parse_response(&response) {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                let now = Instant::now();
+                let routes = self.routes.clone();
+                // Adding a route runs a command, which mustn't stall the runtime
+                let added = tokio::task::spawn_blocking(move || {
+                    let mut routes = routes.lock().unwrap();
+                    for answer in parsed.answers {
+                        let ttl = Duration::from_secs(answer.ttl.into());
+                        routes.add(&name, answer.address, ttl, now);
+                    }
+                })
+                .await;
+                if let Err(e) = added {
+                    log::warn!("Failed to add split routes: {}", e);
+                }
+            }
+        }
+        Some(response)
+    }
+}
+
+async fn serve(socket: Arc<UdpSocket>, listener: TcpListener, resolver: Arc<Resolver>) {
+    let mut buf = [0u8; 1500];
+    let mut expiry = tokio::time::interval(EXPIRY_CHECK);
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let Ok((n, client)) = received else {
+                    continue;
+                };
+                let socket = socket.clone();
+                let resolver = resolver.clone();
+                let query = buf[..n].to_vec();
+                tokio::spawn(async move {
+                    if let Some(response) = resolver.answer(&query, false).await {
+                        let _ = socket.send_to(&response, client).await;
+                    }
+                });
+            }
+            accepted = listener.accept() => {
+                let Ok((stream, _)) = accepted else {
+                    continue;
+                };
+                tokio::spawn(serve_tcp(stream, resolver.clone()));
+            }
+            _ = expiry.tick() => {
+                let routes = resolver.routes.clone();
+                tokio::task::spawn_blocking(move || routes.lock().unwrap().expire(Instant::now()));
+            }
+        }
+    }
+}
+
+/// Answer the queries a client sends over one TCP connection
+async fn serve_tcp(mut stream: TcpStream, resolver: Arc<Resolver>) {
+    while let Ok(Ok(query)) =
+        tokio::time::timeout(TCP_IDLE_TIMEOUT, read_message(&mut stream)).await
+    {
+        let Some(response) = resolver.answer(&query, true).await else {
+            return;
+        };
+        if write_message(&mut stream, &response).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Forward a raw query to the first upstream server that answers it
+async fn relay(upstream: &[SocketAddr], query: &[u8]) -> Option<Vec<u8>> {
+    let id = query.get(..2)?;
+    for server in upstream {
+        let bind_addr = if server.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let Ok(socket) = UdpSocket::bind(bind_addr).await else {
+            continue;
+        };
+        if socket.send_to(query, server).await.is_err() {
+            continue;
+        }
+
+        let mut buf = vec![0u8; 4096];
+        let deadline = tokio::time::Instant::now() + UPSTREAM_TIMEOUT;
+        while let Ok(Ok((n, from))) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            if from == *server && buf.get(..2) == Some(id) {
+                buf.truncate(n);
+                return Some(buf);
+            }
+        }
+        log::debug!("No DNS answer from {}", server);
+    }
+    None
+}
+
+/// Forward a raw query over TCP to the first upstream server that answers it,
+/// for answers too large for UDP
+async fn relay_tcp(upstream: &[SocketAddr], query: &[u8]) -> Option<Vec<u8>> {
+    for server in upstream {
+        let exchange = async {
+            let mut stream = TcpStream::connect(server).await?;
+            write_message(&mut stream, query).await?;
+            read_message(&mut stream).await
+        };
+        match tokio::time::timeout(UPSTREAM_TIMEOUT, exchange).await {
+            Ok(Ok(response)) => return Some(response),
+            _ => log::debug!("No DNS answer over TCP from {}", server),
+        }
+    }
+    None
+}
+
+/// Read one length-prefixed DNS message off a TCP stream
+async fn read_message(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0u8; len.into()];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &[u8],
+) -> std::io::Result<()> {
+    let len = u16::try_from(message.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "DNS message too long")
+    })?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::RouteBackend;
+
+    /// Records the host routes added and deleted, refusing to add `refused`
+    struct FakeBackend {
+        log: Arc<Mutex<Vec<String>>>,
+        refused: Option<IpAddr>,
+    }
+
+    impl RouteBackend for FakeBackend {
+        fn add(&self, route: &Route) -> Result<(), VpnError> {
+            if self.refused == Some(route.destination.addr) {
+                return Err(VpnError::ConnectionFailed("File exists".to_string()));
+            }
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("add {}", route.destination.addr));
+            Ok(())
+        }
+
+        fn delete(&self, route: &Route) -> Result<(), VpnError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("del {}", route.destination.addr));
+            Ok(())
+        }
+
+        fn lookup(&self, _dest: IpAddr) -> Result<Route, VpnError> {
+            Err(VpnError::PlatformNotSupported)
+        }
+    }
+
+    #[test]
+    fn test_split_routes_are_shared_capped_and_aged() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            refused: None,
+        }));
+        let mut routes = SplitRoutes::new(table, None, Some("SACVPN".to_string()));
+        routes.max_routes = 2;
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+
+        // Two names sharing an address get one route, kept for the longer TTL
+        routes.add("mybank.com", a, Duration::from_secs(120), start);
+        routes.add("www.mybank.com", a, Duration::from_secs(600), start);
+        routes.expire(start + Duration::from_secs(300));
+        assert_eq!(routes.stats().active_routes, 1);
+        assert_eq!(routes.stats().references, 1);
+
+        // At the cap, the route closest to expiring makes room
+        routes.add("mybank.com", b, Duration::from_secs(60), start);
+        routes.add(
+            "cdn.mybank.com",
+            "192.0.2.3".parse().unwrap(),
+            Duration::ZERO,
+            start,
+        );
+        assert!(!routes.hosts.contains_key(&b));
+
+        // Lifetimes are capped whatever the TTL
+        routes.add("mybank.com", b, Duration::from_secs(86_400), start);
+        routes.expire(start + MAX_ROUTE_TTL - Duration::from_secs(1));
+        assert_eq!(routes.hosts.keys().collect::<Vec<_>>(), vec![&b]);
+        routes.expire(start + MAX_ROUTE_TTL);
+        assert!(routes.hosts.is_empty());
+
+        let stats = routes.stats();
+        assert_eq!((stats.added, stats.evicted, stats.expired), (4, 2, 2));
+        assert_eq!(stats.peak_routes, 2);
+        let deleted = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|op| op.starts_with("del"))
+            .count();
+        assert_eq!(deleted, 4);
+    }
+
+    #[test]
+    fn test_failed_split_route_leaves_the_others() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let refused: IpAddr = "192.0.2.2".parse().unwrap();
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            refused: Some(refused),
+        }));
+        let mut routes = SplitRoutes::new(table, None, Some("SACVPN".to_string()));
+        let start = Instant::now();
+
+        routes.add(
+            "mybank.com",
+            "192.0.2.1".parse().unwrap(),
+            MIN_ROUTE_TTL,
+            start,
+        );
+        routes.add("mybank.com", refused, MIN_ROUTE_TTL, start);
+        assert_eq!(routes.stats().active_routes, 1);
+        assert_eq!(routes.stats().failed, 1);
+        assert_eq!(*log.lock().unwrap(), vec!["add 192.0.2.1"]);
+
+        // Routing into the tunnel waits until its interface is known
+        let table = RouteTable::with_backend(Box::new(FakeBackend {
+            log: log.clone(),
+            refused: None,
+        }));
+        let mut routes = SplitRoutes::new(table, None, None);
+        routes.add(
+            "mybank.com",
+            "192.0.2.3".parse().unwrap(),
+            MIN_ROUTE_TTL,
+            start,
+        );
+        assert!(routes.hosts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_tcp_frames_query_and_answer() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let query = read_message(&mut stream).await.unwrap();
+            let mut response = query.clone();
+            response[2] |= 0x80; // QR
+            write_message(&mut stream, &response).await.unwrap();
+        });
+
+        let query = dns::build_query(0x1234, "mybank.com", dns::TYPE_A);
+        let response = relay_tcp(&[server], &query).await.unwrap();
+        assert_eq!(response.len(), query.len());
+        assert_eq!(response[..2], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_bypass_refused_with_kill_switch() {
+        let mut settings = SplitTunnelSettings {
+            enabled: true,
+            mode: SplitMode::Bypass,
+            domains: vec!["mybank.com".to_string()],
+        };
+        assert!(matches!(
+            settings.check(true),
+            Err(VpnError::ConfigError(_))
+        ));
+        assert!(settings.check(false).is_ok());
+        settings.mode = SplitMode::Tunnel;
+        assert!(settings.check(true).is_ok());
+    }
+
+    #[test]
+    fn test_matches_domain_and_subdomains() {
+        let domains = vec!["mybank.com".to_string(), ".Example.org.".to_string()];
+        assert!(matches(&domains, "mybank.com"));
+        assert!(matches(&domains, "login.MyBank.com."));
+        assert!(matches(&domains, "cdn.example.org"));
+        assert!(!matches(&domains, "notmybank.com"));
+        assert!(!matches(&domains, "mybank.com.evil.net"));
+        assert!(!matches(&[String::new()], "anything.com"));
+    }
+}
@@ -0,0 +1,299 @@
+//! Connection statistics: session totals and smoothed transfer rates
+//!
+//! Rates are normalized to bytes per second using the real time between samples
+//! and smoothed with an EWMA here, so the tray, the UI and anything else that
+//! reads them sees the same numbers however often it polls.
+
+use super::boottime::BootInstant;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Unit transfer rates are displayed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeedUnit {
+    /// KB/s, MB/s, ...
+    #[default]
+    Bytes,
+    /// Kbps, Mbps, ...
+    Bits,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatsSettings {
+    /// Samples the speed EWMA averages over; 1 shows raw per-sample rates
+    pub smoothing_samples: u32,
+    pub unit: SpeedUnit,
+}
+
+impl Default for StatsSettings {
+    fn default() -> Self {
+        Self {
+            smoothing_samples: 5,
+            unit: SpeedUnit::Bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    /// Smoothed transfer rates in bytes per second
+    pub upload_speed: u64,
+    pub download_speed: u64,
+    /// Session totals over the session's duration, in bytes per second
+    pub average_upload_speed: u64,
+    pub average_download_speed: u64,
+    /// Highest smoothed rates of the session
+    pub peak_upload_speed: u64,
+    pub peak_download_speed: u64,
+    /// Session totals, accumulated across automatic reconnects
+    pub total_uploaded: u64,
+    pub total_downloaded: u64,
+    /// Transfer counters of the current tunnel, reset whenever it is rebuilt
+    pub tunnel_uploaded: u64,
+    pub tunnel_downloaded: u64,
+    /// Wall-clock start of the session as a Unix timestamp, for display only
+    pub connected_since: Option<i64>,
+    /// Start of the session on the boot clock, so clock changes don't corrupt
+    /// the session timer and time asleep still counts
+    pub started_at: Option<BootInstant>,
+    /// When the counters were last sampled
+    pub last_sample: Option<BootInstant>,
+}
+
+impl ConnectionStats {
+    /// Fold the current tunnel counters (rx, tx) sampled at `now` into speeds and
+    /// session totals, smoothing speeds over `window` samples
+    pub(super) fn record_transfer(&mut self, rx: u64, tx: u64, now: BootInstant, window: u32) {
+        // A counter going backwards means the tunnel was rebuilt underneath us
+        let rx_delta = rx.checked_sub(self.tunnel_downloaded).unwrap_or(rx);
+        let tx_delta = tx.checked_sub(self.tunnel_uploaded).unwrap_or(tx);
+
+        self.tunnel_downloaded = rx;
+        self.tunnel_uploaded = tx;
+        self.total_downloaded += rx_delta;
+        self.total_uploaded += tx_delta;
+
+        if let Some(started_at) = self.started_at {
+            let elapsed = now.saturating_duration_since(started_at);
+            self.average_download_speed = average(self.total_downloaded, elapsed);
+            self.average_upload_speed = average(self.total_uploaded, elapsed);
+        }
+
+        let previous = self.last_sample.replace(now).or(self.started_at);
+        let Some(elapsed) = previous
+            .map(|previous| now.saturating_duration_since(previous))
+            .filter(|elapsed| !elapsed.is_zero())
+        else {
+            return;
+        };
+
+        // Standard EWMA weight for an N-sample window
+        let alpha = 2.0 / (window.max(1) as f64 + 1.0);
+        self.download_speed = smooth(self.download_speed, rx_delta, elapsed, alpha);
+        self.upload_speed = smooth(self.upload_speed, tx_delta, elapsed, alpha);
+        self.peak_download_speed = self.peak_download_speed.max(self.download_speed);
+        self.peak_upload_speed = self.peak_upload_speed.max(self.upload_speed);
+    }
+
+    /// How long the session has been up
+    pub fn session_duration(&self) -> Option<Duration> {
+        self.started_at.map(|started| started.elapsed())
+    }
+
+    /// Session start as an RFC 3339 timestamp in local time
+    pub fn connected_at(&self) -> Option<String> {
+        let since = chrono::DateTime::from_timestamp(self.connected_since?, 0)?;
+        Some(since.with_timezone(&chrono::Local).to_rfc3339())
+    }
+
+    /// Summary of the session for the usage history, if one was established
+    pub fn summary(&self, server_id: &str) -> Option<SessionSummary> {
+        let started_at = self.connected_since?;
+        let duration = self.session_duration()?;
+        let duration_secs = duration.as_secs();
+        Some(SessionSummary {
+            server_id: server_id.to_string(),
+            started_at,
+            ended_at: started_at + duration_secs as i64,
+            duration_secs,
+            uploaded: self.total_uploaded,
+            downloaded: self.total_downloaded,
+            average_upload_speed: average(self.total_uploaded, duration),
+            average_download_speed: average(self.total_downloaded, duration),
+            peak_upload_speed: self.peak_upload_speed,
+            peak_download_speed: self.peak_download_speed,
+        })
+    }
+}
+
+/// A finished session, as recorded in the usage history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub server_id: String,
+    /// Unix timestamps
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Measured on the monotonic clock, like the live session timer
+    pub duration_secs: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /// Bytes per second; zero in sessions recorded before these were tracked
+    #[serde(default)]
+    pub average_upload_speed: u64,
+    #[serde(default)]
+    pub average_download_speed: u64,
+    #[serde(default)]
+    pub peak_upload_speed: u64,
+    #[serde(default)]
+    pub peak_download_speed: u64,
+}
+
+fn smooth(previous: u64, bytes: u64, elapsed: Duration, alpha: f64) -> u64 {
+    let rate = bytes as f64 / elapsed.as_secs_f64();
+    (previous as f64 + alpha * (rate - previous as f64)).round() as u64
+}
+
+fn average(bytes: u64, elapsed: Duration) -> u64 {
+    if elapsed.is_zero() {
+        return 0;
+    }
+    (bytes as f64 / elapsed.as_secs_f64()).round() as u64
+}
+
+/// Human-readable rate, e.g. "1.2 MB/s" or "9.6 Mbps"
+pub fn format_speed(bytes_per_second: u64, unit: SpeedUnit) -> String {
+    let (value, units): (f64, [&str; 5]) = match unit {
+        SpeedUnit::Bytes => (
+            bytes_per_second as f64,
+            ["B/s", "KB/s", "MB/s", "GB/s", "TB/s"],
+        ),
+        SpeedUnit::Bits => (
+            bytes_per_second as f64 * 8.0,
+            ["bps", "Kbps", "Mbps", "Gbps", "Tbps"],
+        ),
+    };
+    // Bytes scale by 1024 like the rest of the UI, bits by 1000 like link speeds
+    let step = match unit {
+        SpeedUnit::Bytes => 1024.0,
+        SpeedUnit::Bits => 1000.0,
+    };
+
+    let mut value = value;
+    let mut index = 0;
+    while value >= step && index < units.len() - 1 {
+        value /= step;
+        index += 1;
+    }
+    if index == 0 {
+        format!("{} {}", value as u64, units[0])
+    } else {
+        format!("{:.1} {}", value, units[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_totals_survive_tunnel_reset() {
+        let start = BootInstant::now();
+        let second = |n| start + Duration::from_secs(n);
+
+        let mut stats = ConnectionStats::default();
+        stats.record_transfer(1000, 400, second(0), 1);
+        stats.record_transfer(1500, 500, second(1), 1);
+        assert_eq!(stats.download_speed, 500);
+
+        // Reconnect: the new tunnel starts counting from zero
+        stats.tunnel_downloaded = 0;
+        stats.tunnel_uploaded = 0;
+        stats.record_transfer(200, 100, second(2), 1);
+
+        assert_eq!(stats.total_downloaded, 1700);
+        assert_eq!(stats.total_uploaded, 600);
+        assert_eq!(stats.tunnel_downloaded, 200);
+    }
+
+    #[test]
+    fn test_speeds_are_per_second_and_smoothed() {
+        let start = BootInstant::now();
+        let mut stats = ConnectionStats {
+            started_at: Some(start),
+            ..ConnectionStats::default()
+        };
+
+        // 4000 bytes over two seconds is 2000 B/s, not 4000
+        stats.record_transfer(4000, 0, start + Duration::from_secs(2), 1);
+        assert_eq!(stats.download_speed, 2000);
+
+        // A 3-sample window moves halfway towards a new rate
+        stats.record_transfer(4000, 0, start + Duration::from_secs(3), 3);
+        assert_eq!(stats.download_speed, 1000);
+    }
+
+    #[test]
+    fn test_average_and_peak_speeds() {
+        let start = BootInstant::now();
+        let mut stats = ConnectionStats {
+            started_at: Some(start),
+            ..ConnectionStats::default()
+        };
+
+        stats.record_transfer(6000, 1000, start + Duration::from_secs(1), 1);
+        stats.record_transfer(6000, 3000, start + Duration::from_secs(2), 1);
+        stats.record_transfer(9000, 3000, start + Duration::from_secs(3), 1);
+
+        assert_eq!(stats.peak_download_speed, 6000);
+        assert_eq!(stats.peak_upload_speed, 2000);
+        assert_eq!(stats.average_download_speed, 3000);
+        assert_eq!(stats.average_upload_speed, 1000);
+        assert_eq!(stats.download_speed, 3000);
+    }
+
+    #[test]
+    fn test_session_duration_ignores_wall_clock() {
+        let started = BootInstant::now();
+        let stats = ConnectionStats {
+            // The wall clock jumped forward an hour since the session started
+            connected_since: Some(chrono::Utc::now().timestamp() + 3600),
+            started_at: Some(started),
+            ..ConnectionStats::default()
+        };
+
+        let duration = stats.session_duration().unwrap();
+        assert!(duration <= started.elapsed());
+        assert!(stats.connected_at().is_some());
+        assert_eq!(ConnectionStats::default().session_duration(), None);
+    }
+
+    #[test]
+    fn test_summary_uses_session_totals() {
+        let stats = ConnectionStats {
+            total_uploaded: 300,
+            total_downloaded: 900,
+            connected_since: Some(1_700_000_000),
+            started_at: Some(BootInstant::now()),
+            ..ConnectionStats::default()
+        };
+
+        let summary = stats.summary("us-east-1").unwrap();
+        assert_eq!(summary.server_id, "us-east-1");
+        assert_eq!(summary.started_at, 1_700_000_000);
+        assert_eq!(
+            summary.ended_at,
+            1_700_000_000 + summary.duration_secs as i64
+        );
+        assert_eq!((summary.uploaded, summary.downloaded), (300, 900));
+        assert_eq!(ConnectionStats::default().summary("us-east-1"), None);
+    }
+
+    #[test]
+    fn test_format_speed() {
+        assert_eq!(format_speed(512, SpeedUnit::Bytes), "512 B/s");
+        assert_eq!(format_speed(1_572_864, SpeedUnit::Bytes), "1.5 MB/s");
+        assert_eq!(format_speed(1_250_000, SpeedUnit::Bits), "10.0 Mbps");
+    }
+}
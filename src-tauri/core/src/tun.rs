@@ -0,0 +1,306 @@
+//! TUN devices of the embedded tunnel
+//!
+//! The forwarding loop reads outbound packets from the device and writes
+//! decrypted ones back. Windows uses a wintun session, Linux a /dev/net/tun
+//! interface and macOS a utun control socket; all are non-blocking, so
+//! `receive` returns `None` when nothing is queued and the loop's poller
+//! decides how long to wait.
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use super::VpnError;
+use std::io;
+
+#[cfg(target_os = "windows")]
+pub struct TunDevice {
+    session: std::sync::Arc<wintun::Session>,
+}
+
+#[cfg(target_os = "windows")]
+impl TunDevice {
+    pub fn new(session: std::sync::Arc<wintun::Session>) -> Self {
+        Self { session }
+    }
+
+    /// Copy the next queued packet into `buf`, returning its length
+    pub fn receive(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.session.try_receive() {
+            Ok(Some(packet)) => {
+                let bytes = packet.bytes();
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                Ok(Some(len))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        let mut write = self
+            .session
+            .allocate_send_packet(packet.len() as u16)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        write.bytes_mut().copy_from_slice(packet);
+        self.session.send_packet(write);
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub struct TunDevice {
+    file: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl TunDevice {
+    /// Create the TUN interface `name`, which goes away when the device is dropped
+    ///
+    /// Needs root or CAP_NET_ADMIN; without either this fails with
+    /// `VpnError::PermissionDenied`.
+    pub fn create(name: &str) -> Result<Self, VpnError> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(VpnError::ConfigError(format!(
+                "Invalid interface name '{}'",
+                name
+            )));
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to open /dev/net/tun: {}", e)))?;
+
+        // SAFETY: ifreq is plain old data, for which all zeroes is a valid value
+        let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        // Raw IP packets, without the 4-byte packet information header
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+        // SAFETY: the fd is open and `request` outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut request) } < 0 {
+            let e = io::Error::last_os_error();
+            return Err(if e.raw_os_error() == Some(libc::EPERM) {
+                VpnError::PermissionDenied(
+                    "Creating the tunnel interface needs root or CAP_NET_ADMIN".to_string(),
+                )
+            } else {
+                VpnError::WireGuardError(format!("Failed to create interface {}: {}", name, e))
+            });
+        }
+        Ok(Self { file })
+    }
+
+    /// Read the next queued packet into `buf`, returning its length
+    pub fn receive(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        use std::io::Read;
+
+        match (&self.file).read(buf) {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        match (&self.file).write(packet) {
+            Ok(_) => Ok(()),
+            // A full queue drops the packet, as a full wintun ring would
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Kernel control that hands out utun interfaces
+#[cfg(target_os = "macos")]
+const UTUN_CONTROL_NAME: &str = "com.apple.net.utun_control";
+
+/// Length of the address family header utun puts before each packet
+#[cfg(target_os = "macos")]
+const UTUN_HEADER_LEN: usize = 4;
+
+#[cfg(target_os = "macos")]
+pub struct TunDevice {
+    file: std::fs::File,
+    name: String,
+}
+
+#[cfg(target_os = "macos")]
+impl TunDevice {
+    /// Create a utun interface, numbered by the kernel, which goes away when the
+    /// device is dropped
+    ///
+    /// Needs root; otherwise this fails with `VpnError::PermissionDenied`.
+    pub fn create() -> Result<Self, VpnError> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: plain socket(2) call
+        let fd = unsafe { libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(os_error("Failed to open the utun control socket"));
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it; the file closes it
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+
+        // SAFETY: ctl_info is plain old data, for which all zeroes is a valid value
+        let mut info: libc::ctl_info = unsafe { std::mem::zeroed() };
+        for (dst, src) in info.ctl_name.iter_mut().zip(UTUN_CONTROL_NAME.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        // SAFETY: the fd is open and `info` outlives the call
+        if unsafe { libc::ioctl(fd, libc::CTLIOCGINFO, &mut info) } < 0 {
+            return Err(os_error("Failed to look up the utun control"));
+        }
+
+        // Unit 0 lets the kernel pick the first free utun number
+        let address = libc::sockaddr_ctl {
+            sc_len: std::mem::size_of::<libc::sockaddr_ctl>() as libc::c_uchar,
+            sc_family: libc::AF_SYSTEM as libc::c_uchar,
+            ss_sysaddr: libc::AF_SYS_CONTROL as u16,
+            sc_id: info.ctl_id,
+            sc_unit: 0,
+            sc_reserved: [0; 5],
+        };
+        // SAFETY: `address` is a sockaddr_ctl of the length passed
+        let connected = unsafe {
+            libc::connect(
+                fd,
+                &address as *const libc::sockaddr_ctl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ctl>() as libc::socklen_t,
+            )
+        };
+        if connected < 0 {
+            let e = io::Error::last_os_error();
+            return Err(if e.raw_os_error() == Some(libc::EPERM) {
+                VpnError::PermissionDenied(
+                    "Creating the tunnel interface needs administrator rights".to_string(),
+                )
+            } else {
+                VpnError::WireGuardError(format!("Failed to create utun interface: {}", e))
+            });
+        }
+
+        let mut name = [0u8; libc::IFNAMSIZ];
+        let mut len = name.len() as libc::socklen_t;
+        // SAFETY: `name` has room for the `len` bytes the kernel may write
+        if unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SYSPROTO_CONTROL,
+                libc::UTUN_OPT_IFNAME,
+                name.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        } < 0
+        {
+            return Err(os_error("Failed to read the utun interface name"));
+        }
+        let name = std::ffi::CStr::from_bytes_until_nul(&name)
+            .map_err(|_| VpnError::WireGuardError("Invalid utun interface name".to_string()))?
+            .to_string_lossy()
+            .into_owned();
+
+        // SAFETY: fcntl(2) on an open fd
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0
+            || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+            || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0
+        {
+            return Err(os_error("Failed to configure the utun socket"));
+        }
+
+        Ok(Self { file, name })
+    }
+
+    /// Interface name the kernel gave the device, e.g. "utun4"
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Read the next queued packet into `buf` without its address family
+    /// header, returning its length
+    pub fn receive(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        use std::os::fd::AsRawFd;
+
+        let mut header = [0u8; UTUN_HEADER_LEN];
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            },
+        ];
+        // SAFETY: both buffers outlive the call and match their lengths
+        let n = unsafe {
+            libc::readv(
+                self.file.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as libc::c_int,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(e),
+            };
+        }
+        Ok((n as usize)
+            .checked_sub(UTUN_HEADER_LEN)
+            .filter(|&len| len > 0))
+    }
+
+    pub fn send(&self, packet: &[u8]) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let family = match packet.first().map(|byte| byte >> 4) {
+            Some(6) => libc::AF_INET6,
+            _ => libc::AF_INET,
+        };
+        let header = (family as u32).to_be_bytes();
+        // writev doesn't write through the pointers, whatever their mutability
+        let iov = [
+            libc::iovec {
+                iov_base: header.as_ptr() as *mut libc::c_void,
+                iov_len: header.len(),
+            },
+            libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            },
+        ];
+        // SAFETY: both buffers outlive the call and match their lengths
+        let n = unsafe {
+            libc::writev(
+                self.file.as_raw_fd(),
+                iov.as_ptr(),
+                iov.len() as libc::c_int,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            // A full queue drops the packet, as a full wintun ring would
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn os_error(context: &str) -> VpnError {
+    VpnError::WireGuardError(format!("{}: {}", context, io::Error::last_os_error()))
+}
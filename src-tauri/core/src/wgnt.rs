@@ -0,0 +1,207 @@
+//! WireGuardNT backend (Windows)
+//!
+//! The WireGuardNT kernel driver runs the data path, so packets never pass
+//! through this process. That is faster than the embedded tunnel but leaves
+//! out what it adds along the way: traffic padding, adaptive keepalive, MSS
+//! clamping and autotuning.
+
+use super::backend::{self, TunnelBackend, TunnelParams};
+use super::category;
+use super::{TunnelTuning, VpnConfig, VpnError};
+use futures::future::BoxFuture;
+
+pub struct KernelBackend {
+    /// Tunnel name, which is also the adapter name
+    name: String,
+    /// WireGuardNT adapter while the tunnel is up; dropping it removes the adapter
+    adapter: Option<wireguard_nt::Adapter>,
+    listen_port: Option<u16>,
+}
+
+impl KernelBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            adapter: None,
+            listen_port: None,
+        }
+    }
+
+    /// Create the WireGuardNT adapter, configure the peers and bring it up
+    fn start(&mut self, params: &TunnelParams, tuning: &TunnelTuning) -> Result<(), VpnError> {
+        log::info!("Loading WireGuardNT driver...");
+        let wireguard = load_wireguard_nt()?;
+
+        log::info!("Creating WireGuardNT adapter '{}'...", self.name);
+        let adapter = wireguard_nt::Adapter::create(&wireguard, "SACVPN", &self.name, None)
+            .map_err(|e| {
+                super::wireguard::adapter_error("WireGuardNT", "create the VPN adapter", e)
+            })?;
+
+        // The driver routes each packet to the peer whose AllowedIPs match it best
+        let peers = params
+            .peers
+            .iter()
+            .map(|peer| {
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|prefix| ipnet::IpNet::new(prefix.addr, prefix.len))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| VpnError::ConfigError(format!("Invalid allowed IP: {}", e)))?;
+                Ok(wireguard_nt::SetPeer {
+                    public_key: Some(peer.public_key),
+                    preshared_key: None,
+                    keep_alive: peer.persistent_keepalive,
+                    allowed_ips,
+                    endpoint: peer.endpoint,
+                })
+            })
+            .collect::<Result<Vec<_>, VpnError>>()?;
+        let interface = wireguard_nt::SetInterface {
+            listen_port: super::wireguard::pinned_port(tuning.listen_port),
+            public_key: None,
+            private_key: Some(params.private_key),
+            peers,
+        };
+        adapter
+            .set_config(&interface)
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to configure adapter: {}", e)))?;
+        adapter
+            .up()
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to bring adapter up: {}", e)))?;
+
+        self.listen_port = Some(adapter.get_config().listen_port);
+        self.adapter = Some(adapter);
+        Ok(())
+    }
+
+    fn handshake_done(&self) -> bool {
+        self.adapter.as_ref().is_some_and(|adapter| {
+            adapter
+                .get_config()
+                .peers
+                .iter()
+                .all(|peer| peer.last_handshake.is_some())
+        })
+    }
+}
+
+impl TunnelBackend for KernelBackend {
+    fn name(&self) -> &'static str {
+        "WireGuardNT"
+    }
+
+    fn connect<'a>(
+        &'a mut self,
+        config: &'a VpnConfig,
+        tuning: &'a TunnelTuning,
+        _traffic_padding: bool,
+    ) -> BoxFuture<'a, Result<(), VpnError>> {
+        Box::pin(async move {
+            let params = TunnelParams::parse(config)?;
+            self.start(&params, tuning)?;
+
+            for address in &params.addresses {
+                log::info!("Configuring adapter with IP {}...", address);
+                super::wireguard::configure_adapter_ip(&self.name, address)?;
+            }
+            super::wireguard::configure_adapter_dns(&self.name, &config.interface.dns)?;
+            if let Some(mtu) = config.interface.mtu {
+                if let Err(e) = super::wireguard::set_interface_mtu(&self.name, mtu) {
+                    log::warn!("{}", e);
+                }
+            }
+
+            let timeout = backend::handshake_timeout(tuning);
+            let deadline = tokio::time::Instant::now() + timeout;
+            while !self.handshake_done() {
+                if tokio::time::Instant::now() >= deadline {
+                    let endpoints: Vec<String> = params
+                        .peers
+                        .iter()
+                        .map(|peer| peer.endpoint.to_string())
+                        .collect();
+                    return Err(VpnError::ConnectionFailed(format!(
+                        "Handshake with {} timed out after {:?}",
+                        endpoints.join(", "),
+                        timeout
+                    )));
+                }
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+
+            tokio::spawn(category::apply(self.name.clone(), tuning.network_category));
+
+            log::info!("WireGuardNT tunnel established successfully!");
+            Ok(())
+        })
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            // Dropping the adapter takes the kernel tunnel down and removes it
+            self.listen_port = None;
+            if self.adapter.take().is_some() {
+                log::info!("WireGuardNT tunnel disconnected");
+            }
+            Ok(())
+        })
+    }
+
+    fn interface(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    fn transfer_stats(&self) -> Result<(u64, u64), VpnError> {
+        let Some(adapter) = &self.adapter else {
+            return Ok((0, 0));
+        };
+        Ok(adapter
+            .get_config()
+            .peers
+            .iter()
+            .fold((0, 0), |(rx, tx), peer| {
+                (rx + peer.rx_bytes, tx + peer.tx_bytes)
+            }))
+    }
+
+    /// Anything but the server not answering means the driver can't run the
+    /// tunnel here, which the embedded tunnel may still manage
+    fn falls_back_on(&self, error: &VpnError) -> bool {
+        !matches!(error, VpnError::ConnectionFailed(_))
+    }
+}
+
+/// Locations searched for wireguard.dll, the WireGuardNT driver
+fn wireguard_dll_paths() -> Vec<std::path::PathBuf> {
+    super::wireguard::dll_paths("wireguard.dll")
+}
+
+static WIREGUARD_NT: std::sync::OnceLock<wireguard_nt::Wireguard> = std::sync::OnceLock::new();
+
+/// wireguard.dll, loaded on first use; it isn't bundled, so the kernel backend
+/// is only available where it has been placed next to the executable
+fn load_wireguard_nt() -> Result<wireguard_nt::Wireguard, VpnError> {
+    if let Some(wireguard) = WIREGUARD_NT.get() {
+        return Ok(wireguard.clone());
+    }
+    for path in wireguard_dll_paths() {
+        if path.exists() {
+            match unsafe { wireguard_nt::load_from_path(&path) } {
+                Ok(wireguard) => {
+                    log::info!("Loaded WireGuardNT from {:?}", path);
+                    return Ok(WIREGUARD_NT.get_or_init(|| wireguard).clone());
+                }
+                Err(e) => log::warn!("Failed to load WireGuardNT from {:?}: {}", path, e),
+            }
+        }
+    }
+    Err(VpnError::WireGuardError(
+        "wireguard.dll was not found".to_string(),
+    ))
+}
@@ -0,0 +1,301 @@
+//! wg-quick backend (Linux)
+//!
+//! Covers for the embedded tunnel when this process may not create network
+//! interfaces: wg-quick brings the tunnel up as root, through polkit or sudo,
+//! from a config in a private per-user directory. The config sets
+//! `Table = off`, so routing stays with the manager as for the embedded tunnel,
+//! and carries no PreUp/PostDown commands: nothing from settings or the server
+//! is ever run as root.
+
+#[cfg(target_os = "linux")]
+use super::backend::{self, TunnelBackend};
+#[cfg(target_os = "linux")]
+use super::events::{self, EventCategory, Severity};
+#[cfg(target_os = "linux")]
+use super::wgconf::WgQuickConfig;
+#[cfg(target_os = "linux")]
+use super::{TunnelTuning, VpnConfig, VpnError};
+#[cfg(target_os = "linux")]
+use futures::future::BoxFuture;
+
+/// Run as `sh -c HANDSHAKE_SCRIPT sh <config> <interface> <tenths> [peer key...]`:
+/// brings the tunnel up, starts a handshake with each listed peer, waits up to
+/// `tenths` tenths of a second for every peer's to complete and prints `wg show` dump
+#[cfg(target_os = "linux")]
+const HANDSHAKE_SCRIPT: &str = r#"wg-quick up "$1" || exit
+iface=$2
+tenths=$3
+shift 3
+for peer in "$@"; do
+    wg set "$iface" peer "$peer" persistent-keepalive 25
+    wg set "$iface" peer "$peer" persistent-keepalive off
+done
+n=0
+while [ "$n" -lt "$tenths" ] && wg show "$iface" latest-handshakes | grep -q "[[:space:]]0$"; do
+    sleep 0.1
+    n=$((n + 1))
+done
+wg show "$iface" dump"#;
+
+#[cfg(target_os = "linux")]
+pub struct WgQuickBackend {
+    /// Tunnel name, which wg-quick takes as the interface name
+    name: String,
+    /// Config wg-quick brought the tunnel up from; set until it is taken down
+    config_path: Option<std::path::PathBuf>,
+    /// Listen port `wg show` reported for the interface
+    listen_port: Option<u16>,
+}
+
+#[cfg(target_os = "linux")]
+impl WgQuickBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            config_path: None,
+            listen_port: None,
+        }
+    }
+
+    /// Where the wg-quick config for this tunnel lives; the file name becomes the interface name
+    fn config_path(&self) -> Result<std::path::PathBuf, VpnError> {
+        Ok(super::ownership::private_dir()?.join(format!("{}.conf", self.name)))
+    }
+
+    fn generate_wg_config(
+        &self,
+        config: &VpnConfig,
+        tuning: &TunnelTuning,
+        listen_port: Option<u16>,
+    ) -> Result<String, VpnError> {
+        let mut wg_config = WgQuickConfig::from(config);
+        let interface = &mut wg_config.interface;
+        interface.table = Some("off".to_string());
+        interface.listen_port = listen_port;
+        interface.fwmark = tuning.fwmark;
+        wg_config.render()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl TunnelBackend for WgQuickBackend {
+    fn name(&self) -> &'static str {
+        "wg-quick"
+    }
+
+    fn connect<'a>(
+        &'a mut self,
+        config: &'a VpnConfig,
+        tuning: &'a TunnelTuning,
+        traffic_padding: bool,
+    ) -> BoxFuture<'a, Result<(), VpnError>> {
+        Box::pin(async move {
+            if traffic_padding {
+                log::warn!("Traffic padding needs the embedded tunnel and is off with wg-quick");
+            }
+
+            let config_path = self.config_path()?;
+            let listen_port = super::wireguard::pinned_port(tuning.listen_port);
+            write_private(
+                &config_path,
+                &self.generate_wg_config(config, tuning, listen_port)?,
+            )?;
+            let path = config_path.to_string_lossy().into_owned();
+            self.config_path = Some(config_path);
+
+            if super::wireguard::left_behind(&self.name) {
+                let _ = run_elevated(&["wg-quick", "down", &path]);
+            }
+
+            // Bring the tunnel up, wait for the handshakes and read the state back
+            // under a single auth prompt. Without a persistent keepalive nothing
+            // would start a peer's handshake, so one is switched on and back off.
+            let tenths = (backend::handshake_timeout(tuning).as_millis() / 100).to_string();
+            let mut argv = vec![
+                "sh",
+                "-c",
+                HANDSHAKE_SCRIPT,
+                "sh",
+                &path,
+                &self.name,
+                &tenths,
+            ];
+            argv.extend(
+                config
+                    .peers
+                    .iter()
+                    .filter(|peer| peer.persistent_keepalive.is_none())
+                    .map(|peer| peer.public_key.as_str()),
+            );
+            let output = run_elevated(&argv)
+                .map_err(|e| VpnError::WireGuardError(format!("Failed to run wg-quick: {}", e)))?;
+
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if stderr.contains("Permission denied") {
+                    // Nothing came up, so there is only the config to clean up
+                    if let Some(config_path) = self.config_path.take() {
+                        let _ = std::fs::remove_file(config_path);
+                    }
+                    return Err(VpnError::PermissionDenied(
+                        "WireGuard requires root privileges".to_string(),
+                    ));
+                }
+                // wg-quick may have got the interface up before `wg show` failed,
+                // so leave the config for disconnect to take it down with
+                return Err(VpnError::WireGuardError(format!(
+                    "wg-quick failed: {}",
+                    stderr
+                )));
+            }
+
+            // wg-quick can exit 0 with a peer not applied
+            let peer_keys: Vec<&str> = config
+                .peers
+                .iter()
+                .map(|peer| peer.public_key.as_str())
+                .collect();
+            let port = verify_dump(&String::from_utf8_lossy(&output.stdout), &peer_keys)?;
+            self.listen_port = Some(port);
+            Ok(())
+        })
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), VpnError>> {
+        Box::pin(async move {
+            self.listen_port = None;
+            if let Some(config_path) = self.config_path.take() {
+                let _ = run_elevated(&["wg-quick", "down", &config_path.to_string_lossy()]);
+                let _ = std::fs::remove_file(&config_path);
+            }
+            Ok(())
+        })
+    }
+
+    fn interface(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+    }
+
+    fn transfer_stats(&self) -> Result<(u64, u64), VpnError> {
+        super::wireguard::interface_transfer(&self.name)
+    }
+}
+
+/// Whether wg-quick is on the PATH, to fall back to when the embedded tunnel can't be used
+#[cfg(target_os = "linux")]
+pub fn installed() -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(["/sbin", "/usr/sbin", "/usr/local/bin"].map(std::path::PathBuf::from))
+        .any(|dir| dir.join("wg-quick").is_file())
+}
+
+/// Write a wg-quick config readable only by the current user; it holds the private key
+#[cfg(target_os = "linux")]
+fn write_private(path: &std::path::Path, content: &str) -> Result<(), VpnError> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // The mode only applies on creation, so never reuse a file left behind earlier
+    let _ = std::fs::remove_file(path);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| VpnError::ConfigError(format!("Failed to write config: {}", e)))
+}
+
+/// Run a command as root, through polkit where available and sudo otherwise
+#[cfg(target_os = "linux")]
+fn run_elevated(args: &[&str]) -> std::io::Result<std::process::Output> {
+    use std::process::Command;
+
+    let output = Command::new("pkexec")
+        .args(args)
+        .output()
+        .or_else(|_| Command::new("sudo").args(args).output());
+    let (severity, outcome) = match &output {
+        Ok(output) if output.status.success() => (Severity::Info, String::new()),
+        Ok(_) => (Severity::Error, "Failed: ".to_string()),
+        Err(_) => (Severity::Error, "Could not run: ".to_string()),
+    };
+    events::record(
+        EventCategory::Privileged,
+        severity,
+        format!("{}{}", outcome, args.join(" ")),
+    );
+    output
+}
+
+/// Check `wg show <interface> dump` output for a live interface with each expected
+/// peer having completed a handshake, returning the interface's listen port
+///
+/// The first line describes the interface (private key, public key, listen
+/// port, fwmark); each further line is a peer: public key, preshared key,
+/// endpoint, allowed IPs, latest handshake, and so on.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn verify_dump(dump: &str, peer_public_keys: &[&str]) -> Result<u16, VpnError> {
+    let mut lines = dump.lines();
+    let listen_port = lines
+        .next()
+        .map(|interface| interface.split('\t').collect::<Vec<_>>())
+        .filter(|fields| fields.len() == 4)
+        .and_then(|fields| fields[2].parse().ok())
+        .ok_or_else(|| VpnError::WireGuardError("WireGuard interface did not come up".into()))?;
+    let peers: Vec<Vec<&str>> = lines.map(|peer| peer.split('\t').collect()).collect();
+    for key in peer_public_keys {
+        let peer = peers
+            .iter()
+            .find(|fields| fields.first() == Some(key))
+            .ok_or_else(|| {
+                VpnError::WireGuardError(format!(
+                    "WireGuard interface came up without peer {}",
+                    key
+                ))
+            })?;
+        match peer.get(2..5) {
+            Some([_, _, handshake]) if *handshake != "0" => {}
+            Some([endpoint, _, _]) => {
+                return Err(VpnError::ConnectionFailed(format!(
+                    "Handshake with {} timed out",
+                    endpoint
+                )))
+            }
+            _ => {
+                return Err(VpnError::WireGuardError(
+                    "Unexpected `wg show` output".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(listen_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_dump_requires_interface_and_peer() {
+        let dump = "cHJpdmF0ZQ=\tcHVibGlj=\t51820\toff\n\
+                    c2VydmVy=\t(none)\t203.0.113.7:51820\t0.0.0.0/0\t1700000000\t92\t148\t25\n";
+        assert_eq!(verify_dump(dump, &["c2VydmVy="]).ok(), Some(51820));
+        assert!(verify_dump(dump, &["c2VydmVy=", "b3RoZXI="]).is_err());
+        assert!(verify_dump("", &["c2VydmVy="]).is_err());
+
+        // Up, but the server never answered
+        let silent = "cHJpdmF0ZQ=\tcHVibGlj=\t51820\toff\n\
+                      c2VydmVy=\t(none)\t203.0.113.7:51820\t0.0.0.0/0\t0\t0\t148\t25\n";
+        assert!(matches!(
+            verify_dump(silent, &["c2VydmVy="]),
+            Err(VpnError::ConnectionFailed(_))
+        ));
+    }
+}
@@ -0,0 +1,1050 @@
+//! WireGuard tunnel manager
+//!
+//! The tunnel is run by one of the backends in `backend`, so that nothing
+//! beyond the TUN driver needs to be installed by default:
+//! - Windows: wintun driver + boringtun for userspace WireGuard, or the
+//!   WireGuardNT kernel driver when the tuning asks for it
+//! - Linux: /dev/net/tun + boringtun, falling back to wg-quick when this
+//!   process may not create network interfaces
+//! - macOS: utun + boringtun
+//!
+//! The manager holds what is the same whichever backend runs the tunnel: the
+//! tunnel lock, the routes and the forwarding counters. It also keeps each
+//! peer's alternate endpoints: a connect whose handshake goes unanswered moves
+//! on to them, and a backend that can switch endpoints mid-session moves a
+//! peer that stopped answering without taking the tunnel down, the same way
+//! it follows a hostname that moved to a new address.
+
+use super::backend::{self, TunnelBackend};
+use super::firewall;
+use super::network::InterfaceState;
+use super::ownership::TunnelLock;
+use super::polling::{ForwardingCounters, ForwardingStats};
+use super::recovery;
+use super::resolve;
+use super::routes::{self, Prefix, Route, RouteTable};
+use super::simulate;
+use super::{TunnelTuning, VpnConfig, VpnError};
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Tunnel name used for WireGuard
+const TUNNEL_NAME: &str = "SACVPN";
+
+/// Tunnel MTU when the config doesn't set one
+pub const DEFAULT_MTU: u32 = 1420;
+
+/// WireGuard tunnel manager
+pub struct WireGuardManager {
+    tunnel_name: String,
+    is_connected: Arc<AtomicBool>,
+    forwarding: Arc<ForwardingCounters>,
+    routes: RouteTable,
+    /// Held from connect to disconnect so no other process brings up the same tunnel
+    owner: Option<TunnelLock>,
+    /// Backend running the tunnel, from connect to disconnect
+    backend: Option<Box<dyn TunnelBackend>>,
+    /// Addresses of each peer of the connected tunnel, in config order
+    endpoints: Vec<PeerEndpoints>,
+}
+
+/// Where a peer is reached and where else it answers
+struct PeerEndpoints {
+    current: ResolvedEndpoint,
+    /// Tried in order, the endpoints switched away from going to the back
+    alternates: VecDeque<ResolvedEndpoint>,
+}
+
+/// An endpoint's address and the `host:port` of the config it was resolved from
+#[derive(Debug, Clone, PartialEq)]
+struct ResolvedEndpoint {
+    address: SocketAddr,
+    configured: String,
+}
+
+/// A peer moved to another of its endpoints while the tunnel stayed up
+#[derive(Debug, Clone)]
+pub struct EndpointSwitch {
+    /// Index of the peer in the config
+    pub peer: usize,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    /// The configured endpoint `to` was resolved from, which may be a hostname
+    pub to_endpoint: String,
+}
+
+impl WireGuardManager {
+    pub fn new() -> Self {
+        let routes = if simulate::enabled() {
+            RouteTable::with_backend(Box::new(simulate::LoggedRoutes))
+        } else {
+            RouteTable::new()
+        };
+        Self {
+            tunnel_name: TUNNEL_NAME.to_string(),
+            is_connected: Arc::new(AtomicBool::new(false)),
+            forwarding: Arc::new(ForwardingCounters::default()),
+            routes,
+            owner: None,
+            backend: None,
+            endpoints: Vec::new(),
+        }
+    }
+
+    /// Connect to VPN on the first backend that comes up
+    ///
+    /// If the handshake goes unanswered, as behind a firewall that only lets
+    /// 443 or 53 through, the peers are moved to their alternate endpoints in
+    /// order until one answers. Returns the peers that ended up on an alternate.
+    ///
+    /// With `lan_bypass`, see `routed_ips`, the LAN ranges stay off the tunnel.
+    pub async fn connect(
+        &mut self,
+        config: &VpnConfig,
+        tuning: &TunnelTuning,
+        traffic_padding: bool,
+        lan_bypass: Option<&[IpAddr]>,
+    ) -> Result<Vec<EndpointSwitch>, VpnError> {
+        log::info!("Connecting to WireGuard tunnel '{}' ...", self.tunnel_name);
+        for peer in &config.peers {
+            log::info!("Endpoint: {}", peer.endpoint);
+        }
+        log::info!("Client IP: {}", config.interface.address);
+
+        // Refuse before touching adapters or routes if another process runs the tunnel
+        if self.owner.is_none() {
+            self.owner = Some(TunnelLock::acquire(&self.tunnel_name)?);
+        }
+
+        let routed = match routed_ips(config, lan_bypass) {
+            Ok(routed) => routed,
+            Err(e) => {
+                self.owner = None;
+                return Err(e);
+            }
+        };
+        // Resolve once so every backend and the routes agree on the servers' addresses
+        let mut peer_endpoints = match resolve_peers(config, tuning).await {
+            Ok(peer_endpoints) => peer_endpoints,
+            Err(e) => {
+                self.owner = None;
+                return Err(e);
+            }
+        };
+        let configured: Vec<SocketAddr> = peer_endpoints
+            .iter()
+            .map(|peer| peer.current.address)
+            .collect();
+
+        let mut attempt = 0;
+        loop {
+            match self
+                .start(config, &peer_endpoints, &routed, tuning, traffic_padding)
+                .await
+            {
+                Ok(backend) => {
+                    self.backend = Some(backend);
+                    break;
+                }
+                Err(e) if recovery::is_unanswered(&e) => {
+                    attempt += 1;
+                    let moved = next_endpoints(&mut peer_endpoints, attempt);
+                    if moved.is_empty() {
+                        self.owner = None;
+                        return Err(e);
+                    }
+                    for endpoint in moved {
+                        log::warn!("{}; trying {}", e, endpoint.configured);
+                    }
+                }
+                Err(e) => {
+                    self.owner = None;
+                    return Err(e);
+                }
+            }
+        }
+
+        let switches = peer_endpoints
+            .iter()
+            .zip(configured)
+            .enumerate()
+            .filter(|(_, (peer, from))| peer.current.address != *from)
+            .map(|(index, (peer, from))| EndpointSwitch {
+                peer: index,
+                from,
+                to: peer.current.address,
+                to_endpoint: peer.current.configured.clone(),
+            })
+            .collect();
+        self.endpoints = peer_endpoints;
+        self.is_connected.store(true, Ordering::SeqCst);
+        log::info!("WireGuard tunnel connected successfully");
+        Ok(switches)
+    }
+
+    /// Try the backends for these settings in order and route `routed` through
+    /// the first that comes up
+    async fn start(
+        &mut self,
+        config: &VpnConfig,
+        peer_endpoints: &[PeerEndpoints],
+        routed: &[String],
+        tuning: &TunnelTuning,
+        traffic_padding: bool,
+    ) -> Result<Box<dyn TunnelBackend>, VpnError> {
+        let mut config = config.clone();
+        for (peer, endpoints) in config.peers.iter_mut().zip(peer_endpoints) {
+            peer.endpoint = endpoints.current.address.to_string();
+        }
+        // Alternates are routed around the tunnel too, so switching to one needs no new route
+        let endpoints = exempt_addresses(peer_endpoints);
+
+        let mut candidates =
+            backend::candidates(&self.tunnel_name, tuning, traffic_padding, &self.forwarding)
+                .into_iter()
+                .peekable();
+        while let Some(mut backend) = candidates.next() {
+            if let Err(e) = backend.connect(&config, tuning, traffic_padding).await {
+                // Undo the adapter, DNS and forwarding tasks set up before the failure
+                log::warn!("Connect failed, rolling back partial setup: {}", e);
+                let _ = backend.disconnect().await;
+                match candidates.peek() {
+                    Some(next) if backend.falls_back_on(&e) => {
+                        log::warn!("{}; using {} instead", e, next.name());
+                        continue;
+                    }
+                    _ => return Err(e),
+                }
+            }
+
+            let interface = backend.interface().ok_or(VpnError::NotConnected);
+            let routed = interface.and_then(|interface| {
+                self.routes
+                    .route_through_tunnel(routed, &interface, &endpoints)
+            });
+            if let Err(e) = routed {
+                log::warn!("Routing failed, taking the tunnel down: {}", e);
+                self.routes.rollback();
+                let _ = backend.disconnect().await;
+                return Err(e);
+            }
+            log::info!("Tunnel running on the {} backend", backend.name());
+            return Ok(backend);
+        }
+        Err(VpnError::PlatformNotSupported)
+    }
+
+    /// Disconnect from VPN
+    pub async fn disconnect(&mut self) -> Result<(), VpnError> {
+        log::info!("Disconnecting WireGuard tunnel '{}'...", self.tunnel_name);
+
+        self.routes.rollback();
+        self.endpoints.clear();
+        if let Some(mut backend) = self.backend.take() {
+            backend.disconnect().await?;
+        }
+
+        self.is_connected.store(false, Ordering::SeqCst);
+        self.owner = None;
+        log::info!("WireGuard tunnel disconnected");
+        Ok(())
+    }
+
+    /// Detect a data path that died while the tunnel should be up
+    ///
+    /// For the embedded tunnel this catches the forwarding or timer task
+    /// panicking or returning early; the other backends have no in-process data path.
+    pub async fn check_health(&mut self) -> Result<(), VpnError> {
+        let Some(backend) = &mut self.backend else {
+            return Ok(());
+        };
+        // The OS can take the interface down under the tunnel, as when the
+        // adapter is disabled, without any socket noticing
+        match backend.interface_state() {
+            Some(InterfaceState::Up) | None => backend.check_health().await,
+            Some(state) => Err(VpnError::InterfaceDown(format!(
+                "{} is {}",
+                backend
+                    .interface()
+                    .unwrap_or_else(|| self.tunnel_name.clone()),
+                state
+            ))),
+        }
+    }
+
+    /// State of the tunnel interface as the OS reports it, while connected
+    pub fn interface_state(&self) -> Option<InterfaceState> {
+        self.backend.as_ref()?.interface_state()
+    }
+
+    /// Keepalive interval the adaptive tuner has settled on, if it is running
+    pub async fn keepalive_interval(&self) -> Option<u16> {
+        match &self.backend {
+            Some(backend) => backend.keepalive_interval().await,
+            None => None,
+        }
+    }
+
+    /// Move each peer whose data has gone unanswered for `after` to its next
+    /// endpoint, returning the switches made
+    pub async fn fail_over_endpoints(&mut self, after: std::time::Duration) -> Vec<EndpointSwitch> {
+        let Some(backend) = &self.backend else {
+            return Vec::new();
+        };
+        let mut switches = Vec::new();
+        for (index, waited) in backend.unanswered().await.into_iter().enumerate() {
+            let Some(peer) = self.endpoints.get_mut(index) else {
+                continue;
+            };
+            if waited.is_none_or(|waited| waited < after) {
+                continue;
+            }
+            let Some(next) = peer.alternates.pop_front() else {
+                continue;
+            };
+            match backend.switch_endpoint(index, next.address).await {
+                Ok(()) => {
+                    let from = std::mem::replace(&mut peer.current, next.clone());
+                    switches.push(EndpointSwitch {
+                        peer: index,
+                        from: from.address,
+                        to: next.address,
+                        to_endpoint: next.configured,
+                    });
+                    peer.alternates.push_back(from);
+                }
+                Err(e) => {
+                    log::warn!("Failed to switch to endpoint {}: {}", next.address, e);
+                    peer.alternates.push_back(next);
+                }
+            }
+        }
+        switches
+    }
+
+    /// Peers whose data has gone unanswered for `after` though they were
+    /// configured by hostname, which may have moved to another address, with
+    /// that `host:port`
+    pub async fn unanswered_hostnames(&self, after: std::time::Duration) -> Vec<(usize, String)> {
+        let Some(backend) = &self.backend else {
+            return Vec::new();
+        };
+        backend
+            .unanswered()
+            .await
+            .into_iter()
+            .enumerate()
+            .filter(|(_, waited)| waited.is_some_and(|waited| waited >= after))
+            .filter_map(|(index, _)| {
+                let configured = &self.endpoints.get(index)?.current.configured;
+                configured
+                    .parse::<SocketAddr>()
+                    .is_err()
+                    .then(|| (index, configured.clone()))
+            })
+            .collect()
+    }
+
+    /// Move peer `index` to `address`, where its hostname points now, routing
+    /// it around the tunnel like the address it leaves
+    ///
+    /// Returns `None` if the peer is at `address` already.
+    pub async fn move_endpoint(
+        &mut self,
+        index: usize,
+        address: SocketAddr,
+    ) -> Result<Option<EndpointSwitch>, VpnError> {
+        let backend = self.backend.as_ref().ok_or(VpnError::NotConnected)?;
+        let peer = self
+            .endpoints
+            .get_mut(index)
+            .ok_or(VpnError::NotConnected)?;
+        if peer.current.address == address {
+            return Ok(None);
+        }
+        self.routes
+            .exempt_like(address.ip(), peer.current.address.ip())?;
+        backend.switch_endpoint(index, address).await?;
+        let from = std::mem::replace(&mut peer.current.address, address);
+        Ok(Some(EndpointSwitch {
+            peer: index,
+            from,
+            to: address,
+            to_endpoint: peer.current.configured.clone(),
+        }))
+    }
+
+    /// Route to `endpoint`, i.e. the physical uplink the tunnel runs over
+    pub async fn uplink(&self, endpoint: &str, tuning: &TunnelTuning) -> Option<Route> {
+        let endpoint = resolve::resolve(endpoint, tuning).await.ok()?;
+        self.routes.lookup(endpoint.ip()).ok()
+    }
+
+    pub fn forwarding_stats(&self) -> ForwardingStats {
+        self.forwarding.snapshot()
+    }
+
+    pub fn tunnel_name(&self) -> &str {
+        &self.tunnel_name
+    }
+
+    /// Local UDP port of the connected tunnel
+    pub fn listen_port(&self) -> Option<u16> {
+        self.backend
+            .as_ref()
+            .and_then(|backend| backend.listen_port())
+    }
+
+    /// Apply the socket buffers and batch size of `tuning` to the running tunnel
+    pub async fn retune(&self, tuning: &TunnelTuning) {
+        if let Some(backend) = &self.backend {
+            backend.retune(tuning).await;
+        }
+    }
+
+    /// OS name of the connected tunnel's interface
+    pub fn interface(&self) -> Option<String> {
+        self.backend
+            .as_ref()
+            .and_then(|backend| backend.interface())
+    }
+
+    /// Change the MTU of the live tunnel interface
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), VpnError> {
+        let interface = self.interface().ok_or(VpnError::NotConnected)?;
+        set_interface_mtu(&interface, mtu)
+    }
+
+    /// Route the running tunnel again, with or without `lan_bypass`
+    pub fn reroute(
+        &mut self,
+        config: &VpnConfig,
+        lan_bypass: Option<&[IpAddr]>,
+    ) -> Result<(), VpnError> {
+        let interface = self
+            .backend
+            .as_ref()
+            .and_then(|backend| backend.interface())
+            .ok_or(VpnError::NotConnected)?;
+        let routes = self.routes.plan(
+            &routed_ips(config, lan_bypass)?,
+            &interface,
+            &exempt_addresses(&self.endpoints),
+        )?;
+        self.routes.replace(&routes)
+    }
+
+    /// Get transfer statistics (rx_bytes, tx_bytes)
+    pub async fn get_transfer_stats(&self) -> Result<(u64, u64), VpnError> {
+        if !self.is_connected.load(Ordering::SeqCst) {
+            return Ok((0, 0));
+        }
+        match &self.backend {
+            Some(backend) => backend.transfer_stats(),
+            None => Ok((0, 0)),
+        }
+    }
+}
+
+impl Default for WireGuardManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every peer's endpoint and alternates, resolved
+async fn resolve_peers(
+    config: &VpnConfig,
+    tuning: &TunnelTuning,
+) -> Result<Vec<PeerEndpoints>, VpnError> {
+    let mut peers = Vec::new();
+    for peer in &config.peers {
+        let address = resolve::resolve(&peer.endpoint, tuning).await?;
+        peers.push(PeerEndpoints {
+            current: ResolvedEndpoint {
+                address,
+                configured: peer.endpoint.clone(),
+            },
+            alternates: resolve_alternates(&peer.alternate_endpoints, address, tuning).await,
+        });
+    }
+    Ok(peers)
+}
+
+/// Routes a connect with `config` would add through `interface`
+///
+/// The route lookups run system commands, so they go to the blocking pool.
+pub async fn plan_routes(
+    config: &VpnConfig,
+    interface: &str,
+    tuning: &TunnelTuning,
+    lan_bypass: Option<&[IpAddr]>,
+) -> Result<Vec<Route>, VpnError> {
+    let peer_endpoints = resolve_peers(config, tuning).await?;
+    let allowed_ips = routed_ips(config, lan_bypass)?;
+    let exempt = exempt_addresses(&peer_endpoints);
+    let interface = interface.to_string();
+    tokio::task::spawn_blocking(move || RouteTable::new().plan(&allowed_ips, &interface, &exempt))
+        .await
+        .map_err(|e| VpnError::WireGuardError(format!("Route planning failed: {}", e)))?
+}
+
+/// Move every peer that has an endpoint it hasn't tried on this connect,
+/// `attempt` being the number of tries so far, to the next one
+///
+/// Which peer didn't answer isn't known, so all of them move on together.
+/// Returns the endpoints moved to, none once every peer has tried them all.
+fn next_endpoints(peers: &mut [PeerEndpoints], attempt: usize) -> Vec<ResolvedEndpoint> {
+    let mut moved = Vec::new();
+    for peer in peers {
+        // Rotating keeps the count, so the alternates left are those past `attempt`
+        if attempt > peer.alternates.len() {
+            continue;
+        }
+        let Some(next) = peer.alternates.pop_front() else {
+            continue;
+        };
+        let from = std::mem::replace(&mut peer.current, next.clone());
+        peer.alternates.push_back(from);
+        moved.push(next);
+    }
+    moved
+}
+
+/// Addresses of a peer's alternate endpoints, leaving out those that don't
+/// resolve or duplicate `endpoint`
+async fn resolve_alternates(
+    alternates: &[String],
+    endpoint: SocketAddr,
+    tuning: &TunnelTuning,
+) -> VecDeque<ResolvedEndpoint> {
+    let mut resolved: VecDeque<ResolvedEndpoint> = VecDeque::new();
+    for alternate in alternates {
+        match resolve::resolve(alternate, tuning).await {
+            Ok(address)
+                if address != endpoint
+                    && !resolved.iter().any(|known| known.address == address) =>
+            {
+                resolved.push_back(ResolvedEndpoint {
+                    address,
+                    configured: alternate.clone(),
+                })
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Ignoring alternate endpoint {}: {}", alternate, e),
+        }
+    }
+    resolved
+}
+
+/// Every address the peers may be reached at, which must stay outside the tunnel
+fn exempt_addresses(peers: &[PeerEndpoints]) -> Vec<std::net::IpAddr> {
+    peers
+        .iter()
+        .flat_map(|peer| std::iter::once(&peer.current).chain(&peer.alternates))
+        .map(|endpoint| endpoint.address.ip())
+        .collect()
+}
+
+/// AllowedIPs of every peer, which together are routed through the tunnel
+fn all_allowed_ips(config: &VpnConfig) -> Vec<String> {
+    config
+        .peers
+        .iter()
+        .flat_map(|peer| peer.allowed_ips.iter().cloned())
+        .collect()
+}
+
+/// Prefixes routed through the tunnel: the AllowedIPs, less the LAN ranges
+/// when `lan_bypass` is set
+///
+/// Addresses in `lan_bypass`, such as the tunnel's resolvers, are only
+/// reachable through the tunnel and keep a host route into it even when they
+/// are in a LAN range.
+fn routed_ips(config: &VpnConfig, lan_bypass: Option<&[IpAddr]>) -> Result<Vec<String>, VpnError> {
+    let allowed_ips = all_allowed_ips(config);
+    let Some(tunnel_only) = lan_bypass else {
+        return Ok(allowed_ips);
+    };
+    let allowed = allowed_ips
+        .iter()
+        .map(|ip| ip.parse())
+        .collect::<Result<Vec<Prefix>, _>>()?;
+    let lan = firewall::LAN_RANGES
+        .iter()
+        .map(|range| range.parse())
+        .collect::<Result<Vec<Prefix>, _>>()?;
+
+    let kept = tunnel_only
+        .iter()
+        .map(|&address| Prefix::host(address))
+        .filter(|host| {
+            allowed.iter().any(|prefix| prefix.covers(host))
+                && lan.iter().any(|range| range.covers(host))
+        });
+    Ok(routes::exclude(&allowed, &lan)
+        .into_iter()
+        .chain(kept)
+        .map(|prefix| prefix.to_string())
+        .collect())
+}
+
+/// Change the MTU of the tunnel interface `interface`
+pub(super) fn set_interface_mtu(interface: &str, mtu: u32) -> Result<(), VpnError> {
+    let output = mtu_command(interface, mtu)?
+        .output()
+        .map_err(|e| VpnError::WireGuardError(format!("Failed to set MTU: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(VpnError::WireGuardError(format!(
+            "Failed to set MTU {}: {}",
+            mtu,
+            stderr.trim()
+        )));
+    }
+
+    log::info!("Tunnel MTU set to {}", mtu);
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn mtu_command(interface: &str, mtu: u32) -> Result<std::process::Command, VpnError> {
+    let mut command = std::process::Command::new("netsh");
+    command.args([
+        "interface",
+        "ipv4",
+        "set",
+        "subinterface",
+        interface,
+        &format!("mtu={}", mtu),
+        "store=active",
+    ]);
+    Ok(command)
+}
+
+#[cfg(target_os = "linux")]
+fn mtu_command(interface: &str, mtu: u32) -> Result<std::process::Command, VpnError> {
+    let mut command = std::process::Command::new("ip");
+    command.args(["link", "set", "dev", interface, "mtu", &mtu.to_string()]);
+    Ok(command)
+}
+
+#[cfg(target_os = "macos")]
+fn mtu_command(interface: &str, mtu: u32) -> Result<std::process::Command, VpnError> {
+    let mut command = std::process::Command::new("ifconfig");
+    command.args([interface, "mtu", &mtu.to_string()]);
+    Ok(command)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn mtu_command(_interface: &str, _mtu: u32) -> Result<std::process::Command, VpnError> {
+    Err(VpnError::PlatformNotSupported)
+}
+
+/// Give the adapter `name` one of its addresses
+#[cfg(target_os = "windows")]
+pub(super) fn configure_adapter_ip(
+    name: &str,
+    address: &super::routes::Prefix,
+) -> Result<(), VpnError> {
+    use std::process::Command;
+
+    // Use netsh to set IP (simpler and more reliable); the subnet is the
+    // config's, whatever the server pool's address plan
+    let ip = address.addr.to_string();
+    let netmask = address.netmask().to_string();
+    let prefix = address.to_string();
+    let args: Vec<&str> = match address.addr {
+        std::net::IpAddr::V4(_) => vec![
+            "interface",
+            "ip",
+            "set",
+            "address",
+            name,
+            "static",
+            &ip,
+            &netmask,
+        ],
+        std::net::IpAddr::V6(_) => vec!["interface", "ipv6", "add", "address", name, &prefix],
+    };
+    let output = Command::new("netsh")
+        .args(&args)
+        .output()
+        .map_err(|e| VpnError::WireGuardError(format!("Failed to configure IP: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        log::warn!("netsh IP config warning: {}", stderr);
+    }
+
+    Ok(())
+}
+
+/// Point the adapter `name` at the tunnel's DNS servers
+#[cfg(target_os = "windows")]
+pub(super) fn configure_adapter_dns(name: &str, dns_servers: &[String]) -> Result<(), VpnError> {
+    use std::process::Command;
+
+    for (index, server) in dns_servers.iter().enumerate() {
+        let name = format!("name={}", name);
+        let address = format!("address={}", server);
+        let position = format!("index={}", index + 1);
+
+        let args: Vec<&str> = if index == 0 {
+            vec![
+                "interface",
+                "ip",
+                "set",
+                "dns",
+                &name,
+                "source=static",
+                &address,
+                "validate=no",
+            ]
+        } else {
+            vec![
+                "interface",
+                "ip",
+                "add",
+                "dns",
+                &name,
+                &address,
+                &position,
+                "validate=no",
+            ]
+        };
+
+        let output = Command::new("netsh")
+            .args(&args)
+            .output()
+            .map_err(|e| VpnError::WireGuardError(format!("Failed to configure DNS: {}", e)))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            log::warn!("netsh DNS config warning: {}", stderr);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an interface `name` from an earlier session is still up
+///
+/// Only called while holding the tunnel lock, so no live process owns it;
+/// the app must have crashed or been killed before disconnecting.
+#[cfg(target_os = "linux")]
+pub(super) fn left_behind(name: &str) -> bool {
+    let marker = format!("/sys/class/net/{}", name);
+    let exists = std::path::Path::new(&marker).exists();
+    if exists {
+        log::warn!("Tearing down '{}' left behind by a previous session", name);
+    }
+    exists
+}
+
+/// Transfer counters of the interface `name` (rx_bytes, tx_bytes)
+///
+/// `wg show` needs root and this process isn't, so read the
+/// interface counters from sysfs instead.
+#[cfg(target_os = "linux")]
+pub(super) fn interface_transfer(name: &str) -> Result<(u64, u64), VpnError> {
+    let read = |counter: &str| {
+        let path = format!("/sys/class/net/{}/statistics/{}", name, counter);
+        std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .ok_or_else(|| VpnError::WireGuardError(format!("Failed to read {}", path)))
+    };
+    Ok((read("rx_bytes")?, read("tx_bytes")?))
+}
+
+/// The configured listen port if it is free, else `None` for a random one
+#[cfg_attr(not(any(target_os = "windows", target_os = "linux")), allow(dead_code))]
+pub(super) fn pinned_port(requested: Option<u16>) -> Option<u16> {
+    let port = requested.filter(|&port| port != 0)?;
+    match std::net::UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(_) => Some(port),
+        Err(e) => {
+            log::warn!(
+                "Listen port {} is unavailable ({}), using a random port",
+                port,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Locations searched for wintun.dll, in order: next to the executable, then its resources
+#[cfg(target_os = "windows")]
+pub fn wintun_dll_paths() -> Vec<std::path::PathBuf> {
+    dll_paths("wintun.dll")
+}
+
+#[cfg(target_os = "windows")]
+pub(super) fn dll_paths(file: &str) -> Vec<std::path::PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+    {
+        paths.push(exe_dir.join(file));
+        paths.push(exe_dir.join("resources").join(file));
+    }
+    paths.push(std::path::PathBuf::from(file));
+    paths
+}
+
+#[cfg(target_os = "windows")]
+static WINTUN: std::sync::OnceLock<wintun::Wintun> = std::sync::OnceLock::new();
+
+/// Load wintun.dll ahead of a connect; it stays loaded afterwards
+#[cfg(target_os = "windows")]
+pub(super) fn preload_wintun() -> Result<(), VpnError> {
+    load_wintun().map(drop)
+}
+
+/// wintun.dll, loaded on first use from the first location that works, then the
+/// default search path
+#[cfg(target_os = "windows")]
+pub(super) fn load_wintun() -> Result<wintun::Wintun, VpnError> {
+    if let Some(wintun) = WINTUN.get() {
+        return Ok(wintun.clone());
+    }
+    let wintun = find_wintun()?;
+    Ok(WINTUN.get_or_init(|| wintun).clone())
+}
+
+#[cfg(target_os = "windows")]
+fn find_wintun() -> Result<wintun::Wintun, VpnError> {
+    for path in wintun_dll_paths() {
+        if path.exists() {
+            log::info!("Found wintun.dll at: {:?}", path);
+            match unsafe { wintun::load_from_path(&path) } {
+                Ok(w) => return Ok(w),
+                Err(e) => log::warn!("Failed to load wintun from {:?}: {}", path, e),
+            }
+        }
+    }
+
+    unsafe { wintun::load() }.map_err(|_| {
+        VpnError::WireGuardError(
+            "Failed to load wintun driver. The wintun.dll file is missing or corrupt.".to_string(),
+        )
+    })
+}
+
+/// Install the wintun driver ahead of the first connect by creating and removing an adapter
+#[cfg(target_os = "windows")]
+pub fn install_driver() -> Result<(), VpnError> {
+    let wintun = load_wintun()?;
+    // Dropping the adapter removes it again; the driver stays installed
+    wintun::Adapter::create(&wintun, "SACVPN-Setup", "SACVPN", None)
+        .map(drop)
+        .map_err(|e| adapter_error("wintun", "install the tunnel driver", e))
+}
+
+/// Win32 codes for a driver that Windows won't install or start
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+const DRIVER_ERRORS: [u32; 6] = [
+    577,        // ERROR_INVALID_IMAGE_HASH: the driver signature was rejected
+    654,        // ERROR_DRIVER_FAILED_PRIOR_UNLOAD
+    1058,       // ERROR_SERVICE_DISABLED
+    1275,       // ERROR_DRIVER_BLOCKED
+    0xE000020B, // ERROR_NO_SUCH_DEVINST
+    0xE0000247, // ERROR_DRIVER_STORE_ADD_FAILED
+];
+
+/// Classify a failure to create a wintun or WireGuardNT adapter by its Win32
+/// error code; anything unrecognized stays a generic error rather than being
+/// blamed on the `driver`
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(super) fn adapter_error(
+    driver: &str,
+    action: &str,
+    error: impl Into<Box<dyn std::error::Error>>,
+) -> VpnError {
+    let error = error.into();
+    match win32_code(&*error) {
+        Some(5) => {
+            VpnError::PermissionDenied(format!("Administrator privileges required to {}", action))
+        }
+        // ERROR_ALREADY_EXISTS, ERROR_OBJECT_ALREADY_EXISTS
+        Some(183 | 5010) => VpnError::TunnelInUse(
+            "Another program already has a network adapter with the tunnel's name".to_string(),
+        ),
+        Some(code) if DRIVER_ERRORS.contains(&code) => VpnError::WireGuardError(format!(
+            "Failed to {}: Windows wouldn't load the {} driver ({})",
+            action, driver, error
+        )),
+        _ => VpnError::WireGuardError(format!("Failed to {}: {}", action, error)),
+    }
+}
+
+/// Win32 error code behind `error`, from the OS error it wraps or, for crates
+/// that only keep the text, the "(os error N)" it ends with
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn win32_code(error: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(code) = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+        {
+            return Some(code as u32);
+        }
+        #[cfg(target_os = "windows")]
+        if let Some(error) = error.downcast_ref::<windows::core::Error>() {
+            // HRESULT_FROM_WIN32 wraps the code as 0x8007xxxx
+            let hresult = error.code().0 as u32;
+            return Some(if hresult & 0xFFFF_0000 == 0x8007_0000 {
+                hresult & 0xFFFF
+            } else {
+                hresult
+            });
+        }
+        source = error.source();
+    }
+    error
+        .to_string()
+        .rsplit_once("(os error ")
+        .and_then(|(_, rest)| rest.strip_suffix(')')?.parse::<i32>().ok())
+        .map(|code| code as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InterfaceConfig, PeerConfig};
+
+    #[test]
+    fn test_adapter_errors_blame_the_driver_only_by_code() {
+        let adapter_error = |error: std::io::Error| adapter_error("wintun", "create it", error);
+        let denied = adapter_error(std::io::Error::from_raw_os_error(5));
+        assert!(matches!(denied, VpnError::PermissionDenied(_)));
+
+        let blocked = adapter_error(std::io::Error::from_raw_os_error(1275));
+        assert!(blocked.to_string().contains("wintun driver"));
+
+        // SetupAPI codes are negative as an i32
+        let no_device = adapter_error(std::io::Error::from_raw_os_error(0xE000020Bu32 as i32));
+        assert!(no_device.to_string().contains("wintun driver"));
+
+        // Only the text survived, as from a crate that formats the OS error
+        let formatted = std::io::Error::other("Access is denied. (os error 5)");
+        assert!(matches!(
+            adapter_error(formatted),
+            VpnError::PermissionDenied(_)
+        ));
+
+        for error in [
+            std::io::Error::from_raw_os_error(3),
+            std::io::Error::other("wintun failed"),
+        ] {
+            let other = adapter_error(error);
+            assert!(matches!(other, VpnError::WireGuardError(_)));
+            assert!(!other.to_string().contains("wintun driver"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alternates_skip_duplicates_and_unresolvable() {
+        let endpoint: SocketAddr = "203.0.113.7:51820".parse().unwrap();
+        let alternates = resolve_alternates(
+            &[
+                "203.0.113.7:51820".to_string(),
+                "198.51.100.4:51820".to_string(),
+                "not an endpoint".to_string(),
+                "198.51.100.4:51820".to_string(),
+                "198.51.100.4:443".to_string(),
+            ],
+            endpoint,
+            &TunnelTuning::default(),
+        )
+        .await;
+        let addresses: Vec<SocketAddr> = alternates.iter().map(|known| known.address).collect();
+        assert_eq!(
+            addresses,
+            [
+                "198.51.100.4:51820".parse().unwrap(),
+                "198.51.100.4:443".parse().unwrap()
+            ]
+        );
+        assert_eq!(alternates[1].configured, "198.51.100.4:443");
+
+        let peers = [PeerEndpoints {
+            current: ResolvedEndpoint {
+                address: endpoint,
+                configured: endpoint.to_string(),
+            },
+            alternates,
+        }];
+        assert_eq!(exempt_addresses(&peers).len(), 3);
+    }
+
+    #[test]
+    fn test_next_endpoints_tries_each_alternate_once() {
+        let endpoint = |address: &str| ResolvedEndpoint {
+            address: address.parse().unwrap(),
+            configured: address.to_string(),
+        };
+        let mut peers = [
+            PeerEndpoints {
+                current: endpoint("203.0.113.7:51820"),
+                alternates: [endpoint("203.0.113.7:443"), endpoint("203.0.113.7:53")].into(),
+            },
+            PeerEndpoints {
+                current: endpoint("198.51.100.4:51820"),
+                alternates: VecDeque::new(),
+            },
+        ];
+
+        let moved = next_endpoints(&mut peers, 1);
+        assert_eq!(moved, [endpoint("203.0.113.7:443")]);
+        assert_eq!(peers[0].current, endpoint("203.0.113.7:443"));
+        assert_eq!(peers[1].current, endpoint("198.51.100.4:51820"));
+
+        assert_eq!(next_endpoints(&mut peers, 2), [endpoint("203.0.113.7:53")]);
+        assert!(next_endpoints(&mut peers, 3).is_empty());
+        assert_eq!(peers[0].current, endpoint("203.0.113.7:53"));
+    }
+
+    #[test]
+    fn test_lan_bypass_keeps_tunnel_resolvers() {
+        let config = VpnConfig {
+            interface: InterfaceConfig {
+                private_key: String::new(),
+                address: "10.8.0.2/32".to_string(),
+                dns: vec!["10.8.0.1".to_string()],
+                mtu: None,
+            },
+            peers: vec![PeerConfig {
+                public_key: String::new(),
+                endpoint: "203.0.113.7:51820".to_string(),
+                allowed_ips: vec!["0.0.0.0/0".to_string()],
+                persistent_keepalive: None,
+                alternate_endpoints: Vec::new(),
+            }],
+        };
+        assert_eq!(routed_ips(&config, None).unwrap(), ["0.0.0.0/0"]);
+
+        let resolvers = ["10.8.0.1".parse().unwrap(), "1.1.1.1".parse().unwrap()];
+        let routed: Vec<Prefix> = routed_ips(&config, Some(&resolvers))
+            .unwrap()
+            .iter()
+            .map(|prefix| prefix.parse().unwrap())
+            .collect();
+        let through_tunnel = |address: &str| {
+            let address = address.parse().unwrap();
+            routed.iter().any(|prefix| prefix.contains(address))
+        };
+        assert!(through_tunnel("10.8.0.1"));
+        assert!(through_tunnel("1.1.1.1"));
+        assert!(!through_tunnel("10.8.0.9"));
+        assert!(!through_tunnel("192.168.1.20"));
+        assert_eq!(routed.last().unwrap().to_string(), "10.8.0.1/32");
+    }
+}
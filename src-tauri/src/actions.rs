@@ -0,0 +1,187 @@
+//! Connection actions triggered outside the main window (tray menu, global hotkeys,
+//! jump list and dock menu)
+
+use crate::vpn::{ErrorReport, VpnError, VpnHandle, VpnStatus};
+use crate::{api, connectivity, credentials, failover, servers, taskbar, tray};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Emitted with the `VpnStatus` after an action connected or disconnected, so
+/// the window can follow changes it didn't start
+pub const STATUS_EVENT: &str = "vpn://status";
+
+/// How long Pause keeps the tunnel down before reconnecting to the same server
+const PAUSE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+/// Longest a connect waits for the network to reach the internet, as while
+/// the user signs in to a captive portal
+const NETWORK_WAIT: Duration = Duration::from_secs(10 * 60);
+
+/// Bumped by every action so a pending resume from Pause is dropped
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Connect to the best recommended server
+    QuickConnect,
+    /// Connect to the best recommended server in a country
+    ConnectCountry(String),
+    /// Connect to a specific server, failing over if enabled
+    ConnectServer(String),
+    Disconnect,
+    /// Disconnect, then reconnect to the same server after `PAUSE_DURATION`
+    Pause,
+}
+
+impl Action {
+    /// Command-line arguments that trigger the action in a (possibly already running) instance
+    pub fn to_args(&self) -> Vec<String> {
+        match self {
+            Self::QuickConnect => vec!["--connect".to_string()],
+            Self::ConnectCountry(code) => vec!["--connect-country".to_string(), code.clone()],
+            Self::ConnectServer(id) => vec!["--connect".to_string(), id.clone()],
+            Self::Disconnect => vec!["--disconnect".to_string()],
+            Self::Pause => vec!["--pause".to_string()],
+        }
+    }
+
+    /// Inverse of `to_args`, given the arguments after the program name
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        let value = args.get(1).filter(|v| !v.starts_with("--")).cloned();
+        match (args.first()?.as_str(), value) {
+            ("--connect", None) => Some(Self::QuickConnect),
+            ("--connect", Some(id)) => Some(Self::ConnectServer(id)),
+            ("--connect-country", Some(code)) => Some(Self::ConnectCountry(code)),
+            ("--disconnect", _) => Some(Self::Disconnect),
+            ("--pause", _) => Some(Self::Pause),
+            _ => None,
+        }
+    }
+}
+
+/// Run an action in the background
+pub fn spawn(app: &AppHandle, action: Action) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = run(&app, action.clone()).await {
+            log::warn!("{:?} failed: {}", action, e);
+        }
+    });
+}
+
+pub async fn run(app: &AppHandle, action: Action) -> Result<(), String> {
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+    match action {
+        Action::QuickConnect => connect_recommended(app, None).await,
+        Action::ConnectCountry(country_code) => connect_recommended(app, Some(&country_code)).await,
+        Action::ConnectServer(server_id) => connect(app, &server_id).await.map_err(|e| e.message),
+        Action::Disconnect => disconnect(app).await.map_err(|e| e.message),
+        Action::Pause => pause(app, generation).await,
+    }
+}
+
+async fn connect_recommended(app: &AppHandle, country_code: Option<&str>) -> Result<(), String> {
+    let server = servers::recommend(country_code)
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No cached servers to connect to".to_string())?;
+    log::info!("Connecting to recommended server {}", server.name);
+
+    connect(app, &server.id).await.map_err(|e| e.message)
+}
+
+/// Move the connection to `server_id`, keeping the report of a failed connect
+pub async fn switch_server(app: &AppHandle, server_id: &str) -> Result<(), ErrorReport> {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    disconnect(app).await?;
+    connect(app, server_id).await
+}
+
+async fn connect(app: &AppHandle, server_id: &str) -> Result<(), ErrorReport> {
+    let result = connect_to(app, server_id).await;
+    publish_status(app).await;
+    result
+}
+
+async fn connect_to(app: &AppHandle, server_id: &str) -> Result<(), ErrorReport> {
+    let token = credentials::token().map_err(VpnError::ConfigError)?;
+    let api_url = api::base_url().map_err(VpnError::ConfigError)?;
+
+    let manager = app.state::<VpnHandle>();
+    connectivity::wait_for_internet(&manager, NETWORK_WAIT)
+        .await
+        .map_err(VpnError::ConnectionFailed)?;
+    let result = failover::connect(app, &manager, api_url, &token, server_id, None).await;
+    if result.is_err() {
+        manager
+            .call(|vpn| Box::pin(vpn.release_network_hold()))
+            .await;
+    }
+    result.map(drop)
+}
+
+async fn disconnect(app: &AppHandle) -> Result<(), ErrorReport> {
+    let result = app
+        .state::<VpnHandle>()
+        .call(|vpn| Box::pin(vpn.disconnect()))
+        .await
+        .map_err(ErrorReport::from);
+    publish_status(app).await;
+    result
+}
+
+/// Bring the tray, taskbar and window up to date after an action, whether or
+/// not it succeeded
+async fn publish_status(app: &AppHandle) {
+    tray::refresh(app);
+    taskbar::refresh(app);
+    let status = app.state::<VpnHandle>().with(|vpn| vpn.get_status()).await;
+    let _ = app.emit(STATUS_EVENT, status);
+}
+
+async fn pause(app: &AppHandle, generation: u64) -> Result<(), String> {
+    let manager = app.state::<VpnHandle>().inner().clone();
+    let server_id = manager
+        .with(|vpn| vpn.get_server_id())
+        .await
+        .ok_or_else(|| "Not connected".to_string())?;
+    disconnect(app).await.map_err(|e| e.message)?;
+    log::info!("Paused for {:?}", PAUSE_DURATION);
+
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(PAUSE_DURATION).await;
+        // Another action ran, or the user reconnected from the window meanwhile
+        if GENERATION.load(Ordering::SeqCst) != generation
+            || manager.with(|vpn| vpn.get_status()).await != VpnStatus::Disconnected
+        {
+            return;
+        }
+        if let Err(e) = connect(&app, &server_id).await {
+            log::warn!("Failed to resume after pause: {}", e.message);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args_round_trip() {
+        let actions = [
+            Action::QuickConnect,
+            Action::ConnectCountry("DE".to_string()),
+            Action::ConnectServer("us-east-1".to_string()),
+            Action::Disconnect,
+            Action::Pause,
+        ];
+        for action in actions {
+            assert_eq!(Action::from_args(&action.to_args()), Some(action));
+        }
+        assert_eq!(Action::from_args(&["--minimized".to_string()]), None);
+    }
+}
@@ -0,0 +1,40 @@
+//! Backend API environments
+//!
+//! The base URL is picked from an allowlist compiled into the app, selected by the
+//! environment in settings, and never taken from the webview.
+
+use crate::settings;
+use serde::{Deserialize, Serialize};
+
+const PRODUCTION_URL: &str = "https://scvpn-production.up.railway.app";
+
+/// Only available in builds made with `SACVPN_STAGING_API_URL` set
+const STAGING_URL: Option<&str> = option_env!("SACVPN_STAGING_API_URL");
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiEnvironment {
+    #[default]
+    Production,
+    Staging,
+}
+
+impl ApiEnvironment {
+    pub fn base_url(self) -> Option<&'static str> {
+        match self {
+            Self::Production => Some(PRODUCTION_URL),
+            Self::Staging => STAGING_URL,
+        }
+    }
+}
+
+/// Base URL of the configured environment
+pub fn base_url() -> Result<&'static str, String> {
+    let environment = settings::current().api_environment;
+    environment.base_url().ok_or_else(|| {
+        format!(
+            "API environment {:?} is not available in this build",
+            environment
+        )
+    })
+}
@@ -0,0 +1,115 @@
+//! System clock skew detection
+//!
+//! WireGuard drops handshakes whose timestamp is older than one it has already
+//! seen from the peer, and TLS rejects certificates outside their validity
+//! period, so a clock that is far off makes connecting fail with nothing more
+//! than a handshake timeout. The local clock is compared with the `Date` header
+//! of a server response to tell the user to fix it instead.
+
+use crate::vpn::{ErrorCode, ErrorReport};
+use crate::{api, connectivity};
+use std::time::Duration;
+
+/// Skew tolerated before the clock is reported as wrong
+pub const MAX_SKEW_SECS: i64 = 5 * 60;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds the local clock is ahead of server time (negative when behind), if a
+/// server answered
+pub async fn skew() -> Option<i64> {
+    let client = connectivity::api_client();
+    let mut requests = Vec::new();
+    if let Ok(base_url) = api::base_url() {
+        requests.push(client.head(base_url));
+    }
+    // Plain HTTP still answers when the clock is too far off for TLS
+    requests.push(client.head(connectivity::PORTAL_CHECK_URL));
+
+    for request in requests {
+        let Ok(response) = request.timeout(CHECK_TIMEOUT).send().await else {
+            continue;
+        };
+        let server_time = response
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_http_date);
+        if let Some(server_time) = server_time {
+            return Some(chrono::Utc::now().timestamp() - server_time);
+        }
+    }
+    None
+}
+
+/// Error for a clock off by `skew` seconds, if that is more than tolerated
+pub fn skew_error(skew: i64) -> Option<String> {
+    if skew.abs() <= MAX_SKEW_SECS {
+        return None;
+    }
+    Some(format!(
+        "Your system clock is {} {}. Set it to the correct time, ideally automatically, \
+         and connect again; secure connections fail while the clock is wrong.",
+        describe(skew.abs()),
+        if skew > 0 { "fast" } else { "slow" }
+    ))
+}
+
+/// Replace a connect failure the clock could explain with a clock error when it is off
+pub async fn explain(report: ErrorReport) -> ErrorReport {
+    if !matches!(
+        report.code,
+        ErrorCode::HandshakeTimeout | ErrorCode::EndpointUnreachable | ErrorCode::InvalidConfig
+    ) {
+        return report;
+    }
+    match skew().await.and_then(skew_error) {
+        Some(message) => {
+            log::warn!("Connect failed with the clock off: {}", report.message);
+            ErrorReport::new(ErrorCode::ClockSkew, message)
+        }
+        None => report,
+    }
+}
+
+/// Unix timestamp of an HTTP `Date` header ("Sun, 06 Nov 1994 08:49:37 GMT")
+fn parse_http_date(value: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// Rough size of a time difference, e.g. "3 hours"
+fn describe(secs: i64) -> String {
+    let (amount, unit) = match secs {
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s => (s / (24 * 60 * 60), "day"),
+    };
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_skew_error_beyond_tolerance() {
+        assert_eq!(skew_error(MAX_SKEW_SECS), None);
+        assert_eq!(skew_error(-30), None);
+
+        let fast = skew_error(3 * 60 * 60 + 10).unwrap();
+        assert!(fast.starts_with("Your system clock is 3 hours fast."));
+        let slow = skew_error(-(24 * 60 * 60)).unwrap();
+        assert!(slow.starts_with("Your system clock is 1 day slow."));
+    }
+}
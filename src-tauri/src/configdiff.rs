@@ -0,0 +1,281 @@
+//! Config change audit
+//!
+//! The last config connected to each server is remembered, without its keys,
+//! and every fresh config fetched for a reconnect is compared against it. A
+//! change the user didn't make, such as new DNS servers, AllowedIPs or an
+//! endpoint, is logged, added to the event log and emitted to the UI as
+//! `vpn://config-changed`.
+
+use crate::vpn::events::{self, EventCategory, Severity};
+use crate::vpn::VpnConfig;
+use crate::{servers, storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+const SNAPSHOTS_FILE: &str = "config_snapshots.json";
+
+pub const CONFIG_CHANGED_EVENT: &str = "vpn://config-changed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigField {
+    Address,
+    Dns,
+    Mtu,
+    PeerAdded,
+    PeerRemoved,
+    Endpoint,
+    AllowedIps,
+    PersistentKeepalive,
+}
+
+/// One difference between the previous config and the fresh one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConfigChange {
+    pub field: ConfigField,
+    /// Public key of the peer, for peer fields
+    pub peer: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.field {
+            ConfigField::Address => "address",
+            ConfigField::Dns => "DNS",
+            ConfigField::Mtu => "MTU",
+            ConfigField::PeerAdded => "peer added",
+            ConfigField::PeerRemoved => "peer removed",
+            ConfigField::Endpoint => "endpoint",
+            ConfigField::AllowedIps => "AllowedIPs",
+            ConfigField::PersistentKeepalive => "keepalive",
+        };
+        let none = "none".to_string();
+        write!(
+            f,
+            "{} {} -> {}",
+            label,
+            self.before.as_ref().unwrap_or(&none),
+            self.after.as_ref().unwrap_or(&none)
+        )
+    }
+}
+
+/// The changes a fresh config for `server_id` brought
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigDiff {
+    pub server_id: String,
+    pub changes: Vec<ConfigChange>,
+}
+
+/// What is compared of a config; keys stay out of it so it can be kept on disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ConfigSnapshot {
+    address: String,
+    dns: Vec<String>,
+    mtu: Option<u32>,
+    peers: Vec<PeerSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PeerSnapshot {
+    public_key: String,
+    endpoint: String,
+    allowed_ips: Vec<String>,
+    persistent_keepalive: Option<u32>,
+}
+
+impl From<&VpnConfig> for ConfigSnapshot {
+    fn from(config: &VpnConfig) -> Self {
+        Self {
+            address: config.interface.address.clone(),
+            dns: config.interface.dns.clone(),
+            mtu: config.interface.mtu,
+            peers: config
+                .peers
+                .iter()
+                .map(|peer| PeerSnapshot {
+                    public_key: peer.public_key.clone(),
+                    endpoint: peer.endpoint.clone(),
+                    allowed_ips: peer.allowed_ips.clone(),
+                    persistent_keepalive: peer.persistent_keepalive,
+                })
+                .collect(),
+        }
+    }
+}
+
+static SNAPSHOTS: OnceLock<RwLock<HashMap<String, ConfigSnapshot>>> = OnceLock::new();
+
+fn snapshots() -> &'static RwLock<HashMap<String, ConfigSnapshot>> {
+    SNAPSHOTS.get_or_init(|| RwLock::new(storage::load(SNAPSHOTS_FILE)))
+}
+
+/// Compare a config fetched for `server_id` with the last one, as sent by the
+/// server before any local overrides, and remember it for next time
+///
+/// Returns the changes, if there were any, after logging them. Configs for
+/// servers not in the list, such as imported ones, aren't the server's to change.
+pub fn check(server_id: &str, config: &VpnConfig) -> Option<ConfigDiff> {
+    servers::find(server_id)?;
+    let snapshot = ConfigSnapshot::from(config);
+    let mut snapshots = snapshots().write().unwrap();
+    let previous = snapshots.insert(server_id.to_string(), snapshot.clone());
+    if previous.as_ref() != Some(&snapshot) {
+        if let Err(e) = storage::save(SNAPSHOTS_FILE, &*snapshots) {
+            log::warn!("Failed to save config snapshots: {}", e);
+        }
+    }
+    drop(snapshots);
+
+    let changes = diff(&previous?, &snapshot);
+    if changes.is_empty() {
+        return None;
+    }
+
+    let summary: Vec<String> = changes.iter().map(ToString::to_string).collect();
+    log::warn!(
+        "Config for {} changed since the last connect: {}",
+        server_id,
+        summary.join("; ")
+    );
+    events::record(
+        EventCategory::Config,
+        Severity::Warning,
+        format!(
+            "Server {} changed its config: {}",
+            server_id,
+            summary.join("; ")
+        ),
+    );
+    Some(ConfigDiff {
+        server_id: server_id.to_string(),
+        changes,
+    })
+}
+
+fn diff(before: &ConfigSnapshot, after: &ConfigSnapshot) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    let mut change = |field, peer: Option<&str>, before: Option<String>, after: Option<String>| {
+        if before != after {
+            changes.push(ConfigChange {
+                field,
+                peer: peer.map(str::to_string),
+                before,
+                after,
+            });
+        }
+    };
+
+    change(
+        ConfigField::Address,
+        None,
+        Some(before.address.clone()),
+        Some(after.address.clone()),
+    );
+    change(
+        ConfigField::Dns,
+        None,
+        Some(before.dns.join(", ")),
+        Some(after.dns.join(", ")),
+    );
+    change(
+        ConfigField::Mtu,
+        None,
+        before.mtu.map(|mtu| mtu.to_string()),
+        after.mtu.map(|mtu| mtu.to_string()),
+    );
+
+    // Peers are matched by key, so a reordered cluster isn't reported as changed
+    for old in &before.peers {
+        let key = Some(old.public_key.as_str());
+        let Some(new) = after.peers.iter().find(|p| p.public_key == old.public_key) else {
+            change(
+                ConfigField::PeerRemoved,
+                key,
+                Some(old.endpoint.clone()),
+                None,
+            );
+            continue;
+        };
+        change(
+            ConfigField::Endpoint,
+            key,
+            Some(old.endpoint.clone()),
+            Some(new.endpoint.clone()),
+        );
+        change(
+            ConfigField::AllowedIps,
+            key,
+            Some(old.allowed_ips.join(", ")),
+            Some(new.allowed_ips.join(", ")),
+        );
+        change(
+            ConfigField::PersistentKeepalive,
+            key,
+            old.persistent_keepalive.map(|secs| secs.to_string()),
+            new.persistent_keepalive.map(|secs| secs.to_string()),
+        );
+    }
+    for new in &after.peers {
+        if !before.peers.iter().any(|p| p.public_key == new.public_key) {
+            change(
+                ConfigField::PeerAdded,
+                Some(&new.public_key),
+                None,
+                Some(new.endpoint.clone()),
+            );
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(key: &str, endpoint: &str, allowed_ips: &[&str]) -> PeerSnapshot {
+        PeerSnapshot {
+            public_key: key.to_string(),
+            endpoint: endpoint.to_string(),
+            allowed_ips: allowed_ips.iter().map(|ip| ip.to_string()).collect(),
+            persistent_keepalive: Some(25),
+        }
+    }
+
+    #[test]
+    fn test_diff_reports_changed_fields_and_peers() {
+        let before = ConfigSnapshot {
+            address: "10.8.0.2/32".to_string(),
+            dns: vec!["1.1.1.1".to_string()],
+            mtu: None,
+            peers: vec![
+                peer("server", "203.0.113.7:51820", &["0.0.0.0/0"]),
+                peer("gw2", "203.0.113.8:51820", &["10.20.0.0/16"]),
+            ],
+        };
+        assert!(diff(&before, &before).is_empty());
+
+        let mut after = before.clone();
+        after.dns = vec!["9.9.9.9".to_string()];
+        after.peers[0].endpoint = "203.0.113.9:51820".to_string();
+        after.peers[1] = peer("gw3", "203.0.113.10:51820", &["10.30.0.0/16"]);
+
+        let changes = diff(&before, &after);
+        let fields: Vec<ConfigField> = changes.iter().map(|change| change.field).collect();
+        assert_eq!(
+            fields,
+            [
+                ConfigField::Dns,
+                ConfigField::Endpoint,
+                ConfigField::PeerRemoved,
+                ConfigField::PeerAdded
+            ]
+        );
+        assert_eq!(changes[0].to_string(), "DNS 1.1.1.1 -> 9.9.9.9");
+        assert_eq!(changes[1].peer.as_deref(), Some("server"));
+    }
+}
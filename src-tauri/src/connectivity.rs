@@ -0,0 +1,488 @@
+//! System proxy and captive portal awareness for API requests
+//!
+//! API requests go through the system proxy when one is configured. When a
+//! request can't get through, the network is checked for a captive portal so
+//! the user is told to sign in to the Wi-Fi instead of seeing a bare timeout.
+//! Reconnects and unattended connects wait for the same check to see the
+//! internet, rather than failing against the portal.
+//!
+//! The kill switch holds back the check as it does all other traffic outside
+//! the tunnel, so a portal can't be seen while it is engaged. The user is told
+//! to disconnect, which lifts the kill switch, to sign in to such a network.
+//!
+//! Server list and config requests can be cancelled: a new one replaces the
+//! one still in flight, and the UI cancels them when the user navigates away,
+//! so nothing waits on an answer that is no longer wanted.
+
+use crate::settings;
+use crate::vpn::events::{self, EventCategory, Severity};
+use crate::vpn::{VpnHandle, VpnStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// Time allowed for one API request unless the settings say otherwise
+pub const DEFAULT_API_TIMEOUT_SECS: u64 = 15;
+const MAX_API_TIMEOUT_SECS: u64 = 120;
+
+/// Error of a request cancelled by `cancel` or by a newer request of its kind
+pub const CANCELLED: &str = "Request cancelled";
+
+/// Answers 204 on an open network; portals redirect it or answer with their own page
+pub const PORTAL_CHECK_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+const PORTAL_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a connect waiting for the internet checks again
+const INTERNET_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do about a network the kill switch keeps from being checked
+const KILL_SWITCH_PORTAL_HINT: &str = "The kill switch is blocking this network. If it has a \
+    sign-in page, disconnect to lift the kill switch, sign in, then connect again";
+
+/// What stands between this machine and the internet
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NetworkEnvironment {
+    /// HTTP(S) proxy API requests are sent through
+    pub proxy: Option<String>,
+    /// Sign-in page of the captive portal the network is holding traffic for
+    pub captive_portal: Option<String>,
+    /// The last check got through to the internet, as on an open network
+    pub internet_verified: bool,
+    /// The kill switch held the last check back, so a captive portal can't
+    /// be told apart from an outage
+    pub kill_switch_blocking: bool,
+}
+
+/// What the portal check URL got back
+enum PortalCheck {
+    Open,
+    /// A redirect or a page of its own, from this sign-in page
+    Portal(String),
+    /// Offline, or held back as by the kill switch
+    NoAnswer,
+}
+
+/// API requests the UI can cancel, at most one of each kind in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Servers,
+    Config,
+    /// Config requested ahead of a connect by `prewarm_server`
+    Prewarm,
+}
+
+/// Id of a cancellable request and what cancels it
+type InFlight = (u64, oneshot::Sender<()>);
+
+static ENVIRONMENT: OnceLock<RwLock<NetworkEnvironment>> = OnceLock::new();
+static IN_FLIGHT: OnceLock<Mutex<HashMap<RequestKind, InFlight>>> = OnceLock::new();
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+fn environment() -> &'static RwLock<NetworkEnvironment> {
+    ENVIRONMENT.get_or_init(|| {
+        RwLock::new(NetworkEnvironment {
+            proxy: system_proxy(),
+            captive_portal: None,
+            internet_verified: false,
+            kill_switch_blocking: false,
+        })
+    })
+}
+
+/// Last detected environment
+pub fn current() -> NetworkEnvironment {
+    environment().read().unwrap().clone()
+}
+
+/// Re-read the system proxy and check for a captive portal
+pub async fn detect() -> NetworkEnvironment {
+    let proxy = system_proxy();
+    let check = check_portal(proxy.as_deref()).await;
+    let internet_verified = matches!(check, PortalCheck::Open);
+    let captive_portal = match check {
+        PortalCheck::Portal(portal) => Some(portal),
+        PortalCheck::Open | PortalCheck::NoAnswer => None,
+    };
+
+    let mut detected = NetworkEnvironment {
+        proxy,
+        captive_portal,
+        internet_verified,
+        kill_switch_blocking: false,
+    };
+    let mut environment = environment().write().unwrap();
+    // Only `detect_for` knows about the kill switch; an answer settles it
+    detected.kill_switch_blocking = environment.kill_switch_blocking
+        && !detected.internet_verified
+        && detected.captive_portal.is_none();
+    // Waiting connects check every few seconds; one warning per portal is enough
+    if let Some(ref portal) = detected.captive_portal {
+        if environment.captive_portal.as_ref() != Some(portal) {
+            log::warn!("Captive portal detected: {}", portal);
+        }
+    }
+    *environment = detected.clone();
+    detected
+}
+
+/// `detect`, also telling whether `manager`'s kill switch is what keeps the
+/// check from getting an answer
+pub async fn detect_for(manager: &VpnHandle) -> NetworkEnvironment {
+    let mut detected = detect().await;
+    let unanswered = !detected.internet_verified && detected.captive_portal.is_none();
+    detected.kill_switch_blocking =
+        unanswered && manager.with(|vpn| vpn.kill_switch_engaged()).await;
+
+    let mut environment = environment().write().unwrap();
+    if detected.kill_switch_blocking && !environment.kill_switch_blocking {
+        log::warn!("No answer from the network with the kill switch engaged");
+        events::record(
+            EventCategory::Network,
+            Severity::Warning,
+            KILL_SWITCH_PORTAL_HINT,
+        );
+    }
+    environment.kill_switch_blocking = detected.kill_switch_blocking;
+    detected
+}
+
+/// Last detected environment, minus a kill switch that has been lifted since
+pub async fn current_for(manager: &VpnHandle) -> NetworkEnvironment {
+    let mut environment = current();
+    if environment.kill_switch_blocking {
+        environment.kill_switch_blocking = manager.with(|vpn| vpn.kill_switch_engaged()).await;
+    }
+    environment
+}
+
+/// Whether a dropped session should try to reconnect now: the internet is
+/// reachable, or the kill switch holds the check back too and only an
+/// attempt can tell
+pub async fn ready_to_reconnect(manager: VpnHandle) -> bool {
+    let detected = detect_for(&manager).await;
+    detected.internet_verified || detected.kill_switch_blocking
+}
+
+/// Hold an unattended connect, showing it as waiting for the network, until
+/// the internet is reachable
+///
+/// Fails once `limit` has passed, or if the user connects or disconnects
+/// meanwhile. A connect that doesn't follow should `release_network_hold`.
+pub async fn wait_for_internet(manager: &VpnHandle, limit: Duration) -> Result<(), String> {
+    let started = Instant::now();
+    let mut held = false;
+    while !ready_to_reconnect(manager.clone()).await {
+        if started.elapsed() >= limit {
+            manager
+                .call(|vpn| Box::pin(vpn.release_network_hold()))
+                .await;
+            return Err(match current().captive_portal {
+                Some(portal) => portal_message(&portal),
+                None => "This network doesn't reach the internet".to_string(),
+            });
+        }
+        if held {
+            if manager.with(|vpn| vpn.get_status()).await != VpnStatus::NoNetwork {
+                return Err("Stopped waiting for the network".to_string());
+            }
+        } else {
+            let holding = manager
+                .call(|vpn| Box::pin(async move { vpn.hold_for_network().await }))
+                .await;
+            // A tunnel is up or on its way; connecting says so
+            if !holding {
+                return Ok(());
+            }
+            log::info!("Waiting for the network to reach the internet before connecting");
+            held = true;
+        }
+        tokio::time::sleep(INTERNET_CHECK_INTERVAL).await;
+    }
+    Ok(())
+}
+
+/// HTTP client for API requests, going through the system proxy if there is one
+pub fn api_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().timeout(api_timeout());
+    if let Some(proxy) = current().proxy {
+        match reqwest::Proxy::all(&proxy) {
+            Ok(proxy) => builder = builder.proxy(proxy),
+            Err(e) => log::warn!("Ignoring system proxy {}: {}", proxy, e),
+        }
+    }
+    builder.build().unwrap_or_default()
+}
+
+/// Send an API request, explaining a failure in terms of the network when it can
+pub async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, String> {
+    match request.send().await {
+        Ok(response) => Ok(response),
+        Err(e) if e.is_connect() || e.is_timeout() => Err(explain(&e, &detect().await)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Time allowed for one API request
+pub fn api_timeout() -> Duration {
+    let secs = settings::current().api_timeout_secs;
+    Duration::from_secs(secs.clamp(1, MAX_API_TIMEOUT_SECS))
+}
+
+fn in_flight() -> &'static Mutex<HashMap<RequestKind, InFlight>> {
+    IN_FLIGHT.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Run an API call that a newer call of the same kind, or `cancel`, abandons
+///
+/// `timeout` can only shorten the configured API timeout, which bounds every
+/// request the call makes.
+pub async fn cancellable<T>(
+    kind: RequestKind,
+    timeout: Option<Duration>,
+    call: impl Future<Output = Result<T, String>>,
+) -> Result<T, String> {
+    let id = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+    let (cancel, cancelled) = oneshot::channel();
+    if let Some((_, previous)) = in_flight().lock().unwrap().insert(kind, (id, cancel)) {
+        log::info!("Cancelling {:?} request superseded by a new one", kind);
+        let _ = previous.send(());
+    }
+
+    let result = match timeout {
+        Some(timeout) => tokio::select! {
+            result = tokio::time::timeout(timeout, call) => result.unwrap_or_else(|_| {
+                Err(format!("The server did not answer within {:?}", timeout))
+            }),
+            _ = cancelled => Err(CANCELLED.to_string()),
+        },
+        None => tokio::select! {
+            result = call => result,
+            _ = cancelled => Err(CANCELLED.to_string()),
+        },
+    };
+
+    let mut in_flight = in_flight().lock().unwrap();
+    if in_flight
+        .get(&kind)
+        .is_some_and(|(current, _)| *current == id)
+    {
+        in_flight.remove(&kind);
+    }
+    result
+}
+
+/// Cancel the request of this kind in flight, returning whether there was one
+pub fn cancel(kind: RequestKind) -> bool {
+    let Some((_, cancel)) = in_flight().lock().unwrap().remove(&kind) else {
+        return false;
+    };
+    log::info!("Cancelling {:?} request", kind);
+    cancel.send(()).is_ok()
+}
+
+fn explain(error: &reqwest::Error, environment: &NetworkEnvironment) -> String {
+    match environment {
+        NetworkEnvironment {
+            captive_portal: Some(portal),
+            ..
+        } => portal_message(portal),
+        NetworkEnvironment {
+            proxy: Some(proxy), ..
+        } => format!(
+            "Could not reach the server through proxy {}: {}",
+            proxy, error
+        ),
+        _ => error.to_string(),
+    }
+}
+
+fn portal_message(portal: &str) -> String {
+    format!(
+        "This network requires signing in at {} before SACVPN can connect",
+        portal
+    )
+}
+
+/// Whether the portal check URL gets through, or the captive portal intercepting it
+async fn check_portal(proxy: Option<&str>) -> PortalCheck {
+    let mut builder = reqwest::Client::builder()
+        .timeout(PORTAL_CHECK_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(proxy) = proxy.and_then(|p| reqwest::Proxy::all(p).ok()) {
+        builder = builder.proxy(proxy);
+    }
+    // No answer at all means offline, not a portal
+    let Ok(client) = builder.build() else {
+        return PortalCheck::NoAnswer;
+    };
+    let Ok(response) = client.get(PORTAL_CHECK_URL).send().await else {
+        return PortalCheck::NoAnswer;
+    };
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NO_CONTENT {
+        return PortalCheck::Open;
+    }
+    let location = response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|value| value.to_str().ok());
+    PortalCheck::Portal(match location {
+        Some(location) if status.is_redirection() => location.to_string(),
+        _ => PORTAL_CHECK_URL.to_string(),
+    })
+}
+
+/// Proxy from the environment, then from the OS settings
+fn system_proxy() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+        .or_else(os_proxy)
+}
+
+#[cfg(target_os = "windows")]
+fn os_proxy() -> Option<String> {
+    let output = Command::new("reg")
+        .args([
+            "query",
+            r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings",
+        ])
+        .output()
+        .ok()?;
+    parse_internet_settings(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(target_os = "macos")]
+fn os_proxy() -> Option<String> {
+    let output = Command::new("scutil").arg("--proxy").output().ok()?;
+    parse_scutil_proxy(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Desktop environments on Linux export their proxy settings as environment variables
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn os_proxy() -> Option<String> {
+    None
+}
+
+/// Proxy from `reg query` of the Internet Settings key, when it is enabled
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn parse_internet_settings(output: &str) -> Option<String> {
+    let value = |name: &str| {
+        output.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some(name) {
+                return None;
+            }
+            // Name, type, data
+            fields.nth(1).map(String::from)
+        })
+    };
+    if value("ProxyEnable")? != "0x1" {
+        return None;
+    }
+
+    // Either one proxy for everything or per-protocol "http=host:port;https=host:port"
+    let server = value("ProxyServer")?;
+    let server = if server.contains('=') {
+        let entry = |scheme: &str| {
+            server
+                .split(';')
+                .find_map(|entry| entry.strip_prefix(scheme)?.strip_prefix('='))
+        };
+        entry("https").or_else(|| entry("http"))?
+    } else {
+        server.as_str()
+    };
+    Some(with_scheme(server))
+}
+
+/// HTTPS (or else HTTP) proxy from `scutil --proxy`, when it is enabled
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_scutil_proxy(output: &str) -> Option<String> {
+    let value = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.split_once(" : ")?;
+            (key.trim() == name).then(|| value.trim().to_string())
+        })
+    };
+    ["HTTPS", "HTTP"].into_iter().find_map(|protocol| {
+        if value(&format!("{}Enable", protocol))? != "1" {
+            return None;
+        }
+        let host = value(&format!("{}Proxy", protocol))?;
+        let port = value(&format!("{}Port", protocol))?;
+        Some(with_scheme(&format!("{}:{}", host, port)))
+    })
+}
+
+fn with_scheme(proxy: &str) -> String {
+    if proxy.contains("://") {
+        proxy.to_string()
+    } else {
+        format!("http://{}", proxy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_internet_settings() {
+        let output = "\r\nHKEY_CURRENT_USER\\...\\Internet Settings\r\n\
+                      \x20   ProxyEnable    REG_DWORD    0x1\r\n\
+                      \x20   ProxyServer    REG_SZ    \
+                      http=plain.corp:80;https=secure.corp:8443\r\n";
+        assert_eq!(
+            parse_internet_settings(output),
+            Some("http://secure.corp:8443".to_string())
+        );
+
+        let disabled = output.replace("0x1", "0x0");
+        assert_eq!(parse_internet_settings(&disabled), None);
+    }
+
+    #[test]
+    fn test_parse_scutil_proxy() {
+        let output = "<dictionary> {\n  HTTPEnable : 1\n  HTTPPort : 3128\n  \
+                      HTTPProxy : proxy.example.com\n  HTTPSEnable : 0\n}\n";
+        assert_eq!(
+            parse_scutil_proxy(output),
+            Some("http://proxy.example.com:3128".to_string())
+        );
+        assert_eq!(parse_scutil_proxy("<dictionary> {\n}\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_newer_request_cancels_older() {
+        let hung = tokio::spawn(cancellable(RequestKind::Servers, None, async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let newer = cancellable(RequestKind::Servers, None, async { Ok(7) }).await;
+        assert_eq!(newer, Ok(7));
+        assert_eq!(hung.await.unwrap(), Err(CANCELLED.to_string()));
+        assert!(!cancel(RequestKind::Servers));
+
+        let slow = cancellable(
+            RequestKind::Config,
+            Some(Duration::from_millis(10)),
+            std::future::pending::<Result<(), String>>(),
+        );
+        assert!(slow
+            .await
+            .unwrap_err()
+            .starts_with("The server did not answer"));
+    }
+}
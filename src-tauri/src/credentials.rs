@@ -0,0 +1,78 @@
+//! Account credentials kept in the OS keyring
+//!
+//! The bearer token is written to the keyring once at login and stays in the
+//! backend; commands look it up here instead of receiving it from the webview.
+
+use crate::storage;
+use serde::{Deserialize, Serialize};
+use std::sync::{OnceLock, RwLock};
+
+const KEYRING_SERVICE: &str = "sacvpn";
+const ACCOUNT_FILE: &str = "account.json";
+
+/// The signed-in account; the token itself only lives in the keyring
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Account {
+    email: Option<String>,
+}
+
+static ACCOUNT: OnceLock<RwLock<Account>> = OnceLock::new();
+
+fn account() -> &'static RwLock<Account> {
+    ACCOUNT.get_or_init(|| RwLock::new(storage::load(ACCOUNT_FILE)))
+}
+
+fn entry(email: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(KEYRING_SERVICE, email).map_err(|e| e.to_string())
+}
+
+/// Store the token for an account and make it the active one
+pub fn store(email: &str, token: &str) -> Result<(), String> {
+    entry(email)?
+        .set_password(token)
+        .map_err(|e| e.to_string())?;
+
+    let mut guard = account().write().unwrap();
+    guard.email = Some(email.to_string());
+    storage::save(ACCOUNT_FILE, &*guard)
+}
+
+/// Whether a token is stored for the account
+pub fn has(email: &str) -> bool {
+    entry(email)
+        .and_then(|e| e.get_password().map_err(|e| e.to_string()))
+        .is_ok()
+}
+
+/// Remove the account's token, signing it out if it was active
+pub fn clear(email: &str) -> Result<(), String> {
+    entry(email)?
+        .delete_credential()
+        .map_err(|e| e.to_string())?;
+
+    let mut guard = account().write().unwrap();
+    if guard.email.as_deref() == Some(email) {
+        guard.email = None;
+        storage::save(ACCOUNT_FILE, &*guard)?;
+    }
+    Ok(())
+}
+
+/// Token of the active account
+pub fn token() -> Result<String, String> {
+    let email = account()
+        .read()
+        .unwrap()
+        .email
+        .clone()
+        .ok_or_else(|| "Not signed in".to_string())?;
+    entry(&email)?.get_password().map_err(|e| e.to_string())
+}
+
+/// Use an explicitly passed token, falling back to the stored one
+pub fn resolve(token: Option<String>) -> Result<String, String> {
+    match token {
+        Some(token) => Ok(token),
+        None => self::token(),
+    }
+}
@@ -0,0 +1,235 @@
+//! Cron expressions evaluated in a time zone
+//!
+//! Five fields (minute, hour, day of month, month, day of week) taking `*`,
+//! ranges, steps, lists and three-letter month and day names, plus the
+//! `@hourly`, `@daily`, `@weekly` and `@monthly` shorthands. Fields are matched
+//! against the wall clock of the zone, so a 09:00 schedule stays at 09:00
+//! across DST changes. A time skipped when the clocks go forward fires as they
+//! jump past it; a time repeated when they go back fires the first time only.
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use std::str::FromStr;
+
+/// Days searched for the next match; February 29 comes round within eight years
+const SEARCH_DAYS: usize = 8 * 366;
+
+/// Longest clock jump a skipped time is carried over
+const MAX_GAP_MINUTES: i64 = 3 * 60;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is bit 0
+    weekdays: u64,
+    /// Day of month was `*`; cron matches either day field when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, String> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "Expected 5 fields (minute hour day month weekday), got {}",
+                fields.len()
+            ));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7, &WEEKDAYS)?;
+        // 7 is another name for Sunday
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: parse_field(day, 1, 31, &[])?,
+            months: parse_field(month, 1, 12, &MONTHS)?,
+            weekdays,
+            any_day: day.starts_with('*'),
+            any_weekday: weekday.starts_with('*'),
+        })
+    }
+}
+
+impl CronExpr {
+    /// First time after `after` matching the expression on the wall clock of `zone`
+    pub fn next_after<Z: TimeZone>(
+        &self,
+        after: &DateTime<Utc>,
+        zone: &Z,
+    ) -> Option<DateTime<Utc>> {
+        // The zone's date may lag the UTC one, so start the day before
+        let start = after.with_timezone(zone).date_naive().pred_opt()?;
+        for date in start.iter_days().take(SEARCH_DAYS) {
+            if !self.matches_date(date) {
+                continue;
+            }
+            for hour in bits(self.hours) {
+                for minute in bits(self.minutes) {
+                    let Some(at) = date
+                        .and_hms_opt(hour, minute, 0)
+                        .and_then(|local| resolve(zone, local))
+                    else {
+                        continue;
+                    };
+                    if at > *after {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !has(self.months, date.month()) {
+            return false;
+        }
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        if self.any_day || self.any_weekday {
+            day && weekday
+        } else {
+            day || weekday
+        }
+    }
+}
+
+/// The instant `local` names in `zone`: the earlier one when the clocks went
+/// back, and the end of the jump when they went forward past it
+fn resolve<Z: TimeZone>(zone: &Z, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    (0..=MAX_GAP_MINUTES)
+        .find_map(|minutes| {
+            zone.from_local_datetime(&(local + TimeDelta::minutes(minutes)))
+                .earliest()
+        })
+        .map(|at| at.with_timezone(&Utc))
+}
+
+/// Bit set of the values a field matches, `names[i]` standing for `min + i`
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |text: &str| {
+        names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(text))
+            .map(|index| min + index as u32)
+            .or_else(|| text.parse().ok())
+            .filter(|value| (min..=max).contains(value))
+            .ok_or_else(|| format!("Invalid value '{}' (expected {}-{})", text, min, max))
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<usize>()
+                    .ok()
+                    .filter(|&step| step > 0)
+                    .ok_or_else(|| format!("Invalid step in '{}'", part))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `5/15` runs from 5 to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("Invalid range '{}'", range));
+        }
+        for value in (start..=end).step_by(step) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & 1 << value != 0
+}
+
+fn bits(set: u64) -> impl Iterator<Item = u32> {
+    (0..64).filter(move |&value| has(set, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::America::New_York;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let expr: CronExpr = "*/15 9-17 * jan,jul mon-fri".parse().unwrap();
+        assert_eq!(bits(expr.minutes).collect::<Vec<_>>(), [0, 15, 30, 45]);
+        assert_eq!(bits(expr.months).collect::<Vec<_>>(), [1, 7]);
+        assert_eq!(bits(expr.weekdays).collect::<Vec<_>>(), [1, 2, 3, 4, 5]);
+
+        let sunday: CronExpr = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday, "@weekly".parse().unwrap());
+
+        assert!("0 0 * *".parse::<CronExpr>().is_err());
+        assert!("60 0 * * *".parse::<CronExpr>().is_err());
+        assert!("0 0 * * 1/0".parse::<CronExpr>().is_err());
+    }
+
+    #[test]
+    fn test_next_after_follows_the_wall_clock_across_dst() {
+        let daily: CronExpr = "0 9 * * *".parse().unwrap();
+        // After 10:00 EDT on Saturday the next 09:00 is EST, the clocks having gone back
+        let next = daily.next_after(&utc("2026-10-31T14:00:00Z"), &New_York);
+        assert_eq!(next, Some(utc("2026-11-01T14:00:00Z")));
+
+        // 02:30 doesn't exist on the spring-forward day and fires at the jump to 03:00 EDT
+        let skipped: CronExpr = "30 2 * * *".parse().unwrap();
+        let next = skipped.next_after(&utc("2026-03-08T00:00:00Z"), &New_York);
+        assert_eq!(next, Some(utc("2026-03-08T07:00:00Z")));
+
+        // 01:30 happens twice when the clocks go back; only the first one fires
+        let repeated: CronExpr = "30 1 * * *".parse().unwrap();
+        let first = repeated.next_after(&utc("2026-11-01T00:00:00Z"), &New_York);
+        assert_eq!(first, Some(utc("2026-11-01T05:30:00Z")));
+        let second = repeated.next_after(&first.unwrap(), &New_York);
+        assert_eq!(second, Some(utc("2026-11-02T06:30:00Z")));
+    }
+
+    #[test]
+    fn test_restricted_day_fields_match_either() {
+        // The 13th, or any Friday
+        let expr: CronExpr = "0 0 13 * fri".parse().unwrap();
+        let next = expr.next_after(&utc("2026-10-16T12:00:00Z"), &Utc);
+        assert_eq!(next, Some(utc("2026-10-23T00:00:00Z")));
+
+        let leap: CronExpr = "0 0 29 2 *".parse().unwrap();
+        let next = leap.next_after(&utc("2026-10-16T12:00:00Z"), &Utc);
+        assert_eq!(next, Some(utc("2028-02-29T00:00:00Z")));
+
+        let never: CronExpr = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(never.next_after(&utc("2026-10-16T12:00:00Z"), &Utc), None);
+    }
+}
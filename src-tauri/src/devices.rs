@@ -0,0 +1,164 @@
+//! Devices signed in to the account and its simultaneous connection limit
+//!
+//! Config generation fails once the account has as many devices connected as
+//! its plan allows. That failure is reported as a device limit error so the UI
+//! can list the other devices and let the user disconnect one instead of
+//! showing a generic API error. A request the API refuses outright because
+//! the device or its sign-in was revoked is reported as such, so a dropped
+//! session isn't retried with a peer the server will never answer again.
+
+use crate::{connectivity, signing};
+use serde::{Deserialize, Serialize};
+
+/// Error code the API uses when the account has no connection slot left
+const LIMIT_CODE: &str = "device_limit_reached";
+
+/// Start of the message for a request refused with 401 or 403
+const REVOKED_PREFIX: &str = "Access revoked";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub platform: Option<String>,
+    /// Unix timestamp of the device's last connection
+    #[serde(default)]
+    pub last_seen: Option<i64>,
+    /// Whether the device holds a connection slot right now
+    #[serde(default)]
+    pub connected: bool,
+    /// Whether this is the device making the request
+    #[serde(default)]
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceList {
+    /// Devices that may be connected at once, `None` for no limit
+    #[serde(default)]
+    pub limit: Option<u32>,
+    pub devices: Vec<Device>,
+}
+
+/// Error body of a failed API request
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ApiError {
+    code: Option<String>,
+    limit: Option<u32>,
+}
+
+/// Fetch the account's devices and its connection limit
+pub async fn list(api_url: &str, token: &str) -> Result<DeviceList, String> {
+    let client = connectivity::api_client();
+    let response = connectivity::send(
+        client
+            .get(format!("{}/api/vpn/devices", api_url))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(revoked_error(status).unwrap_or_else(|| format!("API error: {}", status)));
+    }
+    response.json().await.map_err(|e| e.to_string())
+}
+
+/// Whether the API refuses this device's sign-in; a request that fails for
+/// any other reason, as when offline, says it doesn't
+pub async fn revoked(api_url: &str, token: &str) -> bool {
+    matches!(list(api_url, token).await, Err(e) if is_revoked(&e))
+}
+
+/// Disconnect another device, freeing its connection slot
+pub async fn disconnect(api_url: &str, token: &str, device_id: &str) -> Result<(), String> {
+    // The id goes into the URL path
+    if !device_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("Invalid device id: {}", device_id));
+    }
+    let path = format!("/api/vpn/devices/{}/disconnect", device_id);
+    let client = connectivity::api_client();
+    let mut request = client
+        .post(format!("{}{}", api_url, path))
+        .header("Authorization", format!("Bearer {}", token));
+    for (name, value) in signing::sign("POST", &path, b"")? {
+        request = request.header(name, value);
+    }
+
+    let response = connectivity::send(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("API error: {}", response.status()));
+    }
+    log::info!("Disconnected device {}", device_id);
+    Ok(())
+}
+
+/// Message for a failed config request that hit the device limit, if it did
+pub fn limit_error(status: reqwest::StatusCode, body: &str) -> Option<String> {
+    if status.is_success() {
+        return None;
+    }
+    let error: ApiError = serde_json::from_str(body).unwrap_or_default();
+    if error.code.as_deref() != Some(LIMIT_CODE) {
+        return None;
+    }
+    // The "device limit" wording is what error classification keys on
+    Some(match error.limit {
+        Some(limit) => format!(
+            "Device limit reached: your plan allows {} devices connected at once",
+            limit
+        ),
+        None => "Device limit reached: too many devices are connected".to_string(),
+    })
+}
+
+/// Message for a request the API refused because the account no longer
+/// accepts this device, if it did
+pub fn revoked_error(status: reqwest::StatusCode) -> Option<String> {
+    if status != reqwest::StatusCode::UNAUTHORIZED && status != reqwest::StatusCode::FORBIDDEN {
+        return None;
+    }
+    // The "revoked" wording is what error classification keys on
+    Some(format!(
+        "{}: the account no longer accepts this device ({})",
+        REVOKED_PREFIX, status
+    ))
+}
+
+/// Whether a failed request's message says the device was revoked
+pub fn is_revoked(message: &str) -> bool {
+    message.starts_with(REVOKED_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_limit_error() {
+        let body = r#"{"error": "Too many devices", "code": "device_limit_reached", "limit": 5}"#;
+        assert_eq!(
+            limit_error(StatusCode::FORBIDDEN, body).as_deref(),
+            Some("Device limit reached: your plan allows 5 devices connected at once")
+        );
+        assert!(limit_error(StatusCode::CONFLICT, r#"{"code": "device_limit_reached"}"#).is_some());
+        assert!(limit_error(StatusCode::FORBIDDEN, r#"{"code": "plan_expired"}"#).is_none());
+        assert!(limit_error(StatusCode::BAD_GATEWAY, "<html>").is_none());
+    }
+
+    #[test]
+    fn test_revoked_error() {
+        assert_eq!(
+            revoked_error(StatusCode::UNAUTHORIZED).as_deref(),
+            Some("Access revoked: the account no longer accepts this device (401 Unauthorized)")
+        );
+        assert!(revoked_error(StatusCode::FORBIDDEN).is_some_and(|e| is_revoked(&e)));
+        assert!(revoked_error(StatusCode::BAD_GATEWAY).is_none());
+    }
+}
@@ -0,0 +1,497 @@
+//! Connection diagnostics and benchmarks
+//!
+//! Every probe is pinned to the interface it is meant to measure, lookups of
+//! its hostnames included, so running a diagnostic never sends anything out
+//! of another interface. Reports name the interface that was used.
+
+use crate::vpn::scoped::ProbeScope;
+use crate::vpn::{dns, VpnHandle};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Payload downloaded through the tunnel to measure throughput
+const THROUGHPUT_URL: &str = "https://speed.cloudflare.com/__down?bytes=5000000";
+
+/// MTU values tried by the benchmark, smallest first
+const MTU_CANDIDATES: [u32; 5] = [1280, 1320, 1360, 1400, 1420];
+
+/// Default WireGuard MTU when the config doesn't set one
+const DEFAULT_MTU: u32 = 1420;
+
+/// Names resolved by the DNS benchmark
+const DNS_TEST_NAMES: [&str; 5] = [
+    "google.com",
+    "cloudflare.com",
+    "wikipedia.org",
+    "amazon.com",
+    "github.com",
+];
+
+/// How long a single interface probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS-over-HTTPS resolvers included in the benchmark (JSON API endpoints)
+const DOH_RESOLVERS: [(&str, &str); 2] = [
+    ("Cloudflare DoH", "https://cloudflare-dns.com/dns-query"),
+    ("Google DoH", "https://dns.google/resolve"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MtuResult {
+    pub mtu: u32,
+    pub throughput_bps: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MtuBenchmarkReport {
+    pub results: Vec<MtuResult>,
+    pub best_mtu: Option<u32>,
+    pub original_mtu: u32,
+    /// Interface the downloads were pinned to
+    pub interface: String,
+}
+
+/// Measure download throughput at several MTU values and report the fastest
+///
+/// The original MTU is restored afterwards; callers decide whether to keep the
+/// best value as an override.
+pub async fn benchmark_mtu(manager: &VpnHandle) -> Result<MtuBenchmarkReport, String> {
+    let original_mtu = manager
+        .call(|vpn| Box::pin(vpn.get_config()))
+        .await
+        .ok_or_else(|| "Not connected".to_string())?
+        .interface
+        .mtu
+        .unwrap_or(DEFAULT_MTU);
+
+    let scope = probe_scope(manager, "tunnel").await?;
+    let client = scoped_client(&scope, THROUGHPUT_URL, Duration::from_secs(20)).await?;
+
+    let mut results = Vec::new();
+    for mtu in MTU_CANDIDATES {
+        if let Err(e) = manager.call(move |vpn| Box::pin(vpn.set_mtu(mtu))).await {
+            results.push(MtuResult {
+                mtu,
+                throughput_bps: None,
+                error: Some(e.to_string()),
+            });
+            continue;
+        }
+
+        // Give the interface a moment to settle before measuring
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let result = match measure_throughput(&client).await {
+            Ok(bps) => MtuResult {
+                mtu,
+                throughput_bps: Some(bps),
+                error: None,
+            },
+            Err(e) => MtuResult {
+                mtu,
+                throughput_bps: None,
+                error: Some(e),
+            },
+        };
+        log::info!("MTU {}: {:?}", mtu, result.throughput_bps);
+        results.push(result);
+    }
+
+    if let Err(e) = manager
+        .call(move |vpn| Box::pin(vpn.set_mtu(original_mtu)))
+        .await
+    {
+        log::warn!("Failed to restore MTU {}: {}", original_mtu, e);
+    }
+
+    let best_mtu = results
+        .iter()
+        .filter_map(|r| r.throughput_bps.map(|bps| (r.mtu, bps)))
+        .max_by_key(|(_, bps)| *bps)
+        .map(|(mtu, _)| mtu);
+
+    Ok(MtuBenchmarkReport {
+        results,
+        best_mtu,
+        original_mtu,
+        interface: scope.interface,
+    })
+}
+
+/// Download the test payload and return bytes per second
+pub async fn measure_throughput(client: &reqwest::Client) -> Result<u64, String> {
+    let start = Instant::now();
+    let response = client
+        .get(THROUGHPUT_URL)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let bytes = response.bytes().await.map_err(|e| e.to_string())?;
+
+    let elapsed = start.elapsed().as_secs_f64().max(0.001);
+    Ok((bytes.len() as f64 / elapsed) as u64)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverKind {
+    Tunnel,
+    Custom,
+    Doh,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolverResult {
+    pub name: String,
+    pub address: String,
+    pub kind: ResolverKind,
+    pub avg_latency_ms: Option<u32>,
+    pub failure_rate: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsBenchmarkReport {
+    /// Resolvers ordered best first
+    pub results: Vec<ResolverResult>,
+    /// Fastest working plain resolver that can be applied as custom DNS
+    pub recommended: Option<String>,
+    /// Interface every query was pinned to
+    pub interface: String,
+}
+
+/// Measure resolution latency and failure rate for the tunnel, custom and DoH
+/// resolvers, querying each through the tunnel
+pub async fn benchmark_dns(
+    manager: &VpnHandle,
+    custom_dns: &[String],
+) -> Result<DnsBenchmarkReport, String> {
+    let scope = probe_scope(manager, "tunnel").await?;
+    let tunnel_dns: Vec<String> = scope.dns.iter().map(ToString::to_string).collect();
+    let mut results = Vec::new();
+
+    for server in &tunnel_dns {
+        results.push(benchmark_plain(&scope, server, ResolverKind::Tunnel).await);
+    }
+    for server in custom_dns.iter().filter(|s| !tunnel_dns.contains(s)) {
+        results.push(benchmark_plain(&scope, server, ResolverKind::Custom).await);
+    }
+    for (name, url) in DOH_RESOLVERS {
+        results.push(benchmark_doh(&scope, name, url).await);
+    }
+
+    results.sort_by(|a, b| {
+        a.failure_rate.total_cmp(&b.failure_rate).then(
+            a.avg_latency_ms
+                .unwrap_or(u32::MAX)
+                .cmp(&b.avg_latency_ms.unwrap_or(u32::MAX)),
+        )
+    });
+
+    let recommended = results
+        .iter()
+        .find(|r| r.kind != ResolverKind::Doh && r.failure_rate < 1.0)
+        .map(|r| r.address.clone());
+
+    Ok(DnsBenchmarkReport {
+        results,
+        recommended,
+        interface: scope.interface,
+    })
+}
+
+async fn benchmark_plain(scope: &ProbeScope, server: &str, kind: ResolverKind) -> ResolverResult {
+    let addr = server
+        .parse::<IpAddr>()
+        .ok()
+        .map(|ip| SocketAddr::new(ip, 53));
+
+    let mut timings = Vec::new();
+    for name in DNS_TEST_NAMES {
+        let Some(addr) = addr else { break };
+        let start = Instant::now();
+        let query = scope
+            .query(addr, name, dns::TYPE_A, Duration::from_secs(2))
+            .await;
+        if matches!(query, Ok(ref response) if response.rcode == 0 && !response.answers.is_empty())
+        {
+            timings.push(start.elapsed());
+        }
+    }
+
+    summarize(server.to_string(), server.to_string(), kind, &timings)
+}
+
+async fn benchmark_doh(scope: &ProbeScope, name: &str, url: &str) -> ResolverResult {
+    let mut timings = Vec::new();
+    if let Ok(client) = scoped_client(scope, url, Duration::from_secs(3)).await {
+        for test_name in DNS_TEST_NAMES {
+            let start = Instant::now();
+            let response = client
+                .get(url)
+                .query(&[("name", test_name), ("type", "A")])
+                .header("Accept", "application/dns-json")
+                .send()
+                .await;
+            if matches!(response, Ok(ref r) if r.status().is_success()) {
+                timings.push(start.elapsed());
+            }
+        }
+    }
+
+    summarize(
+        name.to_string(),
+        url.to_string(),
+        ResolverKind::Doh,
+        &timings,
+    )
+}
+
+fn summarize(
+    name: String,
+    address: String,
+    kind: ResolverKind,
+    timings: &[Duration],
+) -> ResolverResult {
+    let total = DNS_TEST_NAMES.len() as f32;
+    let avg_latency_ms = (!timings.is_empty()).then(|| {
+        (timings.iter().map(|d| d.as_millis() as u64).sum::<u64>() / timings.len() as u64) as u32
+    });
+
+    ResolverResult {
+        name,
+        address,
+        kind,
+        avg_latency_ms,
+        failure_rate: (total - timings.len() as f32) / total,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeMethod {
+    Http,
+    Icmp,
+}
+
+impl ProbeMethod {
+    /// URLs are fetched over HTTP, bare hosts and addresses are pinged
+    fn for_target(target: &str) -> Self {
+        if target.starts_with("http://") || target.starts_with("https://") {
+            Self::Http
+        } else {
+            Self::Icmp
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InterfaceProbe {
+    pub interface: String,
+    /// OS interface the probe was pinned to
+    pub bound_interface: String,
+    /// Local address the probe was bound to
+    pub local_address: String,
+    pub method: ProbeMethod,
+    pub reachable: bool,
+    pub latency_ms: Option<u32>,
+    /// Response status of an HTTP probe
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Reach a URL or host through one interface only
+///
+/// `interface` is "tunnel", "physical" or a local IP address. Comparing a
+/// tunnel probe with a physical one tells a broken tunnel apart from a broken
+/// internet connection.
+pub async fn probe_via_interface(
+    manager: &VpnHandle,
+    interface: &str,
+    target: &str,
+) -> Result<InterfaceProbe, String> {
+    let scope = probe_scope(manager, interface).await?;
+    let local = scope.address;
+    let method = ProbeMethod::for_target(target);
+
+    let mut probe = InterfaceProbe {
+        interface: interface.to_string(),
+        bound_interface: scope.interface.clone(),
+        local_address: local.to_string(),
+        method,
+        reachable: false,
+        latency_ms: None,
+        status: None,
+        error: None,
+    };
+
+    match method {
+        ProbeMethod::Http => {
+            let client = scoped_client(&scope, target, PROBE_TIMEOUT).await?;
+            let start = Instant::now();
+            match client.get(target).send().await {
+                Ok(response) => {
+                    // Any response proves the path works, whatever the status
+                    probe.reachable = true;
+                    probe.latency_ms = Some(start.elapsed().as_millis() as u32);
+                    probe.status = Some(response.status().as_u16());
+                }
+                Err(e) => probe.error = Some(e.to_string()),
+            }
+        }
+        ProbeMethod::Icmp => match ping(&scope, target).await {
+            Ok(latency_ms) => {
+                probe.reachable = true;
+                probe.latency_ms = latency_ms;
+            }
+            Err(e) => probe.error = Some(e),
+        },
+    }
+
+    log::info!(
+        "Probe of {} via {} ({}, {}): {}",
+        target,
+        interface,
+        scope.interface,
+        local,
+        if probe.reachable {
+            "reachable"
+        } else {
+            "unreachable"
+        }
+    );
+    Ok(probe)
+}
+
+/// The interface `interface` names, which a probe may only send through
+async fn probe_scope(manager: &VpnHandle, interface: &str) -> Result<ProbeScope, String> {
+    let interface = interface.to_string();
+    manager
+        .call(move |vpn| Box::pin(async move { vpn.probe_scope(&interface).await }))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// HTTP client for `url` that can only connect through `scope`'s interface,
+/// with the URL's host looked up through it too
+async fn scoped_client(
+    scope: &ProbeScope,
+    url: &str,
+    timeout: Duration,
+) -> Result<reqwest::Client, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("No host in {}", url))?;
+    let address = scope.resolve(host).await.map_err(|e| e.to_string())?;
+
+    // The port of an overridden address is ignored in favour of the URL's
+    let builder = reqwest::Client::builder()
+        .local_address(scope.address)
+        .resolve(host, SocketAddr::new(address, 0))
+        .timeout(timeout);
+    // Windows sends from the interface that owns the source address
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let builder = builder.interface(&scope.interface);
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Ping `host` once through `scope`, returning the round trip time if ping reported one
+async fn ping(scope: &ProbeScope, host: &str) -> Result<Option<u32>, String> {
+    // Looked up here, as ping's own lookup could go out of any interface
+    let target = scope
+        .resolve(host)
+        .await
+        .map_err(|e| e.to_string())?
+        .to_string();
+    #[cfg(any(target_os = "windows", target_os = "macos"))]
+    let source = scope.address.to_string();
+    let mut command = tokio::process::Command::new("ping");
+
+    #[cfg(target_os = "windows")]
+    command.args([
+        "-n",
+        "1",
+        "-w",
+        &PROBE_TIMEOUT.as_millis().to_string(),
+        "-S",
+        &source,
+        &target,
+    ]);
+    #[cfg(target_os = "macos")]
+    command.args([
+        "-c",
+        "1",
+        "-t",
+        &PROBE_TIMEOUT.as_secs().to_string(),
+        "-b",
+        &scope.interface,
+        "-S",
+        &source,
+        &target,
+    ]);
+    // -I with an interface name binds the socket to that device
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    command.args([
+        "-c",
+        "1",
+        "-W",
+        &PROBE_TIMEOUT.as_secs().to_string(),
+        "-I",
+        &scope.interface,
+        &target,
+    ]);
+
+    let output = command
+        .output()
+        .await
+        .map_err(|e| format!("Failed to run ping: {}", e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let detail = stdout.lines().rev().find(|l| !l.trim().is_empty());
+        return Err(format!(
+            "No reply from {}{}",
+            host,
+            detail
+                .map(|l| format!(" ({})", l.trim()))
+                .unwrap_or_default()
+        ));
+    }
+    Ok(ping_time_ms(&stdout))
+}
+
+/// Round trip time from ping output ("time=12.3 ms", "time=12ms" or "time<1ms")
+fn ping_time_ms(output: &str) -> Option<u32> {
+    let start = output.find("time=").or_else(|| output.find("time<"))? + 5;
+    let value: String = output[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    value.parse::<f64>().ok().map(|ms| ms.round() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_method_and_ping_time() {
+        assert_eq!(
+            ProbeMethod::for_target("https://example.com"),
+            ProbeMethod::Http
+        );
+        assert_eq!(ProbeMethod::for_target("1.1.1.1"), ProbeMethod::Icmp);
+
+        assert_eq!(
+            ping_time_ms("64 bytes from 1.1.1.1: icmp_seq=0 ttl=57 time=12.6 ms"),
+            Some(13)
+        );
+        assert_eq!(
+            ping_time_ms("Reply from 1.1.1.1: bytes=32 time<1ms TTL=57"),
+            Some(1)
+        );
+        assert_eq!(ping_time_ms("Request timed out."), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Automatic failover to alternative servers
+//!
+//! When the selected server keeps failing, try the next recommended servers in
+//! the same country, emitting an event for every attempt so the UI can follow.
+//! When every connect rotates, the selected server may first give way to the
+//! next one in its country's rotation.
+
+use crate::servers::{self, Server};
+use crate::vpn::{ErrorCode, ErrorReport, VpnError, VpnHandle};
+use crate::{
+    clock, configdiff, connectivity, leakwatch, linktune, policy, prewarm, reputation, rotation,
+    settings,
+};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter};
+
+pub const FAILOVER_EVENT: &str = "vpn://failover";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailoverAttempt {
+    pub server_id: String,
+    pub server_name: String,
+    /// Position in the candidate list, 0 being the server the user selected
+    pub candidate: usize,
+    pub attempt: u32,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+/// Selected server first, followed by up to `count` recommended servers in its country
+pub fn candidates(server_id: &str, count: usize) -> Vec<Server> {
+    let Some(selected) = servers::find(server_id) else {
+        return Vec::new();
+    };
+
+    let alternatives = servers::recommend(Some(&selected.country_code))
+        .into_iter()
+        .filter(|s| s.id != server_id)
+        .take(count);
+
+    std::iter::once(selected).chain(alternatives).collect()
+}
+
+/// Connect to the selected server, failing over to alternatives if allowed
+///
+/// Returns the id of the server that was actually connected.
+pub async fn connect(
+    app: &AppHandle,
+    manager: &VpnHandle,
+    api_url: &str,
+    token: &str,
+    server_id: &str,
+    profile: Option<&str>,
+) -> Result<String, ErrorReport> {
+    // Refused outright rather than failed over, so the user sees why
+    policy::check_server(server_id).map_err(VpnError::ConfigError)?;
+    servers::check_available(server_id)?;
+    let server_id = &rotation::on_connect(server_id);
+
+    let app_settings = settings::current();
+    let failover = &app_settings.failover;
+    let policy = app_settings.session_policy(profile);
+
+    let (extra, attempts) = if failover.enabled {
+        (failover.max_servers, failover.attempts_per_server.max(1))
+    } else {
+        (0, 1)
+    };
+    let list = candidates(server_id, extra);
+    if list.is_empty() {
+        return Err(VpnError::ConfigError(format!("Unknown server: {}", server_id)).into());
+    }
+    leakwatch::sample_isp(manager).await;
+
+    let mut last_error = None;
+    for (candidate, server) in list.into_iter().enumerate() {
+        if let Err(e) = policy::check_server(&server.id) {
+            log::warn!("{}", e);
+            last_error = Some(VpnError::ConfigError(e).into());
+            continue;
+        }
+        for attempt in 1..=attempts {
+            log::info!(
+                "Connecting to {} (candidate {}, attempt {})",
+                server.name,
+                candidate,
+                attempt
+            );
+
+            let config = match prewarm::take_config(&server.id) {
+                Some(config) => Ok(config),
+                None => {
+                    connectivity::cancellable(
+                        connectivity::RequestKind::Config,
+                        None,
+                        servers::generate_config(api_url, token, &server.id),
+                    )
+                    .await
+                }
+            };
+            let result = match config {
+                Ok(mut config) => {
+                    if let Some(diff) = configdiff::check(&server.id, &config) {
+                        let _ = app.emit(configdiff::CONFIG_CHANGED_EVENT, diff);
+                    }
+                    app_settings.apply_server_overrides(&server.id, &mut config);
+                    let (server_id, policy) = (server.id.clone(), policy.clone());
+                    manager
+                        .call(move |vpn| {
+                            Box::pin(async move { vpn.connect(&server_id, config, policy).await })
+                        })
+                        .await
+                        .map_err(ErrorReport::from)
+                }
+                // A newer connect took over; it does its own failover
+                Err(e) if e == connectivity::CANCELLED => {
+                    return Err(VpnError::ConfigError(e).into())
+                }
+                Err(e) => Err(VpnError::ConfigError(e).into()),
+            };
+
+            let _ = app.emit(
+                FAILOVER_EVENT,
+                FailoverAttempt {
+                    server_id: server.id.clone(),
+                    server_name: server.name.clone(),
+                    candidate,
+                    attempt,
+                    succeeded: result.is_ok(),
+                    error: result.as_ref().err().map(|e| e.message.clone()),
+                },
+            );
+
+            match result {
+                Ok(()) => {
+                    servers::record_usage(&server.id);
+                    reputation::spawn_check(app, &server.id);
+                    linktune::spawn_measure(manager, &server.id);
+                    return Ok(server.id);
+                }
+                // Every server counts against the same limit and the same account
+                Err(e)
+                    if matches!(
+                        e.code,
+                        ErrorCode::DeviceLimitReached | ErrorCode::AccessRevoked
+                    ) =>
+                {
+                    return Err(e)
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+    }
+
+    let error = last_error.unwrap_or_else(|| VpnError::NotConnected.into());
+    Err(clock::explain(error).await)
+}
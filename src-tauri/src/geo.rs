@@ -0,0 +1,140 @@
+//! Approximate locations
+//!
+//! Distances to servers are measured from where the system time zone places
+//! the user, not from a geolocation lookup, which would see the tunnel's exit
+//! while connected and leak the real address otherwise. Zones and server
+//! cities are placed by the table in `data/locations.tsv`; a city it doesn't
+//! list is placed at its country's zone when the country has only one.
+
+use std::sync::OnceLock;
+
+const LOCATIONS: &str = include_str!("../data/locations.tsv");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Coordinates {
+    /// Great-circle distance to `other` in kilometres
+    pub fn distance_km(&self, other: &Coordinates) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+struct Location {
+    /// A time zone such as `America/New_York`, or a city
+    name: &'static str,
+    country: &'static str,
+    coordinates: Coordinates,
+}
+
+impl Location {
+    fn is_zone(&self) -> bool {
+        self.name.contains('/')
+    }
+
+    /// The city, which for a zone is its last part with spaces for underscores
+    fn city(&self) -> String {
+        self.name
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .replace('_', " ")
+    }
+}
+
+static TABLE: OnceLock<Vec<Location>> = OnceLock::new();
+
+fn table() -> &'static [Location] {
+    TABLE.get_or_init(|| {
+        LOCATIONS
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .skip(1)
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let name = fields.next()?;
+                let country = fields.next()?;
+                let latitude = fields.next()?.parse().ok()?;
+                let longitude = fields.next()?.parse().ok()?;
+                Some(Location {
+                    name,
+                    country,
+                    coordinates: Coordinates {
+                        latitude,
+                        longitude,
+                    },
+                })
+            })
+            .collect()
+    })
+}
+
+/// Where the user roughly is, from the system time zone
+pub fn user_location() -> Option<Coordinates> {
+    let zone = iana_time_zone::get_timezone()
+        .inspect_err(|e| log::debug!("Failed to read the system time zone: {}", e))
+        .ok()?;
+    zone_location(&zone)
+}
+
+fn zone_location(zone: &str) -> Option<Coordinates> {
+    table()
+        .iter()
+        .find(|location| location.name == zone)
+        .map(|location| location.coordinates)
+}
+
+/// Where a server in `city`, `country_code` is
+pub fn server_location(country_code: &str, city: &str) -> Option<Coordinates> {
+    let in_country: Vec<&Location> = table()
+        .iter()
+        .filter(|location| location.country.eq_ignore_ascii_case(country_code))
+        .collect();
+    if let Some(location) = in_country
+        .iter()
+        .find(|location| location.city().eq_ignore_ascii_case(city.trim()))
+    {
+        return Some(location.coordinates);
+    }
+
+    // Anywhere in a country with several zones could be far from all of them
+    let mut zones = in_country
+        .iter()
+        .filter(|location| location.is_zone())
+        .map(|location| location.coordinates);
+    let first = zones.next()?;
+    zones.all(|other| other == first).then_some(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_location_by_city_then_single_zone_country() {
+        let new_york = server_location("us", "New York").unwrap();
+        assert_eq!(new_york, zone_location("America/New_York").unwrap());
+        assert_eq!(
+            server_location("DE", "Frankfurt").unwrap().latitude,
+            50.1109
+        );
+        // Germany has one zone, the US several
+        assert_eq!(
+            server_location("DE", "Nuremberg"),
+            zone_location("Europe/Berlin")
+        );
+        assert_eq!(server_location("US", "Omaha"), None);
+
+        let london = zone_location("Europe/London").unwrap();
+        let distance = new_york.distance_km(&london);
+        assert!((5550.0..5600.0).contains(&distance), "{}", distance);
+    }
+}
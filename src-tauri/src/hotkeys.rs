@@ -0,0 +1,95 @@
+//! Global keyboard shortcuts for connection actions
+
+use crate::actions::{self, Action};
+use crate::settings::{self, HotkeySettings};
+use tauri::AppHandle;
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutEvent, ShortcutState};
+
+/// Parse the configured shortcuts, rejecting invalid ones and shortcuts bound twice
+fn bindings(hotkeys: &HotkeySettings) -> Result<Vec<(&'static str, Action, Shortcut)>, String> {
+    let configured = [
+        (
+            "Quick Connect",
+            Action::QuickConnect,
+            &hotkeys.quick_connect,
+        ),
+        ("Disconnect", Action::Disconnect, &hotkeys.disconnect),
+        ("Pause", Action::Pause, &hotkeys.pause),
+    ];
+
+    let mut bindings: Vec<(&'static str, Action, Shortcut)> = Vec::new();
+    for (name, action, accelerator) in configured {
+        let Some(accelerator) = accelerator else {
+            continue;
+        };
+        let shortcut: Shortcut = accelerator
+            .parse()
+            .map_err(|e| format!("Invalid shortcut \"{}\" for {}: {}", accelerator, name, e))?;
+
+        if let Some((other, _, _)) = bindings.iter().find(|(_, _, s)| *s == shortcut) {
+            return Err(format!(
+                "\"{}\" is assigned to both {} and {}",
+                accelerator, other, name
+            ));
+        }
+        bindings.push((name, action, shortcut));
+    }
+
+    Ok(bindings)
+}
+
+/// Register the shortcuts in place of any registered before
+///
+/// Fails if a shortcut is invalid, duplicated, or already taken by another
+/// application; in that case none of them stay registered.
+pub fn register(app: &AppHandle, hotkeys: &HotkeySettings) -> Result<(), String> {
+    let bindings = bindings(hotkeys)?;
+    let manager = app.global_shortcut();
+    manager.unregister_all().map_err(|e| e.to_string())?;
+
+    for (name, _, shortcut) in bindings {
+        if let Err(e) = manager.register(shortcut) {
+            let _ = manager.unregister_all();
+            return Err(format!(
+                "The shortcut for {} is already in use: {}",
+                name, e
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Shortcut handler installed with the global-shortcut plugin
+pub fn handle(app: &AppHandle, shortcut: &Shortcut, event: ShortcutEvent) {
+    if event.state() != ShortcutState::Pressed {
+        return;
+    }
+
+    let Ok(bindings) = bindings(&settings::current().hotkeys) else {
+        return;
+    };
+    if let Some((_, action, _)) = bindings.into_iter().find(|(_, _, s)| s == shortcut) {
+        actions::spawn(app, action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_shortcuts_are_rejected() {
+        let hotkeys = HotkeySettings {
+            quick_connect: Some("Control+Alt+C".to_string()),
+            disconnect: Some("Control+Alt+C".to_string()),
+            pause: None,
+        };
+        assert!(bindings(&hotkeys).is_err());
+
+        let hotkeys = HotkeySettings {
+            disconnect: Some("Control+Alt+D".to_string()),
+            ..hotkeys
+        };
+        assert_eq!(bindings(&hotkeys).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,329 @@
+//! Background latency probing over the cached server list
+//!
+//! Probes a few servers per round at a low rate, keeps the latest measurement per
+//! server with a timestamp, and flags measurements that have gone stale. Recent
+//! successful probes are kept too, to aggregate latency per country for the map.
+
+use crate::{servers, settings, storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+const LATENCY_FILE: &str = "latency.json";
+
+/// Time between probe rounds
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Servers probed per round
+const SERVERS_PER_ROUND: usize = 3;
+
+/// Time allowed to look up a server's hostname before its probe is skipped
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Measurements older than this are reported as stale
+pub const STALE_AFTER_SECS: i64 = 15 * 60;
+
+/// Successful probes remembered per server
+const HISTORY_LEN: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencySample {
+    /// Round-trip time in milliseconds, `None` if the probe failed
+    pub latency_ms: Option<u32>,
+    pub measured_at: i64,
+    /// Recent successful round-trip times, oldest first
+    #[serde(default)]
+    pub history: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencyMeasurement {
+    pub latency_ms: Option<u32>,
+    pub measured_at: i64,
+    pub stale: bool,
+}
+
+/// Latency over all servers in a country, for coloring the world map
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CountryLatency {
+    pub country: String,
+    /// Median of the recent successful probes of the country's servers
+    pub median_ms: Option<u32>,
+    /// Lowest recent latency of any server there
+    pub best_ms: Option<u32>,
+    pub servers: usize,
+    /// Probes the aggregate is based on
+    pub samples: usize,
+    /// No server there has been measured recently
+    pub stale: bool,
+}
+
+static SAMPLES: OnceLock<RwLock<HashMap<String, LatencySample>>> = OnceLock::new();
+
+fn samples() -> &'static RwLock<HashMap<String, LatencySample>> {
+    SAMPLES.get_or_init(|| RwLock::new(storage::load(LATENCY_FILE)))
+}
+
+/// Latest measurement per server id, with staleness evaluated now
+pub fn measurements() -> HashMap<String, LatencyMeasurement> {
+    let now = chrono::Utc::now().timestamp();
+    samples()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(id, sample)| {
+            (
+                id.clone(),
+                LatencyMeasurement {
+                    latency_ms: sample.latency_ms,
+                    measured_at: sample.measured_at,
+                    stale: now - sample.measured_at > STALE_AFTER_SECS,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Fresh latency for a server, if one was measured recently
+pub fn fresh_latency(server_id: &str) -> Option<u32> {
+    let now = chrono::Utc::now().timestamp();
+    samples()
+        .read()
+        .unwrap()
+        .get(server_id)
+        .filter(|s| now - s.measured_at <= STALE_AFTER_SECS)
+        .and_then(|s| s.latency_ms)
+}
+
+/// Latency per country code over the cached servers
+pub fn country_map() -> HashMap<String, CountryLatency> {
+    let now = chrono::Utc::now().timestamp();
+    aggregate(&servers::cached(), &samples().read().unwrap(), now)
+}
+
+fn aggregate(
+    servers: &[servers::Server],
+    samples: &HashMap<String, LatencySample>,
+    now: i64,
+) -> HashMap<String, CountryLatency> {
+    let mut countries: HashMap<String, (CountryLatency, Vec<u32>)> = HashMap::new();
+    for server in servers {
+        let (country, history) =
+            countries
+                .entry(server.country_code.clone())
+                .or_insert_with(|| {
+                    let country = CountryLatency {
+                        country: server.country.clone(),
+                        median_ms: None,
+                        best_ms: None,
+                        servers: 0,
+                        samples: 0,
+                        stale: true,
+                    };
+                    (country, Vec::new())
+                });
+        country.servers += 1;
+        if let Some(sample) = samples.get(&server.id) {
+            history.extend(&sample.history);
+            if now - sample.measured_at <= STALE_AFTER_SECS {
+                country.stale = false;
+            }
+        }
+    }
+
+    countries
+        .into_iter()
+        .map(|(code, (mut country, mut history))| {
+            history.sort_unstable();
+            country.samples = history.len();
+            country.best_ms = history.first().copied();
+            country.median_ms = median(&history);
+            (code, country)
+        })
+        .collect()
+}
+
+/// Median of sorted values, averaging the middle two of an even count
+fn median(sorted: &[u32]) -> Option<u32> {
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(sorted[mid]),
+        _ => Some((sorted[mid - 1] + sorted[mid]).div_ceil(2)),
+    }
+}
+
+fn record(server_id: &str, latency_ms: Option<u32>) {
+    let mut map = samples().write().unwrap();
+    let sample = map
+        .entry(server_id.to_string())
+        .or_insert_with(|| LatencySample {
+            latency_ms: None,
+            measured_at: 0,
+            history: Vec::new(),
+        });
+    sample.latency_ms = latency_ms;
+    sample.measured_at = chrono::Utc::now().timestamp();
+    if let Some(latency) = latency_ms {
+        sample.history.push(latency);
+        let excess = sample.history.len().saturating_sub(HISTORY_LEN);
+        sample.history.drain(..excess);
+    }
+    if let Err(e) = storage::save(LATENCY_FILE, &*map) {
+        log::warn!("Failed to persist latency samples: {}", e);
+    }
+}
+
+/// Run the prober forever; intended to be spawned once at startup
+pub async fn run_prober() {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        if settings::current().metered_mode {
+            continue;
+        }
+
+        for server in next_batch() {
+            let latency = probe(&server.ip).await;
+            log::debug!("Probed {} ({}): {:?}", server.name, server.ip, latency);
+            record(&server.id, latency);
+        }
+    }
+}
+
+/// Pick the servers whose measurements are oldest (never-measured first)
+fn next_batch() -> Vec<servers::Server> {
+    let mut list = servers::cached();
+    let map = samples().read().unwrap();
+    list.sort_by_key(|s| map.get(&s.id).map(|m| m.measured_at).unwrap_or(0));
+    list.truncate(SERVERS_PER_ROUND);
+    list
+}
+
+/// Measure round-trip time to a host with a single ICMP echo via the system ping tool
+pub async fn probe(host: &str) -> Option<u32> {
+    // Looked up here, so ping is only ever handed an address and never an option
+    let target = lookup(host).await?.to_string();
+    let mut command = tokio::process::Command::new("ping");
+
+    #[cfg(target_os = "windows")]
+    command.args(["-n", "1", "-w", "1000", &target]);
+    #[cfg(target_os = "macos")]
+    command.args(["-c", "1", "-W", "1000", &target]);
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    command.args(["-c", "1", "-W", "1", &target]);
+
+    let output = tokio::time::timeout(Duration::from_secs(3), command.output())
+        .await
+        .ok()?
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    parse_ping_time(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Extract the round-trip time from ping output ("time=23.4 ms", "time<1ms")
+fn parse_ping_time(output: &str) -> Option<u32> {
+    let start = output.find("time")? + "time".len();
+    let rest = output[start..].trim_start_matches(['=', '<']);
+    let number: String = rest
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let ms: f64 = number.parse().ok()?;
+    Some(ms.round().max(1.0) as u32)
+}
+
+/// Address to ping for `host`, itself if it is one; IPv4 first, as the
+/// macOS ping only takes IPv4
+async fn lookup(host: &str) -> Option<IpAddr> {
+    if let Ok(address) = host.parse() {
+        return Some(address);
+    }
+    let addresses: Vec<IpAddr> =
+        tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((host, 0)))
+            .await
+            .ok()?
+            .ok()?
+            .map(|address| address.ip())
+            .collect();
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ping_time() {
+        let linux = "64 bytes from 1.1.1.1: icmp_seq=1 ttl=57 time=23.4 ms";
+        assert_eq!(parse_ping_time(linux), Some(23));
+
+        let windows = "Reply from 1.1.1.1: bytes=32 time<1ms TTL=57";
+        assert_eq!(parse_ping_time(windows), Some(1));
+
+        assert_eq!(parse_ping_time("Request timed out."), None);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_hands_ping_an_address() {
+        let address = lookup("203.0.113.7").await;
+        assert_eq!(address, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(
+            lookup("localhost").await.map(|a| a.is_loopback()),
+            Some(true)
+        );
+    }
+
+    fn server(id: &str, country_code: &str) -> servers::Server {
+        let country = format!("Country {}", country_code);
+        servers::Server {
+            ip: "203.0.113.1".to_string(),
+            ..servers::test_server(id, &country, country_code)
+        }
+    }
+
+    #[test]
+    fn test_country_aggregates() {
+        let now = 1_700_000_000;
+        let sample = |history: &[u32], age: i64| LatencySample {
+            latency_ms: history.last().copied(),
+            measured_at: now - age,
+            history: history.to_vec(),
+        };
+        let samples = HashMap::from([
+            ("de-1".to_string(), sample(&[30, 34], 60)),
+            (
+                "de-2".to_string(),
+                sample(&[20, 90, 40], STALE_AFTER_SECS + 1),
+            ),
+            ("jp-1".to_string(), sample(&[210], STALE_AFTER_SECS + 1)),
+        ]);
+        let servers = [
+            server("de-1", "DE"),
+            server("de-2", "DE"),
+            server("jp-1", "JP"),
+            server("us-1", "US"),
+        ];
+
+        let map = aggregate(&servers, &samples, now);
+        let germany = &map["DE"];
+        assert_eq!(germany.median_ms, Some(34));
+        assert_eq!(germany.best_ms, Some(20));
+        assert_eq!((germany.servers, germany.samples), (2, 5));
+        assert!(!germany.stale);
+        assert!(map["JP"].stale);
+        assert_eq!(map["US"].median_ms, None);
+
+        assert_eq!(median(&[10, 15]), Some(13));
+    }
+}
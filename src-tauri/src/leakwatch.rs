@@ -0,0 +1,171 @@
+//! Exit IP monitoring
+//!
+//! A tunnel can collapse while routes and status still look healthy, quietly
+//! sending traffic out through the ISP. With `exit_ip_check` on, the public
+//! address is looked up just before each connect and, while connected, the
+//! exit address is checked every minute; if it matches the ISP address the
+//! user gets a notification and the UI a `security://possible-leak` event.
+//!
+//! Nothing is looked up while disconnected, and the check is skipped when the
+//! tunnel doesn't carry all traffic, since the lookup services may then be
+//! reached through the ISP on purpose.
+
+use crate::settings;
+use crate::vpn::events::{self, EventCategory, Severity};
+use crate::vpn::{VpnHandle, VpnStatus};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tauri_plugin_notification::NotificationExt;
+
+pub const POSSIBLE_LEAK_EVENT: &str = "security://possible-leak";
+
+/// Time between exit address checks while connected
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Plain-text "what is my IP" services, tried in order
+const LOOKUP_URLS: [&str; 2] = ["https://api.ipify.org", "https://icanhazip.com"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PossibleLeak {
+    /// Address traffic is leaving from, which is the ISP's
+    pub exit_ip: String,
+    pub server_id: Option<String>,
+    pub detected_at: i64,
+}
+
+/// Public address seen before the latest connect
+static ISP_ADDRESS: Mutex<Option<IpAddr>> = Mutex::new(None);
+
+/// Note the ISP's address ahead of a connect, while traffic still leaves through it
+///
+/// Does nothing unless `exit_ip_check` is on, or while a tunnel is up.
+pub async fn sample_isp(manager: &VpnHandle) {
+    if !settings::current().exit_ip_check {
+        return;
+    }
+    let status = manager.with(|vpn| vpn.get_status()).await;
+    if !matches!(status, VpnStatus::Disconnected | VpnStatus::Error(_)) {
+        return;
+    }
+    if let Some(address) = public_address().await {
+        *ISP_ADDRESS.lock().unwrap() = Some(address);
+    }
+}
+
+/// Watch the exit address forever; intended to be spawned once at startup
+pub async fn run(app: AppHandle, manager: VpnHandle) {
+    let mut alerted = false;
+
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        let (status, server_id, full_tunnel) = manager
+            .call(|vpn| {
+                Box::pin(async move {
+                    (
+                        vpn.get_status(),
+                        vpn.get_server_id(),
+                        vpn.full_tunnel().await,
+                    )
+                })
+            })
+            .await;
+        // Without a known ISP address there is nothing to compare against
+        let isp = *ISP_ADDRESS.lock().unwrap();
+        let watching = settings::current().exit_ip_check && full_tunnel && isp.is_some();
+        if status == VpnStatus::Disconnected {
+            alerted = false;
+        }
+        if status != VpnStatus::Connected || !watching {
+            continue;
+        }
+
+        match public_address().await {
+            Some(exit) if isp == Some(exit) => {
+                if !alerted {
+                    alerted = true;
+                    alert(&app, exit, server_id);
+                }
+            }
+            Some(_) => alerted = false,
+            None => {}
+        }
+    }
+}
+
+fn alert(app: &AppHandle, exit: IpAddr, server_id: Option<String>) {
+    log::error!(
+        "Traffic is leaving through the ISP address {} while connected",
+        exit
+    );
+
+    events::record(
+        EventCategory::Leak,
+        Severity::Error,
+        format!("Traffic leaving through the ISP address {}", exit),
+    );
+
+    let leak = PossibleLeak {
+        exit_ip: exit.to_string(),
+        server_id,
+        detected_at: chrono::Utc::now().timestamp(),
+    };
+    let _ = app.emit(POSSIBLE_LEAK_EVENT, &leak);
+
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title("Your traffic may not be protected")
+        .body(format!(
+            "SACVPN is connected, but your traffic is leaving from your own address ({}). \
+             Disconnect and reconnect to restore protection.",
+            exit
+        ))
+        .show()
+    {
+        log::warn!("Failed to show leak notification: {}", e);
+    }
+}
+
+/// Public address traffic currently leaves from, bypassing any system proxy
+pub async fn public_address() -> Option<IpAddr> {
+    let client = reqwest::Client::builder()
+        .timeout(LOOKUP_TIMEOUT)
+        .no_proxy()
+        .build()
+        .ok()?;
+    for url in LOOKUP_URLS {
+        let body = match client.get(url).send().await {
+            Ok(response) if response.status().is_success() => response.text().await.ok(),
+            _ => None,
+        };
+        if let Some(address) = body.as_deref().and_then(parse_address) {
+            return Some(address);
+        }
+    }
+    None
+}
+
+fn parse_address(body: &str) -> Option<IpAddr> {
+    body.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address("203.0.113.9\n"),
+            Some(IpAddr::from([203, 0, 113, 9]))
+        );
+        assert!(parse_address(" 2001:db8::1 ").is_some());
+        assert_eq!(parse_address("<html>blocked</html>"), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! Measuring new networks for data-path tuning
+//!
+//! The first connect from a network downloads a test payload through the
+//! tunnel once it has settled; the measured throughput and the server's
+//! latency size the socket buffers and batch size for that network. Results
+//! are kept on disk, so each network is only measured again once its tuning
+//! is a month old.
+
+use crate::vpn::{LinkTuning, VpnHandle};
+use crate::{diagnostics, latency, servers, settings, storage};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+const LINK_TUNING_FILE: &str = "link_tuning.json";
+
+/// Age at which a network's tuning is measured again
+const RETUNE_AFTER_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Time for the new tunnel to settle before the measurement
+const SETTLE: Duration = Duration::from_secs(3);
+
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Round trip assumed when the server's latency is unknown
+const DEFAULT_RTT_MS: u32 = 50;
+
+static TUNING: OnceLock<RwLock<HashMap<String, LinkTuning>>> = OnceLock::new();
+
+fn tuning() -> &'static RwLock<HashMap<String, LinkTuning>> {
+    TUNING.get_or_init(|| RwLock::new(storage::load(LINK_TUNING_FILE)))
+}
+
+/// Tuning measured so far, keyed by network
+pub fn stored() -> HashMap<String, LinkTuning> {
+    tuning().read().unwrap().clone()
+}
+
+/// Measure the network of the connection to `server_id` in the background,
+/// unless it was measured recently, autotuning is off or the network is metered
+pub fn spawn_measure(manager: &VpnHandle, server_id: &str) {
+    let app_settings = settings::current();
+    if !app_settings.tuning.autotune || app_settings.metered_mode {
+        return;
+    }
+
+    let (manager, server_id) = (manager.clone(), server_id.to_string());
+    tauri::async_runtime::spawn(async move {
+        let Some(network) = manager.with(|vpn| vpn.network()).await else {
+            return;
+        };
+        let now = chrono::Utc::now().timestamp();
+        let measured_at = tuning()
+            .read()
+            .unwrap()
+            .get(&network)
+            .map(|link| link.measured_at);
+        if measured_at.is_some_and(|at| now - at < RETUNE_AFTER_SECS) {
+            return;
+        }
+
+        tokio::time::sleep(SETTLE).await;
+        let throughput = match measure().await {
+            Ok(throughput) => throughput,
+            Err(e) => {
+                log::warn!("Failed to measure the link on {}: {}", network, e);
+                return;
+            }
+        };
+        let rtt = latency::fresh_latency(&server_id)
+            .or_else(|| servers::find(&server_id).map(|server| server.latency))
+            .filter(|&ms| ms > 0)
+            .unwrap_or(DEFAULT_RTT_MS);
+        let link = LinkTuning::for_link(throughput, Duration::from_millis(rtt.into()), now);
+
+        {
+            let mut map = tuning().write().unwrap();
+            map.insert(network.clone(), link.clone());
+            if let Err(e) = storage::save(LINK_TUNING_FILE, &*map) {
+                log::warn!("Failed to persist link tuning: {}", e);
+            }
+        }
+        manager
+            .call(move |vpn| Box::pin(vpn.tune_link(network, link)))
+            .await;
+    });
+}
+
+async fn measure() -> Result<u64, String> {
+    let client = reqwest::Client::builder()
+        .timeout(MEASURE_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    diagnostics::measure_throughput(&client).await
+}
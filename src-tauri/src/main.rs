@@ -4,156 +4,888 @@
     windows_subsystem = "windows"
 )]
 
-mod vpn;
+mod actions;
+mod api;
+mod clock;
+mod configdiff;
+mod connectivity;
+mod credentials;
+mod cron;
+mod devices;
+mod diagnostics;
+mod failover;
+mod geo;
+mod hotkeys;
+mod latency;
+mod leakwatch;
+mod linktune;
+mod onboarding;
+mod places;
+mod policy;
+mod preflight;
+mod presets;
+mod prewarm;
+mod reputation;
+mod rotation;
+mod schedule;
+mod servers;
+mod settings;
+mod signing;
+mod storage;
+mod taskbar;
+mod tray;
+mod usage;
+mod usagewatch;
 
+// The engine lives in the sacvpn-core crate; the app knows it as `vpn`
+use sacvpn_core as vpn;
+
+use actions::Action;
+use api::ApiEnvironment;
+use connectivity::RequestKind;
+use latency::{CountryLatency, LatencyMeasurement};
+use onboarding::OnboardingStep;
+use presets::Preset;
+use schedule::{Schedule, ScheduledRun};
 use serde::{Deserialize, Serialize};
-use tauri::{
-    menu::{Menu, MenuItem},
-    tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
-    Manager, Runtime,
+use servers::{EnrichedServer, Server, ServerAnnotation};
+use settings::{AppSettings, ConnectionProfile, HotkeySettings};
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State, WebviewWindow};
+use tauri_plugin_dialog::DialogExt;
+use vpn::{
+    format_speed, ChangePlan, DisconnectReason, ErrorRecord, ErrorReport, ForwardingStats, LanInfo,
+    SessionPolicy, SplitRouteStats, TunnelInfo, VpnConfig, VpnError, VpnHandle, VpnManager,
+    VpnStatus,
 };
-use vpn::{VpnConfig, VpnManager, VpnStatus};
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Server {
-    id: String,
-    name: String,
-    country: String,
-    country_code: String,
-    city: String,
-    ip: String,
-    public_key: String,
-    load: u8,
-    latency: u32,
-}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ConnectionStats {
+    /// Smoothed rates in bytes per second
     upload_speed: u64,
     download_speed: u64,
+    /// Smoothed rates formatted in the configured unit
+    upload_display: String,
+    download_display: String,
+    /// Session averages and peaks in bytes per second
+    average_upload_speed: u64,
+    average_download_speed: u64,
+    peak_upload_speed: u64,
+    peak_download_speed: u64,
     total_uploaded: u64,
     total_downloaded: u64,
+    tunnel_uploaded: u64,
+    tunnel_downloaded: u64,
     connected_since: Option<i64>,
+    /// Session start in local time (RFC 3339), for display
+    connected_at: Option<String>,
+    /// Seconds connected, measured on the monotonic clock
+    session_duration_secs: Option<u64>,
+    /// The tunnel is faked by `--simulate`; nothing goes through the VPN
+    simulated: bool,
 }
 
-// Initialize VPN manager
-static VPN_MANAGER: std::sync::OnceLock<tokio::sync::Mutex<VpnManager>> =
-    std::sync::OnceLock::new();
-
-fn get_vpn_manager() -> &'static tokio::sync::Mutex<VpnManager> {
-    VPN_MANAGER.get_or_init(|| tokio::sync::Mutex::new(VpnManager::new()))
+/// The server behind the current connection, so the UI doesn't have to correlate state
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CurrentConnection {
+    server_id: String,
+    server_name: Option<String>,
+    country: Option<String>,
+    country_code: Option<String>,
+    city: Option<String>,
+    endpoint: String,
+    connected_since: Option<i64>,
+    connected_at: Option<String>,
+    session_duration_secs: Option<u64>,
+    simulated: bool,
 }
 
 // Tauri commands
 #[tauri::command]
-async fn connect_vpn(server_id: String, config: VpnConfig) -> Result<(), String> {
+async fn connect_vpn(
+    app: AppHandle,
+    vpn: State<'_, VpnHandle>,
+    server_id: String,
+    mut config: VpnConfig,
+    profile: Option<String>,
+) -> Result<(), ErrorReport> {
     log::info!("Connecting to VPN server: {}", server_id);
+    policy::check_server(&server_id).map_err(VpnError::ConfigError)?;
+    servers::check_available(&server_id)?;
+
+    // The config passed in is for the selected server, so a rotated one needs its own
+    let rotated = rotation::on_connect(&server_id);
+    if rotated != server_id {
+        policy::check_server(&rotated).map_err(VpnError::ConfigError)?;
+        let token = credentials::token().map_err(VpnError::ConfigError)?;
+        let api_url = api::base_url().map_err(VpnError::ConfigError)?;
+        config = servers::generate_config(api_url, &token, &rotated)
+            .await
+            .map_err(VpnError::ConfigError)?;
+    }
+    let server_id = rotated;
+
+    let app_settings = settings::current();
+    let policy = app_settings.session_policy(profile.as_deref());
+    if let Some(diff) = configdiff::check(&server_id, &config) {
+        let _ = app.emit(configdiff::CONFIG_CHANGED_EVENT, diff);
+    }
+    app_settings.apply_server_overrides(&server_id, &mut config);
+    leakwatch::sample_isp(&vpn).await;
+
+    let id = server_id.clone();
+    let result = vpn
+        .call(move |vpn| Box::pin(async move { vpn.connect(&id, config, policy).await }))
+        .await;
+    if let Err(e) = result {
+        return Err(clock::explain(ErrorReport::from(e)).await);
+    }
+    servers::record_usage(&server_id);
+    reputation::spawn_check(&app, &server_id);
+    linktune::spawn_measure(&vpn, &server_id);
+    tray::refresh(&app);
+    taskbar::refresh(&app);
+    Ok(())
+}
+
+/// The routes, DNS and firewall changes `connect_vpn` would make, without applying them
+#[tauri::command]
+async fn preview_connect(
+    vpn: State<'_, VpnHandle>,
+    server_id: String,
+    mut config: VpnConfig,
+    profile: Option<String>,
+) -> Result<ChangePlan, ErrorReport> {
+    policy::check_server(&server_id).map_err(VpnError::ConfigError)?;
+
+    let app_settings = settings::current();
+    let policy = app_settings.session_policy(profile.as_deref());
+    app_settings.apply_server_overrides(&server_id, &mut config);
+
+    // Only the tunnel name comes from the manager; the lookups run without holding it
+    let interface = vpn.with(|vpn| vpn.tunnel_name()).await;
+    vpn::preview(interface, config, policy)
+        .await
+        .map_err(ErrorReport::from)
+}
+
+#[tauri::command]
+async fn connect_with_failover(
+    app: AppHandle,
+    vpn: State<'_, VpnHandle>,
+    token: Option<String>,
+    server_id: String,
+    profile: Option<String>,
+) -> Result<String, ErrorReport> {
+    let token = credentials::resolve(token).map_err(VpnError::ConfigError)?;
+    let api_url = api::base_url().map_err(VpnError::ConfigError)?;
 
-    let manager = get_vpn_manager();
-    let mut vpn = manager.lock().await;
+    let connected =
+        failover::connect(&app, &vpn, api_url, &token, &server_id, profile.as_deref()).await?;
+    tray::refresh(&app);
+    taskbar::refresh(&app);
+    Ok(connected)
+}
 
-    vpn.connect(config).await.map_err(|e| e.to_string())
+/// Move a connection whose exit IP is widely blocklisted to the server the
+/// reputation check offered, returning its id
+#[tauri::command]
+async fn switch_exit_server(app: AppHandle) -> Result<String, ErrorReport> {
+    reputation::switch(&app).await
 }
 
 #[tauri::command]
-async fn disconnect_vpn() -> Result<(), String> {
+async fn disconnect_vpn(vpn: State<'_, VpnHandle>) -> Result<(), ErrorReport> {
     log::info!("Disconnecting from VPN");
 
-    let manager = get_vpn_manager();
-    let mut vpn = manager.lock().await;
+    vpn.call(|vpn| Box::pin(vpn.disconnect()))
+        .await
+        .map_err(ErrorReport::from)
+}
+
+#[tauri::command]
+async fn get_vpn_status(vpn: State<'_, VpnHandle>) -> Result<VpnStatus, String> {
+    Ok(vpn.with(|vpn| vpn.get_status()).await)
+}
+
+#[tauri::command]
+async fn get_current_connection(
+    vpn: State<'_, VpnHandle>,
+) -> Result<Option<CurrentConnection>, String> {
+    let current = vpn
+        .call(|vpn| {
+            Box::pin(async move {
+                if !vpn.get_status().is_up() {
+                    return None;
+                }
+                let server_id = vpn.get_server_id()?;
+                let config = vpn.get_config().await?;
+                Some((server_id, config, vpn.get_stats()))
+            })
+        })
+        .await;
+    let Some((server_id, config, stats)) = current else {
+        return Ok(None);
+    };
+
+    let server = servers::find(&server_id);
+    Ok(Some(CurrentConnection {
+        server_name: server.as_ref().map(|s| s.name.clone()),
+        country: server.as_ref().map(|s| s.country.clone()),
+        country_code: server.as_ref().map(|s| s.country_code.clone()),
+        city: server.as_ref().map(|s| s.city.clone()),
+        server_id,
+        endpoint: config.primary_peer().endpoint.clone(),
+        connected_since: stats.connected_since,
+        connected_at: stats.connected_at(),
+        session_duration_secs: stats.session_duration().map(|d| d.as_secs()),
+        simulated: vpn::simulate::enabled(),
+    }))
+}
+
+#[tauri::command]
+async fn get_forwarding_stats(vpn: State<'_, VpnHandle>) -> Result<ForwardingStats, String> {
+    Ok(vpn.with(|vpn| vpn.get_forwarding_stats()).await)
+}
+
+/// Host routes added by domain split tunneling, or none while it is off
+#[tauri::command]
+async fn get_split_route_stats(
+    vpn: State<'_, VpnHandle>,
+) -> Result<Option<SplitRouteStats>, String> {
+    Ok(vpn.with(|vpn| vpn.get_split_route_stats()).await)
+}
 
-    vpn.disconnect().await.map_err(|e| e.to_string())
+/// Interface, MTU and listen port of the connected tunnel
+#[tauri::command]
+async fn get_tunnel_info(vpn: State<'_, VpnHandle>) -> Result<Option<TunnelInfo>, String> {
+    Ok(vpn.call(|vpn| Box::pin(vpn.tunnel_info())).await)
 }
 
 #[tauri::command]
-async fn get_vpn_status() -> Result<VpnStatus, String> {
-    let manager = get_vpn_manager();
-    let vpn = manager.lock().await;
+async fn get_disconnect_reason(
+    vpn: State<'_, VpnHandle>,
+) -> Result<Option<DisconnectReason>, String> {
+    Ok(vpn.with(|vpn| vpn.last_disconnect_reason()).await)
+}
 
-    Ok(vpn.get_status())
+/// Recent connection errors with when and during what they happened
+#[tauri::command]
+async fn get_error_history(vpn: State<'_, VpnHandle>) -> Result<Vec<ErrorRecord>, String> {
+    Ok(vpn.with(|vpn| vpn.error_history()).await)
 }
 
+/// Status transitions, reconnects, network changes, leak warnings and privileged
+/// commands as one timeline, oldest first
 #[tauri::command]
-async fn get_connection_stats() -> Result<ConnectionStats, String> {
-    let manager = get_vpn_manager();
-    let vpn = manager.lock().await;
+async fn get_event_log(
+    filter: vpn::events::EventFilter,
+    range: usage::TimeRange,
+) -> Result<Vec<vpn::events::Event>, String> {
+    Ok(vpn::events::query(&filter, range.from, range.to))
+}
 
+#[tauri::command]
+async fn get_connection_stats(vpn: State<'_, VpnHandle>) -> Result<ConnectionStats, String> {
     // Update stats from WireGuard before returning
-    let _ = vpn.update_stats().await;
-
-    let stats = vpn.get_stats();
+    let settings = settings::current().stats;
+    let stats_settings = settings.clone();
+    let stats = vpn
+        .call(move |vpn| {
+            Box::pin(async move {
+                let _ = vpn.update_stats(&stats_settings).await;
+                vpn.get_stats()
+            })
+        })
+        .await;
     Ok(ConnectionStats {
         upload_speed: stats.upload_speed,
         download_speed: stats.download_speed,
+        upload_display: format_speed(stats.upload_speed, settings.unit),
+        download_display: format_speed(stats.download_speed, settings.unit),
+        average_upload_speed: stats.average_upload_speed,
+        average_download_speed: stats.average_download_speed,
+        peak_upload_speed: stats.peak_upload_speed,
+        peak_download_speed: stats.peak_download_speed,
         total_uploaded: stats.total_uploaded,
         total_downloaded: stats.total_downloaded,
+        tunnel_uploaded: stats.tunnel_uploaded,
+        tunnel_downloaded: stats.tunnel_downloaded,
         connected_since: stats.connected_since,
+        connected_at: stats.connected_at(),
+        session_duration_secs: stats.session_duration().map(|d| d.as_secs()),
+        simulated: vpn::simulate::enabled(),
     })
 }
 
 #[tauri::command]
-async fn fetch_servers(api_url: String, token: String) -> Result<Vec<Server>, String> {
-    log::info!("Fetching servers from API");
+async fn get_active_policy(vpn: State<'_, VpnHandle>) -> Result<Option<SessionPolicy>, String> {
+    Ok(vpn.with(|vpn| vpn.get_active_policy()).await)
+}
+
+#[tauri::command]
+async fn benchmark_mtu(
+    vpn: State<'_, VpnHandle>,
+    server_id: Option<String>,
+    save: bool,
+) -> Result<diagnostics::MtuBenchmarkReport, String> {
+    let report = diagnostics::benchmark_mtu(&vpn).await?;
+
+    if let (true, Some(server_id), Some(best)) = (save, server_id, report.best_mtu) {
+        settings::update(|s| {
+            s.mtu_overrides.insert(server_id, best);
+        })?;
+        vpn.call(move |vpn| Box::pin(vpn.set_mtu(best)))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(report)
+}
+
+#[tauri::command]
+async fn benchmark_dns(
+    vpn: State<'_, VpnHandle>,
+    apply_best: bool,
+) -> Result<diagnostics::DnsBenchmarkReport, String> {
+    let report = diagnostics::benchmark_dns(&vpn, &settings::current().custom_dns).await?;
+
+    if let (true, Some(best)) = (apply_best, report.recommended.clone()) {
+        // Takes effect on the next connect
+        settings::update(|s| s.custom_dns = vec![best])?;
+    }
+
+    Ok(report)
+}
+
+/// Write session history and daily totals in a time range to a file the user picks
+///
+/// The destination comes from a save dialog shown here, never from the webview.
+/// Returns the number of sessions written, or `None` if the dialog was cancelled.
+#[tauri::command]
+async fn export_usage(
+    app: AppHandle,
+    window: WebviewWindow,
+    range: usage::TimeRange,
+    format: usage::ExportFormat,
+) -> Result<Option<usize>, String> {
+    if window.label() != "main" {
+        return Err("Usage can only be exported from the main window".to_string());
+    }
+
+    let extension = format.extension();
+    let picked = tauri::async_runtime::spawn_blocking(move || {
+        app.dialog()
+            .file()
+            .set_parent(&window)
+            .set_file_name(format!("sacvpn-usage.{}", extension))
+            .add_filter(extension.to_uppercase(), &[extension])
+            .blocking_save_file()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+    let Some(path) = picked else {
+        return Ok(None);
+    };
+    let path = path.into_path().map_err(|e| e.to_string())?;
+    usage::export(range, format, &path).map(Some)
+}
+
+/// Reach a URL or host through the tunnel, the physical interface or a given local address
+#[tauri::command]
+async fn probe_via_interface(
+    vpn: State<'_, VpnHandle>,
+    interface: String,
+    url: String,
+) -> Result<diagnostics::InterfaceProbe, String> {
+    diagnostics::probe_via_interface(&vpn, &interface, &url).await
+}
+
+/// Size up the local network before allowing LAN access or sharing the connection
+///
+/// Without an interface, checks the physical uplink.
+#[tauri::command]
+async fn check_lan_safety(
+    vpn: State<'_, VpnHandle>,
+    interface: Option<String>,
+) -> Result<LanInfo, String> {
+    vpn.call(|vpn| Box::pin(async move { vpn.lan_safety(interface.as_deref()).await }))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Share the tunnel with devices on a LAN interface (ICS on Windows, NAT on Linux)
+#[tauri::command]
+async fn enable_connection_sharing(
+    vpn: State<'_, VpnHandle>,
+    interface: String,
+) -> Result<(), String> {
+    vpn.call(|vpn| Box::pin(async move { vpn.enable_sharing(&interface).await }))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn disable_connection_sharing(vpn: State<'_, VpnHandle>) -> Result<(), String> {
+    vpn.with(|vpn| vpn.disable_sharing())
+        .await
+        .map_err(|e| e.to_string())
+}
 
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("{}/api/vpn/servers", api_url))
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
+/// Keep the local network ranges out of the tunnel, applied to the running
+/// session straight away
+#[tauri::command]
+async fn set_lan_bypass(vpn: State<'_, VpnHandle>, enabled: bool) -> Result<AppSettings, String> {
+    let updated = settings::update(|s| s.allow_lan = enabled)?;
+    // A profile or the managed policy may still decide otherwise
+    let allow_lan = updated.session_policy(None).allow_lan;
+    vpn.call(move |vpn| Box::pin(async move { vpn.set_allow_lan(allow_lan).await }))
         .await
         .map_err(|e| e.to_string())?;
+    Ok(updated)
+}
+
+/// LAN interface the tunnel is shared with, if sharing is on
+#[tauri::command]
+async fn get_connection_sharing(vpn: State<'_, VpnHandle>) -> Result<Option<String>, String> {
+    Ok(vpn.with(|vpn| vpn.sharing_interface()).await)
+}
+
+/// Re-check the system proxy and whether a captive portal is holding traffic
+#[tauri::command]
+async fn check_network_environment(
+    vpn: State<'_, VpnHandle>,
+) -> Result<connectivity::NetworkEnvironment, String> {
+    Ok(connectivity::detect_for(&vpn).await)
+}
+
+/// The network environment as last checked, without checking again
+#[tauri::command]
+async fn get_network_environment(
+    vpn: State<'_, VpnHandle>,
+) -> Result<connectivity::NetworkEnvironment, String> {
+    Ok(connectivity::current_for(&vpn).await)
+}
+
+/// Check environment prerequisites (driver/tooling, privileges, keyring, firewall, API)
+#[tauri::command]
+async fn run_preflight_checks() -> Result<preflight::PreflightReport, String> {
+    Ok(preflight::run().await)
+}
+
+#[tauri::command]
+async fn get_onboarding_state() -> Result<onboarding::OnboardingState, String> {
+    Ok(onboarding::state().await)
+}
 
-    if !response.status().is_success() {
-        return Err(format!("API error: {}", response.status()));
+/// Finish an onboarding step; `accept` runs the step's action (driver install, autostart)
+#[tauri::command]
+async fn complete_onboarding_step(
+    app: AppHandle,
+    window: WebviewWindow,
+    step: OnboardingStep,
+    accept: bool,
+) -> Result<onboarding::OnboardingState, String> {
+    if window.label() != "main" {
+        return Err("Onboarding can only be completed from the main window".to_string());
+    }
+
+    match step {
+        #[cfg(target_os = "windows")]
+        OnboardingStep::Driver if accept => {
+            tokio::task::spawn_blocking(vpn::install_driver)
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+        }
+        OnboardingStep::Autostart => {
+            use tauri_plugin_autostart::ManagerExt;
+            let autolaunch = app.autolaunch();
+            if accept {
+                autolaunch.enable()
+            } else {
+                autolaunch.disable()
+            }
+            .map_err(|e| e.to_string())?;
+        }
+        _ => {}
+    }
+
+    onboarding::complete(step)?;
+    Ok(onboarding::state().await)
+}
+
+/// Whether settings are locked by an organization policy, and which
+#[tauri::command]
+async fn get_managed_policy() -> Result<policy::PolicyStatus, String> {
+    Ok(policy::status().clone())
+}
+
+#[tauri::command]
+async fn get_settings() -> Result<AppSettings, String> {
+    Ok(settings::current())
+}
+
+#[tauri::command]
+async fn update_settings(mut new_settings: AppSettings) -> Result<AppSettings, String> {
+    settings::update(|s| {
+        // The API environment and hotkeys have their own commands, and tuning
+        // reaches wg-quick's root config, so it is only edited in settings.json
+        new_settings.api_environment = s.api_environment;
+        new_settings.hotkeys = s.hotkeys.clone();
+        new_settings.tuning = s.tuning.clone();
+        *s = new_settings;
+    })
+}
+
+#[tauri::command]
+async fn set_api_environment(
+    window: WebviewWindow,
+    vpn: State<'_, VpnHandle>,
+    environment: ApiEnvironment,
+) -> Result<AppSettings, String> {
+    if window.label() != "main" {
+        return Err("The API environment can only be changed from the main window".to_string());
+    }
+    if environment.base_url().is_none() {
+        return Err(format!(
+            "API environment {:?} is not available",
+            environment
+        ));
+    }
+    if vpn.with(|vpn| vpn.get_status()).await != VpnStatus::Disconnected {
+        return Err("Disconnect before switching the API environment".to_string());
+    }
+
+    log::info!("Switching API environment to {:?}", environment);
+    settings::update(|s| s.api_environment = environment)
+}
+
+/// Register and persist global shortcuts, keeping the previous ones on conflict
+#[tauri::command]
+async fn set_hotkeys(app: AppHandle, hotkeys: HotkeySettings) -> Result<AppSettings, String> {
+    if let Err(e) = hotkeys::register(&app, &hotkeys) {
+        if let Err(e) = hotkeys::register(&app, &settings::current().hotkeys) {
+            log::warn!("Failed to restore previous hotkeys: {}", e);
+        }
+        return Err(e);
     }
+    settings::update(|s| s.hotkeys = hotkeys)
+}
 
-    let servers: Vec<Server> = response.json().await.map_err(|e| e.to_string())?;
+#[tauri::command]
+async fn save_profile(profile: ConnectionProfile) -> Result<AppSettings, String> {
+    settings::update(|s| {
+        s.profiles.retain(|p| p.name != profile.name);
+        s.profiles.push(profile);
+    })
+}
+
+#[tauri::command]
+async fn delete_profile(name: String) -> Result<AppSettings, String> {
+    settings::update(|s| {
+        s.profiles.retain(|p| p.name != name);
+        if s.active_profile.as_deref() == Some(name.as_str()) {
+            s.active_profile = None;
+        }
+    })
+}
+
+#[tauri::command]
+async fn set_active_profile(name: Option<String>) -> Result<AppSettings, String> {
+    if let Some(ref name) = name {
+        if settings::current().profile(name).is_none() {
+            return Err(format!("Unknown profile: {}", name));
+        }
+    }
+    settings::update(|s| s.active_profile = name)
+}
+
+/// Built-in, API-provided and user presets
+#[tauri::command]
+async fn get_presets() -> Result<Vec<Preset>, String> {
+    Ok(presets::list())
+}
+
+/// Refresh the presets published by the API
+#[tauri::command]
+async fn fetch_presets(token: Option<String>) -> Result<Vec<Preset>, String> {
+    let token = credentials::resolve(token)?;
+    presets::fetch(api::base_url()?, &token).await
+}
+
+#[tauri::command]
+async fn save_preset(preset: Preset) -> Result<Vec<Preset>, String> {
+    presets::save(preset)
+}
+
+#[tauri::command]
+async fn delete_preset(id: String) -> Result<Vec<Preset>, String> {
+    presets::delete(&id)
+}
+
+/// Save a preset as a connection profile and make it the active one
+#[tauri::command]
+async fn apply_preset(id: String) -> Result<AppSettings, String> {
+    let preset = presets::find(&id).ok_or_else(|| format!("Unknown preset: {}", id))?;
+    let profile = preset.to_profile();
+    settings::update(|s| {
+        s.profiles.retain(|p| p.name != profile.name);
+        s.active_profile = Some(profile.name.clone());
+        s.profiles.push(profile);
+    })
+}
+
+/// Cached servers matching a preset's filter, best first
+#[tauri::command]
+async fn get_preset_servers(id: String) -> Result<Vec<Server>, String> {
+    presets::find(&id)
+        .map(|preset| preset.servers())
+        .ok_or_else(|| format!("Unknown preset: {}", id))
+}
+
+#[tauri::command]
+async fn get_schedules() -> Result<Vec<Schedule>, String> {
+    Ok(schedule::list())
+}
+
+#[tauri::command]
+async fn save_schedule(schedule: Schedule) -> Result<Vec<Schedule>, String> {
+    schedule::save(schedule)
+}
+
+#[tauri::command]
+async fn delete_schedule(id: String) -> Result<Vec<Schedule>, String> {
+    schedule::delete(&id)
+}
+
+/// Upcoming runs of the enabled schedules, soonest first
+#[tauri::command]
+async fn get_schedule_preview() -> Result<Vec<ScheduledRun>, String> {
+    Ok(schedule::preview())
+}
+
+/// Fetch the server list, cancelling a fetch still in flight
+///
+/// `timeout_secs` shortens the configured API timeout for this call.
+#[tauri::command]
+async fn fetch_servers(
+    app: AppHandle,
+    token: Option<String>,
+    timeout_secs: Option<u64>,
+) -> Result<Vec<Server>, String> {
+    log::info!("Fetching servers from API");
+
+    let token = credentials::resolve(token)?;
+    let api_url = api::base_url()?;
+    let servers = connectivity::cancellable(
+        RequestKind::Servers,
+        timeout_secs.map(Duration::from_secs),
+        servers::fetch(api_url, &token),
+    )
+    .await?;
+    tray::refresh(&app);
     Ok(servers)
 }
 
+/// Fetch this account's dedicated IP servers; they stay in the server list afterwards
+///
+/// Place names are localized for `locale`, or the system's when not given.
+#[tauri::command]
+async fn fetch_dedicated_servers(
+    app: AppHandle,
+    token: Option<String>,
+    locale: Option<String>,
+) -> Result<Vec<EnrichedServer>, String> {
+    let token = credentials::resolve(token)?;
+    let servers = servers::fetch_dedicated(api::base_url()?, &token).await?;
+    tray::refresh(&app);
+    Ok(servers::enrich(servers, &ui_locale(locale)))
+}
+
+/// Fetch the server list with annotations and place names localized for
+/// `locale`, or the system's when not given
+#[tauri::command]
+async fn get_servers_enriched(
+    app: AppHandle,
+    token: Option<String>,
+    locale: Option<String>,
+) -> Result<Vec<EnrichedServer>, String> {
+    let token = credentials::resolve(token)?;
+    let servers = servers::fetch(api::base_url()?, &token).await?;
+    tray::refresh(&app);
+    Ok(servers::enrich(servers, &ui_locale(locale)))
+}
+
+/// The cached server list with flags, distance, latency, favorites and
+/// localized place names, for every view of the servers to share
+#[tauri::command]
+async fn get_servers_view(locale: Option<String>) -> Result<Vec<EnrichedServer>, String> {
+    Ok(servers::view(&ui_locale(locale)))
+}
+
+/// The UI's locale, or the system's when the UI doesn't say
+fn ui_locale(locale: Option<String>) -> String {
+    locale.or_else(tauri_plugin_os::locale).unwrap_or_default()
+}
+
+#[tauri::command]
+async fn get_server_latencies() -> Result<HashMap<String, LatencyMeasurement>, String> {
+    Ok(latency::measurements())
+}
+
+/// Median latency per country code, for coloring the world map
+#[tauri::command]
+async fn get_latency_map() -> Result<HashMap<String, CountryLatency>, String> {
+    Ok(latency::country_map())
+}
+
+#[tauri::command]
+async fn get_recommended_servers(country_code: Option<String>) -> Result<Vec<Server>, String> {
+    Ok(servers::recommend(country_code.as_deref()))
+}
+
+#[tauri::command]
+async fn set_server_annotation(
+    server_id: String,
+    custom_name: Option<String>,
+    notes: Option<String>,
+) -> Result<(), String> {
+    servers::set_annotation(&server_id, ServerAnnotation { custom_name, notes })
+}
+
+#[tauri::command]
+async fn set_server_favorite(server_id: String, favorite: bool) -> Result<(), String> {
+    servers::set_favorite(&server_id, favorite)
+}
+
+/// Request a config for a server, cancelling a request still in flight
 #[tauri::command]
 async fn generate_config(
-    api_url: String,
-    token: String,
+    token: Option<String>,
     server_id: String,
+    timeout_secs: Option<u64>,
 ) -> Result<VpnConfig, String> {
+    if let Some(config) = prewarm::take_config(&server_id) {
+        return Ok(config);
+    }
     log::info!("Generating config for server: {}", server_id);
 
-    let client = reqwest::Client::new();
-    let response = client
-        .post(format!("{}/api/vpn/config", api_url))
-        .header("Authorization", format!("Bearer {}", token))
-        .json(&serde_json::json!({ "serverId": server_id }))
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+    let token = credentials::resolve(token)?;
+    let api_url = api::base_url()?;
+    connectivity::cancellable(
+        RequestKind::Config,
+        timeout_secs.map(Duration::from_secs),
+        servers::generate_config(api_url, &token, &server_id),
+    )
+    .await
+}
+
+/// Read a wg-quick .conf file the user picks into a config to connect with
+///
+/// The file comes from an open dialog shown here, never from the webview.
+/// Returns `None` if the dialog was cancelled.
+#[tauri::command]
+async fn import_wg_config(
+    app: AppHandle,
+    window: WebviewWindow,
+) -> Result<Option<VpnConfig>, ErrorReport> {
+    let picked = tauri::async_runtime::spawn_blocking(move || {
+        app.dialog()
+            .file()
+            .set_parent(&window)
+            .add_filter("WireGuard", &["conf"])
+            .blocking_pick_file()
+    })
+    .await
+    .map_err(|e| VpnError::ConfigError(e.to_string()))?;
+    let Some(path) = picked else {
+        return Ok(None);
+    };
+    let path = path
+        .into_path()
+        .map_err(|e| VpnError::ConfigError(e.to_string()))?;
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| VpnError::ConfigError(format!("Couldn't read {}: {}", path.display(), e)))?;
+    Ok(Some(vpn::import_wg_quick(&text)?))
+}
+
+/// Get a connect to the hovered or selected server ready ahead of the click
+#[tauri::command]
+async fn prewarm_server(
+    token: Option<String>,
+    server_id: String,
+) -> Result<prewarm::PrewarmStatus, String> {
+    policy::check_server(&server_id)?;
+    let token = credentials::resolve(token)?;
+    prewarm::server(api::base_url()?, &token, &server_id).await
+}
 
-    if !response.status().is_success() {
-        return Err(format!("API error: {}", response.status()));
+/// Abandon a server list or config request the UI no longer waits for
+#[tauri::command]
+async fn cancel_api_request(kind: RequestKind) -> Result<bool, String> {
+    Ok(connectivity::cancel(kind))
+}
+
+/// Devices on the account and how many may be connected at once
+#[tauri::command]
+async fn get_devices(token: Option<String>) -> Result<devices::DeviceList, String> {
+    let token = credentials::resolve(token)?;
+    devices::list(api::base_url()?, &token).await
+}
+
+/// Free a connection slot by disconnecting another device on the account
+#[tauri::command]
+async fn disconnect_device(
+    window: WebviewWindow,
+    token: Option<String>,
+    device_id: String,
+) -> Result<devices::DeviceList, String> {
+    if window.label() != "main" {
+        return Err("Devices can only be managed from the main window".to_string());
     }
+    let token = credentials::resolve(token)?;
+    let api_url = api::base_url()?;
+    devices::disconnect(api_url, &token, &device_id).await?;
+    devices::list(api_url, &token).await
+}
 
-    let config: VpnConfig = response.json().await.map_err(|e| e.to_string())?;
-    Ok(config)
+/// Hand the login token to the backend; it is never returned to the webview
+#[tauri::command]
+async fn store_credentials(
+    window: WebviewWindow,
+    email: String,
+    token: String,
+) -> Result<(), String> {
+    if window.label() != "main" {
+        return Err("Credentials can only be stored from the main window".to_string());
+    }
+    credentials::store(&email, &token)
 }
 
+/// Hand the device signing key issued at registration to the backend
 #[tauri::command]
-async fn store_credentials(email: String, token: String) -> Result<(), String> {
-    let entry = keyring::Entry::new("sacvpn", &email).map_err(|e| e.to_string())?;
-    entry.set_password(&token).map_err(|e| e.to_string())?;
-    Ok(())
+async fn store_device_key(
+    window: WebviewWindow,
+    device_id: String,
+    key: String,
+) -> Result<(), String> {
+    if window.label() != "main" {
+        return Err("Device keys can only be stored from the main window".to_string());
+    }
+    signing::store_key(&device_id, &key)
 }
 
 #[tauri::command]
-async fn get_credentials(email: String) -> Result<String, String> {
-    let entry = keyring::Entry::new("sacvpn", &email).map_err(|e| e.to_string())?;
-    entry.get_password().map_err(|e| e.to_string())
+async fn has_credentials(email: String) -> Result<bool, String> {
+    Ok(credentials::has(&email))
 }
 
 #[tauri::command]
 async fn clear_credentials(email: String) -> Result<(), String> {
-    let entry = keyring::Entry::new("sacvpn", &email).map_err(|e| e.to_string())?;
-    entry.delete_credential().map_err(|e| e.to_string())?;
-    Ok(())
+    prewarm::clear();
+    credentials::clear(&email)
 }
 
 #[tauri::command]
@@ -190,10 +922,7 @@ fn get_primary_mac_address() -> Option<String> {
     #[cfg(target_os = "macos")]
     {
         use std::process::Command;
-        let output = Command::new("ifconfig")
-            .arg("en0")
-            .output()
-            .ok()?;
+        let output = Command::new("ifconfig").arg("en0").output().ok()?;
 
         let stdout = String::from_utf8_lossy(&output.stdout);
         for line in stdout.lines() {
@@ -250,54 +979,74 @@ async fn get_device_fingerprint() -> Result<String, String> {
     Ok(fingerprint)
 }
 
-fn setup_tray<R: Runtime>(app: &tauri::App<R>) -> Result<(), Box<dyn std::error::Error>> {
-    let quit = MenuItem::with_id(app, "quit", "Quit SACVPN", true, None::<&str>)?;
-    let show = MenuItem::with_id(app, "show", "Show Window", true, None::<&str>)?;
-    let connect = MenuItem::with_id(app, "connect", "Quick Connect", true, None::<&str>)?;
-    let disconnect = MenuItem::with_id(app, "disconnect", "Disconnect", true, None::<&str>)?;
-
-    let menu = Menu::with_items(app, &[&show, &connect, &disconnect, &quit])?;
+/// Startup options, also forwarded to the running instance by a second launch
+#[derive(Debug, Default, PartialEq)]
+struct LaunchArgs {
+    /// `--connect [server]`, `--disconnect` and the other action arguments
+    action: Option<Action>,
+    /// `--minimized`: keep the main window hidden
+    minimized: bool,
+    /// `--quit-after`: exit once the action has finished
+    quit_after: bool,
+    /// `--simulate`: fake the tunnel (see `vpn::simulate`); only honoured at
+    /// startup, and only by debug builds or those with the `simulate` feature
+    simulate: bool,
+}
 
-    let _tray = TrayIconBuilder::new()
-        .menu(&menu)
-        .tooltip("SACVPN - Disconnected")
-        .on_menu_event(|app, event| match event.id.as_ref() {
-            "quit" => {
-                app.exit(0);
-            }
-            "show" => {
-                if let Some(window) = app.get_webview_window("main") {
-                    let _ = window.show();
-                    let _ = window.set_focus();
+fn parse_args(args: &[String]) -> LaunchArgs {
+    let mut launch = LaunchArgs::default();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--minimized" => launch.minimized = true,
+            "--quit-after" => launch.quit_after = true,
+            "--simulate" => launch.simulate = true,
+            arg => match Action::from_args(&args[i..]) {
+                Some(action) => {
+                    i += action.to_args().len() - 1;
+                    launch.action = Some(action);
                 }
-            }
-            "connect" => {
-                // TODO: Implement quick connect
-                log::info!("Quick connect requested");
-            }
-            "disconnect" => {
-                // TODO: Implement disconnect
-                log::info!("Disconnect requested");
-            }
-            _ => {}
-        })
-        .on_tray_icon_event(|tray, event| {
-            if let TrayIconEvent::Click {
-                button: MouseButton::Left,
-                button_state: MouseButtonState::Up,
-                ..
-            } = event
-            {
-                let app = tray.app_handle();
-                if let Some(window) = app.get_webview_window("main") {
-                    let _ = window.show();
-                    let _ = window.set_focus();
-                }
-            }
-        })
-        .build(app)?;
+                // macOS adds its own arguments (e.g. -psn_...) to GUI launches
+                None => log::debug!("Ignoring argument {}", arg),
+            },
+        }
+        i += 1;
+    }
+    launch
+}
 
-    Ok(())
+/// Apply startup options; `forwarded` is set when they came from a second launch
+fn apply_launch_args(app: &AppHandle, mut launch: LaunchArgs, forwarded: bool) {
+    // `--quit-after` and `--simulate` concern the process they were passed to;
+    // a second launch must never close the instance that is already running
+    if forwarded {
+        launch.quit_after = false;
+        launch.simulate = false;
+    }
+
+    if let Some(window) = app.get_webview_window("main") {
+        if launch.minimized {
+            let _ = window.hide();
+        } else if forwarded && launch.action.is_none() {
+            let _ = window.show();
+            let _ = window.set_focus();
+        }
+    }
+
+    if launch.action.is_none() && !launch.quit_after {
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Some(action) = launch.action {
+            if let Err(e) = actions::run(&app, action.clone()).await {
+                log::warn!("{:?} failed: {}", action, e);
+            }
+        }
+        if launch.quit_after {
+            app.exit(0);
+        }
+    });
 }
 
 fn main() {
@@ -306,35 +1055,166 @@ fn main() {
 
     log::info!("Starting SACVPN Desktop v{}", env!("CARGO_PKG_VERSION"));
 
+    // Read the managed policy before anything can connect
+    policy::status();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let launch = parse_args(&args);
+    // Backends are picked from the simulation flag when the manager is created
+    if launch.simulate {
+        if cfg!(any(debug_assertions, feature = "simulate")) {
+            vpn::simulate::enable();
+        } else {
+            log::warn!("Ignoring --simulate: this build can't fake the tunnel");
+        }
+    }
+
     tauri::Builder::default()
+        // Must come first so a second launch hands its arguments over before anything starts
+        .plugin(tauri_plugin_single_instance::init(|app, argv, _cwd| {
+            apply_launch_args(app, parse_args(argv.get(1..).unwrap_or_default()), true);
+        }))
         .plugin(tauri_plugin_autostart::init(
             tauri_plugin_autostart::MacosLauncher::LaunchAgent,
             None,
         ))
+        .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_os::init())
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_store::Builder::new().build())
+        .plugin(
+            tauri_plugin_global_shortcut::Builder::new()
+                .with_handler(hotkeys::handle)
+                .build(),
+        )
         // Updater disabled - needs signing keys to be configured
         // .plugin(tauri_plugin_updater::Builder::new().build())
-        .setup(|app| {
+        .setup(move |app| {
+            // Commands and background tasks all reach the manager through its handle
+            let mut manager = VpnManager::new();
+            manager.on_session_end(usage::record);
+            manager.load_link_tuning(linktune::stored());
+            let (handle, task) = VpnHandle::new(manager);
+            tauri::async_runtime::spawn(task);
+            app.manage(handle.clone());
+
             // Setup system tray
-            if let Err(e) = setup_tray(app) {
+            if let Err(e) = tray::setup(app) {
                 log::error!("Failed to setup tray: {}", e);
             }
+            taskbar::refresh(app.handle());
+
+            apply_launch_args(app.handle(), launch, false);
+
+            if let Err(e) = hotkeys::register(app.handle(), &settings::current().hotkeys) {
+                log::warn!("Failed to register hotkeys: {}", e);
+            }
+
+            // Restore dropped sessions according to the reconnect policy, once
+            // the network reaches the internet, unless the account revoked the device
+            let online = handle.clone();
+            tauri::async_runtime::spawn(vpn::watchdog::run(
+                handle.clone(),
+                || settings::current().reconnect,
+                move || Box::pin(connectivity::ready_to_reconnect(online.clone())),
+                || {
+                    Box::pin(async {
+                        match (credentials::token(), api::base_url()) {
+                            (Ok(token), Ok(api_url)) => devices::revoked(api_url, &token).await,
+                            _ => false,
+                        }
+                    })
+                },
+            ));
+
+            // Warn if traffic starts leaving through the ISP while connected
+            tauri::async_runtime::spawn(leakwatch::run(app.handle().clone(), handle.clone()));
+
+            // Badge the tray and notify as the monthly data cap runs out
+            tauri::async_runtime::spawn(usagewatch::run(app.handle().clone(), handle.clone()));
+
+            // Move sessions on to other servers in their country, if the user asked
+            tauri::async_runtime::spawn(rotation::run(app.handle().clone(), handle));
+
+            // Keep server latencies fresh in the background
+            tauri::async_runtime::spawn(latency::run_prober());
+
+            // Report session totals to the account, if the user opted in
+            tauri::async_runtime::spawn(usage::run_sync());
+
+            // Connect and disconnect on the user's schedules
+            tauri::async_runtime::spawn(schedule::run(app.handle().clone()));
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             connect_vpn,
+            preview_connect,
+            connect_with_failover,
+            switch_exit_server,
             disconnect_vpn,
             get_vpn_status,
+            get_current_connection,
+            get_disconnect_reason,
+            get_error_history,
+            get_event_log,
             get_connection_stats,
+            get_forwarding_stats,
+            get_split_route_stats,
+            get_tunnel_info,
+            get_active_policy,
+            export_usage,
+            benchmark_mtu,
+            benchmark_dns,
+            probe_via_interface,
+            check_lan_safety,
+            enable_connection_sharing,
+            disable_connection_sharing,
+            set_lan_bypass,
+            get_connection_sharing,
+            check_network_environment,
+            get_network_environment,
+            run_preflight_checks,
+            get_onboarding_state,
+            complete_onboarding_step,
+            get_managed_policy,
+            get_settings,
+            update_settings,
+            set_api_environment,
+            set_hotkeys,
+            save_profile,
+            delete_profile,
+            set_active_profile,
+            get_presets,
+            fetch_presets,
+            save_preset,
+            delete_preset,
+            apply_preset,
+            get_preset_servers,
+            get_schedules,
+            save_schedule,
+            delete_schedule,
+            get_schedule_preview,
             fetch_servers,
+            fetch_dedicated_servers,
+            get_servers_enriched,
+            get_servers_view,
+            set_server_annotation,
+            set_server_favorite,
+            get_server_latencies,
+            get_latency_map,
+            get_recommended_servers,
             generate_config,
+            import_wg_config,
+            prewarm_server,
+            cancel_api_request,
+            get_devices,
+            disconnect_device,
             store_credentials,
-            get_credentials,
+            has_credentials,
+            store_device_key,
             clear_credentials,
             get_mac_address,
             get_device_fingerprint,
@@ -342,3 +1222,31 @@ fn main() {
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args: Vec<String> = ["--minimized", "--connect", "us-east-1", "--quit-after"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            parse_args(&args),
+            LaunchArgs {
+                action: Some(Action::ConnectServer("us-east-1".to_string())),
+                minimized: true,
+                quit_after: true,
+                simulate: false,
+            }
+        );
+
+        let args: Vec<String> = ["--connect", "--minimized"].map(String::from).to_vec();
+        assert_eq!(parse_args(&args).action, Some(Action::QuickConnect));
+        assert!(parse_args(&args).minimized);
+
+        let args: Vec<String> = ["--simulate"].map(String::from).to_vec();
+        assert!(parse_args(&args).simulate);
+    }
+}
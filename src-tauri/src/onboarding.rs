@@ -0,0 +1,134 @@
+//! First-run onboarding progress
+//!
+//! Tracks which onboarding steps the user has gone through so the UI only
+//! shows the flow on a fresh install, and resumes it if the app was closed
+//! part-way.
+
+use crate::preflight::{self, PreflightReport};
+use crate::{credentials, storage};
+use serde::{Deserialize, Serialize};
+use std::sync::{OnceLock, RwLock};
+
+const ONBOARDING_FILE: &str = "onboarding.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStep {
+    /// Environment checks were shown to the user
+    Preflight,
+    /// Tunnel driver installed ahead of the first connect (Windows only)
+    Driver,
+    /// User chose whether SACVPN starts at login
+    Autostart,
+}
+
+impl OnboardingStep {
+    /// Steps that apply on this platform, in the order the UI presents them
+    pub fn all() -> Vec<Self> {
+        let mut steps = vec![Self::Preflight];
+        if cfg!(target_os = "windows") {
+            steps.push(Self::Driver);
+        }
+        steps.push(Self::Autostart);
+        steps
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct OnboardingRecord {
+    completed_steps: Vec<OnboardingStep>,
+    /// Unix timestamp of when the last step was completed
+    completed_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepState {
+    pub step: OnboardingStep,
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OnboardingState {
+    /// No onboarding progress and no signed-in account yet
+    pub fresh_install: bool,
+    pub steps: Vec<StepState>,
+    pub completed: bool,
+    pub preflight: PreflightReport,
+}
+
+static RECORD: OnceLock<RwLock<OnboardingRecord>> = OnceLock::new();
+
+fn record() -> &'static RwLock<OnboardingRecord> {
+    RECORD.get_or_init(|| RwLock::new(storage::load(ONBOARDING_FILE)))
+}
+
+impl OnboardingRecord {
+    fn is_completed(&self) -> bool {
+        OnboardingStep::all()
+            .iter()
+            .all(|step| self.completed_steps.contains(step))
+    }
+
+    fn mark(&mut self, step: OnboardingStep) {
+        if !self.completed_steps.contains(&step) {
+            self.completed_steps.push(step);
+        }
+        if self.is_completed() && self.completed_at.is_none() {
+            self.completed_at = Some(chrono::Utc::now().timestamp());
+        }
+    }
+}
+
+/// Current onboarding progress together with a fresh preflight report
+pub async fn state() -> OnboardingState {
+    let record = record().read().unwrap().clone();
+    let fresh_install = record.completed_steps.is_empty() && credentials::token().is_err();
+
+    OnboardingState {
+        fresh_install,
+        steps: OnboardingStep::all()
+            .into_iter()
+            .map(|step| StepState {
+                step,
+                completed: record.completed_steps.contains(&step),
+            })
+            .collect(),
+        completed: record.is_completed(),
+        preflight: preflight::run().await,
+    }
+}
+
+/// Record a step as done and persist the progress
+pub fn complete(step: OnboardingStep) -> Result<(), String> {
+    if !OnboardingStep::all().contains(&step) {
+        return Err(format!("{:?} does not apply on this platform", step));
+    }
+
+    let mut guard = record().write().unwrap();
+    guard.mark(step);
+    storage::save(ONBOARDING_FILE, &*guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completed_once_every_step_is_marked() {
+        let mut record = OnboardingRecord::default();
+        let steps = OnboardingStep::all();
+
+        for step in &steps[..steps.len() - 1] {
+            record.mark(*step);
+            record.mark(*step);
+        }
+        assert!(!record.is_completed());
+        assert!(record.completed_at.is_none());
+
+        record.mark(*steps.last().unwrap());
+        assert!(record.is_completed());
+        assert!(record.completed_at.is_some());
+        assert_eq!(record.completed_steps.len(), steps.len());
+    }
+}
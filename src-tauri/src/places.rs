@@ -0,0 +1,145 @@
+//! Localized place names
+//!
+//! Country and city names in the UI's language, looked up by country code so
+//! a non-English UI needs no geo database of its own. The names are CLDR's,
+//! compiled in from `data/places.tsv`; a language or place it doesn't cover
+//! keeps the name the API sent.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+const PLACES: &str = include_str!("../data/places.tsv");
+
+/// A country and city as shown in one language
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocalizedPlace {
+    pub country: String,
+    pub city: String,
+}
+
+struct Table {
+    /// Language of each name column
+    languages: Vec<&'static str>,
+    /// Names by country code and lowercase English city, empty for the country
+    names: HashMap<(String, String), Vec<&'static str>>,
+}
+
+static TABLE: OnceLock<Table> = OnceLock::new();
+
+fn table() -> &'static Table {
+    TABLE.get_or_init(|| parse(PLACES))
+}
+
+fn parse(text: &'static str) -> Table {
+    let mut rows = text
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split('\t').collect::<Vec<_>>());
+    let languages: Vec<&str> = rows
+        .next()
+        .map(|header| header.into_iter().skip(2).collect())
+        .unwrap_or_default();
+
+    let mut names = HashMap::new();
+    for row in rows {
+        if row.len() != languages.len() + 2 {
+            log::warn!("Skipping malformed place row: {}", row.join(" | "));
+            continue;
+        }
+        names.insert(
+            (row[0].to_string(), row[1].to_lowercase()),
+            row[2..].to_vec(),
+        );
+    }
+    Table { languages, names }
+}
+
+impl Table {
+    /// Column of a locale such as `de-AT` or `zh_CN`
+    ///
+    /// The Chinese names are Simplified, so Traditional Chinese locales have none.
+    fn column(&self, locale: &str) -> Option<usize> {
+        let locale = locale.to_ascii_lowercase().replace('_', "-");
+        let subtags: Vec<&str> = locale.split('-').collect();
+        let language = subtags.first()?;
+        if *language == "zh"
+            && !subtags.contains(&"hans")
+            && subtags
+                .iter()
+                .any(|tag| matches!(*tag, "hant" | "tw" | "hk" | "mo"))
+        {
+            return None;
+        }
+        self.languages.iter().position(|known| known == language)
+    }
+
+    fn name(&self, column: usize, country_code: &str, city: &str) -> Option<&'static str> {
+        let key = (
+            country_code.to_ascii_uppercase(),
+            city.trim().to_lowercase(),
+        );
+        self.names
+            .get(&key)
+            .and_then(|names| names.get(column))
+            .copied()
+    }
+}
+
+/// `country` and `city` of a server in `locale`'s language, each left as
+/// given when there is no translation
+pub fn localize(country_code: &str, country: &str, city: &str, locale: &str) -> LocalizedPlace {
+    let table = table();
+    let Some(column) = table.column(locale) else {
+        return LocalizedPlace {
+            country: country.to_string(),
+            city: city.to_string(),
+        };
+    };
+    LocalizedPlace {
+        country: table
+            .name(column, country_code, "")
+            .unwrap_or(country)
+            .to_string(),
+        city: table
+            .name(column, country_code, city)
+            .filter(|_| !city.trim().is_empty())
+            .unwrap_or(city)
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_place_row_has_all_languages() {
+        let rows = PLACES
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .count();
+        assert_eq!(table().names.len(), rows - 1);
+        assert_eq!(table().languages.len(), 10);
+    }
+
+    #[test]
+    fn test_localize_by_language_with_fallback() {
+        let place = |locale| localize("de", "Germany", "Berlin", locale);
+        assert_eq!(
+            place("es-MX"),
+            LocalizedPlace {
+                country: "Alemania".to_string(),
+                city: "Berlín".to_string(),
+            }
+        );
+        assert_eq!(place("zh_CN").country, "德国");
+        assert_eq!(place("zh-Hant-TW").country, "Germany");
+        assert_eq!(place("en-US").city, "Berlin");
+
+        // A city without a CLDR name keeps the API's
+        let frankfurt = localize("DE", "Germany", "Frankfurt", "ja");
+        assert_eq!(frankfurt.country, "ドイツ");
+        assert_eq!(frankfurt.city, "Frankfurt");
+    }
+}
@@ -0,0 +1,330 @@
+//! Organization-managed policy
+//!
+//! Administrators can deploy a JSON policy file (`%ProgramData%\SACVPN\policy.json`
+//! on Windows, `/etc/sacvpn/policy.json` elsewhere) that locks settings for every
+//! user on the machine. It lives outside the per-user data directory so users
+//! can't edit it, and is read once at startup.
+//!
+//! On Windows, values under `HKLM\SOFTWARE\Policies\SACVPN` (pushed through Group
+//! Policy or Intune) are read as well and take precedence over the file.
+
+use crate::servers;
+use crate::vpn::SessionPolicy;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// Settings locked by the organization
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManagedPolicy {
+    /// Kill switch always on, whatever the settings or profile say
+    pub force_kill_switch: bool,
+    /// Country codes servers may be used from; empty allows every country
+    pub allowed_countries: Vec<String>,
+    /// Ignore custom DNS from settings and profiles
+    pub disable_custom_dns: bool,
+}
+
+impl ManagedPolicy {
+    /// Override the parts of a session policy the organization has locked
+    pub fn enforce(&self, session: &mut SessionPolicy) {
+        if self.force_kill_switch {
+            session.kill_switch = true;
+        }
+        if self.disable_custom_dns {
+            session.dns.clear();
+        }
+    }
+
+    pub fn allows_country(&self, country_code: &str) -> bool {
+        self.allowed_countries.is_empty()
+            || self
+                .allowed_countries
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(country_code))
+    }
+}
+
+/// Individually set policy values that override the policy file
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+struct PolicyOverrides {
+    force_kill_switch: Option<bool>,
+    allowed_countries: Option<Vec<String>>,
+    disable_custom_dns: Option<bool>,
+}
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+impl PolicyOverrides {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn apply_to(self, policy: &mut ManagedPolicy) {
+        if let Some(force) = self.force_kill_switch {
+            policy.force_kill_switch = force;
+        }
+        if let Some(countries) = self.allowed_countries {
+            policy.allowed_countries = countries;
+        }
+        if let Some(disable) = self.disable_custom_dns {
+            policy.disable_custom_dns = disable;
+        }
+    }
+}
+
+/// What the UI needs to show "managed by your organization"
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PolicyStatus {
+    pub managed: bool,
+    /// Where the policy was read from
+    pub source: Option<String>,
+    pub policy: Option<ManagedPolicy>,
+    /// Set when a policy file exists but couldn't be read
+    pub error: Option<String>,
+}
+
+static STATUS: OnceLock<PolicyStatus> = OnceLock::new();
+
+/// Machine-wide location of the policy file
+pub fn policy_path() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        let base = std::env::var("ProgramData").unwrap_or_else(|_| r"C:\ProgramData".to_string());
+        PathBuf::from(base).join("SACVPN").join("policy.json")
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        PathBuf::from("/etc/sacvpn/policy.json")
+    }
+}
+
+fn load() -> PolicyStatus {
+    #[cfg(target_os = "windows")]
+    {
+        registry::merge(load_file())
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        load_file()
+    }
+}
+
+fn load_file() -> PolicyStatus {
+    let path = policy_path();
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return PolicyStatus::default(),
+        Err(e) => {
+            log::error!("Failed to read policy file {:?}: {}", path, e);
+            return PolicyStatus {
+                error: Some(e.to_string()),
+                ..PolicyStatus::default()
+            };
+        }
+    };
+
+    match serde_json::from_str::<ManagedPolicy>(&content) {
+        Ok(policy) => {
+            log::info!("Managed policy loaded from {:?}: {:?}", path, policy);
+            PolicyStatus {
+                managed: true,
+                source: Some(path.display().to_string()),
+                policy: Some(policy),
+                error: None,
+            }
+        }
+        Err(e) => {
+            log::error!("Ignoring invalid policy file {:?}: {}", path, e);
+            PolicyStatus {
+                error: Some(format!("Invalid policy file: {}", e)),
+                ..PolicyStatus::default()
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod registry {
+    use super::{PolicyOverrides, PolicyStatus};
+    use windows::core::HSTRING;
+    use windows::Win32::System::Registry::{
+        RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RRF_RT_REG_MULTI_SZ, RRF_RT_REG_SZ,
+    };
+
+    const KEY: &str = r"SOFTWARE\Policies\SACVPN";
+
+    /// Layer the registry values on top of the policy file
+    pub fn merge(mut status: PolicyStatus) -> PolicyStatus {
+        let overrides = read();
+        if overrides.is_empty() {
+            return status;
+        }
+        log::info!("Managed policy values found in HKLM\\{}", KEY);
+
+        let mut policy = status.policy.take().unwrap_or_default();
+        overrides.apply_to(&mut policy);
+
+        let registry_source = format!("HKLM\\{}", KEY);
+        status.source = Some(match status.source.take() {
+            Some(file) => format!("{}; {}", file, registry_source),
+            None => registry_source,
+        });
+        status.policy = Some(policy);
+        status.managed = true;
+        status
+    }
+
+    /// Read the policy values an administrator has set; missing values stay `None`
+    fn read() -> PolicyOverrides {
+        PolicyOverrides {
+            force_kill_switch: dword("ForceKillSwitch").map(|v| v != 0),
+            allowed_countries: strings("AllowedCountries"),
+            disable_custom_dns: dword("DisableCustomDns").map(|v| v != 0),
+        }
+    }
+
+    fn dword(name: &str) -> Option<u32> {
+        let mut value = 0u32;
+        let mut size = std::mem::size_of::<u32>() as u32;
+        unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                &HSTRING::from(KEY),
+                &HSTRING::from(name),
+                RRF_RT_REG_DWORD,
+                None,
+                Some(&mut value as *mut u32 as *mut _),
+                Some(&mut size),
+            )
+        }
+        .ok()
+        .ok()?;
+        Some(value)
+    }
+
+    /// A REG_MULTI_SZ list, or a REG_SZ separated by commas or semicolons
+    fn strings(name: &str) -> Option<Vec<String>> {
+        let flags = RRF_RT_REG_MULTI_SZ | RRF_RT_REG_SZ;
+        let (key, value) = (HSTRING::from(KEY), HSTRING::from(name));
+
+        let mut size = 0u32;
+        unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                &key,
+                &value,
+                flags,
+                None,
+                None,
+                Some(&mut size),
+            )
+        }
+        .ok()
+        .ok()?;
+
+        let mut buffer = vec![0u16; (size as usize).div_ceil(2)];
+        unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                &key,
+                &value,
+                flags,
+                None,
+                Some(buffer.as_mut_ptr() as *mut _),
+                Some(&mut size),
+            )
+        }
+        .ok()
+        .ok()?;
+
+        buffer.truncate(size as usize / 2);
+        Some(
+            String::from_utf16_lossy(&buffer)
+                .split(['\0', ',', ';'])
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect(),
+        )
+    }
+}
+
+/// Policy state, read on first use
+pub fn status() -> &'static PolicyStatus {
+    STATUS.get_or_init(load)
+}
+
+/// The active managed policy, if the machine has one
+pub fn current() -> Option<&'static ManagedPolicy> {
+    status().policy.as_ref()
+}
+
+/// Whether the organization lets `server` be used
+pub fn allows_server(server: &servers::Server) -> bool {
+    current().is_none_or(|policy| policy.allows_country(&server.country_code))
+}
+
+/// Reject servers outside the countries the organization allows
+pub fn check_server(server_id: &str) -> Result<(), String> {
+    let Some(policy) = current().filter(|p| !p.allowed_countries.is_empty()) else {
+        return Ok(());
+    };
+
+    match servers::find(server_id) {
+        Some(server) if policy.allows_country(&server.country_code) => Ok(()),
+        _ => Err(format!(
+            "Server {} is not allowed by your organization's policy",
+            server_id
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enforce_locks_kill_switch_and_dns() {
+        let policy: ManagedPolicy = serde_json::from_str(
+            r#"{"force_kill_switch": true, "disable_custom_dns": true, "allowed_countries": ["DE"]}"#,
+        )
+        .unwrap();
+
+        let mut session = SessionPolicy {
+            kill_switch: false,
+            dns: vec!["1.1.1.1".to_string()],
+            ..SessionPolicy::default()
+        };
+        policy.enforce(&mut session);
+
+        assert!(session.kill_switch);
+        assert!(session.dns.is_empty());
+        assert!(policy.allows_country("de"));
+        assert!(!policy.allows_country("US"));
+        assert!(ManagedPolicy::default().allows_country("US"));
+    }
+
+    #[test]
+    fn test_overrides_replace_only_set_values() {
+        let mut policy = ManagedPolicy {
+            force_kill_switch: true,
+            allowed_countries: vec!["DE".to_string()],
+            disable_custom_dns: false,
+        };
+        PolicyOverrides {
+            allowed_countries: Some(vec!["US".to_string(), "CA".to_string()]),
+            disable_custom_dns: Some(true),
+            ..PolicyOverrides::default()
+        }
+        .apply_to(&mut policy);
+
+        assert!(policy.force_kill_switch);
+        assert_eq!(policy.allowed_countries, vec!["US", "CA"]);
+        assert!(policy.disable_custom_dns);
+        assert!(PolicyOverrides::default().is_empty());
+    }
+}
@@ -0,0 +1,403 @@
+//! Environment self-checks shown during onboarding
+//!
+//! Each check reports whether a prerequisite for connecting is in place
+//! (tunnel driver or tooling, privileges, keyring, firewall, API reachability,
+//! system clock) along with a human-readable detail for the UI.
+
+use crate::{api, clock, connectivity};
+use serde::Serialize;
+#[cfg(not(target_os = "windows"))]
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Time allowed for the API reachability request
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    /// Connecting may still work, but something is degraded
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightCheck {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl PreflightCheck {
+    fn new(
+        id: &'static str,
+        name: &'static str,
+        status: CheckStatus,
+        detail: impl Into<String>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+    /// True when no check failed
+    pub ready: bool,
+}
+
+impl PreflightReport {
+    fn new(checks: Vec<PreflightCheck>) -> Self {
+        let ready = checks.iter().all(|c| c.status != CheckStatus::Fail);
+        Self { checks, ready }
+    }
+}
+
+/// Run every check for the current platform
+pub async fn run() -> PreflightReport {
+    let checks = vec![
+        check_tunnel_driver(),
+        check_privileges(),
+        check_keyring(),
+        check_firewall(),
+        check_api().await,
+        check_clock().await,
+    ];
+    PreflightReport::new(checks)
+}
+
+#[cfg(target_os = "windows")]
+fn check_tunnel_driver() -> PreflightCheck {
+    let name = "Wintun driver";
+    match crate::vpn::wintun_dll_paths()
+        .into_iter()
+        .find(|p| p.exists())
+    {
+        Some(path) => PreflightCheck::new(
+            "tunnel_driver",
+            name,
+            CheckStatus::Pass,
+            format!("Found {}", path.display()),
+        ),
+        None => PreflightCheck::new(
+            "tunnel_driver",
+            name,
+            CheckStatus::Fail,
+            "wintun.dll is missing; reinstall SACVPN",
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_tunnel_driver() -> PreflightCheck {
+    // The embedded tunnel only needs the kernel's TUN driver; wg-quick is the fallback
+    if std::path::Path::new("/dev/net/tun").exists() {
+        return PreflightCheck::new(
+            "tunnel_driver",
+            "Tunnel driver",
+            CheckStatus::Pass,
+            "/dev/net/tun found",
+        );
+    }
+    check_wireguard_tools()
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn check_tunnel_driver() -> PreflightCheck {
+    PreflightCheck::new(
+        "tunnel_driver",
+        "Tunnel driver",
+        CheckStatus::Pass,
+        "utun is built into macOS",
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn check_wireguard_tools() -> PreflightCheck {
+    let name = "WireGuard tools";
+    let missing: Vec<&str> = ["wg", "wg-quick"]
+        .into_iter()
+        .filter(|tool| find_executable(tool).is_none())
+        .collect();
+
+    if missing.is_empty() {
+        PreflightCheck::new(
+            "tunnel_driver",
+            name,
+            CheckStatus::Pass,
+            "wg and wg-quick found",
+        )
+    } else {
+        PreflightCheck::new(
+            "tunnel_driver",
+            name,
+            CheckStatus::Fail,
+            format!(
+                "Not found: {} (install wireguard-tools)",
+                missing.join(", ")
+            ),
+        )
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn check_privileges() -> PreflightCheck {
+    // `net session` only succeeds from an elevated process
+    let elevated = std::process::Command::new("net")
+        .arg("session")
+        .output()
+        .map(|o| o.status.success())
+        .unwrap_or(false);
+
+    if elevated {
+        PreflightCheck::new(
+            "privileges",
+            "Administrator",
+            CheckStatus::Pass,
+            "Running elevated",
+        )
+    } else {
+        PreflightCheck::new(
+            "privileges",
+            "Administrator",
+            CheckStatus::Fail,
+            "Creating the tunnel adapter requires running SACVPN as administrator",
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check_privileges() -> PreflightCheck {
+    // Without CAP_NET_ADMIN the tunnel falls back to wg-quick, which is run
+    // through pkexec or sudo, so either is enough
+    if is_root() {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Pass,
+            "Running as root",
+        )
+    } else if has_net_admin() {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Pass,
+            "Allowed to create the tunnel interface (CAP_NET_ADMIN)",
+        )
+    } else if let Some(tool) = ["pkexec", "sudo"]
+        .into_iter()
+        .find(|t| find_executable(t).is_some())
+    {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Pass,
+            format!("{} will be used to bring the tunnel up", tool),
+        )
+    } else {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Fail,
+            "Neither pkexec nor sudo is available to run wg-quick",
+        )
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+fn check_privileges() -> PreflightCheck {
+    if is_root() {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Pass,
+            "Running as root",
+        )
+    } else {
+        PreflightCheck::new(
+            "privileges",
+            "Privileges",
+            CheckStatus::Fail,
+            "Creating the tunnel interface requires running SACVPN as root",
+        )
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_root() -> bool {
+    std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim() == "0")
+        .unwrap_or(false)
+}
+
+/// Whether this process may create network interfaces without being root
+#[cfg(target_os = "linux")]
+fn has_net_admin() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| effective_caps(&status))
+        .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+}
+
+#[cfg(any(target_os = "linux", test))]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Effective capability set from the contents of /proc/<pid>/status
+#[cfg(any(target_os = "linux", test))]
+fn effective_caps(status: &str) -> Option<u64> {
+    let hex = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(hex.trim(), 16).ok()
+}
+
+fn check_keyring() -> PreflightCheck {
+    let name = "Credential storage";
+    let result = keyring::Entry::new("sacvpn", "preflight").and_then(|entry| entry.get_password());
+
+    match result {
+        Ok(_) | Err(keyring::Error::NoEntry) => PreflightCheck::new(
+            "keyring",
+            name,
+            CheckStatus::Pass,
+            "System keyring is available",
+        ),
+        Err(e) => PreflightCheck::new(
+            "keyring",
+            name,
+            CheckStatus::Fail,
+            format!("System keyring is unavailable: {}", e),
+        ),
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn check_firewall() -> PreflightCheck {
+    let output = std::process::Command::new("netsh")
+        .args(["advfirewall", "show", "currentprofile"])
+        .output();
+
+    match output {
+        Ok(o) if o.status.success() => PreflightCheck::new(
+            "firewall",
+            "Firewall",
+            CheckStatus::Pass,
+            "Windows Firewall is reachable",
+        ),
+        _ => PreflightCheck::new(
+            "firewall",
+            "Firewall",
+            CheckStatus::Warn,
+            "Windows Firewall could not be queried; the kill switch may not work",
+        ),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_firewall() -> PreflightCheck {
+    #[cfg(target_os = "macos")]
+    let tool = "pfctl";
+    #[cfg(not(target_os = "macos"))]
+    let tool = "iptables";
+
+    match find_executable(tool) {
+        Some(path) => PreflightCheck::new(
+            "firewall",
+            "Firewall",
+            CheckStatus::Pass,
+            format!("Found {}", path.display()),
+        ),
+        None => PreflightCheck::new(
+            "firewall",
+            "Firewall",
+            CheckStatus::Warn,
+            format!("{} not found; the kill switch will not work", tool),
+        ),
+    }
+}
+
+async fn check_api() -> PreflightCheck {
+    let name = "SACVPN API";
+    let base_url = match api::base_url() {
+        Ok(url) => url,
+        Err(e) => return PreflightCheck::new("api", name, CheckStatus::Fail, e),
+    };
+
+    let request = connectivity::api_client()
+        .get(base_url)
+        .timeout(API_TIMEOUT);
+
+    // Any HTTP response means the server is reachable
+    match connectivity::send(request).await {
+        Ok(_) => PreflightCheck::new(
+            "api",
+            name,
+            CheckStatus::Pass,
+            format!("{} is reachable", base_url),
+        ),
+        Err(e) => PreflightCheck::new(
+            "api",
+            name,
+            CheckStatus::Fail,
+            format!("Could not reach {}: {}", base_url, e),
+        ),
+    }
+}
+
+async fn check_clock() -> PreflightCheck {
+    let name = "System clock";
+    match clock::skew().await {
+        Some(skew) => match clock::skew_error(skew) {
+            None => PreflightCheck::new("clock", name, CheckStatus::Pass, "Clock is correct"),
+            Some(error) => PreflightCheck::new("clock", name, CheckStatus::Fail, error),
+        },
+        None => PreflightCheck::new(
+            "clock",
+            name,
+            CheckStatus::Warn,
+            "Could not compare the clock with server time",
+        ),
+    }
+}
+
+/// Locate a tool on PATH, also looking in the sbin directories GUI sessions often omit
+#[cfg(not(target_os = "windows"))]
+fn find_executable(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(["/sbin", "/usr/sbin", "/usr/local/bin", "/opt/homebrew/bin"].map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_ready_unless_a_check_fails() {
+        let pass = PreflightCheck::new("a", "A", CheckStatus::Pass, "");
+        let warn = PreflightCheck::new("b", "B", CheckStatus::Warn, "");
+        let fail = PreflightCheck::new("c", "C", CheckStatus::Fail, "");
+
+        assert!(PreflightReport::new(vec![pass.clone(), warn.clone()]).ready);
+        assert!(!PreflightReport::new(vec![pass, warn, fail]).ready);
+    }
+
+    #[test]
+    fn test_effective_caps_reads_cap_eff() {
+        let status = "Name:\tsacvpn\nCapInh:\t0000000000000000\nCapEff:\t0000000000001000\n";
+        let caps = effective_caps(status).unwrap();
+        assert_eq!(caps, 1 << CAP_NET_ADMIN);
+        assert_eq!(effective_caps("Name:\tsacvpn\n"), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! Content presets: ready-made profiles for common uses
+//!
+//! A preset bundles a server filter with the split tunneling and DNS settings
+//! that use needs (e.g. US streaming, banking from abroad). A few ship with
+//! the app; the API can add to or replace them, and users can save their own.
+//! Applying a preset turns it into a connection profile and activates it.
+
+use crate::servers::{self, Server};
+use crate::settings::ConnectionProfile;
+use crate::vpn::{SplitMode, SplitTunnelSettings};
+use crate::{connectivity, storage};
+use serde::{Deserialize, Serialize};
+use std::sync::{OnceLock, RwLock};
+
+const PRESETS_FILE: &str = "presets.json";
+
+/// Which servers suit a preset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerFilter {
+    /// Allowed country codes; empty allows every country
+    pub country_codes: Vec<String>,
+    /// Skip servers busier than this (percent)
+    pub max_load: Option<u8>,
+}
+
+impl ServerFilter {
+    pub fn matches(&self, server: &Server) -> bool {
+        (self.country_codes.is_empty()
+            || self
+                .country_codes
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(&server.country_code)))
+            && self.max_load.is_none_or(|max| server.load <= max)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresetSource {
+    /// Shipped with the app
+    Builtin,
+    /// Fetched from the API
+    Remote,
+    #[default]
+    User,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub servers: ServerFilter,
+    pub split_tunnel: Option<SplitTunnelSettings>,
+    pub custom_dns: Option<Vec<String>>,
+    /// Set when listing; the API and the UI don't need to send it
+    #[serde(default)]
+    pub source: PresetSource,
+}
+
+impl Preset {
+    /// The connection profile this preset is applied as
+    pub fn to_profile(&self) -> ConnectionProfile {
+        ConnectionProfile {
+            name: self.name.clone(),
+            // The kill switch would block the domains the preset lets bypass the tunnel
+            kill_switch: self
+                .split_tunnel
+                .as_ref()
+                .filter(|split| split.is_active() && split.mode == SplitMode::Bypass)
+                .map(|_| false),
+            custom_dns: self.custom_dns.clone(),
+            allow_lan: None,
+            split_tunnel: self.split_tunnel.clone(),
+        }
+    }
+
+    /// Cached servers that match the filter, best first
+    pub fn servers(&self) -> Vec<Server> {
+        servers::recommend(None)
+            .into_iter()
+            .filter(|s| self.servers.matches(s))
+            .collect()
+    }
+}
+
+/// Presets fetched from the API and saved by the user
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoredPresets {
+    remote: Vec<Preset>,
+    user: Vec<Preset>,
+}
+
+static PRESETS: OnceLock<RwLock<StoredPresets>> = OnceLock::new();
+
+fn stored() -> &'static RwLock<StoredPresets> {
+    PRESETS.get_or_init(|| RwLock::new(storage::load(PRESETS_FILE)))
+}
+
+fn persist(presets: &StoredPresets) -> Result<(), String> {
+    storage::save(PRESETS_FILE, presets)
+}
+
+fn builtin() -> Vec<Preset> {
+    let preset = |id: &str, name: &str, description: &str| Preset {
+        id: id.to_string(),
+        name: name.to_string(),
+        description: description.to_string(),
+        servers: ServerFilter::default(),
+        split_tunnel: None,
+        custom_dns: None,
+        source: PresetSource::Builtin,
+    };
+
+    vec![
+        Preset {
+            servers: ServerFilter {
+                country_codes: vec!["US".to_string()],
+                max_load: Some(70),
+            },
+            ..preset(
+                "us-streaming",
+                "US streaming",
+                "US servers with headroom for video",
+            )
+        },
+        Preset {
+            servers: ServerFilter {
+                country_codes: Vec::new(),
+                max_load: Some(50),
+            },
+            ..preset(
+                "p2p",
+                "P2P optimized",
+                "Lightly loaded servers for long-running transfers",
+            )
+        },
+        Preset {
+            split_tunnel: Some(SplitTunnelSettings {
+                enabled: true,
+                mode: SplitMode::Bypass,
+                domains: [
+                    "bankofamerica.com",
+                    "chase.com",
+                    "wellsfargo.com",
+                    "citi.com",
+                    "capitalone.com",
+                    "usbank.com",
+                    "pnc.com",
+                ]
+                .map(String::from)
+                .to_vec(),
+            }),
+            ..preset(
+                "banking-safe",
+                "Banking-safe",
+                "Bank sites bypass the tunnel so they see your usual location",
+            )
+        },
+    ]
+}
+
+/// Every preset, later sources replacing earlier ones with the same id:
+/// built-in, then remote, then user
+fn merge(builtin: Vec<Preset>, stored: &StoredPresets) -> Vec<Preset> {
+    let mut presets = builtin;
+    let layers = [
+        (&stored.remote, PresetSource::Remote),
+        (&stored.user, PresetSource::User),
+    ];
+    for (layer, source) in layers {
+        for preset in layer {
+            presets.retain(|p| p.id != preset.id);
+            presets.push(Preset {
+                source,
+                ..preset.clone()
+            });
+        }
+    }
+    presets
+}
+
+pub fn list() -> Vec<Preset> {
+    merge(builtin(), &stored().read().unwrap())
+}
+
+pub fn find(id: &str) -> Option<Preset> {
+    list().into_iter().find(|p| p.id == id)
+}
+
+/// Save a user preset, replacing any preset with the same id
+pub fn save(preset: Preset) -> Result<Vec<Preset>, String> {
+    if preset.id.trim().is_empty() || preset.name.trim().is_empty() {
+        return Err("A preset needs an id and a name".to_string());
+    }
+    let mut stored = stored().write().unwrap();
+    stored.user.retain(|p| p.id != preset.id);
+    stored.user.push(preset);
+    persist(&stored)?;
+    Ok(merge(builtin(), &stored))
+}
+
+/// Delete a user preset; a built-in or remote one it replaced comes back
+pub fn delete(id: &str) -> Result<Vec<Preset>, String> {
+    let mut stored = stored().write().unwrap();
+    if !stored.user.iter().any(|p| p.id == id) {
+        return Err(format!("Only your own presets can be deleted: {}", id));
+    }
+    stored.user.retain(|p| p.id != id);
+    persist(&stored)?;
+    Ok(merge(builtin(), &stored))
+}
+
+/// Fetch the presets published by the API, replacing the previously fetched set
+pub async fn fetch(api_url: &str, token: &str) -> Result<Vec<Preset>, String> {
+    let client = connectivity::api_client();
+    let response = connectivity::send(
+        client
+            .get(format!("{}/api/vpn/presets", api_url))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("API error: {}", response.status()));
+    }
+
+    let remote: Vec<Preset> = response.json().await.map_err(|e| e.to_string())?;
+    let mut stored = stored().write().unwrap();
+    stored.remote = remote;
+    persist(&stored)?;
+    Ok(merge(builtin(), &stored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(country_code: &str, load: u8) -> Server {
+        let id = format!("{}-{}", country_code, load);
+        Server {
+            load,
+            ..servers::test_server(&id, "", country_code)
+        }
+    }
+
+    #[test]
+    fn test_server_filter() {
+        let filter = ServerFilter {
+            country_codes: vec!["us".to_string()],
+            max_load: Some(70),
+        };
+        assert!(filter.matches(&server("US", 70)));
+        assert!(!filter.matches(&server("US", 71)));
+        assert!(!filter.matches(&server("CA", 10)));
+        assert!(ServerFilter::default().matches(&server("CA", 100)));
+    }
+
+    #[test]
+    fn test_merge_prefers_user_then_remote() {
+        let custom = |id: &str, name: &str| Preset {
+            id: id.to_string(),
+            name: name.to_string(),
+            description: String::new(),
+            servers: ServerFilter::default(),
+            split_tunnel: None,
+            custom_dns: None,
+            source: PresetSource::User,
+        };
+        let stored = StoredPresets {
+            remote: vec![custom("p2p", "P2P (updated)"), custom("uk-tv", "UK TV")],
+            user: vec![custom("p2p", "My P2P")],
+        };
+
+        let presets = merge(builtin(), &stored);
+        let by_id = |id: &str| presets.iter().find(|p| p.id == id).unwrap();
+        assert_eq!(presets.len(), 4);
+        assert_eq!(by_id("us-streaming").source, PresetSource::Builtin);
+        assert_eq!(by_id("uk-tv").source, PresetSource::Remote);
+        assert_eq!(by_id("p2p").name, "My P2P");
+        assert_eq!(by_id("p2p").source, PresetSource::User);
+        assert_eq!(by_id("banking-safe").to_profile().kill_switch, Some(false));
+        assert_eq!(by_id("p2p").to_profile().kill_switch, None);
+    }
+}
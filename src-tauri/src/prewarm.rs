@@ -0,0 +1,112 @@
+//! Pre-warming a connect while the user hovers or selects a server
+//!
+//! The config request is the slowest step of a connect, so the config for the
+//! server the user is looking at is requested ahead of time and handed to the
+//! next connect to that server. Only the latest server is kept; pre-warming
+//! another one cancels the request still in flight.
+//!
+//! A config request registers a peer on the server, so browsing the list
+//! would leave peers behind on every server looked at; pre-warming only
+//! happens once the user turns on `prewarm_config`.
+
+use crate::connectivity::{self, RequestKind};
+use crate::servers;
+use crate::settings;
+use crate::vpn::{self, VpnConfig};
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How long a pre-generated config is handed out before it is requested again
+const CONFIG_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PrewarmStatus {
+    pub server_id: String,
+    /// Address the endpoint resolved to
+    pub endpoint: Option<String>,
+    /// A config is ready for the next connect to this server
+    pub config_ready: bool,
+}
+
+struct Prewarmed {
+    server_id: String,
+    config: VpnConfig,
+    at: Instant,
+}
+
+static PREWARMED: OnceLock<Mutex<Option<Prewarmed>>> = OnceLock::new();
+
+fn prewarmed() -> &'static Mutex<Option<Prewarmed>> {
+    PREWARMED.get_or_init(|| Mutex::new(None))
+}
+
+/// Get a connect to `server_id` ready: fetch its config if enabled, resolve
+/// the endpoint and load the tunnel driver
+pub async fn server(api_url: &str, token: &str, server_id: &str) -> Result<PrewarmStatus, String> {
+    let mut status = PrewarmStatus {
+        server_id: server_id.to_string(),
+        endpoint: None,
+        config_ready: false,
+    };
+    if !settings::current().prewarm_config {
+        return Ok(status);
+    }
+
+    let config = match fresh_config(server_id) {
+        Some(config) => config,
+        None => {
+            let config = connectivity::cancellable(
+                RequestKind::Prewarm,
+                None,
+                servers::generate_config(api_url, token, server_id),
+            )
+            .await?;
+            *prewarmed().lock().unwrap() = Some(Prewarmed {
+                server_id: server_id.to_string(),
+                config: config.clone(),
+                at: Instant::now(),
+            });
+            config
+        }
+    };
+    status.config_ready = true;
+
+    // The config is still worth having when this fails; the connect reports it
+    match vpn::prewarm(&config, &settings::current().tuning).await {
+        Ok(address) => status.endpoint = Some(address.to_string()),
+        Err(e) => log::warn!("Failed to pre-warm {}: {}", server_id, e),
+    }
+    log::info!("Pre-warmed connect to {}", server_id);
+    Ok(status)
+}
+
+/// The pre-generated config for `server_id`, handed out once
+pub fn take_config(server_id: &str) -> Option<VpnConfig> {
+    let mut prewarmed = prewarmed().lock().unwrap();
+    match prewarmed.take() {
+        Some(entry) if entry.server_id == server_id && entry.at.elapsed() < CONFIG_TTL => {
+            log::info!("Using pre-generated config for {}", server_id);
+            Some(entry.config)
+        }
+        // Keep another server's config for its own connect
+        entry => {
+            *prewarmed = entry.filter(|entry| entry.at.elapsed() < CONFIG_TTL);
+            None
+        }
+    }
+}
+
+/// Forget the pre-generated config, e.g. when the account signs out
+pub fn clear() {
+    *prewarmed().lock().unwrap() = None;
+}
+
+fn fresh_config(server_id: &str) -> Option<VpnConfig> {
+    prewarmed()
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|entry| entry.server_id == server_id && entry.at.elapsed() < CONFIG_TTL)
+        .map(|entry| entry.config.clone())
+}
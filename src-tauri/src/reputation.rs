@@ -0,0 +1,217 @@
+//! Exit IP reputation
+//!
+//! Shared exit addresses end up on spam blocklists, and sites that consult
+//! them answer with CAPTCHAs or refuse service. When enabled, the exit
+//! address is looked up on a few DNS blocklists after every connect; if
+//! several list it the user is warned, the UI gets a
+//! `security://exit-reputation` event, and `switch` moves the connection to
+//! another server in the same city.
+
+use crate::actions;
+use crate::servers::{self, Server};
+use crate::vpn::{ErrorReport, VpnError};
+use crate::{leakwatch, settings};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tauri_plugin_notification::NotificationExt;
+
+pub const EXIT_REPUTATION_EVENT: &str = "security://exit-reputation";
+
+/// DNS blocklists consulted; an address is listed when its reversed form
+/// under the zone resolves
+const BLOCKLISTS: [&str; 4] = [
+    "zen.spamhaus.org",
+    "bl.spamcop.net",
+    "b.barracudacentral.org",
+    "dnsbl.dronebl.org",
+];
+
+/// Listings before the address counts as widely blacklisted
+const WIDELY_LISTED: usize = 2;
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlternativeServer {
+    pub server_id: String,
+    pub server_name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExitReputation {
+    pub server_id: String,
+    pub exit_ip: String,
+    /// Blocklists the exit address is on
+    pub listed_on: Vec<String>,
+    /// Another server in the same city, offered when the address is widely listed
+    pub alternative: Option<AlternativeServer>,
+    pub checked_at: i64,
+}
+
+static LAST: OnceLock<Mutex<Option<ExitReputation>>> = OnceLock::new();
+
+fn last() -> &'static Mutex<Option<ExitReputation>> {
+    LAST.get_or_init(|| Mutex::new(None))
+}
+
+/// Check the exit address of the connection to `server_id` in the background,
+/// if the user turned the check on
+pub fn spawn_check(app: &AppHandle, server_id: &str) {
+    *last().lock().unwrap() = None;
+    if !settings::current().check_exit_reputation {
+        return;
+    }
+
+    let (app, server_id) = (app.clone(), server_id.to_string());
+    tauri::async_runtime::spawn(async move {
+        let Some(exit) = leakwatch::public_address().await else {
+            log::warn!("Skipping the exit IP reputation check, the exit address is unknown");
+            return;
+        };
+        let listed_on = listings(exit).await;
+        let widely_listed = listed_on.len() >= WIDELY_LISTED;
+        let report = ExitReputation {
+            alternative: widely_listed
+                .then(|| alternative(&server_id))
+                .flatten()
+                .map(|server| AlternativeServer {
+                    server_id: server.id,
+                    server_name: server.name,
+                }),
+            server_id,
+            exit_ip: exit.to_string(),
+            listed_on,
+            checked_at: chrono::Utc::now().timestamp(),
+        };
+        if widely_listed {
+            warn(&app, &report);
+        } else {
+            log::info!(
+                "Exit IP {} is on {} blocklists",
+                report.exit_ip,
+                report.listed_on.len()
+            );
+        }
+        *last().lock().unwrap() = Some(report);
+    });
+}
+
+/// Disconnect and connect to the alternative offered by the last check,
+/// returning its id
+pub async fn switch(app: &AppHandle) -> Result<String, ErrorReport> {
+    let alternative = last()
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|report| report.alternative.clone())
+        .ok_or_else(|| VpnError::ConfigError("No alternative server to switch to".to_string()))?;
+    log::info!(
+        "Switching to {} for a cleaner exit IP",
+        alternative.server_name
+    );
+
+    actions::switch_server(app, &alternative.server_id).await?;
+    Ok(alternative.server_id)
+}
+
+/// Best-ranked other server in the same city as `server_id`
+fn alternative(server_id: &str) -> Option<Server> {
+    let current = servers::find(server_id)?;
+    servers::recommend(Some(&current.country_code))
+        .into_iter()
+        .find(|server| {
+            server.id != current.id
+                && !server.dedicated
+                && server.city.eq_ignore_ascii_case(&current.city)
+        })
+}
+
+fn warn(app: &AppHandle, report: &ExitReputation) {
+    log::warn!(
+        "Exit IP {} is listed on {}",
+        report.exit_ip,
+        report.listed_on.join(", ")
+    );
+    let _ = app.emit(EXIT_REPUTATION_EVENT, report);
+
+    let advice = match &report.alternative {
+        Some(alternative) => format!("Switch to {} from SACVPN.", alternative.server_name),
+        None => "Connecting to another server may help.".to_string(),
+    };
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title("Websites may show CAPTCHAs")
+        .body(format!(
+            "Your exit address ({}) is on {} spam blocklists. {}",
+            report.exit_ip,
+            report.listed_on.len(),
+            advice
+        ))
+        .show()
+    {
+        log::warn!("Failed to show reputation notification: {}", e);
+    }
+}
+
+/// Blocklists `address` is on; the lists only cover IPv4
+async fn listings(address: IpAddr) -> Vec<String> {
+    let IpAddr::V4(address) = address else {
+        return Vec::new();
+    };
+    let lookups = BLOCKLISTS.map(|zone| async move {
+        let name = query_name(address, zone);
+        let listed = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((name, 0)))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .is_some_and(|mut answers| answers.any(|answer| is_listing(answer.ip())));
+        listed.then(|| zone.to_string())
+    });
+    futures::future::join_all(lookups)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Name looked up for `address` on the blocklist `zone`, e.g.
+/// 9.113.0.203.zen.spamhaus.org for 203.0.113.9
+fn query_name(address: Ipv4Addr, zone: &str) -> String {
+    let [a, b, c, d] = address.octets();
+    format!("{}.{}.{}.{}.{}", d, c, b, a, zone)
+}
+
+/// Whether a blocklist answer means "listed"
+///
+/// Listings are in 127.0.0.0/8; 127.255.255.0/24 is how Spamhaus refuses
+/// queries from public resolvers, which says nothing about the address.
+fn is_listing(answer: IpAddr) -> bool {
+    match answer {
+        IpAddr::V4(answer) => {
+            let [a, b, c, _] = answer.octets();
+            a == 127 && (b, c) != (255, 255)
+        }
+        IpAddr::V6(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist_queries_and_answers() {
+        assert_eq!(
+            query_name(Ipv4Addr::new(203, 0, 113, 9), "zen.spamhaus.org"),
+            "9.113.0.203.zen.spamhaus.org"
+        );
+        assert!(is_listing(IpAddr::from([127, 0, 0, 2])));
+        assert!(is_listing(IpAddr::from([127, 0, 0, 10])));
+        assert!(!is_listing(IpAddr::from([127, 255, 255, 254])));
+        assert!(!is_listing(IpAddr::from([203, 0, 113, 9])));
+    }
+}
@@ -0,0 +1,234 @@
+//! Country-level server rotation
+//!
+//! With rotation on, the session moves to another server in its country every
+//! few hours, or each connect goes to the next server in the country. Servers
+//! are taken best-ranked first among those not used yet in the country's
+//! current round, so rotation goes round the whole country rather than back
+//! and forth between its top two. Dedicated IPs never rotate.
+
+use crate::servers::{self, Server};
+use crate::vpn::events::{self, EventCategory, Severity};
+use crate::vpn::{BootInstant, RotationMode, VpnHandle};
+use crate::{
+    api, configdiff, credentials, devices, linktune, policy, reputation, settings, taskbar, tray,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+
+pub const ROTATED_EVENT: &str = "vpn://rotated";
+
+/// Time between checks for a session due to move on
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wait after a failed rotation before trying again
+const RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerRotation {
+    pub from_server_id: String,
+    pub to_server_id: String,
+    pub to_server_name: String,
+}
+
+/// Servers used in each country's current round, by uppercase country code
+static ROUNDS: OnceLock<Mutex<HashMap<String, Vec<String>>>> = OnceLock::new();
+
+fn rounds() -> &'static Mutex<HashMap<String, Vec<String>>> {
+    ROUNDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Server to connect to when the user picked `server_id`: itself, unless
+/// every connect rotates and it was already used this round
+pub fn on_connect(server_id: &str) -> String {
+    if settings::current().rotation.mode != RotationMode::EveryConnect {
+        return server_id.to_string();
+    }
+    let Some(selected) = servers::find(server_id).filter(|server| !server.dedicated) else {
+        return server_id.to_string();
+    };
+
+    // The selected server leads, so a fresh round starts with it
+    let candidates: Vec<Server> = std::iter::once(selected.clone())
+        .chain(
+            servers::recommend(Some(&selected.country_code))
+                .into_iter()
+                .filter(|server| server.id != selected.id && !server.dedicated),
+        )
+        .collect();
+    let mut rounds = rounds().lock().unwrap();
+    let round = rounds
+        .entry(selected.country_code.to_ascii_uppercase())
+        .or_default();
+    match pick(&candidates, round) {
+        Some(next) if next.id != selected.id => {
+            log::info!("Rotating to {} instead of {}", next.name, selected.name);
+            next.id.clone()
+        }
+        _ => selected.id,
+    }
+}
+
+/// Server to move a session on `current` to, if its country has another
+fn next_server(current: &Server) -> Option<Server> {
+    let candidates: Vec<Server> = servers::recommend(Some(&current.country_code))
+        .into_iter()
+        .filter(|server| server.id != current.id && !server.dedicated)
+        .collect();
+    let mut rounds = rounds().lock().unwrap();
+    let round = rounds
+        .entry(current.country_code.to_ascii_uppercase())
+        .or_default();
+    if !round.contains(&current.id) {
+        round.push(current.id.clone());
+    }
+    pick(&candidates, round).cloned()
+}
+
+/// First of `candidates` not used in `round`, starting a new round once all
+/// have been
+fn pick<'a>(candidates: &'a [Server], round: &mut Vec<String>) -> Option<&'a Server> {
+    if candidates.iter().all(|server| round.contains(&server.id)) {
+        round.clear();
+    }
+    let next = candidates
+        .iter()
+        .find(|server| !round.contains(&server.id))?;
+    round.push(next.id.clone());
+    Some(next)
+}
+
+/// Move sessions on to the next server as the rotation interval runs out;
+/// intended to be spawned once at startup
+pub async fn run(app: AppHandle, manager: VpnHandle) {
+    let mut held_since: Option<BootInstant> = None;
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if held_since.is_some_and(|at| at.elapsed() < RETRY_AFTER) {
+            continue;
+        }
+
+        let rotation = settings::current().rotation;
+        let due = manager
+            .call(move |vpn| {
+                Box::pin(async move {
+                    if vpn.rotation_due(&rotation).await {
+                        vpn.get_server_id()
+                    } else {
+                        None
+                    }
+                })
+            })
+            .await;
+        let Some(server_id) = due else {
+            continue;
+        };
+
+        held_since = match rotate(&app, &manager, &server_id).await {
+            Ok(()) => None,
+            Err(e) => {
+                log::warn!("Server rotation away from {} failed: {}", server_id, e);
+                Some(BootInstant::now())
+            }
+        };
+    }
+}
+
+/// Move the session on `server_id` to the next server in its country
+async fn rotate(app: &AppHandle, manager: &VpnHandle, server_id: &str) -> Result<(), String> {
+    let current = servers::find(server_id)
+        .filter(|server| !server.dedicated)
+        .ok_or_else(|| format!("{} is not a rotating server", server_id))?;
+    let next =
+        next_server(&current).ok_or_else(|| format!("No other server in {}", current.country))?;
+    policy::check_server(&next.id)?;
+
+    // The new config is in hand before the tunnel is touched
+    let token = credentials::token()?;
+    let api_url = api::base_url()?;
+    let mut config = match servers::generate_config(api_url, &token, &next.id).await {
+        Ok(config) => config,
+        Err(e) => {
+            if devices::is_revoked(&e) {
+                end_revoked(app, manager).await;
+            }
+            return Err(e);
+        }
+    };
+    if let Some(diff) = configdiff::check(&next.id, &config) {
+        let _ = app.emit(configdiff::CONFIG_CHANGED_EVENT, diff);
+    }
+    settings::current().apply_server_overrides(&next.id, &mut config);
+
+    let id = next.id.clone();
+    manager
+        .call(move |vpn| Box::pin(async move { vpn.switch_server(&id, config).await }))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    log::info!("Rotated from {} to {}", current.name, next.name);
+    events::record(
+        EventCategory::Rotation,
+        Severity::Info,
+        format!("Rotated from {} to {}", current.name, next.name),
+    );
+    let _ = app.emit(
+        ROTATED_EVENT,
+        ServerRotation {
+            from_server_id: current.id,
+            to_server_id: next.id.clone(),
+            to_server_name: next.name,
+        },
+    );
+    servers::record_usage(&next.id);
+    reputation::spawn_check(app, &next.id);
+    linktune::spawn_measure(manager, &next.id);
+    tray::refresh(app);
+    taskbar::refresh(app);
+    Ok(())
+}
+
+/// End a session whose config refresh the account refused; its peer is gone
+/// or about to be, so the watchdog must not restore it
+async fn end_revoked(app: &AppHandle, manager: &VpnHandle) {
+    log::error!("The account revoked this device, ending the session");
+    events::record(
+        EventCategory::Rotation,
+        Severity::Error,
+        "The account no longer accepts this device, disconnected",
+    );
+    manager
+        .call(|vpn| {
+            Box::pin(async move {
+                if let Err(e) = vpn.disconnect().await {
+                    log::warn!("Failed to disconnect revoked session: {}", e);
+                }
+                vpn.mark_revoked();
+            })
+        })
+        .await;
+    tray::refresh(app);
+    taskbar::refresh(app);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(id: &str) -> Server {
+        servers::test_server(id, "Germany", "DE")
+    }
+
+    #[test]
+    fn test_pick_goes_round_every_server() {
+        let candidates = [server("de-1"), server("de-2"), server("de-3")];
+        let mut round = vec!["de-2".to_string()];
+        let picked: Vec<String> = (0..4)
+            .map(|_| pick(&candidates, &mut round).unwrap().id.clone())
+            .collect();
+        assert_eq!(picked, ["de-1", "de-3", "de-1", "de-2"]);
+        assert!(pick(&[], &mut round).is_none());
+    }
+}
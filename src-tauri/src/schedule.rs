@@ -0,0 +1,273 @@
+//! Scheduled connects and disconnects
+//!
+//! A schedule pairs a cron expression with a time zone (the system one unless
+//! set) and an action. Rather than arming a timer for the next run, the runner
+//! wakes at least every `MAX_SLEEP` and works out from the wall clock what has
+//! come due, so resuming from sleep or a clock change just recomputes the next
+//! runs. Runs missed by more than `MISSED_GRACE_SECS` while asleep are dropped.
+
+use crate::actions::{self, Action};
+use crate::cron::CronExpr;
+use crate::storage;
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use tauri::AppHandle;
+
+const SCHEDULES_FILE: &str = "schedules.json";
+
+/// Longest the runner sleeps before checking the clock; timers don't advance
+/// while the machine sleeps on every OS
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+
+/// How late a run missed while asleep may still happen
+const MISSED_GRACE_SECS: i64 = 5 * 60;
+
+/// Upcoming runs listed per schedule in the preview
+const PREVIEW_RUNS: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduledAction {
+    QuickConnect,
+    ConnectCountry { country_code: String },
+    ConnectServer { server_id: String },
+    Disconnect,
+}
+
+impl ScheduledAction {
+    fn to_action(&self) -> Action {
+        match self {
+            Self::QuickConnect => Action::QuickConnect,
+            Self::ConnectCountry { country_code } => Action::ConnectCountry(country_code.clone()),
+            Self::ConnectServer { server_id } => Action::ConnectServer(server_id.clone()),
+            Self::Disconnect => Action::Disconnect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: String,
+    pub name: String,
+    /// Cron expression: minute hour day-of-month month day-of-week
+    pub cron: String,
+    /// IANA time zone such as "Europe/Berlin"; the system zone when unset
+    #[serde(default)]
+    pub timezone: Option<String>,
+    pub action: ScheduledAction,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Schedule {
+    /// First run after `after`
+    pub fn next_after(&self, after: &DateTime<Utc>) -> Result<Option<DateTime<Utc>>, String> {
+        let cron: CronExpr = self.cron.parse()?;
+        Ok(match self.zone()? {
+            Some(zone) => cron.next_after(after, &zone),
+            None => cron.next_after(after, &Local),
+        })
+    }
+
+    /// `at` on the schedule's wall clock, with its UTC offset
+    fn local_time(&self, at: &DateTime<Utc>) -> String {
+        match self.zone() {
+            Ok(Some(zone)) => at.with_timezone(&zone).to_rfc3339(),
+            _ => at.with_timezone(&Local).to_rfc3339(),
+        }
+    }
+
+    fn zone(&self) -> Result<Option<Tz>, String> {
+        self.timezone
+            .as_deref()
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                name.parse()
+                    .map_err(|_| format!("Unknown time zone: {}", name))
+            })
+            .transpose()
+    }
+}
+
+/// One upcoming run of a schedule
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledRun {
+    pub schedule_id: String,
+    pub name: String,
+    pub action: ScheduledAction,
+    /// Unix timestamp
+    pub at: i64,
+    /// RFC 3339 time in the schedule's zone
+    pub local_time: String,
+}
+
+static SCHEDULES: OnceLock<RwLock<Vec<Schedule>>> = OnceLock::new();
+
+fn schedules() -> &'static RwLock<Vec<Schedule>> {
+    SCHEDULES.get_or_init(|| RwLock::new(storage::load(SCHEDULES_FILE)))
+}
+
+pub fn list() -> Vec<Schedule> {
+    schedules().read().unwrap().clone()
+}
+
+/// Save a schedule, replacing any with the same id
+pub fn save(schedule: Schedule) -> Result<Vec<Schedule>, String> {
+    if schedule.id.trim().is_empty() || schedule.name.trim().is_empty() {
+        return Err("A schedule needs an id and a name".to_string());
+    }
+    if schedule.next_after(&Utc::now())?.is_none() {
+        return Err(format!("'{}' never runs", schedule.cron));
+    }
+    let mut list = schedules().write().unwrap();
+    list.retain(|s| s.id != schedule.id);
+    list.push(schedule);
+    storage::save(SCHEDULES_FILE, &*list)?;
+    Ok(list.clone())
+}
+
+pub fn delete(id: &str) -> Result<Vec<Schedule>, String> {
+    let mut list = schedules().write().unwrap();
+    list.retain(|s| s.id != id);
+    storage::save(SCHEDULES_FILE, &*list)?;
+    Ok(list.clone())
+}
+
+/// The next few runs of every enabled schedule, soonest first
+pub fn preview() -> Vec<ScheduledRun> {
+    let now = Utc::now();
+    let mut runs: Vec<ScheduledRun> = list()
+        .into_iter()
+        .filter(|schedule| schedule.enabled)
+        .flat_map(|schedule| {
+            let mut at = now;
+            let mut runs = Vec::new();
+            while runs.len() < PREVIEW_RUNS {
+                let Ok(Some(next)) = schedule.next_after(&at) else {
+                    break;
+                };
+                runs.push(ScheduledRun {
+                    schedule_id: schedule.id.clone(),
+                    name: schedule.name.clone(),
+                    action: schedule.action.clone(),
+                    at: next.timestamp(),
+                    local_time: schedule.local_time(&next),
+                });
+                at = next;
+            }
+            runs
+        })
+        .collect();
+    runs.sort_by_key(|run| run.at);
+    runs
+}
+
+/// Run schedules forever; intended to be spawned once at startup
+pub async fn run(app: AppHandle) {
+    let mut checked = Utc::now();
+    loop {
+        let sleep = next_run(&checked)
+            .and_then(|at| (at - Utc::now()).to_std().ok())
+            .map_or(MAX_SLEEP, |until| until.min(MAX_SLEEP));
+        tokio::time::sleep(sleep).await;
+
+        let now = Utc::now();
+        let slept = now - checked;
+        if slept > TimeDelta::seconds(MISSED_GRACE_SECS) || slept < TimeDelta::zero() {
+            log::info!(
+                "Clock moved {}s while the scheduler slept, recomputing runs",
+                slept.num_seconds()
+            );
+        }
+        let since = checked.max(now - TimeDelta::seconds(MISSED_GRACE_SECS));
+        checked = now;
+
+        for schedule in due(&list(), &since, &now) {
+            log::info!("Running schedule '{}'", schedule.name);
+            if let Err(e) = actions::run(&app, schedule.action.to_action()).await {
+                log::warn!("Schedule '{}' failed: {}", schedule.name, e);
+            }
+        }
+    }
+}
+
+/// Soonest run of any enabled schedule after `after`
+fn next_run(after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    list()
+        .iter()
+        .filter(|schedule| schedule.enabled)
+        .filter_map(|schedule| schedule.next_after(after).ok().flatten())
+        .min()
+}
+
+/// Enabled schedules with a run in (`since`, `now`], in run order; runs
+/// missed several times over only happen once
+fn due(schedules: &[Schedule], since: &DateTime<Utc>, now: &DateTime<Utc>) -> Vec<Schedule> {
+    let mut due: Vec<(DateTime<Utc>, &Schedule)> = schedules
+        .iter()
+        .filter(|schedule| schedule.enabled)
+        .filter_map(|schedule| {
+            let at = schedule.next_after(since).ok().flatten()?;
+            (at <= *now).then_some((at, schedule))
+        })
+        .collect();
+    due.sort_by_key(|(at, _)| *at);
+    due.into_iter()
+        .map(|(_, schedule)| schedule.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(id: &str, cron: &str, timezone: &str) -> Schedule {
+        Schedule {
+            id: id.to_string(),
+            name: id.to_string(),
+            cron: cron.to_string(),
+            timezone: Some(timezone.to_string()),
+            action: ScheduledAction::Disconnect,
+            enabled: true,
+        }
+    }
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_due_runs_in_each_zone() {
+        let schedules = [
+            schedule("berlin", "0 9 * * *", "Europe/Berlin"),
+            schedule("tokyo", "58 15 * * *", "Asia/Tokyo"),
+            Schedule {
+                enabled: false,
+                ..schedule("off", "* * * * *", "UTC")
+            },
+        ];
+        // 06:58Z is 15:58 in Tokyo, 07:00Z is 09:00 in Berlin (CEST)
+        let due_ids = |since, now| {
+            due(&schedules, &utc(since), &utc(now))
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            due_ids("2026-10-16T06:50:00Z", "2026-10-16T07:00:00Z"),
+            ["tokyo", "berlin"]
+        );
+        assert!(due_ids("2026-10-16T07:00:00Z", "2026-10-16T07:30:00Z").is_empty());
+
+        assert!(schedule("bad", "0 9 * * *", "Mars/Olympus")
+            .next_after(&Utc::now())
+            .is_err());
+    }
+}
@@ -0,0 +1,617 @@
+//! Server list model and locally stored server annotations
+
+use crate::latency::{self, LatencyMeasurement};
+use crate::vpn::{ErrorCode, ErrorReport, VpnConfig};
+use crate::{connectivity, devices, geo, places, policy, signing, storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+const ANNOTATIONS_FILE: &str = "server_notes.json";
+const FAVORITES_FILE: &str = "server_favorites.json";
+const CACHE_FILE: &str = "server_cache.json";
+const USAGE_FILE: &str = "server_usage.json";
+
+/// Recently used servers remembered for quick-connect menus
+const MAX_RECENT: usize = 10;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+
+/// Warn about a dedicated IP this long before its subscription runs out
+const DEDICATED_EXPIRY_WARNING_SECS: i64 = 7 * DAY_SECS;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Server {
+    pub id: String,
+    pub name: String,
+    pub country: String,
+    pub country_code: String,
+    pub city: String,
+    pub ip: String,
+    pub public_key: String,
+    pub load: u8,
+    pub latency: u32,
+    /// A dedicated IP belonging to this account, from `/api/vpn/dedicated-servers`
+    #[serde(default)]
+    pub dedicated: bool,
+    /// Unix timestamp when the dedicated IP subscription ends
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    /// Listed, but not accepting connections for now
+    #[serde(default)]
+    pub maintenance: bool,
+}
+
+impl Server {
+    /// Shown while a dedicated IP is about to expire, or has
+    pub fn expiry_warning(&self, now: i64) -> Option<String> {
+        let expires_at = self.expires_at.filter(|_| self.dedicated)?;
+        let remaining = expires_at - now;
+        if remaining > DEDICATED_EXPIRY_WARNING_SECS {
+            return None;
+        }
+        Some(if remaining <= 0 {
+            format!("Your dedicated IP {} has expired", self.ip)
+        } else {
+            format!(
+                "Your dedicated IP {} expires in {} days",
+                self.ip,
+                (remaining + DAY_SECS - 1) / DAY_SECS
+            )
+        })
+    }
+}
+
+/// User-provided display name and notes for a server, stored only on this device
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerAnnotation {
+    pub custom_name: Option<String>,
+    pub notes: Option<String>,
+}
+
+impl ServerAnnotation {
+    fn is_empty(&self) -> bool {
+        self.custom_name.is_none() && self.notes.is_none()
+    }
+}
+
+/// Connection history used to order quick-connect menus
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Usage {
+    /// Successful connections per country code
+    countries: HashMap<String, u32>,
+    /// Server ids, most recently connected first
+    recent: Vec<String>,
+}
+
+/// A server merged with its local annotation
+#[derive(Debug, Clone, Serialize)]
+pub struct EnrichedServer {
+    #[serde(flatten)]
+    pub server: Server,
+    pub custom_name: Option<String>,
+    pub notes: Option<String>,
+    pub expiry_warning: Option<String>,
+    /// `country` in the UI's language, or as the API sent it
+    pub localized_country: String,
+    /// `city` in the UI's language, or as the API sent it
+    pub localized_city: String,
+    /// Flag emoji of the country
+    pub flag: String,
+    /// Lowercase country code naming the flag asset, `unknown` without a valid code
+    pub flag_key: String,
+    /// Kilometres from where the system time zone places the user, when both are known
+    pub distance_km: Option<u32>,
+    /// Latest background probe of the server
+    pub measured_latency: Option<LatencyMeasurement>,
+    pub favorite: bool,
+}
+
+static CACHE: OnceLock<RwLock<Vec<Server>>> = OnceLock::new();
+static ANNOTATIONS: OnceLock<RwLock<HashMap<String, ServerAnnotation>>> = OnceLock::new();
+static USAGE: OnceLock<RwLock<Usage>> = OnceLock::new();
+static FAVORITES: OnceLock<RwLock<Vec<String>>> = OnceLock::new();
+/// Servers dropped from the list since startup, kept to find their nearest replacement
+static RETIRED: OnceLock<RwLock<HashMap<String, Server>>> = OnceLock::new();
+
+fn annotations() -> &'static RwLock<HashMap<String, ServerAnnotation>> {
+    ANNOTATIONS.get_or_init(|| RwLock::new(storage::load(ANNOTATIONS_FILE)))
+}
+
+fn cache() -> &'static RwLock<Vec<Server>> {
+    CACHE.get_or_init(|| RwLock::new(storage::load(CACHE_FILE)))
+}
+
+fn usage() -> &'static RwLock<Usage> {
+    USAGE.get_or_init(|| RwLock::new(storage::load(USAGE_FILE)))
+}
+
+fn favorites() -> &'static RwLock<Vec<String>> {
+    FAVORITES.get_or_init(|| RwLock::new(storage::load(FAVORITES_FILE)))
+}
+
+fn retired() -> &'static RwLock<HashMap<String, Server>> {
+    RETIRED.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+/// Last server list successfully fetched from the API, without the servers
+/// the organization's policy rules out
+pub fn cached() -> Vec<Server> {
+    cache()
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|s| policy::allows_server(s))
+        .cloned()
+        .collect()
+}
+
+/// Look up a server in the cached list
+pub fn find(server_id: &str) -> Option<Server> {
+    cache()
+        .read()
+        .unwrap()
+        .iter()
+        .find(|s| s.id == server_id)
+        .cloned()
+}
+
+/// Fetch the server list from the API and refresh the cache, keeping dedicated servers
+pub async fn fetch(api_url: &str, token: &str) -> Result<Vec<Server>, String> {
+    let client = connectivity::api_client();
+    let response = connectivity::send(
+        client
+            .get(format!("{}/api/vpn/servers", api_url))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("API error: {}", response.status()));
+    }
+
+    let mut servers: Vec<Server> = response.json().await.map_err(|e| e.to_string())?;
+    if let Some(managed) = policy::current() {
+        servers.retain(|s| managed.allows_country(&s.country_code));
+    }
+    servers.retain(|s| !s.dedicated);
+    servers.extend(cached().into_iter().filter(|s| s.dedicated));
+
+    store(servers.clone());
+    Ok(servers)
+}
+
+/// Fetch this account's dedicated IP servers and refresh them in the cache
+pub async fn fetch_dedicated(api_url: &str, token: &str) -> Result<Vec<Server>, String> {
+    let client = connectivity::api_client();
+    let response = connectivity::send(
+        client
+            .get(format!("{}/api/vpn/dedicated-servers", api_url))
+            .header("Authorization", format!("Bearer {}", token)),
+    )
+    .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("API error: {}", response.status()));
+    }
+
+    let mut dedicated: Vec<Server> = response.json().await.map_err(|e| e.to_string())?;
+    let now = chrono::Utc::now().timestamp();
+    for server in &mut dedicated {
+        server.dedicated = true;
+        if let Some(warning) = server.expiry_warning(now) {
+            log::warn!("{}", warning);
+        }
+    }
+
+    let mut servers = cached();
+    servers.retain(|s| !s.dedicated);
+    servers.extend(dedicated.iter().cloned());
+    store(servers);
+    Ok(dedicated)
+}
+
+fn store(servers: Vec<Server>) {
+    if let Err(e) = storage::save(CACHE_FILE, &servers) {
+        log::warn!("Failed to persist server cache: {}", e);
+    }
+    let mut cache = cache().write().unwrap();
+    let mut retired = retired().write().unwrap();
+    for server in cache.drain(..) {
+        if !servers.iter().any(|s| s.id == server.id) {
+            retired.insert(server.id.clone(), server);
+        }
+    }
+    retired.retain(|id, _| !servers.iter().any(|s| s.id == *id));
+    *cache = servers;
+}
+
+/// Refuse a server that is under maintenance or no longer listed, offering
+/// the nearest one that is up
+///
+/// Without a server list to go by, every server is let through.
+pub fn check_available(server_id: &str) -> Result<(), ErrorReport> {
+    let servers = cached();
+    if servers.is_empty() {
+        return Ok(());
+    }
+    let (message, unavailable) = match servers.iter().find(|s| s.id == server_id) {
+        Some(server) if !server.maintenance => return Ok(()),
+        Some(server) => (
+            format!("{} is under maintenance", server.name),
+            Some(server.clone()),
+        ),
+        None => (
+            format!("Server {} is no longer available", server_id),
+            retired().read().unwrap().get(server_id).cloned(),
+        ),
+    };
+    log::warn!("{}", message);
+
+    let alternative = nearest(unavailable.as_ref(), &recommend(None))
+        .map(|server| (server.id.clone(), server.name.clone()));
+    Err(ErrorReport::new(ErrorCode::ServerUnavailable, message).with_alternative(alternative))
+}
+
+/// First of the `ranked` servers in the same city as `server`, else the same
+/// country, else any; with `server` unknown, simply the first
+fn nearest<'a>(server: Option<&Server>, ranked: &'a [Server]) -> Option<&'a Server> {
+    let candidates: Vec<&Server> = ranked
+        .iter()
+        .filter(|s| !s.dedicated && server.is_none_or(|server| s.id != server.id))
+        .collect();
+    let Some(server) = server else {
+        return candidates.first().copied();
+    };
+    let same_country = |s: &&Server| s.country_code.eq_ignore_ascii_case(&server.country_code);
+    candidates
+        .iter()
+        .find(|s| same_country(s) && s.city.eq_ignore_ascii_case(&server.city))
+        .or_else(|| candidates.iter().find(|s| same_country(s)))
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+/// Request a WireGuard config for this device on a server
+///
+/// Configs for a dedicated server are pinned to its dedicated IP.
+pub async fn generate_config(
+    api_url: &str,
+    token: &str,
+    server_id: &str,
+) -> Result<VpnConfig, String> {
+    const PATH: &str = "/api/vpn/config";
+    let dedicated = find(server_id).filter(|s| s.dedicated);
+    let body = serde_json::json!({
+        "serverId": server_id,
+        "dedicated": dedicated.is_some(),
+    })
+    .to_string();
+
+    let client = connectivity::api_client();
+    let mut request = client
+        .post(format!("{}{}", api_url, PATH))
+        .header("Authorization", format!("Bearer {}", token))
+        .header("Content-Type", "application/json");
+    for (name, value) in signing::sign("POST", PATH, body.as_bytes())? {
+        request = request.header(name, value);
+    }
+
+    let response = connectivity::send(request.body(body)).await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(devices::limit_error(status, &body)
+            .or_else(|| devices::revoked_error(status))
+            .unwrap_or_else(|| format!("API error: {}", status)));
+    }
+
+    let mut config: VpnConfig = response.json().await.map_err(|e| e.to_string())?;
+    if let Some(server) = dedicated {
+        let peer = config.primary_peer_mut();
+        let pinned = pin_endpoint(&peer.endpoint, &server.ip)?;
+        if pinned != peer.endpoint {
+            log::warn!(
+                "Config for {} pointed at {}, pinning it to dedicated IP {}",
+                server_id,
+                peer.endpoint,
+                server.ip
+            );
+            peer.endpoint = pinned;
+        }
+    }
+    Ok(config)
+}
+
+/// `endpoint` with its host replaced by `ip`, keeping the port
+fn pin_endpoint(endpoint: &str, ip: &str) -> Result<String, String> {
+    let ip: std::net::IpAddr = ip
+        .parse()
+        .map_err(|_| format!("Invalid dedicated IP: {}", ip))?;
+    let port = endpoint
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .ok_or_else(|| format!("Invalid endpoint: {}", endpoint))?;
+    Ok(std::net::SocketAddr::new(ip, port).to_string())
+}
+
+/// Rank cached servers for connecting, optionally limited to one country
+///
+/// Fresh probe results take precedence over the API-reported latency, and load
+/// is weighted so a nearby but saturated server doesn't always win. Servers
+/// under maintenance are left out.
+pub fn recommend(country_code: Option<&str>) -> Vec<Server> {
+    let mut list: Vec<Server> = cached()
+        .into_iter()
+        .filter(|s| !s.maintenance)
+        .filter(|s| country_code.is_none_or(|c| s.country_code.eq_ignore_ascii_case(c)))
+        .collect();
+
+    list.sort_by_key(|s| {
+        let latency = latency::fresh_latency(&s.id).unwrap_or(s.latency);
+        latency + u32::from(s.load) * 2
+    });
+    list
+}
+
+/// Record a successful connection for the recent list and the server's country
+pub fn record_usage(server_id: &str) {
+    let Some(server) = find(server_id) else {
+        return;
+    };
+
+    let mut usage = usage().write().unwrap();
+    *usage
+        .countries
+        .entry(server.country_code.to_ascii_uppercase())
+        .or_default() += 1;
+    usage.recent.retain(|id| id != server_id);
+    usage.recent.insert(0, server.id);
+    usage.recent.truncate(MAX_RECENT);
+
+    if let Err(e) = storage::save(USAGE_FILE, &*usage) {
+        log::warn!("Failed to persist server usage: {}", e);
+    }
+}
+
+/// Countries in the cached list as (code, name), most used first
+pub fn top_countries(limit: usize) -> Vec<(String, String)> {
+    rank_countries(&cached(), &usage().read().unwrap().countries, limit)
+}
+
+/// Recently connected servers that are still in the cached list, most recent first
+pub fn recent(limit: usize) -> Vec<Server> {
+    usage()
+        .read()
+        .unwrap()
+        .recent
+        .iter()
+        .filter_map(|id| find(id))
+        .take(limit)
+        .collect()
+}
+
+fn rank_countries(
+    servers: &[Server],
+    usage: &HashMap<String, u32>,
+    limit: usize,
+) -> Vec<(String, String)> {
+    let mut countries: Vec<(String, String)> = Vec::new();
+    for server in servers {
+        let code = server.country_code.to_ascii_uppercase();
+        if !countries.iter().any(|(c, _)| *c == code) {
+            countries.push((code, server.country.clone()));
+        }
+    }
+
+    // Most used first, then alphabetically so unused countries have a stable order
+    countries.sort_by(|(a_code, a_name), (b_code, b_name)| {
+        let a_uses = usage.get(a_code).copied().unwrap_or(0);
+        let b_uses = usage.get(b_code).copied().unwrap_or(0);
+        b_uses.cmp(&a_uses).then_with(|| a_name.cmp(b_name))
+    });
+    countries.truncate(limit);
+    countries
+}
+
+/// Set or clear the annotation for a server; blank values remove the field
+pub fn set_annotation(server_id: &str, annotation: ServerAnnotation) -> Result<(), String> {
+    let annotation = ServerAnnotation {
+        custom_name: annotation.custom_name.filter(|s| !s.trim().is_empty()),
+        notes: annotation.notes.filter(|s| !s.trim().is_empty()),
+    };
+
+    let mut map = annotations().write().unwrap();
+    if annotation.is_empty() {
+        map.remove(server_id);
+    } else {
+        map.insert(server_id.to_string(), annotation);
+    }
+    storage::save(ANNOTATIONS_FILE, &*map)
+}
+
+/// Mark or unmark a server as a favorite
+pub fn set_favorite(server_id: &str, favorite: bool) -> Result<(), String> {
+    let mut favorites = favorites().write().unwrap();
+    favorites.retain(|id| id != server_id);
+    if favorite {
+        favorites.push(server_id.to_string());
+    }
+    storage::save(FAVORITES_FILE, &*favorites)
+}
+
+/// The cached server list with everything shown alongside a server, so every
+/// part of the UI shows the same
+pub fn view(locale: &str) -> Vec<EnrichedServer> {
+    enrich(cached(), locale)
+}
+
+/// Merge local annotations, favorites, latency probes, the distance from the
+/// user and the place names in `locale` into a server list
+pub fn enrich(servers: Vec<Server>, locale: &str) -> Vec<EnrichedServer> {
+    let map = annotations().read().unwrap();
+    let favorites = favorites().read().unwrap();
+    let mut latencies = latency::measurements();
+    let user = geo::user_location();
+    let now = chrono::Utc::now().timestamp();
+    servers
+        .into_iter()
+        .map(|server| {
+            let annotation = map.get(&server.id).cloned().unwrap_or_default();
+            let place =
+                places::localize(&server.country_code, &server.country, &server.city, locale);
+            let distance_km = user
+                .zip(geo::server_location(&server.country_code, &server.city))
+                .map(|(user, server)| user.distance_km(&server).round() as u32);
+            let (flag, flag_key) = flag(&server.country_code);
+            EnrichedServer {
+                expiry_warning: server.expiry_warning(now),
+                localized_country: place.country,
+                localized_city: place.city,
+                flag,
+                flag_key,
+                distance_km,
+                measured_latency: latencies.remove(&server.id),
+                favorite: favorites.contains(&server.id),
+                server,
+                custom_name: annotation.custom_name,
+                notes: annotation.notes,
+            }
+        })
+        .collect()
+}
+
+/// Flag emoji and asset key of a country code
+fn flag(country_code: &str) -> (String, String) {
+    let code = country_code.trim().to_ascii_uppercase();
+    if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_uppercase()) {
+        return ("\u{1F30D}".to_string(), "unknown".to_string());
+    }
+    // Each letter maps to its regional indicator symbol
+    let emoji = code
+        .bytes()
+        .filter_map(|b| char::from_u32(0x1F1E6 + u32::from(b - b'A')))
+        .collect();
+    (emoji, code.to_ascii_lowercase())
+}
+
+/// A server named after its id with only its country set, for tests here and
+/// in the modules that pick servers
+#[cfg(test)]
+pub fn test_server(id: &str, country: &str, country_code: &str) -> Server {
+    Server {
+        id: id.to_string(),
+        name: id.to_string(),
+        country: country.to_string(),
+        country_code: country_code.to_string(),
+        city: String::new(),
+        ip: String::new(),
+        public_key: String::new(),
+        load: 0,
+        latency: 0,
+        dedicated: false,
+        expires_at: None,
+        maintenance: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_countries_by_usage_then_name() {
+        let servers = vec![
+            test_server("us-1", "United States", "us"),
+            test_server("de-1", "Germany", "DE"),
+            test_server("us-2", "United States", "US"),
+            test_server("jp-1", "Japan", "JP"),
+        ];
+        let usage = HashMap::from([("JP".to_string(), 3)]);
+
+        let ranked = rank_countries(&servers, &usage, 2);
+        assert_eq!(
+            ranked,
+            vec![
+                ("JP".to_string(), "Japan".to_string()),
+                ("DE".to_string(), "Germany".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedicated_expiry_warning() {
+        let day = DAY_SECS;
+        let mut dedicated = Server {
+            ip: "203.0.113.7".to_string(),
+            dedicated: true,
+            expires_at: Some(10 * day),
+            ..test_server("us-dedicated", "United States", "US")
+        };
+        assert_eq!(dedicated.expiry_warning(0), None);
+        assert_eq!(
+            dedicated.expiry_warning(4 * day).as_deref(),
+            Some("Your dedicated IP 203.0.113.7 expires in 6 days")
+        );
+        assert!(dedicated
+            .expiry_warning(11 * day)
+            .unwrap()
+            .contains("expired"));
+
+        dedicated.dedicated = false;
+        assert_eq!(dedicated.expiry_warning(11 * day), None);
+    }
+
+    #[test]
+    fn test_nearest_prefers_city_then_country() {
+        let in_city = |id: &str, code: &str, city: &str| Server {
+            city: city.to_string(),
+            ..test_server(id, "", code)
+        };
+        let down = in_city("us-1", "US", "New York");
+        let ranked = vec![
+            in_city("de-1", "DE", "Berlin"),
+            in_city("us-2", "US", "Chicago"),
+            down.clone(),
+            in_city("us-3", "us", "new york"),
+        ];
+        let nearest_id = |server, ranked: &[Server]| nearest(server, ranked).map(|s| s.id.clone());
+
+        assert_eq!(nearest_id(Some(&down), &ranked).as_deref(), Some("us-3"));
+        assert_eq!(
+            nearest_id(Some(&down), &ranked[..3]).as_deref(),
+            Some("us-2")
+        );
+        assert_eq!(
+            nearest_id(Some(&down), &ranked[..1]).as_deref(),
+            Some("de-1")
+        );
+        assert_eq!(nearest_id(None, &ranked).as_deref(), Some("de-1"));
+        assert_eq!(nearest_id(Some(&down), &ranked[2..3]), None);
+    }
+
+    #[test]
+    fn test_flag_from_country_code() {
+        assert_eq!(
+            flag("de"),
+            ("\u{1F1E9}\u{1F1EA}".to_string(), "de".to_string())
+        );
+        assert_eq!(flag("EU1").1, "unknown");
+        assert_eq!(flag("").1, "unknown");
+    }
+
+    #[test]
+    fn test_pin_endpoint_keeps_port() {
+        assert_eq!(
+            pin_endpoint("vpn.example.com:51820", "203.0.113.7"),
+            Ok("203.0.113.7:51820".to_string())
+        );
+        assert_eq!(
+            pin_endpoint("198.51.100.1:443", "2001:db8::7"),
+            Ok("[2001:db8::7]:443".to_string())
+        );
+        assert!(pin_endpoint("vpn.example.com", "203.0.113.7").is_err());
+    }
+}
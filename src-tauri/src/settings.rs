@@ -0,0 +1,261 @@
+//! Backend application settings and connection profiles
+
+use crate::api::ApiEnvironment;
+use crate::usage::UsageCap;
+use crate::vpn::{
+    ReconnectPolicy, RotationPolicy, SessionPolicy, SplitTunnelSettings, StatsSettings,
+    TunnelTuning, VpnConfig,
+};
+use crate::{connectivity, policy, storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Automatic retry against other servers when the selected one keeps failing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FailoverSettings {
+    /// Explicit user consent to be moved to a different server
+    pub enabled: bool,
+    /// Alternative servers tried after the selected one
+    pub max_servers: usize,
+    /// Connect attempts per server before moving on
+    pub attempts_per_server: u32,
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_servers: 3,
+            attempts_per_server: 2,
+        }
+    }
+}
+
+/// Global keyboard shortcuts, as accelerator strings like "CommandOrControl+Alt+C"
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotkeySettings {
+    pub quick_connect: Option<String>,
+    pub disconnect: Option<String>,
+    pub pause: Option<String>,
+}
+
+/// A named set of overrides for the global settings (e.g. "Streaming", "Public Wi-Fi")
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionProfile {
+    pub name: String,
+    pub kill_switch: Option<bool>,
+    pub custom_dns: Option<Vec<String>>,
+    pub allow_lan: Option<bool>,
+    pub split_tunnel: Option<SplitTunnelSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppSettings {
+    pub kill_switch: bool,
+    pub custom_dns: Vec<String>,
+    /// Reach RFC1918 and link-local ranges outside the tunnel and through the kill switch
+    pub allow_lan: bool,
+    /// Disable Teredo/6to4 and keep WebRTC STUN traffic inside the tunnel while connected
+    pub block_webrtc_leaks: bool,
+    /// Drop unsolicited inbound traffic from the tunnel, for servers that don't NAT
+    pub block_inbound: bool,
+    /// Ports that stay reachable through the tunnel when `block_inbound` is on
+    pub forwarded_ports: Vec<u16>,
+    /// Retry on the server's alternate ports (53, 123, 443) when the default port is blocked
+    pub stealth_ports: bool,
+    /// Domains routed around (or into) the tunnel; bypassed domains stay blocked
+    /// while the kill switch is on
+    pub split_tunnel: SplitTunnelSettings,
+    /// Pad packets and send decoy traffic to resist traffic analysis, at a bandwidth cost
+    pub traffic_padding: bool,
+    /// Look the exit address up on public spam blocklists after connecting and
+    /// warn when it is widely listed
+    pub check_exit_reputation: bool,
+    /// Report per-session byte totals to the account for multi-device usage dashboards;
+    /// nothing is sent unless the user turns this on
+    pub sync_usage: bool,
+    /// Monthly data allowance; the tray and a notification warn as it runs out
+    pub usage_cap: UsageCap,
+    pub profiles: Vec<ConnectionProfile>,
+    pub active_profile: Option<String>,
+    /// Reduce background network activity (e.g. latency probing) on metered connections
+    pub metered_mode: bool,
+    /// Per-server MTU chosen by the MTU benchmark, keyed by server id
+    pub mtu_overrides: HashMap<String, u32>,
+    pub reconnect: ReconnectPolicy,
+    pub failover: FailoverSettings,
+    /// Moving to other servers in the same country over time, for privacy
+    pub rotation: RotationPolicy,
+    /// Speed smoothing window and display unit
+    pub stats: StatsSettings,
+    /// Advanced data-path tuning, only editable in settings.json
+    pub tuning: TunnelTuning,
+    /// Seconds an API request may take before it is abandoned
+    pub api_timeout_secs: u64,
+    /// Request the config of a hovered or selected server before the connect click;
+    /// off by default, since every request registers a peer on that server
+    pub prewarm_config: bool,
+    /// Look up the public address before each connect and check while connected
+    /// that traffic doesn't leave from it; sends requests to ipify/icanhazip
+    pub exit_ip_check: bool,
+    /// Only changed through `set_api_environment`
+    pub api_environment: ApiEnvironment,
+    /// Only changed through `set_hotkeys`, which registers them
+    pub hotkeys: HotkeySettings,
+}
+
+impl Default for AppSettings {
+    fn default() -> Self {
+        Self {
+            kill_switch: true,
+            custom_dns: Vec::new(),
+            allow_lan: false,
+            block_webrtc_leaks: false,
+            block_inbound: false,
+            forwarded_ports: Vec::new(),
+            stealth_ports: false,
+            split_tunnel: SplitTunnelSettings::default(),
+            traffic_padding: false,
+            check_exit_reputation: false,
+            sync_usage: false,
+            usage_cap: UsageCap::default(),
+            profiles: Vec::new(),
+            active_profile: None,
+            metered_mode: false,
+            mtu_overrides: HashMap::new(),
+            reconnect: ReconnectPolicy::default(),
+            failover: FailoverSettings::default(),
+            rotation: RotationPolicy::default(),
+            stats: StatsSettings::default(),
+            tuning: TunnelTuning::default(),
+            api_timeout_secs: connectivity::DEFAULT_API_TIMEOUT_SECS,
+            prewarm_config: false,
+            exit_ip_check: false,
+            api_environment: ApiEnvironment::default(),
+            hotkeys: HotkeySettings::default(),
+        }
+    }
+}
+
+impl AppSettings {
+    pub fn profile(&self, name: &str) -> Option<&ConnectionProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Apply per-server overrides (e.g. a benchmarked MTU) to a config before connecting
+    pub fn apply_server_overrides(&self, server_id: &str, config: &mut VpnConfig) {
+        if let Some(mtu) = self.mtu_overrides.get(server_id) {
+            config.interface.mtu = Some(*mtu);
+        }
+    }
+
+    /// Resolve the settings to apply for a connection, letting the profile override globals
+    /// and the managed policy override both
+    pub fn session_policy(&self, profile: Option<&str>) -> SessionPolicy {
+        let profile = profile
+            .or(self.active_profile.as_deref())
+            .and_then(|name| self.profile(name));
+
+        let mut session = SessionPolicy {
+            kill_switch: profile
+                .and_then(|p| p.kill_switch)
+                .unwrap_or(self.kill_switch),
+            dns: profile
+                .and_then(|p| p.custom_dns.clone())
+                .unwrap_or_else(|| self.custom_dns.clone()),
+            allow_lan: profile.and_then(|p| p.allow_lan).unwrap_or(self.allow_lan),
+            block_webrtc_leaks: self.block_webrtc_leaks,
+            block_inbound: self.block_inbound,
+            forwarded_ports: self.forwarded_ports.clone(),
+            stealth_ports: self.stealth_ports,
+            split_tunnel: profile
+                .and_then(|p| p.split_tunnel.clone())
+                .unwrap_or_else(|| self.split_tunnel.clone()),
+            traffic_padding: self.traffic_padding,
+            tuning: self.tuning.clone(),
+        };
+
+        // Settings locked by the organization win over both
+        if let Some(managed) = policy::current() {
+            managed.enforce(&mut session);
+        }
+        session
+    }
+}
+
+static SETTINGS: OnceLock<RwLock<AppSettings>> = OnceLock::new();
+
+fn settings() -> &'static RwLock<AppSettings> {
+    SETTINGS.get_or_init(|| RwLock::new(storage::load(SETTINGS_FILE)))
+}
+
+/// Snapshot of the current settings
+pub fn current() -> AppSettings {
+    settings().read().unwrap().clone()
+}
+
+/// Modify the settings and persist the result
+pub fn update<F: FnOnce(&mut AppSettings)>(f: F) -> Result<AppSettings, String> {
+    let mut guard = settings().write().unwrap();
+    f(&mut guard);
+    storage::save(SETTINGS_FILE, &*guard)?;
+    Ok(guard.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_overrides_globals() {
+        let settings = AppSettings {
+            kill_switch: true,
+            custom_dns: vec!["1.1.1.1".to_string()],
+            allow_lan: false,
+            block_webrtc_leaks: false,
+            block_inbound: true,
+            forwarded_ports: Vec::new(),
+            stealth_ports: false,
+            split_tunnel: SplitTunnelSettings::default(),
+            traffic_padding: false,
+            check_exit_reputation: false,
+            sync_usage: false,
+            usage_cap: UsageCap::default(),
+            profiles: vec![ConnectionProfile {
+                name: "Streaming".to_string(),
+                kill_switch: Some(false),
+                custom_dns: None,
+                allow_lan: Some(true),
+                split_tunnel: None,
+            }],
+            active_profile: None,
+            metered_mode: false,
+            mtu_overrides: HashMap::new(),
+            reconnect: ReconnectPolicy::default(),
+            failover: FailoverSettings::default(),
+            rotation: RotationPolicy::default(),
+            stats: StatsSettings::default(),
+            tuning: TunnelTuning::default(),
+            api_timeout_secs: connectivity::DEFAULT_API_TIMEOUT_SECS,
+            prewarm_config: true,
+            exit_ip_check: false,
+            api_environment: ApiEnvironment::default(),
+            hotkeys: HotkeySettings::default(),
+        };
+
+        let global = settings.session_policy(None);
+        assert!(global.kill_switch);
+        assert!(!global.allow_lan);
+
+        let streaming = settings.session_policy(Some("Streaming"));
+        assert!(!streaming.kill_switch);
+        assert!(streaming.allow_lan);
+        assert_eq!(streaming.dns, vec!["1.1.1.1".to_string()]);
+    }
+}
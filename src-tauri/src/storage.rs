@@ -0,0 +1,193 @@
+//! Local persistence for backend state
+//!
+//! Small JSON documents stored in the per-user SACVPN data directory, encrypted
+//! with a key kept in the OS keyring. Plaintext files from older versions are
+//! still read and get encrypted on their next save.
+//!
+//! Nothing is written while the keyring is unavailable, and a document that
+//! can't be decrypted is never replaced by defaults: it is set aside when the
+//! key doesn't fit, and left alone when there is no key to try.
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// Marks an encrypted document: magic, 24-byte nonce, ciphertext
+const ENCRYPTED_MAGIC: &[u8] = b"SACVPN-ENC1\0";
+const NONCE_LEN: usize = 24;
+
+const KEYRING_SERVICE: &str = "sacvpn-storage";
+const KEYRING_USER: &str = "data-key";
+
+/// Per-user data directory for SACVPN state
+pub fn data_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        let base = std::env::var("APPDATA").unwrap_or_else(|_| ".".to_string());
+        PathBuf::from(base).join("SACVPN")
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
+        PathBuf::from(home).join(".config").join("sacvpn")
+    }
+}
+
+static DATA_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+/// Encrypted documents that couldn't be read for want of a key
+static LOCKED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn locked() -> &'static Mutex<HashSet<String>> {
+    LOCKED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Storage key from the keyring, created on first use
+///
+/// Only a key is remembered, so a keyring that comes up late is asked again.
+fn data_key() -> Result<[u8; 32], String> {
+    let mut key = DATA_KEY.lock().unwrap();
+    if let Some(key) = *key {
+        return Ok(key);
+    }
+    let loaded = load_or_create_key().map_err(|e| format!("Keyring unavailable: {}", e))?;
+    *key = Some(loaded);
+    Ok(loaded)
+}
+
+fn load_or_create_key() -> Result<[u8; 32], String> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(|e| e.to_string())?;
+    let engine = base64::engine::general_purpose::STANDARD;
+
+    match entry.get_password() {
+        Ok(encoded) => engine
+            .decode(encoded)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| "Stored data key is corrupt".to_string()),
+        Err(keyring::Error::NoEntry) => {
+            let key: [u8; 32] = XChaCha20Poly1305::generate_key(&mut OsRng).into();
+            entry
+                .set_password(&engine.encode(key))
+                .map_err(|e| e.to_string())?;
+            Ok(key)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Encryption failed".to_string())?;
+
+    let mut out = Vec::with_capacity(ENCRYPTED_MAGIC.len() + NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(ENCRYPTED_MAGIC);
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a stored document, passing legacy plaintext through unchanged
+fn open(key: Option<&[u8; 32]>, data: &[u8]) -> Result<Vec<u8>, String> {
+    let Some(sealed) = data.strip_prefix(ENCRYPTED_MAGIC) else {
+        return Ok(data.to_vec());
+    };
+    let key = key.ok_or_else(|| "Data key unavailable".to_string())?;
+    if sealed.len() < NONCE_LEN {
+        return Err("Truncated document".to_string());
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| "Decryption failed".to_string())
+}
+
+/// Load a JSON document, falling back to the default value if it is missing or unreadable
+pub fn load<T: DeserializeOwned + Default>(name: &str) -> T {
+    let path = data_dir().join(name);
+    let Ok(data) = fs::read(&path) else {
+        return T::default();
+    };
+
+    let key = if data.starts_with(ENCRYPTED_MAGIC) {
+        match data_key() {
+            Ok(key) => Some(key),
+            Err(e) => {
+                // Saving defaults over it would lose the document for good
+                log::warn!("Can't read {:?} until the keyring is back: {}", path, e);
+                locked().lock().unwrap().insert(name.to_string());
+                return T::default();
+            }
+        }
+    } else {
+        None
+    };
+
+    let content = match open(key.as_ref(), &data) {
+        Ok(content) => content,
+        Err(e) => {
+            let aside = path.with_file_name(format!("{}.unreadable", name));
+            log::warn!("Moving undecryptable {:?} to {:?}: {}", path, aside, e);
+            if let Err(e) = fs::rename(&path, &aside) {
+                log::warn!("Failed to move {:?} aside: {}", path, e);
+                locked().lock().unwrap().insert(name.to_string());
+            }
+            return T::default();
+        }
+    };
+    serde_json::from_slice(&content).unwrap_or_else(|e| {
+        log::warn!("Ignoring unreadable {:?}: {}", path, e);
+        T::default()
+    })
+}
+
+/// Save a JSON document, creating the data directory if needed
+///
+/// Refused while the keyring is unavailable, and for a document whose stored
+/// copy couldn't be read, so neither plaintext nor defaults end up on disk.
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<(), String> {
+    if locked().lock().unwrap().contains(name) {
+        return Err(format!(
+            "Not saving {}: its stored copy couldn't be read",
+            name
+        ));
+    }
+    let key = data_key().map_err(|e| format!("Not saving {}: {}", name, e))?;
+
+    let dir = data_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create data dir: {}", e))?;
+
+    let content = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    let content = seal(&key, &content)?;
+    fs::write(dir.join(name), content).map_err(|e| format!("Failed to write {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip_and_plaintext_passthrough() {
+        let key = [7u8; 32];
+        let sealed = seal(&key, b"{\"kill_switch\":true}").unwrap();
+
+        assert!(sealed.starts_with(ENCRYPTED_MAGIC));
+        assert_eq!(
+            open(Some(&key), &sealed).unwrap(),
+            b"{\"kill_switch\":true}"
+        );
+        assert!(open(Some(&[8u8; 32]), &sealed).is_err());
+
+        assert_eq!(open(None, b"{}").unwrap(), b"{}");
+    }
+}
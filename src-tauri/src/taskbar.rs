@@ -0,0 +1,213 @@
+//! Taskbar integrations: Windows jump list tasks and the macOS dock menu
+//!
+//! Both offer Quick Connect, Disconnect and the most recently used servers.
+//! Jump list tasks relaunch the executable with action arguments, which the
+//! single-instance handler forwards to the running app; dock menu items run
+//! the action directly.
+
+use crate::actions::Action;
+use crate::servers;
+use tauri::AppHandle;
+
+/// Recently used servers listed after the fixed entries
+const RECENT_ENTRIES: usize = 5;
+
+/// Entries to show, as (title, action)
+fn entries() -> Vec<(String, Action)> {
+    let mut entries = vec![
+        ("Quick Connect".to_string(), Action::QuickConnect),
+        ("Disconnect".to_string(), Action::Disconnect),
+    ];
+    entries.extend(servers::recent(RECENT_ENTRIES).into_iter().map(|server| {
+        (
+            format!("Connect to {}", server.name),
+            Action::ConnectServer(server.id),
+        )
+    }));
+    entries
+}
+
+/// Rebuild the platform menu from the current recent servers
+pub fn refresh(app: &AppHandle) {
+    let entries = entries();
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = app;
+        // The jump list is built through COM, which needs its own apartment
+        std::thread::spawn(move || {
+            if let Err(e) = jump_list::update(&entries) {
+                log::warn!("Failed to update jump list: {}", e);
+            }
+        });
+    }
+
+    #[cfg(target_os = "macos")]
+    dock_menu::update(app, entries);
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let _ = (app, entries);
+}
+
+#[cfg(target_os = "windows")]
+mod jump_list {
+    use crate::actions::Action;
+    use windows::core::{Interface, HSTRING, PROPVARIANT};
+    use windows::Win32::Storage::EnhancedStorage::PKEY_Title;
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER,
+        COINIT_APARTMENTTHREADED,
+    };
+    use windows::Win32::UI::Shell::Common::{IObjectArray, IObjectCollection};
+    use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
+    use windows::Win32::UI::Shell::{
+        DestinationList, EnumerableObjectCollection, ICustomDestinationList, IShellLinkW, ShellLink,
+    };
+
+    /// Replace the jump list's user tasks
+    pub fn update(entries: &[(String, Action)]) -> windows::core::Result<()> {
+        let exe = std::env::current_exe().map_err(|e| {
+            windows::core::Error::new(windows::Win32::Foundation::E_FAIL, e.to_string())
+        })?;
+        let exe = HSTRING::from(exe.as_os_str());
+
+        unsafe {
+            CoInitializeEx(None, COINIT_APARTMENTTHREADED).ok()?;
+            let result = build(&exe, entries);
+            CoUninitialize();
+            result
+        }
+    }
+
+    unsafe fn build(exe: &HSTRING, entries: &[(String, Action)]) -> windows::core::Result<()> {
+        let list: ICustomDestinationList =
+            CoCreateInstance(&DestinationList, None, CLSCTX_INPROC_SERVER)?;
+        let mut min_slots = 0u32;
+        let _removed: IObjectArray = list.BeginList(&mut min_slots)?;
+
+        let tasks: IObjectCollection =
+            CoCreateInstance(&EnumerableObjectCollection, None, CLSCTX_INPROC_SERVER)?;
+        for (title, action) in entries {
+            let link: IShellLinkW = CoCreateInstance(&ShellLink, None, CLSCTX_INPROC_SERVER)?;
+            link.SetPath(exe)?;
+            link.SetArguments(&HSTRING::from(action.to_args().join(" ")))?;
+            link.SetIconLocation(exe, 0)?;
+
+            let properties: IPropertyStore = link.cast()?;
+            properties.SetValue(&PKEY_Title, &PROPVARIANT::from(title.as_str()))?;
+            properties.Commit()?;
+
+            tasks.AddObject(&link)?;
+        }
+
+        list.AddUserTasks(&tasks.cast::<IObjectArray>()?)?;
+        list.CommitList()
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod dock_menu {
+    use crate::actions::{self, Action};
+    use objc::declare::ClassDecl;
+    use objc::runtime::{class_addMethod, object_getClass, Class, Object, Sel};
+    use objc::{class, msg_send, sel, sel_impl};
+    use std::ffi::c_char;
+    use std::sync::{Mutex, OnceLock};
+    use tauri::AppHandle;
+
+    const NS_UTF8_STRING_ENCODING: usize = 4;
+
+    /// Entries shown in the dock menu, indexed by menu item tag
+    static ENTRIES: Mutex<Vec<(String, Action)>> = Mutex::new(Vec::new());
+    static APP: OnceLock<AppHandle> = OnceLock::new();
+
+    /// Store the entries; the menu itself is built each time the dock asks for it
+    pub fn update(app: &AppHandle, entries: Vec<(String, Action)>) {
+        *ENTRIES.lock().unwrap() = entries;
+
+        if APP.set(app.clone()).is_ok() {
+            // The delegate can only be touched from the main thread
+            let _ = app.run_on_main_thread(|| unsafe { install() });
+        }
+    }
+
+    /// Add `applicationDockMenu:` to the app delegate Tauri installed
+    unsafe fn install() {
+        let ns_app: *mut Object = msg_send![class!(NSApplication), sharedApplication];
+        let delegate: *mut Object = msg_send![ns_app, delegate];
+        if delegate.is_null() {
+            log::warn!("No app delegate, dock menu unavailable");
+            return;
+        }
+
+        let added = class_addMethod(
+            object_getClass(delegate) as *mut Class,
+            sel!(applicationDockMenu:),
+            std::mem::transmute::<
+                extern "C" fn(&Object, Sel, *mut Object) -> *mut Object,
+                objc::runtime::Imp,
+            >(dock_menu),
+            c"@@:@".as_ptr() as *const c_char,
+        );
+        if added == objc::runtime::NO {
+            log::warn!("App delegate already provides a dock menu");
+        }
+    }
+
+    /// Target object receiving the menu item clicks
+    fn target() -> *mut Object {
+        static TARGET: OnceLock<usize> = OnceLock::new();
+        *TARGET.get_or_init(|| unsafe {
+            let mut decl = ClassDecl::new("SACVPNDockMenuTarget", class!(NSObject))
+                .expect("dock menu target class registered twice");
+            decl.add_method(
+                sel!(itemSelected:),
+                item_selected as extern "C" fn(&Object, Sel, *mut Object),
+            );
+            let target: *mut Object = msg_send![decl.register(), new];
+            target as usize
+        }) as *mut Object
+    }
+
+    extern "C" fn dock_menu(_this: &Object, _cmd: Sel, _sender: *mut Object) -> *mut Object {
+        unsafe {
+            let menu: *mut Object = msg_send![class!(NSMenu), new];
+            let key_equivalent = ns_string("");
+            for (tag, (title, _)) in ENTRIES.lock().unwrap().iter().enumerate() {
+                let item: *mut Object = msg_send![class!(NSMenuItem), alloc];
+                let item: *mut Object = msg_send![item,
+                    initWithTitle: ns_string(title)
+                    action: sel!(itemSelected:)
+                    keyEquivalent: key_equivalent];
+                let _: () = msg_send![item, setTarget: target()];
+                let _: () = msg_send![item, setTag: tag as isize];
+                let _: () = msg_send![menu, addItem: item];
+                let _: () = msg_send![item, release];
+            }
+            msg_send![menu, autorelease]
+        }
+    }
+
+    extern "C" fn item_selected(_this: &Object, _cmd: Sel, sender: *mut Object) {
+        let tag: isize = unsafe { msg_send![sender, tag] };
+        let action = ENTRIES
+            .lock()
+            .unwrap()
+            .get(tag as usize)
+            .map(|(_, action)| action.clone());
+
+        if let (Some(app), Some(action)) = (APP.get(), action) {
+            actions::spawn(app, action);
+        }
+    }
+
+    /// Autoreleased NSString copy of a Rust string
+    unsafe fn ns_string(s: &str) -> *mut Object {
+        let string: *mut Object = msg_send![class!(NSString), alloc];
+        let string: *mut Object = msg_send![string,
+            initWithBytes: s.as_ptr()
+            length: s.len()
+            encoding: NS_UTF8_STRING_ENCODING];
+        msg_send![string, autorelease]
+    }
+}
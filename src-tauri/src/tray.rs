@@ -0,0 +1,237 @@
+//! System tray icon and menu
+
+use crate::actions::{self, Action};
+use crate::servers;
+use crate::vpn::{VpnHandle, VpnStatus};
+use std::sync::Mutex;
+use tauri::{
+    image::Image,
+    menu::{IsMenuItem, Menu, MenuItem, Submenu},
+    tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
+    AppHandle, Manager, Wry,
+};
+
+const TRAY_ID: &str = "main";
+
+/// Countries listed under "Connect to…"
+const COUNTRY_ENTRIES: usize = 8;
+
+/// Menu id prefix of the per-country entries, followed by the country code
+const COUNTRY_ID_PREFIX: &str = "connect-country:";
+
+/// Menu id prefix of the reconnect entry, followed by the server id
+const RECONNECT_ID_PREFIX: &str = "reconnect-server:";
+
+/// Badge diameter as a share of the icon size
+const BADGE_SIZE: f32 = 0.45;
+
+/// Badge colours for a warning threshold and for a used-up cap
+const BADGE_WARNING: [u8; 3] = [245, 158, 11];
+const BADGE_EXHAUSTED: [u8; 3] = [239, 68, 68];
+
+/// Data cap line last set by `set_usage_badge`, shown under the status
+static USAGE_NOTE: Mutex<Option<String>> = Mutex::new(None);
+
+fn build_menu<M: Manager<Wry>>(manager: &M) -> tauri::Result<Menu<Wry>> {
+    let quit = MenuItem::with_id(manager, "quit", "Quit SACVPN", true, None::<&str>)?;
+    let show = MenuItem::with_id(manager, "show", "Show Window", true, None::<&str>)?;
+    let widget = MenuItem::with_id(manager, "widget", "Show Stats Widget", true, None::<&str>)?;
+    let connect = MenuItem::with_id(manager, "connect", "Quick Connect", true, None::<&str>)?;
+    let disconnect = MenuItem::with_id(manager, "disconnect", "Disconnect", true, None::<&str>)?;
+
+    // Straight to the last server, skipping the recommendation
+    let reconnect = servers::recent(1)
+        .into_iter()
+        .next()
+        .map(|server| {
+            MenuItem::with_id(
+                manager,
+                format!("{}{}", RECONNECT_ID_PREFIX, server.id),
+                format!("Reconnect to {}", server.name),
+                true,
+                None::<&str>,
+            )
+        })
+        .transpose()?;
+
+    let countries = servers::top_countries(COUNTRY_ENTRIES)
+        .into_iter()
+        .map(|(code, name)| {
+            MenuItem::with_id(
+                manager,
+                format!("{}{}", COUNTRY_ID_PREFIX, code),
+                name,
+                true,
+                None::<&str>,
+            )
+        })
+        .collect::<tauri::Result<Vec<_>>>()?;
+    let country_items: Vec<&dyn IsMenuItem<Wry>> = countries
+        .iter()
+        .map(|item| item as &dyn IsMenuItem<Wry>)
+        .collect();
+    let connect_to = Submenu::with_items(
+        manager,
+        "Connect to…",
+        !countries.is_empty(),
+        &country_items,
+    )?;
+
+    let mut items: Vec<&dyn IsMenuItem<Wry>> = vec![&show, &widget, &connect];
+    if let Some(reconnect) = &reconnect {
+        items.push(reconnect);
+    }
+    items.extend([&connect_to as &dyn IsMenuItem<Wry>, &disconnect, &quit]);
+    Menu::with_items(manager, &items)
+}
+
+pub fn setup(app: &tauri::App) -> Result<(), Box<dyn std::error::Error>> {
+    let menu = build_menu(app)?;
+
+    let _tray = TrayIconBuilder::with_id(TRAY_ID)
+        .menu(&menu)
+        .tooltip("SACVPN - Disconnected")
+        .on_menu_event(|app, event| match event.id.as_ref() {
+            "quit" => {
+                app.exit(0);
+            }
+            "show" => {
+                if let Some(window) = app.get_webview_window("main") {
+                    let _ = window.show();
+                    let _ = window.set_focus();
+                }
+            }
+            "widget" => {
+                if let Some(window) = app.get_webview_window("widget") {
+                    let _ = window.show();
+                }
+            }
+            "connect" => actions::spawn(app, Action::QuickConnect),
+            "disconnect" => actions::spawn(app, Action::Disconnect),
+            id => {
+                if let Some(country_code) = id.strip_prefix(COUNTRY_ID_PREFIX) {
+                    actions::spawn(app, Action::ConnectCountry(country_code.to_string()));
+                } else if let Some(server_id) = id.strip_prefix(RECONNECT_ID_PREFIX) {
+                    actions::spawn(app, Action::ConnectServer(server_id.to_string()));
+                }
+            }
+        })
+        .on_tray_icon_event(|tray, event| {
+            if let TrayIconEvent::Click {
+                button: MouseButton::Left,
+                button_state: MouseButtonState::Up,
+                ..
+            } = event
+            {
+                let app = tray.app_handle();
+                if let Some(window) = app.get_webview_window("main") {
+                    let _ = window.show();
+                    let _ = window.set_focus();
+                }
+            }
+        })
+        .build(app)?;
+
+    Ok(())
+}
+
+/// Rebuild the menu so the reconnect and country entries follow the server
+/// cache and usage, and show the connection status in the tooltip
+pub fn refresh(app: &AppHandle) {
+    let Some(tray) = app.tray_by_id(TRAY_ID) else {
+        return;
+    };
+    if let Err(e) = build_menu(app).and_then(|menu| tray.set_menu(Some(menu))) {
+        log::warn!("Failed to refresh tray menu: {}", e);
+    }
+    update_tooltip(app);
+}
+
+/// Set the tooltip to the connection status, in the background as it waits
+/// on the VPN manager
+fn update_tooltip(app: &AppHandle) {
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let Some(tray) = app.tray_by_id(TRAY_ID) else {
+            return;
+        };
+        let (status, server_id) = app
+            .state::<VpnHandle>()
+            .with(|vpn| (vpn.get_status(), vpn.get_server_id()))
+            .await;
+        let server = server_id
+            .and_then(|id| servers::find(&id))
+            .map(|server| server.name);
+        let mut tooltip = status_tooltip(&status, server.as_deref());
+        if let Some(note) = USAGE_NOTE.lock().unwrap().as_deref() {
+            tooltip = format!("{}\n{}", tooltip, note);
+        }
+        if let Err(e) = tray.set_tooltip(Some(tooltip)) {
+            log::warn!("Failed to update tray tooltip: {}", e);
+        }
+    });
+}
+
+fn status_tooltip(status: &VpnStatus, server: Option<&str>) -> String {
+    let state = match (status, server) {
+        (VpnStatus::Connected, Some(server)) => format!("Connected to {}", server),
+        (VpnStatus::Connected, None) => "Connected".to_string(),
+        (VpnStatus::Connecting, _) => "Connecting…".to_string(),
+        (VpnStatus::Reconnecting, _) => "Reconnecting…".to_string(),
+        (VpnStatus::Disconnecting, _) => "Disconnecting…".to_string(),
+        (VpnStatus::NoNetwork, _) => "Waiting for the network".to_string(),
+        (VpnStatus::Error(_), _) => "Connection error".to_string(),
+        (VpnStatus::Disconnected, _) => "Disconnected".to_string(),
+    };
+    format!("SACVPN - {}", state)
+}
+
+/// Badge the tray icon with the data cap threshold reached this month, or clear it
+///
+/// macOS draws tray icons as monochrome templates, so there the percentage is
+/// shown as text next to the icon instead.
+pub fn set_usage_badge(app: &AppHandle, percent: Option<u8>) {
+    let Some(tray) = app.tray_by_id(TRAY_ID) else {
+        return;
+    };
+    *USAGE_NOTE.lock().unwrap() = match percent {
+        Some(percent) if percent >= 100 => Some("Monthly data cap reached".to_string()),
+        Some(percent) => Some(format!("{}% of monthly data used", percent)),
+        None => None,
+    };
+
+    let result = if cfg!(target_os = "macos") {
+        tray.set_title(percent.map(|percent| format!("{}%", percent)))
+    } else {
+        let icon = app.default_window_icon().map(|icon| match percent {
+            Some(percent) if percent >= 100 => badged(icon, BADGE_EXHAUSTED),
+            Some(_) => badged(icon, BADGE_WARNING),
+            None => icon.clone().to_owned(),
+        });
+        tray.set_icon(icon)
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to update tray usage badge: {}", e);
+    }
+    update_tooltip(app);
+}
+
+/// Copy of `icon` with a dot of `color` in the bottom-right corner
+fn badged(icon: &Image<'_>, color: [u8; 3]) -> Image<'static> {
+    let (width, height) = (icon.width(), icon.height());
+    let radius = width.min(height) as f32 * BADGE_SIZE / 2.0;
+    let (center_x, center_y) = (width as f32 - radius, height as f32 - radius);
+
+    let mut rgba = icon.rgba().to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let dx = x as f32 + 0.5 - center_x;
+            let dy = y as f32 + 0.5 - center_y;
+            if dx * dx + dy * dy <= radius * radius {
+                let offset = ((y * width + x) * 4) as usize;
+                rgba[offset..offset + 4].copy_from_slice(&[color[0], color[1], color[2], 255]);
+            }
+        }
+    }
+    Image::new_owned(rgba, width, height)
+}
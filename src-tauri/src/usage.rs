@@ -0,0 +1,540 @@
+//! Session history and usage export
+//!
+//! Every finished session is kept with its server, duration and transfer
+//! totals. `export` writes the sessions in a time range, plus per-day totals,
+//! as CSV or JSON for expense reports and spreadsheets.
+//!
+//! Users who opt in also have the byte totals reported to their account for
+//! multi-device dashboards. Sessions queue up locally and are uploaded in
+//! batches, retrying with backoff while the API can't be reached.
+//!
+//! With a monthly cap set, `check_cap` reports when this month's traffic
+//! reaches each warning threshold, once per threshold and month.
+
+use crate::vpn::SessionSummary;
+use crate::{api, connectivity, credentials, servers, settings, signing, storage};
+use chrono::{DateTime, Datelike, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+const HISTORY_FILE: &str = "usage_history.json";
+const SYNC_QUEUE_FILE: &str = "usage_sync.json";
+const CAP_WARNINGS_FILE: &str = "usage_cap_warnings.json";
+
+/// Oldest sessions are dropped beyond this
+const MAX_SESSIONS: usize = 10_000;
+
+/// Time between uploads while they succeed
+const SYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Longest wait between retries after failed uploads
+const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Sessions sent per upload
+const SYNC_BATCH: usize = 50;
+
+/// A finished session with the server details known when it ended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionRecord {
+    #[serde(flatten)]
+    pub session: SessionSummary,
+    pub server_name: Option<String>,
+    pub country_code: Option<String>,
+}
+
+/// Sessions starting in `[from, to)`, as Unix timestamps; open ends are unbounded
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeRange {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl TimeRange {
+    fn contains(&self, timestamp: i64) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Totals for the sessions started on one local day
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyUsage {
+    /// YYYY-MM-DD
+    pub date: String,
+    pub sessions: u32,
+    pub duration_secs: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// Monthly data allowance and the shares of it that trigger a warning
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageCap {
+    /// Bytes per calendar month, uploads and downloads together; unset means no cap
+    pub monthly_bytes: Option<u64>,
+    /// Percentages of the cap at which to warn
+    pub warn_at: Vec<u8>,
+}
+
+impl Default for UsageCap {
+    fn default() -> Self {
+        Self {
+            monthly_bytes: None,
+            warn_at: vec![80, 100],
+        }
+    }
+}
+
+/// This month's usage measured against the cap
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CapStatus {
+    /// YYYY-MM
+    pub month: String,
+    pub used: u64,
+    pub cap: u64,
+    /// Highest threshold reached this month
+    pub reached: Option<u8>,
+    /// Whether `reached` was first reached by this check
+    pub newly_reached: bool,
+}
+
+/// Thresholds already warned about, so each fires once a month
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CapWarnings {
+    month: String,
+    warned: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct UsageExport {
+    range: TimeRange,
+    sessions: Vec<SessionRecord>,
+    daily: Vec<DailyUsage>,
+}
+
+static HISTORY: OnceLock<RwLock<Vec<SessionRecord>>> = OnceLock::new();
+static SYNC_QUEUE: OnceLock<RwLock<Vec<SessionSummary>>> = OnceLock::new();
+static CAP_WARNINGS: OnceLock<RwLock<CapWarnings>> = OnceLock::new();
+
+fn history() -> &'static RwLock<Vec<SessionRecord>> {
+    HISTORY.get_or_init(|| RwLock::new(storage::load(HISTORY_FILE)))
+}
+
+/// Sessions waiting to be reported to the account
+fn sync_queue() -> &'static RwLock<Vec<SessionSummary>> {
+    SYNC_QUEUE.get_or_init(|| RwLock::new(storage::load(SYNC_QUEUE_FILE)))
+}
+
+fn cap_warnings() -> &'static RwLock<CapWarnings> {
+    CAP_WARNINGS.get_or_init(|| RwLock::new(storage::load(CAP_WARNINGS_FILE)))
+}
+
+fn save_sync_queue(queue: &[SessionSummary]) {
+    if let Err(e) = storage::save(SYNC_QUEUE_FILE, &queue) {
+        log::warn!("Failed to persist usage sync queue: {}", e);
+    }
+}
+
+/// Add a finished session to the history, and to the sync queue if the user opted in
+pub fn record(session: SessionSummary) {
+    if settings::current().sync_usage {
+        let mut queue = sync_queue().write().unwrap();
+        queue.push(session.clone());
+        save_sync_queue(&queue);
+    }
+
+    let server = servers::find(&session.server_id);
+    let record = SessionRecord {
+        server_name: server.as_ref().map(|s| s.name.clone()),
+        country_code: server.map(|s| s.country_code),
+        session,
+    };
+
+    let mut history = history().write().unwrap();
+    history.push(record);
+    if history.len() > MAX_SESSIONS {
+        let excess = history.len() - MAX_SESSIONS;
+        history.drain(..excess);
+    }
+    if let Err(e) = storage::save(HISTORY_FILE, &*history) {
+        log::warn!("Failed to persist usage history: {}", e);
+    }
+}
+
+/// Measure this month's sessions plus `live` bytes of the current one against
+/// the cap; `None` when no cap is set
+pub fn check_cap(live: u64) -> Option<CapStatus> {
+    let cap = settings::current().usage_cap;
+    let limit = cap.monthly_bytes.filter(|&bytes| bytes > 0)?;
+    let now = Local::now();
+    let month = month_key(&now);
+    let used = live + month_total(&history().read().unwrap(), &now);
+    let reached = highest_reached(used, limit, &cap.warn_at);
+
+    let mut warnings = cap_warnings().write().unwrap();
+    if warnings.month != month {
+        *warnings = CapWarnings {
+            month: month.clone(),
+            warned: Vec::new(),
+        };
+    }
+    let newly_reached = reached.is_some_and(|threshold| !warnings.warned.contains(&threshold));
+    if let Some(threshold) = reached.filter(|_| newly_reached) {
+        // Lower thresholds crossed in the same jump don't warn separately
+        warnings
+            .warned
+            .extend(cap.warn_at.iter().filter(|&&t| t <= threshold));
+        if let Err(e) = storage::save(CAP_WARNINGS_FILE, &*warnings) {
+            log::warn!("Failed to persist usage cap warnings: {}", e);
+        }
+    }
+
+    Some(CapStatus {
+        month,
+        used,
+        cap: limit,
+        reached,
+        newly_reached,
+    })
+}
+
+/// Highest of the `thresholds`, in percent of `limit`, that `used` has reached
+fn highest_reached(used: u64, limit: u64, thresholds: &[u8]) -> Option<u8> {
+    let percent = used.saturating_mul(100) / limit;
+    thresholds
+        .iter()
+        .copied()
+        .filter(|&threshold| percent >= u64::from(threshold))
+        .max()
+}
+
+/// Bytes moved in the sessions started in the month of `now`, in its time zone
+fn month_total<Tz: TimeZone>(sessions: &[SessionRecord], now: &DateTime<Tz>) -> u64 {
+    let tz = now.timezone();
+    sessions
+        .iter()
+        .filter(|record| {
+            tz.timestamp_opt(record.session.started_at, 0)
+                .single()
+                .is_some_and(|start| start.year() == now.year() && start.month() == now.month())
+        })
+        .map(|record| record.session.uploaded + record.session.downloaded)
+        .sum()
+}
+
+fn month_key<Tz: TimeZone>(now: &DateTime<Tz>) -> String {
+    format!("{:04}-{:02}", now.year(), now.month())
+}
+
+/// Upload queued sessions forever; intended to be spawned once at startup
+pub async fn run_sync() {
+    let mut delay = SYNC_INTERVAL;
+    loop {
+        tokio::time::sleep(delay).await;
+
+        // Turning the setting off also drops what hasn't been sent yet
+        if !settings::current().sync_usage {
+            let mut queue = sync_queue().write().unwrap();
+            if !queue.is_empty() {
+                queue.clear();
+                save_sync_queue(&queue);
+            }
+            continue;
+        }
+
+        let batch: Vec<SessionSummary> = sync_queue()
+            .read()
+            .unwrap()
+            .iter()
+            .take(SYNC_BATCH)
+            .cloned()
+            .collect();
+        if batch.is_empty() {
+            continue;
+        }
+
+        let succeeded = match upload(&batch).await {
+            Ok(()) => {
+                log::debug!("Reported {} sessions to the account", batch.len());
+                let mut queue = sync_queue().write().unwrap();
+                queue.retain(|session| !batch.contains(session));
+                save_sync_queue(&queue);
+                true
+            }
+            Err(e) => {
+                log::debug!("Usage sync failed, will retry: {}", e);
+                false
+            }
+        };
+        delay = next_sync_delay(delay, succeeded);
+    }
+}
+
+fn next_sync_delay(current: Duration, succeeded: bool) -> Duration {
+    if succeeded {
+        SYNC_INTERVAL
+    } else {
+        (current * 2).min(MAX_SYNC_BACKOFF)
+    }
+}
+
+/// Report a batch of session totals to the signed-in account
+async fn upload(sessions: &[SessionSummary]) -> Result<(), String> {
+    const PATH: &str = "/api/vpn/usage";
+    let token = credentials::token()?;
+    let body = serde_json::json!({ "sessions": sessions }).to_string();
+
+    let mut request = connectivity::api_client()
+        .post(format!("{}{}", api::base_url()?, PATH))
+        .header("Authorization", format!("Bearer {}", token))
+        .header("Content-Type", "application/json");
+    for (name, value) in signing::sign("POST", PATH, body.as_bytes())? {
+        request = request.header(name, value);
+    }
+
+    let response = connectivity::send(request.body(body)).await?;
+    if !response.status().is_success() {
+        return Err(format!("API error: {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Write the sessions in `range` and their daily totals to `path`; returns the session count
+pub fn export(range: TimeRange, format: ExportFormat, path: &Path) -> Result<usize, String> {
+    let sessions: Vec<SessionRecord> = history()
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|record| range.contains(record.session.started_at))
+        .cloned()
+        .collect();
+    let export = UsageExport {
+        range,
+        daily: daily(&sessions, &Local),
+        sessions,
+    };
+
+    let content = match format {
+        ExportFormat::Csv => to_csv(&export),
+        ExportFormat::Json => serde_json::to_string_pretty(&export).map_err(|e| e.to_string())?,
+    };
+    std::fs::write(path, content)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(export.sessions.len())
+}
+
+/// Per-day totals in `tz`, oldest first
+fn daily<Tz: TimeZone>(sessions: &[SessionRecord], tz: &Tz) -> Vec<DailyUsage> {
+    let mut days: Vec<DailyUsage> = Vec::new();
+    for record in sessions {
+        let Some(date) = tz
+            .timestamp_opt(record.session.started_at, 0)
+            .single()
+            .map(|start| start.date_naive().to_string())
+        else {
+            continue;
+        };
+        let index = match days.iter().position(|day| day.date == date) {
+            Some(index) => index,
+            None => {
+                days.push(DailyUsage {
+                    date,
+                    sessions: 0,
+                    duration_secs: 0,
+                    uploaded: 0,
+                    downloaded: 0,
+                });
+                days.len() - 1
+            }
+        };
+        let day = &mut days[index];
+        day.sessions += 1;
+        day.duration_secs += record.session.duration_secs;
+        day.uploaded += record.session.uploaded;
+        day.downloaded += record.session.downloaded;
+    }
+    days.sort_by(|a, b| a.date.cmp(&b.date));
+    days
+}
+
+/// Sessions, a blank line, then daily totals, each with a header row
+fn to_csv(export: &UsageExport) -> String {
+    let mut csv = String::from(
+        "started_at,ended_at,duration_secs,server_id,server_name,country_code,uploaded_bytes,\
+         downloaded_bytes\n",
+    );
+    for record in &export.sessions {
+        let session = &record.session;
+        let row = [
+            local_time(session.started_at),
+            local_time(session.ended_at),
+            session.duration_secs.to_string(),
+            session.server_id.clone(),
+            record.server_name.clone().unwrap_or_default(),
+            record.country_code.clone().unwrap_or_default(),
+            session.uploaded.to_string(),
+            session.downloaded.to_string(),
+        ];
+        push_row(&mut csv, &row);
+    }
+
+    csv.push_str("\ndate,sessions,duration_secs,uploaded_bytes,downloaded_bytes\n");
+    for day in &export.daily {
+        let row = [
+            day.date.clone(),
+            day.sessions.to_string(),
+            day.duration_secs.to_string(),
+            day.uploaded.to_string(),
+            day.downloaded.to_string(),
+        ];
+        push_row(&mut csv, &row);
+    }
+    csv
+}
+
+fn push_row(csv: &mut String, fields: &[String]) {
+    let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    csv.push_str(&fields.join(","));
+    csv.push('\n');
+}
+
+/// Quote a field containing separators or quotes
+///
+/// Text a spreadsheet would run as a formula, such as a server name starting
+/// with `=`, gets a leading `'` so it is shown as typed.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn local_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.with_timezone(&Local).to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn record(started_at: i64, duration_secs: u64, downloaded: u64) -> SessionRecord {
+        SessionRecord {
+            session: SessionSummary {
+                server_id: "us-east-1".to_string(),
+                started_at,
+                ended_at: started_at + duration_secs as i64,
+                duration_secs,
+                uploaded: 10,
+                downloaded,
+                average_upload_speed: 0,
+                average_download_speed: 0,
+                peak_upload_speed: 0,
+                peak_download_speed: 0,
+            },
+            server_name: Some("New York, \"East\"".to_string()),
+            country_code: Some("US".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_daily_totals() {
+        // 2024-01-01 08:00 and 23:00 UTC, then 2024-01-02 01:00 UTC
+        let sessions = vec![
+            record(1_704_096_000, 600, 100),
+            record(1_704_150_000, 300, 200),
+            record(1_704_157_200, 60, 50),
+        ];
+
+        let days = daily(&sessions, &Utc);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2024-01-01");
+        assert_eq!(days[0].sessions, 2);
+        assert_eq!(days[0].duration_secs, 900);
+        assert_eq!(days[0].downloaded, 300);
+        assert_eq!(days[1].uploaded, 10);
+    }
+
+    #[test]
+    fn test_month_total() {
+        // 2023-12-31 23:00, 2024-01-01 08:00 and 2024-02-01 00:00 UTC
+        let sessions = vec![
+            record(1_704_063_600, 60, 1000),
+            record(1_704_096_000, 60, 200),
+            record(1_706_745_600, 60, 50),
+        ];
+        let now = Utc.timestamp_opt(1_705_000_000, 0).unwrap();
+        assert_eq!(month_total(&sessions, &now), 210);
+        assert_eq!(month_key(&now), "2024-01");
+    }
+
+    #[test]
+    fn test_highest_reached_threshold() {
+        let thresholds = [80, 100, 50];
+        assert_eq!(highest_reached(499, 1000, &thresholds), None);
+        assert_eq!(highest_reached(500, 1000, &thresholds), Some(50));
+        assert_eq!(highest_reached(850, 1000, &thresholds), Some(80));
+        assert_eq!(highest_reached(5000, 1000, &thresholds), Some(100));
+        assert_eq!(highest_reached(u64::MAX, 1000, &[]), None);
+    }
+
+    #[test]
+    fn test_sync_backs_off_until_success() {
+        let failed = next_sync_delay(SYNC_INTERVAL, false);
+        assert_eq!(failed, SYNC_INTERVAL * 2);
+        assert_eq!(next_sync_delay(MAX_SYNC_BACKOFF, false), MAX_SYNC_BACKOFF);
+        assert_eq!(next_sync_delay(failed, true), SYNC_INTERVAL);
+    }
+
+    #[test]
+    fn test_range_and_csv_quoting() {
+        let range = TimeRange {
+            from: Some(100),
+            to: Some(200),
+        };
+        assert!(range.contains(100));
+        assert!(!range.contains(200));
+        assert!(TimeRange::default().contains(0));
+
+        let export = UsageExport {
+            range,
+            sessions: vec![record(150, 60, 5)],
+            daily: Vec::new(),
+        };
+        let csv = to_csv(&export);
+        assert!(csv.contains(",us-east-1,\"New York, \"\"East\"\"\",US,10,5\n"));
+        assert!(csv.ends_with("\ndate,sessions,duration_secs,uploaded_bytes,downloaded_bytes\n"));
+
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("us-east-1"), "us-east-1");
+    }
+}
@@ -0,0 +1,90 @@
+//! Data cap warnings
+//!
+//! Every minute the watcher adds the live session's traffic to this month's
+//! history and measures it against the cap from the settings. The tray badge
+//! follows the highest warning threshold reached, and the first time each one
+//! is reached the user gets a notification and the UI a `usage://cap-warning`
+//! event.
+
+use crate::usage::{self, CapStatus};
+use crate::vpn::VpnHandle;
+use crate::{settings, tray};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tauri_plugin_notification::NotificationExt;
+
+pub const CAP_WARNING_EVENT: &str = "usage://cap-warning";
+
+/// Time between usage checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Watch this month's usage forever; intended to be spawned once at startup
+pub async fn run(app: AppHandle, manager: VpnHandle) {
+    let mut badge = None;
+
+    loop {
+        let stats_settings = settings::current().stats;
+        let stats = manager
+            .call(move |vpn| {
+                Box::pin(async move {
+                    let _ = vpn.update_stats(&stats_settings).await;
+                    vpn.get_stats()
+                })
+            })
+            .await;
+
+        let status = usage::check_cap(stats.total_uploaded + stats.total_downloaded);
+        let reached = status.as_ref().and_then(|status| status.reached);
+        if reached != badge {
+            badge = reached;
+            tray::set_usage_badge(&app, reached);
+        }
+        if let Some(status) = status.filter(|status| status.newly_reached) {
+            warn(&app, &status);
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+fn warn(app: &AppHandle, status: &CapStatus) {
+    let percent = status.reached.unwrap_or_default();
+    log::info!(
+        "{}% of the monthly data cap used ({} of {} bytes)",
+        percent,
+        status.used,
+        status.cap
+    );
+
+    let _ = app.emit(CAP_WARNING_EVENT, status);
+
+    let title = if percent >= 100 {
+        "Monthly data cap reached".to_string()
+    } else {
+        format!("{}% of your monthly data used", percent)
+    };
+    let body = format!(
+        "You have used {} of your {} monthly allowance.",
+        format_bytes(status.used),
+        format_bytes(status.cap)
+    );
+    if let Err(e) = app.notification().builder().title(title).body(body).show() {
+        log::warn!("Failed to show data cap notification: {}", e);
+    }
+}
+
+/// Human-readable size, e.g. "8.2 GB", scaled by 1024 like speeds
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -26,7 +26,7 @@ type Tab = "connect" | "servers" | "manual" | "settings" | "account";
 function App() {
   const [activeTab, setActiveTab] = useState<Tab>("connect");
   const [sidebarCollapsed, setSidebarCollapsed] = useState(false);
-  const { status } = useVPNStore();
+  const { status, syncStatus, subscribeToBackend } = useVPNStore();
   const { checkAuth, user } = useAuthStore();
 
   // Check auth on startup
@@ -34,6 +34,15 @@ function App() {
     checkAuth();
   }, [checkAuth]);
 
+  // Follow connects and disconnects from the tray, taskbar and deep links
+  useEffect(() => {
+    syncStatus();
+    const unsubscribe = subscribeToBackend();
+    return () => {
+      unsubscribe.then((stop) => stop());
+    };
+  }, [syncStatus, subscribeToBackend]);
+
   // Redirect to account tab if not authenticated
   useEffect(() => {
     if (!user && activeTab !== "account") {
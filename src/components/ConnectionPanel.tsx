@@ -12,6 +12,7 @@ import {
   Lock,
   User,
   AlertCircle,
+  Info,
 } from "lucide-react";
 import { useVPNStore } from "../stores/vpnStore";
 import { useAuthStore } from "../stores/authStore";
@@ -63,9 +64,12 @@ export default function ConnectionPanel() {
     selectedServer,
     connectionStats,
     connectionError,
+    connectionSuggestions,
+    connectionNotice,
     connect,
     disconnect,
     clearConnectionError,
+    clearConnectionNotice,
   } = useVPNStore();
   const { user, subscription } = useAuthStore();
 
@@ -77,6 +81,7 @@ export default function ConnectionPanel() {
 
   const handleToggleConnection = async () => {
     clearConnectionError();
+    clearConnectionNotice();
     if (isConnected || isDisconnecting) {
       await disconnect();
     } else if (!isConnecting) {
@@ -121,7 +126,28 @@ export default function ConnectionPanel() {
           className="mb-6 p-4 rounded-xl bg-red-500/10 border border-red-500/20 flex items-center gap-3 max-w-md"
         >
           <AlertCircle className="w-5 h-5 text-red-400 flex-shrink-0" />
-          <p className="text-red-400 text-sm">{connectionError}</p>
+          <div>
+            <p className="text-red-400 text-sm">{connectionError}</p>
+            {connectionSuggestions.length > 0 && (
+              <ul className="mt-2 list-disc list-inside text-surface-300 text-xs space-y-1">
+                {connectionSuggestions.map((suggestion) => (
+                  <li key={suggestion.action}>{suggestion.description}</li>
+                ))}
+              </ul>
+            )}
+          </div>
+        </motion.div>
+      )}
+
+      {/* Failover, rotation and config changes made by the backend */}
+      {connectionNotice && !connectionError && (
+        <motion.div
+          initial={{ opacity: 0, y: -10 }}
+          animate={{ opacity: 1, y: 0 }}
+          className="mb-6 p-4 rounded-xl bg-brand-500/10 border border-brand-500/20 flex items-center gap-3 max-w-md"
+        >
+          <Info className="w-5 h-5 text-brand-400 flex-shrink-0" />
+          <p className="text-brand-400 text-sm">{connectionNotice}</p>
         </motion.div>
       )}
 
@@ -240,13 +266,19 @@ export default function ConnectionPanel() {
             <StatCard
               icon={ArrowDown}
               label="Download"
-              value={formatSpeed(connectionStats.downloadSpeed)}
+              value={
+                connectionStats.downloadDisplay ??
+                formatSpeed(connectionStats.downloadSpeed)
+              }
               iconColor="text-green-400"
             />
             <StatCard
               icon={ArrowUp}
               label="Upload"
-              value={formatSpeed(connectionStats.uploadSpeed)}
+              value={
+                connectionStats.uploadDisplay ??
+                formatSpeed(connectionStats.uploadSpeed)
+              }
               iconColor="text-blue-400"
             />
             <StatCard
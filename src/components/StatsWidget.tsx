@@ -0,0 +1,78 @@
+import { useEffect, useState } from "react";
+import { ArrowUp, ArrowDown, X } from "lucide-react";
+import { getCurrentWindow } from "@tauri-apps/api/window";
+import * as wireguard from "../services/wireguard";
+
+// The widget window may only read status and statistics (capabilities/widget.json)
+export default function StatsWidget() {
+  const [status, setStatus] = useState<wireguard.VpnStatus>("disconnected");
+  const [connection, setConnection] = useState<wireguard.CurrentConnection | null>(null);
+  const [stats, setStats] = useState<wireguard.ConnectionStats | null>(null);
+
+  useEffect(() => {
+    const refresh = async () => {
+      try {
+        const current = await wireguard.getVpnStatus();
+        setStatus(current);
+        if (current === "connected") {
+          setConnection(await wireguard.getCurrentConnection());
+          setStats(await wireguard.getConnectionStats());
+        } else {
+          setConnection(null);
+          setStats(null);
+        }
+      } catch (error) {
+        console.error("Failed to refresh widget:", error);
+      }
+    };
+    refresh();
+    const id = setInterval(refresh, 1000);
+    const unlisten = wireguard.onBackendEvent(wireguard.STATUS_EVENT, refresh);
+    return () => {
+      clearInterval(id);
+      unlisten.then((stop) => stop());
+    };
+  }, []);
+
+  const isConnected = status === "connected";
+
+  return (
+    <div
+      data-tauri-drag-region
+      className="h-screen flex flex-col justify-between p-3 bg-dark-900 text-xs"
+    >
+      <div data-tauri-drag-region className="flex items-center justify-between">
+        <div className="flex items-center gap-2">
+          <div
+            className={`w-2 h-2 rounded-full ${
+              isConnected ? "bg-green-500 animate-pulse" : "bg-surface-600"
+            }`}
+          />
+          <span className="text-surface-300 truncate">
+            {isConnected
+              ? connection?.server_name ?? connection?.server_id ?? "Connected"
+              : "Not connected"}
+          </span>
+        </div>
+        <button
+          onClick={() => getCurrentWindow().hide()}
+          className="text-surface-500 hover:text-white"
+          title="Hide"
+        >
+          <X className="w-3 h-3" />
+        </button>
+      </div>
+
+      <div className="flex items-center justify-around font-mono text-surface-300">
+        <div className="flex items-center gap-1">
+          <ArrowDown className="w-3 h-3 text-green-400" />
+          <span>{stats?.download_display ?? "0 B/s"}</span>
+        </div>
+        <div className="flex items-center gap-1">
+          <ArrowUp className="w-3 h-3 text-blue-400" />
+          <span>{stats?.upload_display ?? "0 B/s"}</span>
+        </div>
+      </div>
+    </div>
+  );
+}
@@ -1,5 +1,7 @@
+import { useEffect, useState } from "react";
 import { Clock, ArrowUp, ArrowDown } from "lucide-react";
 import { useVPNStore } from "../stores/vpnStore";
+import * as wireguard from "../services/wireguard";
 import packageJson from "../../package.json";
 
 function formatBytes(bytes: number): string {
@@ -36,6 +38,28 @@ export default function StatusBar() {
     ? Date.now() - connectionStats.connectedSince
     : 0;
 
+  // Signing in to a captive portal is impossible while the kill switch holds traffic
+  const [networkHint, setNetworkHint] = useState<string | null>(null);
+  useEffect(() => {
+    const check = async () => {
+      try {
+        const environment = await wireguard.getNetworkEnvironment();
+        setNetworkHint(
+          environment.kill_switch_blocking
+            ? "Kill switch is blocking this network. To sign in to it, disconnect first."
+            : environment.captive_portal
+            ? `Sign in to this network at ${environment.captive_portal}`
+            : null
+        );
+      } catch (error) {
+        console.error("Failed to get network environment:", error);
+      }
+    };
+    check();
+    const id = setInterval(check, 10000);
+    return () => clearInterval(id);
+  }, []);
+
   return (
     <div className="h-8 flex items-center justify-between px-4 bg-surface-900 border-t border-surface-800 text-xs">
       {/* Left - Connection Status */}
@@ -59,12 +83,17 @@ export default function StatusBar() {
           </span>
         </div>
 
+        {networkHint && <span className="text-yellow-400">{networkHint}</span>}
+
         {isConnected && (
           <>
             <div className="flex items-center gap-1.5 text-surface-400">
               <Clock className="w-3.5 h-3.5" />
               <span>{formatDuration(connectedDuration)}</span>
             </div>
+            {connectionStats.simulated && (
+              <span className="text-yellow-400">Simulated tunnel</span>
+            )}
           </>
         )}
       </div>
@@ -4,6 +4,17 @@
  */
 
 import { invoke } from "@tauri-apps/api/core";
+import { listen, type UnlistenFn } from "@tauri-apps/api/event";
+
+// Emitted after a connect or disconnect started outside the window (tray,
+// taskbar, hotkeys, deep links)
+export const STATUS_EVENT = "vpn://status";
+// Emitted for every server a failover connect tries
+export const FAILOVER_EVENT = "vpn://failover";
+// Emitted when a fresh config differs from the last one used for its server
+export const CONFIG_CHANGED_EVENT = "vpn://config-changed";
+// Emitted when server rotation moved the session to another server
+export const ROTATED_EVENT = "vpn://rotated";
 
 // Matches the Rust VpnConfig struct
 export interface VpnConfig {
@@ -28,12 +39,103 @@ export interface PeerConfig {
 export interface ConnectionStats {
   upload_speed: number;
   download_speed: number;
+  upload_display: string;
+  download_display: string;
   total_uploaded: number;
   total_downloaded: number;
   connected_since: number | null;
+  connected_at: string | null;
+  session_duration_secs: number | null;
+  simulated: boolean;
+}
+
+// Matches the Rust NetworkEnvironment struct
+export interface NetworkEnvironment {
+  proxy: string | null;
+  captive_portal: string | null;
+  internet_verified: boolean;
+  kill_switch_blocking: boolean;
+}
+
+// Matches the Rust RecoverySuggestion struct
+export interface RecoverySuggestion {
+  action: string;
+  description: string;
+}
+
+// Matches the Rust ErrorReport struct, which VPN commands reject with
+export interface ErrorReport {
+  code: string;
+  message: string;
+  suggestions: RecoverySuggestion[];
+  alternative_server: string | null;
+}
+
+/**
+ * Read the message and recovery suggestions from a rejected command,
+ * whether it rejected with an ErrorReport, an Error or a plain string
+ */
+export function toErrorReport(error: unknown, fallback: string): ErrorReport {
+  if (error && typeof error === "object" && "message" in error) {
+    const report = error as Partial<ErrorReport>;
+    return {
+      code: report.code ?? "unknown",
+      message: report.message || fallback,
+      suggestions: Array.isArray(report.suggestions) ? report.suggestions : [],
+      alternative_server: report.alternative_server ?? null,
+    };
+  }
+  return {
+    code: "unknown",
+    message: typeof error === "string" && error ? error : fallback,
+    suggestions: [],
+    alternative_server: null,
+  };
+}
+
+// Matches the Rust FailoverAttempt struct
+export interface FailoverAttempt {
+  server_id: string;
+  server_name: string;
+  candidate: number;
+  attempt: number;
+  succeeded: boolean;
+  error: string | null;
+}
+
+// Matches the Rust ConfigDiff struct
+export interface ConfigDiff {
+  server_id: string;
+  changes: {
+    field: string;
+    peer: string | null;
+    before: string | null;
+    after: string | null;
+  }[];
+}
+
+// Matches the Rust ServerRotation struct
+export interface ServerRotation {
+  from_server_id: string;
+  to_server_id: string;
+  to_server_name: string;
+}
+
+// Matches the Rust CurrentConnection struct
+export interface CurrentConnection {
+  server_id: string;
+  server_name: string | null;
+  country: string | null;
+  country_code: string | null;
+  city: string | null;
+  endpoint: string;
+  connected_since: number | null;
+  connected_at: string | null;
+  session_duration_secs: number | null;
+  simulated: boolean;
 }
 
-export type VpnStatus = "disconnected" | "connecting" | "connected" | "disconnecting" | { error: string };
+export type VpnStatus = "disconnected" | "connecting" | "connected" | "reconnecting" | "disconnecting" | "no_network" | { error: string };
 
 /**
  * Parse raw WireGuard config text into structured VpnConfig
@@ -146,6 +248,17 @@ export async function connectVpn(serverId: string, config: VpnConfig): Promise<v
   await invoke("connect_vpn", { serverId, config });
 }
 
+/**
+ * Import a WireGuard .conf file picked in a dialog; null if it was cancelled
+ */
+export async function importWireGuardConfig(): Promise<VpnConfig | null> {
+  if (!isTauri()) {
+    return null;
+  }
+
+  return await invoke("import_wg_config");
+}
+
 /**
  * Disconnect from VPN via Tauri backend
  */
@@ -170,6 +283,31 @@ export async function getVpnStatus(): Promise<VpnStatus> {
   return await invoke("get_vpn_status");
 }
 
+/**
+ * Get the server and endpoint of the current connection, if connected
+ */
+export async function getCurrentConnection(): Promise<CurrentConnection | null> {
+  if (!isTauri()) {
+    return null;
+  }
+
+  return await invoke("get_current_connection");
+}
+
+/**
+ * Listen for an event from the Tauri backend; a no-op outside Tauri
+ */
+export async function onBackendEvent<T>(
+  event: string,
+  handler: (payload: T) => void
+): Promise<UnlistenFn> {
+  if (!isTauri()) {
+    return () => {};
+  }
+
+  return await listen<T>(event, (e) => handler(e.payload));
+}
+
 /**
  * Get connection statistics from Tauri backend
  */
@@ -178,11 +316,32 @@ export async function getConnectionStats(): Promise<ConnectionStats> {
     return {
       upload_speed: 0,
       download_speed: 0,
+      upload_display: "0 B/s",
+      download_display: "0 B/s",
       total_uploaded: 0,
       total_downloaded: 0,
       connected_since: null,
+      connected_at: null,
+      session_duration_secs: null,
+      simulated: false,
     };
   }
 
   return await invoke("get_connection_stats");
 }
+
+/**
+ * Get the network environment as last checked by the backend
+ */
+export async function getNetworkEnvironment(): Promise<NetworkEnvironment> {
+  if (!isTauri()) {
+    return {
+      proxy: null,
+      captive_portal: null,
+      internet_verified: true,
+      kill_switch_blocking: false,
+    };
+  }
+
+  return await invoke("get_network_environment");
+}
@@ -23,9 +23,14 @@ export type ConnectionStatus =
 export interface ConnectionStats {
   uploadSpeed: number;
   downloadSpeed: number;
+  /** Backend-formatted speeds in the configured unit */
+  uploadDisplay?: string;
+  downloadDisplay?: string;
   totalUploaded: number;
   totalDownloaded: number;
   connectedSince: number | null;
+  /** The app was started with --simulate and fakes the tunnel */
+  simulated?: boolean;
 }
 
 interface VPNState {
@@ -35,6 +40,10 @@ interface VPNState {
   connectionStats: ConnectionStats;
   wgConfig: string | null;
   connectionError: string | null;
+  /** Ways out of `connectionError` the backend suggested, most useful first */
+  connectionSuggestions: wireguard.RecoverySuggestion[];
+  /** Something the backend did on its own, like failing over or rotating servers */
+  connectionNotice: string | null;
 
   // Servers
   servers: Server[];
@@ -67,6 +76,7 @@ interface VPNState {
   setCustomDns: (value: string) => void;
   setShowNotifications: (value: boolean) => void;
   clearConnectionError: () => void;
+  clearConnectionNotice: () => void;
 
   // API Actions
   fetchServers: () => Promise<void>;
@@ -75,11 +85,30 @@ interface VPNState {
   switchServer: (newServerId: string) => Promise<void>;
   startStatsPolling: () => void;
   stopStatsPolling: () => void;
+  /** Catch up with the backend's connection, which may have changed outside the window */
+  syncStatus: () => Promise<void>;
+  /** Follow backend events; resolves to a function that stops listening */
+  subscribeToBackend: () => Promise<() => void>;
 }
 
 // Stats polling interval ID
 let statsIntervalId: ReturnType<typeof setInterval> | null = null;
 
+// Collapse the backend status into the states the UI shows
+function toConnectionStatus(status: wireguard.VpnStatus): ConnectionStatus {
+  switch (status) {
+    case "connected":
+      return "connected";
+    case "connecting":
+    case "reconnecting":
+      return "connecting";
+    case "disconnecting":
+      return "disconnecting";
+    default:
+      return "disconnected";
+  }
+}
+
 // Convert API server to local format
 function convertServer(apiServer: api.VpnServer, favoriteIds: string[]): Server {
   return {
@@ -108,6 +137,8 @@ export const useVPNStore = create<VPNState>()(
       },
       wgConfig: null,
       connectionError: null,
+      connectionSuggestions: [],
+      connectionNotice: null,
       servers: [],
       selectedServer: null,
       favoriteServerIds: [],
@@ -152,7 +183,8 @@ export const useVPNStore = create<VPNState>()(
       setSplitTunneling: (value) => set({ splitTunneling: value }),
       setCustomDns: (value) => set({ customDns: value }),
       setShowNotifications: (value) => set({ showNotifications: value }),
-      clearConnectionError: () => set({ connectionError: null }),
+      clearConnectionError: () => set({ connectionError: null, connectionSuggestions: [] }),
+      clearConnectionNotice: () => set({ connectionNotice: null }),
 
       // API Actions
       fetchServers: async () => {
@@ -195,7 +227,7 @@ export const useVPNStore = create<VPNState>()(
           return;
         }
 
-        set({ status: "connecting", connectionError: null });
+        set({ status: "connecting", connectionError: null, connectionSuggestions: [] });
 
         try {
           const deviceName = api.generateDeviceName();
@@ -254,6 +286,8 @@ export const useVPNStore = create<VPNState>()(
 
           // Start polling for stats
           get().startStatsPolling();
+          // Server rotation may have connected another server than the one selected
+          get().syncStatus();
 
           // Send notification
           if (get().showNotifications) {
@@ -268,9 +302,11 @@ export const useVPNStore = create<VPNState>()(
             os_version: api.getOSVersion(),
           });
         } catch (error) {
+          const report = wireguard.toErrorReport(error, "Connection failed");
           set({
             status: "disconnected",
-            connectionError: error instanceof Error ? error.message : "Connection failed",
+            connectionError: report.message,
+            connectionSuggestions: report.suggestions,
           });
         }
       },
@@ -318,9 +354,11 @@ export const useVPNStore = create<VPNState>()(
             tauriService.notifyDisconnected();
           }
         } catch (error) {
+          const report = wireguard.toErrorReport(error, "Disconnect failed");
           set({
             status: "disconnected",
-            connectionError: error instanceof Error ? error.message : "Disconnect failed",
+            connectionError: report.message,
+            connectionSuggestions: report.suggestions,
           });
         }
       },
@@ -365,11 +403,17 @@ export const useVPNStore = create<VPNState>()(
               connectionStats: {
                 uploadSpeed: stats.upload_speed,
                 downloadSpeed: stats.download_speed,
+                uploadDisplay: stats.upload_display,
+                downloadDisplay: stats.download_display,
                 totalUploaded: stats.total_uploaded,
                 totalDownloaded: stats.total_downloaded,
-                connectedSince: stats.connected_since
-                  ? stats.connected_since * 1000
-                  : get().connectionStats.connectedSince,
+                // Derived from the monotonic session duration so wall-clock
+                // changes don't skew the timer
+                connectedSince:
+                  stats.session_duration_secs !== null
+                    ? Date.now() - stats.session_duration_secs * 1000
+                    : get().connectionStats.connectedSince,
+                simulated: stats.simulated,
               },
             });
           } catch (error) {
@@ -384,6 +428,74 @@ export const useVPNStore = create<VPNState>()(
           statsIntervalId = null;
         }
       },
+
+      syncStatus: async () => {
+        try {
+          const status = toConnectionStatus(await wireguard.getVpnStatus());
+          if (status === "connected") {
+            const current = await wireguard.getCurrentConnection();
+            const { servers, favoriteServerIds } = get();
+            const server = current
+              ? servers.find((s) => s.id === current.server_id) ?? {
+                  id: current.server_id,
+                  name: current.server_name ?? current.server_id,
+                  region: current.country ?? "",
+                  ip: current.endpoint,
+                  load: 0,
+                  isFavorite: favoriteServerIds.includes(current.server_id),
+                  isRecommended: false,
+                }
+              : get().currentServer;
+            set({ status, currentServer: server, connectionError: null });
+            get().startStatsPolling();
+          } else if (status === "disconnected") {
+            get().stopStatsPolling();
+            set({ status, currentServer: null });
+          } else {
+            set({ status });
+          }
+        } catch (error) {
+          console.error("Failed to sync VPN status:", error);
+        }
+      },
+
+      subscribeToBackend: async () => {
+        const unlisteners = await Promise.all([
+          wireguard.onBackendEvent(wireguard.STATUS_EVENT, () => get().syncStatus()),
+          wireguard.onBackendEvent<wireguard.FailoverAttempt>(
+            wireguard.FAILOVER_EVENT,
+            (attempt) => {
+              if (!attempt.succeeded) {
+                set({
+                  connectionNotice: `Couldn't connect to ${attempt.server_name}, trying again`,
+                });
+              } else if (attempt.candidate > 0) {
+                set({ connectionNotice: `Connected to ${attempt.server_name} instead` });
+                get().syncStatus();
+              } else {
+                set({ connectionNotice: null });
+              }
+            }
+          ),
+          wireguard.onBackendEvent<wireguard.ConfigDiff>(
+            wireguard.CONFIG_CHANGED_EVENT,
+            (diff) => {
+              const fields = [...new Set(diff.changes.map((change) => change.field))];
+              set({
+                connectionNotice: `The server's configuration changed: ${fields.join(", ")}`,
+              });
+            }
+          ),
+          wireguard.onBackendEvent<wireguard.ServerRotation>(
+            wireguard.ROTATED_EVENT,
+            (rotation) => {
+              set({ connectionNotice: `Rotated to ${rotation.to_server_name}` });
+              get().syncStatus();
+            }
+          ),
+        ]);
+        return () => unlisteners.forEach((unlisten) => unlisten());
+      },
     }),
     {
       name: "sacvpn-vpn-storage",
@@ -0,0 +1,15 @@
+import React from "react";
+import ReactDOM from "react-dom/client";
+import StatsWidget from "./components/StatsWidget";
+import "./index.css";
+
+// Disable right-click context menu in production
+if (import.meta.env.PROD) {
+  document.addEventListener("contextmenu", (e) => e.preventDefault());
+}
+
+ReactDOM.createRoot(document.getElementById("root") as HTMLElement).render(
+  <React.StrictMode>
+    <StatsWidget />
+  </React.StrictMode>
+);